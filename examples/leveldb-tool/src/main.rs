@@ -19,12 +19,12 @@ fn get(db: &mut DB, k: &str) {
 
 fn put(db: &mut DB, k: &str, v: &str) {
     db.put(k.as_bytes(), v.as_bytes()).unwrap();
-    db.flush().unwrap();
+    db.sync().unwrap();
 }
 
 fn delete(db: &mut DB, k: &str) {
     db.delete(k.as_bytes()).unwrap();
-    db.flush().unwrap();
+    db.sync().unwrap();
 }
 
 fn iter(db: &mut DB) {
@@ -41,7 +41,8 @@ fn iter(db: &mut DB) {
 }
 
 fn compact(db: &mut DB, from: &str, to: &str) {
-    db.compact_range(from.as_bytes(), to.as_bytes()).unwrap();
+    db.compact_range(Some(from.as_bytes()), Some(to.as_bytes()))
+        .unwrap();
 }
 
 fn main() {
@@ -17,7 +17,7 @@ fn fill_db(db: &mut DB, entries: usize) -> Result<(), Box<dyn Error>> {
         db.put(k.as_bytes(), v.as_bytes())?;
 
         if i % 100 == 0 {
-            db.flush()?;
+            db.sync()?;
         }
     }
     Ok(())
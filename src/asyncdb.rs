@@ -19,6 +19,7 @@ enum Request {
     Delete { key: Vec<u8> },
     Write { batch: WriteBatch, sync: bool },
     Flush,
+    Sync,
     GetAt { snapshot: SnapshotRef, key: Vec<u8> },
     Get { key: Vec<u8> },
     GetSnapshot,
@@ -105,6 +106,8 @@ impl AsyncDB {
             }),
         }
     }
+    /// flush writes the memtable to a table, like `DB::flush()`. Use `sync()` to only make
+    /// writes durable.
     pub async fn flush(&self) -> Result<()> {
         let r = self.process_request(Request::Flush).await?;
         match r {
@@ -116,6 +119,18 @@ impl AsyncDB {
             }),
         }
     }
+    /// sync makes all writes so far durable, like `DB::sync()`.
+    pub async fn sync(&self) -> Result<()> {
+        let r = self.process_request(Request::Sync).await?;
+        match r {
+            Response::OK => Ok(()),
+            Response::Error(s) => Err(s),
+            _ => Err(Status {
+                code: StatusCode::AsyncError,
+                err: "Wrong response type in AsyncDB.".to_string(),
+            }),
+        }
+    }
     pub async fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let r = self.process_request(Request::Get { key }).await?;
         match r {
@@ -208,8 +223,23 @@ impl AsyncDB {
         while let Some(message) = recv.blocking_recv() {
             match message.req {
                 Request::Close => {
-                    message.resp_channel.send(Response::OK).ok();
+                    // Shut down gracefully: stop accepting requests, and reject those that are
+                    // already queued (e.g. compactions that have not started yet) instead of
+                    // executing them. The database is closed (and its lock released) before
+                    // close() returns.
                     recv.close();
+                    while let Some(queued) = recv.blocking_recv() {
+                        queued
+                            .resp_channel
+                            .send(Response::Error(Status {
+                                code: StatusCode::Aborted,
+                                err: "AsyncDB is shutting down".to_string(),
+                            }))
+                            .ok();
+                    }
+                    let ok = db.sync();
+                    drop(db);
+                    send_response(message.resp_channel, ok);
                     return;
                 }
                 Request::Put { key, val } => {
@@ -228,6 +258,10 @@ impl AsyncDB {
                     let ok = db.flush();
                     send_response(message.resp_channel, ok);
                 }
+                Request::Sync => {
+                    let ok = db.sync();
+                    send_response(message.resp_channel, ok);
+                }
                 Request::GetAt { snapshot, key } => {
                     let snapshot_id = snapshot.0;
                     if let Some(snapshot) = snapshots.get(&snapshot_id) {
@@ -265,7 +299,7 @@ impl AsyncDB {
                     send_response(message.resp_channel, Ok(()));
                 }
                 Request::CompactRange { from, to } => {
-                    let ok = db.compact_range(&from, &to);
+                    let ok = db.compact_range(Some(&from), Some(&to));
                     send_response(message.resp_channel, ok);
                 }
             }
@@ -280,3 +314,80 @@ fn send_response(ch: oneshot::Sender<Response>, result: Result<()>) {
         ch.send(Response::OK).ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::options;
+
+    fn queue(send: &mpsc::Sender<Message>, req: Request) -> oneshot::Receiver<Response> {
+        let (tx, rx) = oneshot::channel();
+        send.blocking_send(Message {
+            req,
+            resp_channel: tx,
+        })
+        .ok();
+        rx
+    }
+
+    fn is_aborted(r: Response) -> bool {
+        matches!(
+            r,
+            Response::Error(Status {
+                code: StatusCode::Aborted,
+                ..
+            })
+        )
+    }
+
+    #[test]
+    fn test_asyncdb_close_rejects_queued_requests() {
+        let opt = options::for_test();
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for i in 0..2000 {
+            db.put(format!("key{:05}", i).as_bytes(), b"value").unwrap();
+        }
+
+        // Queue all requests before the server starts, so that the ones behind Close have
+        // definitely not been started yet.
+        let (send, recv) = mpsc::channel(CHANNEL_BUFFER_SIZE);
+        let compact = || Request::CompactRange {
+            from: b"key00000".to_vec(),
+            to: b"key01999".to_vec(),
+        };
+        let before: Vec<_> = (0..3).map(|_| queue(&send, compact())).collect();
+        let close = queue(&send, Request::Close);
+        let after: Vec<_> = (0..3).map(|_| queue(&send, compact())).collect();
+        let put = queue(
+            &send,
+            Request::Put {
+                key: b"late".to_vec(),
+                val: b"value".to_vec(),
+            },
+        );
+
+        let server = thread::spawn(move || AsyncDB::run_server(db, recv));
+
+        for rx in before {
+            assert!(matches!(rx.blocking_recv(), Ok(Response::OK)));
+        }
+        assert!(matches!(close.blocking_recv(), Ok(Response::OK)));
+        for rx in after {
+            assert!(is_aborted(rx.blocking_recv().ok().unwrap()));
+        }
+        assert!(is_aborted(put.blocking_recv().ok().unwrap()));
+        server.join().unwrap();
+
+        // The lock has been released, and the data is intact.
+        let mut db = DB::open("db", opt).unwrap();
+        for i in 0..2000 {
+            assert_eq!(
+                Some(b"value".to_vec()),
+                db.get(format!("key{:05}", i).as_bytes())
+            );
+        }
+        assert_eq!(None, db.get(b"late"));
+    }
+}
@@ -1,6 +1,6 @@
 use std::{cmp::Ordering, rc::Rc};
 
-use crate::{options::Options, types::LdbIterator};
+use crate::{cache::Charge, options::Options, types::LdbIterator};
 
 use integer_encoding::{FixedInt, VarInt};
 
@@ -50,6 +50,7 @@ impl Block {
 
             key: Vec::new(),
             val_offset: 0,
+            past_end: false,
         }
     }
 
@@ -64,6 +65,17 @@ impl Block {
             opt,
         }
     }
+
+    /// Returns the length of the block contents in bytes.
+    pub fn size(&self) -> usize {
+        self.block.len()
+    }
+}
+
+impl Charge for Block {
+    fn charge(&self) -> usize {
+        self.size()
+    }
 }
 
 /// BlockIter is an iterator over the entries in a block. It doesn't depend on the Block's
@@ -86,6 +98,8 @@ pub struct BlockIter {
     key: Vec<u8>,
     /// Offset of the current value within the block.
     val_offset: usize,
+    /// Set after a seek past the last entry.
+    past_end: bool,
 }
 
 impl BlockIter {
@@ -154,28 +168,13 @@ impl BlockIter {
         self.key
             .extend_from_slice(&self.block[off..off + non_shared]);
     }
-
-    pub fn seek_to_last(&mut self) {
-        if self.number_restarts() > 0 {
-            let num_restarts = self.number_restarts();
-            self.seek_to_restart_point(num_restarts - 1);
-        } else {
-            self.reset();
-        }
-
-        // Stop at last entry, before the iterator becomes invalid.
-        //
-        // We're checking the position before calling advance; if a restart point points to the
-        // last entry, calling advance() will directly reset the iterator.
-        while self.offset < self.restarts_off {
-            self.advance();
-        }
-        assert!(self.valid());
-    }
 }
 
 impl LdbIterator for BlockIter {
     fn advance(&mut self) -> bool {
+        if self.past_end {
+            return false;
+        }
         if self.offset >= self.restarts_off {
             self.reset();
             return false;
@@ -203,9 +202,38 @@ impl LdbIterator for BlockIter {
         self.val_offset = 0;
         self.current_restart_ix = 0;
         self.key.clear();
+        self.past_end = false;
+    }
+
+    fn seek_to_last(&mut self) {
+        self.past_end = false;
+        if self.restarts_off == 0 {
+            // Empty block.
+            self.reset();
+            return;
+        }
+        if self.number_restarts() > 0 {
+            let num_restarts = self.number_restarts();
+            self.seek_to_restart_point(num_restarts - 1);
+        } else {
+            self.reset();
+        }
+
+        // Stop at last entry, before the iterator becomes invalid.
+        //
+        // We're checking the position before calling advance; if a restart point points to the
+        // last entry, calling advance() will directly reset the iterator.
+        while self.offset < self.restarts_off {
+            self.advance();
+        }
+        assert!(self.valid());
     }
 
     fn prev(&mut self) -> bool {
+        if self.past_end {
+            self.seek_to_last();
+            return self.valid();
+        }
         // as in the original implementation -- seek to last restart point, then look for key
         let orig_offset = self.current_entry_offset;
 
@@ -276,6 +304,8 @@ impl LdbIterator for BlockIter {
                 return;
             }
         }
+        self.reset();
+        self.past_end = true;
     }
 
     fn valid(&self) -> bool {
@@ -301,7 +331,7 @@ mod tests {
     use crate::{
         block_builder::BlockBuilder,
         options,
-        test_util::{test_iterator_properties, LdbIteratorIter},
+        test_util::{test_iterator_properties, test_iterator_seek_past_end, LdbIteratorIter},
         types::current_key_val,
     };
 
@@ -328,12 +358,13 @@ mod tests {
         let mut data = get_data();
         data.truncate(4);
         for &(k, v) in data.iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
         }
         let block_contents = builder.finish();
 
-        let block = Block::new(o.clone(), block_contents).iter();
+        let block = Block::new(o.clone(), block_contents.clone()).iter();
         test_iterator_properties(block);
+        test_iterator_seek_past_end(Block::new(o.clone(), block_contents).iter(), b"zzz");
     }
 
     #[test]
@@ -359,7 +390,7 @@ mod tests {
         let mut builder = BlockBuilder::new(options::for_test());
 
         for &(k, v) in data.iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
         }
 
         let block_contents = builder.finish();
@@ -384,7 +415,7 @@ mod tests {
         let mut builder = BlockBuilder::new(o.clone());
 
         for &(k, v) in data.iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
         }
 
         let block_contents = builder.finish();
@@ -431,7 +462,7 @@ mod tests {
         let mut builder = BlockBuilder::new(o.clone());
 
         for &(k, v) in data.iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
         }
 
         let block_contents = builder.finish();
@@ -480,6 +511,49 @@ mod tests {
         assert_eq!(current_key_val(&block), None);
     }
 
+    #[test]
+    fn test_block_seek_restart_points() {
+        // Keys k000, k002, ..., k058; the odd ones are absent.
+        let key = |i: usize| format!("k{:03}", i).into_bytes();
+        let keys: Vec<Vec<u8>> = (0..30).map(|i| key(2 * i)).collect();
+
+        for interval in [1, 3, 4, 16, 64] {
+            let mut o = options::for_test();
+            o.block_restart_interval = interval;
+            let mut builder = BlockBuilder::new(o.clone());
+            for k in keys.iter() {
+                builder.add(k, k).unwrap();
+            }
+            let block = Block::new(o, builder.finish());
+            let mut iter = block.iter();
+            assert_eq!(keys.len().div_ceil(interval), iter.number_restarts());
+
+            // Present keys, including the first and last of each restart interval, land on
+            // themselves; absent keys between two present ones on the next one.
+            for i in 0..59 {
+                iter.seek(&key(i));
+                let expected = key(i.next_multiple_of(2));
+                assert_eq!(
+                    Some((expected.clone(), expected)),
+                    current_key_val(&iter),
+                    "interval {} seek {}",
+                    interval,
+                    i
+                );
+            }
+            // Before the first and after the last key.
+            iter.seek(b"a");
+            assert_eq!(
+                Some(keys[0].clone()),
+                current_key_val(&iter).map(|(k, _)| k)
+            );
+            iter.seek(b"k059");
+            assert!(!iter.valid());
+            iter.seek(b"z");
+            assert!(!iter.valid());
+        }
+    }
+
     #[test]
     fn test_block_seek_to_last() {
         let mut o = options::for_test();
@@ -494,7 +568,7 @@ mod tests {
             let mut builder = BlockBuilder::new(o.clone());
 
             for &(k, v) in data.iter() {
-                builder.add(k, v);
+                builder.add(k, v).unwrap();
             }
 
             let block_contents = builder.finish();
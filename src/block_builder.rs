@@ -2,7 +2,16 @@ use std::cmp::Ordering;
 
 use integer_encoding::{FixedIntWriter, VarIntWriter};
 
-use crate::{block::BlockContents, Options};
+use crate::{
+    block::BlockContents,
+    error::{err, Result, StatusCode},
+    Options,
+};
+
+/// With `Options::adaptive_restart`, an entry sharing fewer than this many bytes with the previous
+/// key starts a new restart point. It then costs at most this many bytes more, plus 4 bytes for the
+/// restart offset.
+const ADAPTIVE_RESTART_MIN_SHARED: usize = 4;
 
 /// BlockBuilder contains functionality for building a block consisting of consecutive key-value
 /// entries.
@@ -51,7 +60,20 @@ impl BlockBuilder {
         self.counter = 0;
     }
 
-    pub fn add(&mut self, key: &[u8], val: &[u8]) {
+    /// check_key returns an `InvalidArgument` error if `key` is longer than
+    /// `Options::max_key_length`.
+    pub fn check_key(&self, key: &[u8]) -> Result<()> {
+        match self.opt.max_key_length {
+            Some(max) if key.len() > max => err(
+                StatusCode::InvalidArgument,
+                &format!("key of length {} exceeds max_key_length {}", key.len(), max),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn add(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        self.check_key(key)?;
         assert!(self.restart_counter <= self.opt.block_restart_interval);
         assert!(
             self.buffer.is_empty()
@@ -70,7 +92,13 @@ impl BlockBuilder {
             while shared < smallest && self.last_key[shared] == key[shared] {
                 shared += 1;
             }
-        } else {
+        }
+
+        let adaptive_restart = self.opt.adaptive_restart
+            && self.restart_counter > 0
+            && shared < ADAPTIVE_RESTART_MIN_SHARED;
+        if self.restart_counter >= self.opt.block_restart_interval || adaptive_restart {
+            shared = 0;
             self.restarts.push(self.buffer.len() as u32);
             self.last_key.clear();
             self.restart_counter = 0;
@@ -97,6 +125,7 @@ impl BlockBuilder {
 
         self.restart_counter += 1;
         self.counter += 1;
+        Ok(())
     }
 
     pub fn finish(mut self) -> BlockContents {
@@ -122,7 +151,12 @@ impl BlockBuilder {
 
 #[cfg(test)]
 mod tests {
-    use crate::options;
+    use crate::{
+        block::Block,
+        options,
+        test_util::LdbIteratorIter,
+        types::{current_key_val, LdbIterator},
+    };
 
     use super::*;
 
@@ -148,7 +182,7 @@ mod tests {
         let mut builder = BlockBuilder::new(o);
 
         for &(k, v) in get_data().iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
             assert!(builder.restart_counter <= 3);
             assert_eq!(builder.last_key(), k);
         }
@@ -166,7 +200,7 @@ mod tests {
         let d = get_data();
 
         for &(k, v) in d.iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
             assert!(builder.restart_counter <= 3);
             assert_eq!(builder.last_key(), k);
         }
@@ -186,9 +220,65 @@ mod tests {
 
         let mut builder = BlockBuilder::new(options::for_test());
         for &(k, v) in d.iter() {
-            builder.add(k, v);
+            builder.add(k, v).unwrap();
             assert_eq!(k, builder.last_key());
         }
     }
+    #[test]
+    fn test_block_builder_max_key_length() {
+        let mut o = options::for_test();
+        o.max_key_length = Some(11);
+        let mut builder = BlockBuilder::new(o);
+
+        builder.add(b"key1", b"value1").unwrap();
+        let d = get_data();
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            builder.add(d[1].0, d[1].1).unwrap_err().code
+        );
+        // The rejected key left no trace.
+        assert_eq!(1, builder.entries());
+        assert_eq!(b"key1", builder.last_key());
+        builder.add(b"prefix_key1", b"value").unwrap();
+        assert_eq!(2, builder.entries());
+    }
+
+    #[test]
+    fn test_block_builder_adaptive_restart() {
+        // Keys sharing a long prefix, and keys differing in the first byte.
+        let similar: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("user/{:06}", i).into_bytes())
+            .collect();
+        let mut dissimilar: Vec<Vec<u8>> = (0..100u32)
+            .map(|i| format!("{:08x}", i.wrapping_mul(2654435761)).into_bytes())
+            .collect();
+        dissimilar.sort();
+
+        let build = |keys: &[Vec<u8>], adaptive| {
+            let mut o = options::for_test();
+            o.adaptive_restart = adaptive;
+            let mut builder = BlockBuilder::new(o.clone());
+            for k in keys {
+                builder.add(k, b"value").unwrap();
+            }
+            let restarts = builder.restarts.len();
+            let block = Block::new(o, builder.finish());
+            let mut iter = block.iter();
+            let decoded: Vec<Vec<u8>> = LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect();
+            assert_eq!(keys, decoded.as_slice());
+            for k in keys {
+                iter.seek(k);
+                assert_eq!(Some(k), current_key_val(&iter).map(|(k, _)| k).as_ref());
+            }
+            restarts
+        };
+
+        // One restart every 16 entries, unless keys are dissimilar.
+        assert_eq!(7, build(&similar, false));
+        assert_eq!(7, build(&similar, true));
+        assert_eq!(7, build(&dissimilar, false));
+        assert!(build(&dissimilar, true) > 50);
+    }
+
     // Additional test coverage is provided by tests in block.rs.
 }
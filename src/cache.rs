@@ -155,7 +155,13 @@ impl<T> LRUList<T> {
 
 pub type CacheKey = [u8; 16];
 pub type CacheID = u64;
-type CacheEntry<T> = (T, LRUHandle<CacheKey>);
+type CacheEntry<T> = (T, LRUHandle<CacheKey>, usize);
+
+/// Charge is implemented by types that can be stored in a byte-size-aware `Cache`; `charge()`
+/// returns the number of bytes an element accounts for.
+pub trait Charge {
+    fn charge(&self) -> usize;
+}
 
 /// Implementation of `SharedLRUCache`.
 /// Based on a HashMap; the elements are linked in order to support the LRU ordering.
@@ -166,16 +172,26 @@ pub struct Cache<T> {
     list: LRUList<CacheKey>,
     map: HashMap<CacheKey, CacheEntry<T>>,
     cap: usize,
+    // Sum of the charges of all elements, and how an element is charged.
+    usage: usize,
+    charge: fn(&T) -> usize,
     id: u64,
 }
 
 impl<T> Cache<T> {
+    /// Returns a cache holding up to `capacity` elements.
     pub fn new(capacity: usize) -> Cache<T> {
+        Cache::with_charge(capacity, |_| 1)
+    }
+
+    fn with_charge(capacity: usize, charge: fn(&T) -> usize) -> Cache<T> {
         assert!(capacity > 0);
         Cache {
             list: LRUList::new(),
             map: HashMap::with_capacity(1024),
             cap: capacity,
+            usage: 0,
+            charge,
             id: 0,
         }
     }
@@ -192,26 +208,44 @@ impl<T> Cache<T> {
         self.list.count()
     }
 
-    /// The capacity of this cache
+    /// The capacity of this cache, in elements or bytes depending on how it was created.
     pub fn cap(&self) -> usize {
         self.cap
     }
 
+    /// The summed charge of all elements in the cache; for a count-based cache this is equal to
+    /// `count()`.
+    pub fn usage(&self) -> usize {
+        self.usage
+    }
+
     /// Insert a new element into the cache. The returned `CacheHandle` can be used for further
     /// operations on that element.
-    /// If the capacity has been reached, the least recently used element is removed form the
+    /// If the capacity has been exceeded, the least recently used elements are removed form the
     /// cache.
     pub fn insert(&mut self, key: &CacheKey, elem: T) {
-        if self.list.count() >= self.cap {
+        let charge = (self.charge)(&elem);
+        // Replace an existing entry in place; otherwise the list would hold the key twice.
+        if let Some((old, lru_handle, old_charge)) = self.map.get_mut(key) {
+            *old = elem;
+            self.usage = self.usage - *old_charge + charge;
+            *old_charge = charge;
+            self.list.reinsert_front(*lru_handle);
+        } else {
+            let lru_handle = self.list.insert(*key);
+            self.map.insert(*key, (elem, lru_handle, charge));
+            self.usage += charge;
+        }
+
+        // The element inserted last is kept even if it exceeds the capacity on its own.
+        while self.usage > self.cap && self.list.count() > 1 {
             if let Some(removed_key) = self.list.remove_last() {
-                assert!(self.map.remove(&removed_key).is_some());
+                let (_, _, removed_charge) = self.map.remove(&removed_key).unwrap();
+                self.usage -= removed_charge;
             } else {
                 panic!("could not remove_last(); bug!");
             }
         }
-
-        let lru_handle = self.list.insert(*key);
-        self.map.insert(*key, (elem, lru_handle));
     }
 
     /// Retrieve an element from the cache.
@@ -219,7 +253,7 @@ impl<T> Cache<T> {
     pub fn get<'a>(&'a mut self, key: &CacheKey) -> Option<&'a T> {
         match self.map.get(key) {
             None => None,
-            Some((elem, lru_handle)) => {
+            Some((elem, lru_handle, _)) => {
                 self.list.reinsert_front(*lru_handle);
                 Some(elem)
             }
@@ -230,18 +264,27 @@ impl<T> Cache<T> {
     pub fn remove(&mut self, key: &CacheKey) -> Option<T> {
         match self.map.remove(key) {
             None => None,
-            Some((elem, lru_handle)) => {
+            Some((elem, lru_handle, charge)) => {
                 self.list.remove(lru_handle);
+                self.usage -= charge;
                 Some(elem)
             }
         }
     }
 }
 
+impl<T: Charge> Cache<T> {
+    /// Returns a cache whose elements are charged by their byte size, evicting elements once
+    /// their total size exceeds `bytes`.
+    pub fn with_capacity_bytes(bytes: usize) -> Cache<T> {
+        Cache::with_charge(bytes, T::charge)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{Cache, CacheKey};
-    use crate::cache::LRUList;
+    use crate::{block::Block, cache::LRUList, options};
 
     fn make_key(a: u8, b: u8, c: u8) -> CacheKey {
         [a, b, c, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]
@@ -300,6 +343,102 @@ mod tests {
         assert_eq!(cache.get(&h_899), Some(&899));
     }
 
+    #[test]
+    fn test_blockcache_cache_get_promotes() {
+        let mut cache = Cache::new(3);
+
+        let h_1 = make_key(1, 0, 0);
+        let h_2 = make_key(2, 0, 0);
+        let h_3 = make_key(3, 0, 0);
+        let h_4 = make_key(4, 0, 0);
+        let h_5 = make_key(5, 0, 0);
+
+        cache.insert(&h_1, 1);
+        cache.insert(&h_2, 2);
+        cache.insert(&h_3, 3);
+
+        // h_1 is the least recently used entry; touching it makes h_2 the next victim.
+        assert_eq!(cache.get(&h_1), Some(&1));
+        cache.insert(&h_4, 4);
+        assert_eq!(cache.count(), 3);
+        assert_eq!(cache.get(&h_2), None);
+
+        // Repeated gets keep h_1 alive, so h_3 is evicted next.
+        assert_eq!(cache.get(&h_1), Some(&1));
+        assert_eq!(cache.get(&h_1), Some(&1));
+        cache.insert(&h_5, 5);
+        assert_eq!(cache.get(&h_3), None);
+        assert_eq!(cache.get(&h_1), Some(&1));
+        assert_eq!(cache.get(&h_4), Some(&4));
+        assert_eq!(cache.get(&h_5), Some(&5));
+    }
+
+    #[test]
+    fn test_blockcache_cache_reinsert_key() {
+        let mut cache = Cache::new(2);
+
+        let h_1 = make_key(1, 0, 0);
+        let h_2 = make_key(2, 0, 0);
+        let h_3 = make_key(3, 0, 0);
+
+        cache.insert(&h_1, 1);
+        cache.insert(&h_2, 2);
+        cache.insert(&h_1, 11);
+        assert_eq!(cache.count(), 2);
+        assert_eq!(cache.get(&h_1), Some(&11));
+
+        // Overwriting h_1 promoted it, so h_2 is evicted.
+        cache.insert(&h_3, 3);
+        assert_eq!(cache.count(), 2);
+        assert_eq!(cache.get(&h_2), None);
+        assert_eq!(cache.get(&h_1), Some(&11));
+        assert_eq!(cache.get(&h_3), Some(&3));
+    }
+
+    #[test]
+    fn test_blockcache_cache_capacity_bytes() {
+        let block = |size: usize| Block::new(options::for_test(), vec![0; size]);
+        let mut cache = Cache::with_capacity_bytes(1000);
+
+        let h_1 = make_key(1, 0, 0);
+        let h_2 = make_key(2, 0, 0);
+        let h_3 = make_key(3, 0, 0);
+        let h_4 = make_key(4, 0, 0);
+        let h_5 = make_key(5, 0, 0);
+
+        cache.insert(&h_1, block(100));
+        cache.insert(&h_2, block(200));
+        cache.insert(&h_3, block(300));
+        assert_eq!(cache.count(), 3);
+        assert_eq!(cache.usage(), 600);
+
+        // 600 + 500 bytes exceed the budget; evicting the oldest 100 byte block is enough.
+        cache.insert(&h_4, block(500));
+        assert_eq!(cache.count(), 3);
+        assert_eq!(cache.usage(), 1000);
+        assert!(cache.get(&h_1).is_none());
+        assert!(cache.get(&h_2).is_some());
+
+        // A single large block pushes out all three others (h_2 was promoted by the get above).
+        cache.insert(&h_5, block(900));
+        assert_eq!(cache.count(), 1);
+        assert_eq!(cache.usage(), 900);
+        assert!(cache.get(&h_5).is_some());
+
+        // Many small blocks fit where a count-based cache of this size would evict.
+        for i in 0..50 {
+            cache.insert(&make_key(10, i, 0), block(10));
+        }
+        assert_eq!(cache.count(), 50);
+        assert_eq!(cache.usage(), 500);
+
+        // Replacing an entry adjusts the usage by the difference.
+        cache.insert(&make_key(10, 0, 0), block(20));
+        assert_eq!(cache.usage(), 510);
+        assert_eq!(cache.remove(&make_key(10, 0, 0)).unwrap().size(), 20);
+        assert_eq!(cache.usage(), 490);
+    }
+
     #[test]
     fn test_blockcache_lru_remove() {
         let mut lru = LRUList::<usize>::new();
@@ -1,4 +1,9 @@
-use std::{cmp::Ordering, rc::Rc};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    rc::Rc,
+    sync::{Mutex, OnceLock},
+};
 
 use crate::{
     key_types::{self, LookupKey},
@@ -60,31 +65,21 @@ impl Cmp for DefaultCmp {
             diff_at += 1;
         }
 
-        let mut sep = Vec::with_capacity(a.len() + 1);
-        sep.extend_from_slice(a);
         // Try increasing a and check if it's still smaller than b. First find the last byte
         // smaller than 0xff, and then increment that byte. Only if the separator is lesser than b.
         // return it.
-        let mut i = a.len() - 1;
-        while i > 0 && sep[i] == 0xff {
-            i -= 1;
-        }
-        if sep[i] < 0xff {
+        if let Some(i) = a.iter().rposition(|&c| c < 0xff) {
+            let mut sep = a.to_vec();
             sep[i] += 1;
             if self.cmp(&sep, b) == Ordering::Less {
                 return sep;
-            } else {
-                sep[i] -= 1;
             }
         }
 
-        // Backup case: either `a` is full of 0xff, or all different places are less than 2
-        // characters apart.
-        // The result is not necessarily short, but a good separator: e.g., "abc" vs "abd" ->
-        // "abc\0", which is greater than abc and lesser than abd.
-        // Append a 0 byte; by making it longer than a, it will compare greater to it.
-        sep.extend_from_slice(&[0]);
-        sep
+        // Backup case: `a` is empty or full of 0xff, or all different places are less than 2
+        // characters apart (e.g. "abc" vs "abd"). Like LevelDB, don't shorten; `a` itself is a
+        // valid separator.
+        a.to_vec()
     }
 
     fn find_short_succ(&self, a: &[u8]) -> Vec<u8> {
@@ -102,6 +97,76 @@ impl Cmp for DefaultCmp {
     }
 }
 
+/// A comparator for keys starting with a fixed-length prefix (e.g. a shard ID). Keys are ordered
+/// bytewise by their first `prefix_len` bytes, and then by the remainder using `inner`.
+///
+/// Separators and successors never leave the prefix of the key they are derived from, so index
+/// entries of a table always belong to a shard that is actually stored in it. Keys shorter than
+/// `prefix_len` are treated as consisting only of a prefix.
+#[derive(Clone)]
+pub struct FixedPrefixCmp {
+    pub prefix_len: usize,
+    pub inner: WrappedCmp,
+}
+
+impl FixedPrefixCmp {
+    pub fn new(prefix_len: usize, inner: WrappedCmp) -> FixedPrefixCmp {
+        FixedPrefixCmp { prefix_len, inner }
+    }
+
+    /// split returns the prefix and the remainder of a key.
+    fn split<'a>(&self, key: &'a [u8]) -> (&'a [u8], &'a [u8]) {
+        key.split_at(key.len().min(self.prefix_len))
+    }
+}
+
+impl Cmp for FixedPrefixCmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let (pa, ra) = self.split(a);
+        let (pb, rb) = self.split(b);
+        pa.cmp(pb).then_with(|| self.inner.cmp(ra, rb))
+    }
+
+    /// id includes the prefix length and the inner comparator's ID, so that a database can't be
+    /// opened with a different key order. Each distinct ID is allocated once and then reused.
+    fn id(&self) -> &'static str {
+        static IDS: OnceLock<Mutex<HashMap<(usize, &'static str), &'static str>>> = OnceLock::new();
+        let mut ids = IDS.get_or_init(Default::default).lock().unwrap();
+        ids.entry((self.prefix_len, self.inner.id()))
+            .or_insert_with(|| {
+                let id = format!(
+                    "leveldb.FixedPrefixComparator.{}.{}",
+                    self.prefix_len,
+                    self.inner.id()
+                );
+                Box::leak(id.into_boxed_str())
+            })
+    }
+
+    fn find_shortest_sep(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        let (pa, ra) = self.split(a);
+        let (pb, rb) = self.split(b);
+        if pa == pb {
+            let mut sep = pa.to_vec();
+            sep.extend_from_slice(&self.inner.find_shortest_sep(ra, rb));
+            return sep;
+        }
+        if pa.len() < self.prefix_len {
+            // Any successor of an incomplete prefix may be in another shard.
+            return a.to_vec();
+        }
+        // b is in a later shard; stay in the shard of a.
+        self.find_short_succ(a)
+    }
+
+    fn find_short_succ(&self, key: &[u8]) -> Vec<u8> {
+        let (p, r) = self.split(key);
+        let mut succ = p.to_vec();
+        succ.extend_from_slice(&self.inner.find_short_succ(r));
+        succ
+    }
+}
+
 impl InternalKeyCmp {
     /// cmp_inner compares a and b using the underlying comparator (the "user comparator").
     pub fn cmp_inner(&self, a: &[u8], b: &[u8]) -> Ordering {
@@ -175,6 +240,7 @@ impl Cmp for MemtableKeyCmp {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_util::ReverseCmp;
     use key_types::LookupKey;
 
     #[test]
@@ -197,7 +263,7 @@ mod tests {
         );
         assert_eq!(
             DefaultCmp.find_shortest_sep("a".as_bytes(), "b".as_bytes()),
-            "a\0".as_bytes()
+            "a".as_bytes()
         );
         assert_eq!(
             DefaultCmp.find_shortest_sep("abc".as_bytes(), "zzz".as_bytes()),
@@ -211,6 +277,20 @@ mod tests {
             DefaultCmp.find_shortest_sep("".as_bytes(), "".as_bytes()),
             "".as_bytes()
         );
+        // Cases that used to panic or return a separator not less than b.
+        assert_eq!(
+            DefaultCmp.find_shortest_sep(&[0xff], &[0xff, 0x00]),
+            &[0xff]
+        );
+        assert_eq!(DefaultCmp.find_shortest_sep(&[], b"abc"), &[] as &[u8]);
+        assert_eq!(
+            DefaultCmp.find_shortest_sep(&[0xff, 0xff], &[]),
+            &[0xff, 0xff]
+        );
+        assert_eq!(
+            DefaultCmp.find_shortest_sep(&[0xfe, 0xff], &[0xff]),
+            &[0xfe, 0xff]
+        );
     }
 
     #[test]
@@ -295,6 +375,68 @@ mod tests {
         assert_eq!(Ordering::Greater, cmp.cmp_inner(e, d));
     }
 
+    #[test]
+    fn test_cmp_fixedprefixcmp() {
+        let cmp = FixedPrefixCmp::new(4, Rc::new(Box::new(ReverseCmp)));
+        // Ordered by prefix first, then by the remainder using the inner comparator.
+        let mut keys: Vec<&[u8]> =
+            vec![b"0002aaa", b"0001aaa", b"0001zzz", b"0001", b"000", b"0002"];
+        keys.sort_by(|a, b| cmp.cmp(a, b));
+        let want: Vec<&[u8]> = vec![b"000", b"0001zzz", b"0001aaa", b"0001", b"0002aaa", b"0002"];
+        assert_eq!(want, keys);
+        assert_eq!(Ordering::Equal, cmp.cmp(b"0001abc", b"0001abc"));
+    }
+
+    #[test]
+    fn test_cmp_fixedprefixcmp_id() {
+        let cmp = FixedPrefixCmp::new(4, Rc::new(Box::new(ReverseCmp)));
+        assert_eq!(
+            "leveldb.FixedPrefixComparator.4.test.ReverseComparator",
+            cmp.id()
+        );
+        // The same ID is reused.
+        let other = FixedPrefixCmp::new(4, Rc::new(Box::new(ReverseCmp)));
+        assert!(std::ptr::eq(cmp.id(), other.id()));
+        assert_ne!(cmp.id(), FixedPrefixCmp::new(8, cmp.inner.clone()).id());
+        assert_ne!(
+            cmp.id(),
+            FixedPrefixCmp::new(4, Rc::new(Box::new(DefaultCmp))).id()
+        );
+    }
+
+    #[test]
+    fn test_cmp_fixedprefixcmp_shortest_sep() {
+        let cmp = FixedPrefixCmp::new(8, Rc::new(Box::new(DefaultCmp)));
+        // Same shard: the remainder is shortened.
+        assert_eq!(
+            cmp.find_shortest_sep(b"shard001abcd", b"shard001abcf"),
+            b"shard001abce"
+        );
+        assert_eq!(
+            cmp.find_shortest_sep(b"shard001abc", b"shard001zzz"),
+            b"shard001b"
+        );
+        // Different shards: DefaultCmp would return "shard002", which is in a shard that neither
+        // key belongs to.
+        assert_eq!(
+            DefaultCmp.find_shortest_sep(b"shard001zzz", b"shard005aaa"),
+            b"shard002"
+        );
+        let sep = cmp.find_shortest_sep(b"shard001zzz", b"shard005aaa");
+        assert_eq!(sep, b"shard001{");
+        assert_eq!(Ordering::Less, cmp.cmp(b"shard001zzz", &sep));
+        assert_eq!(Ordering::Less, cmp.cmp(&sep, b"shard005aaa"));
+        assert_eq!(
+            cmp.find_shortest_sep(b"shard001", b"shard002"),
+            b"shard001\xff"
+        );
+        // Incomplete prefixes are not shortened.
+        assert_eq!(cmp.find_shortest_sep(b"shard", b"shard002"), b"shard");
+
+        assert_eq!(cmp.find_short_succ(b"shard001abc"), b"shard001b");
+        assert_eq!(cmp.find_short_succ(b"sha"), b"sha\xff");
+    }
+
     #[test]
     #[should_panic]
     fn test_cmp_memtablekeycmp_panics() {
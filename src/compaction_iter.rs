@@ -0,0 +1,492 @@
+//! CompactionIterator decides which entries of a compaction's inputs are written to its outputs.
+
+use std::{cmp::Ordering, collections::VecDeque, rc::Rc};
+
+use crate::{
+    cmp::Cmp,
+    infolog::Logger,
+    key_types::{parse_internal_key, LookupKey, RangeTombstone, UserKey, ValueType},
+    merge_op::{self, BoxedMergeOperator},
+    options::Options,
+    ttl::{self, Expiry, TtlIter},
+    types::{LdbIterator, SequenceNumber, Shared, MAX_SEQUENCE_NUMBER},
+};
+
+/// CompactionIterator wraps the merged inputs of a compaction (in internal key order) and skips
+/// the entries that no reader can observe anymore:
+///
+/// - versions of a key that are shadowed by a newer version which all snapshots see, i.e. whose
+///   sequence number is at most `smallest_seq` (the oldest live snapshot);
+/// - entries covered by a range tombstone that all snapshots see;
+/// - deletions that all snapshots see, if `is_base_level` returns true for the key: then no
+///   older version of it exists in levels below the compaction's output level.
+///
+/// All other versions are kept, as they are needed by live snapshots.
+///
+/// A merge operand doesn't shadow older versions, as it applies to them. If a merge operator is
+/// set, the operands that all snapshots see are merged with the version they apply to into a
+/// single value.
+///
+/// If `Options::ttl` is set, expired values are treated as deletions. Values merged from
+/// operands are stamped with the time of the compaction.
+pub struct CompactionIterator<F: FnMut(UserKey) -> bool> {
+    input: Box<dyn LdbIterator>,
+    cmp: Rc<Box<dyn Cmp>>,
+    log: Option<Shared<Logger>>,
+    merge_op: Option<BoxedMergeOperator>,
+    expiry: Option<Expiry>,
+    smallest_seq: SequenceNumber,
+    tombstones: Vec<RangeTombstone>,
+    is_base_level: F,
+
+    valid: bool,
+    key: Vec<u8>,
+    val: Vec<u8>,
+    have_ukey: bool,
+    current_ukey: Vec<u8>,
+    // Sequence number of the previous entry with the same user key.
+    last_seq_for_key: SequenceNumber,
+    dropped: usize,
+    // Entries read ahead while trying to merge operands, to be returned after the current one.
+    pending: VecDeque<(Vec<u8>, Vec<u8>)>,
+    // Set if the input is already positioned at the entry following the current one.
+    input_ahead: bool,
+}
+
+impl<F: FnMut(UserKey) -> bool> CompactionIterator<F> {
+    pub fn new(
+        input: Box<dyn LdbIterator>,
+        opt: &Options,
+        smallest_seq: SequenceNumber,
+        tombstones: Vec<RangeTombstone>,
+        is_base_level: F,
+    ) -> CompactionIterator<F> {
+        let expiry = Expiry::from_options(opt);
+        let input: Box<dyn LdbIterator> = match expiry {
+            Some(expiry) => Box::new(TtlIter::new(input, expiry, false)),
+            None => input,
+        };
+        CompactionIterator {
+            input,
+            cmp: opt.cmp.clone(),
+            log: opt.log.clone(),
+            merge_op: opt.merge_operator.clone(),
+            expiry,
+            smallest_seq,
+            tombstones,
+            is_base_level,
+
+            valid: false,
+            key: vec![],
+            val: vec![],
+            have_ukey: false,
+            current_ukey: vec![],
+            last_seq_for_key: MAX_SEQUENCE_NUMBER,
+            dropped: 0,
+            pending: VecDeque::new(),
+            input_ahead: false,
+        }
+    }
+
+    /// seek_to_first positions the iterator at the first entry to be kept.
+    pub fn seek_to_first(&mut self) {
+        self.input.seek_to_first();
+        self.have_ukey = false;
+        self.current_ukey.clear();
+        self.last_seq_for_key = MAX_SEQUENCE_NUMBER;
+        self.pending.clear();
+        self.input_ahead = false;
+        self.find_next_kept();
+    }
+
+    /// advance moves to the next entry to be kept, returning false if there is none.
+    pub fn advance(&mut self) -> bool {
+        if !self.valid {
+            return false;
+        }
+        if let Some((k, v)) = self.pending.pop_front() {
+            self.key = k;
+            self.val = v;
+            return true;
+        }
+        if self.input_ahead {
+            self.input_ahead = false;
+        } else {
+            self.input.advance();
+        }
+        self.find_next_kept()
+    }
+
+    pub fn valid(&self) -> bool {
+        self.valid
+    }
+
+    /// current stores the current entry (with an internal key) in key and val.
+    pub fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        if !self.valid {
+            return false;
+        }
+        key.clone_from(&self.key);
+        val.clone_from(&self.val);
+        true
+    }
+
+    /// dropped returns the number of input entries skipped so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    fn find_next_kept(&mut self) -> bool {
+        while self.input.current(&mut self.key, &mut self.val) {
+            if !self.should_drop() {
+                self.valid = true;
+                let (ktyp, seq, _) = parse_internal_key(&self.key);
+                if ktyp == ValueType::TypeMerge
+                    && seq <= self.smallest_seq
+                    && self.merge_op.is_some()
+                {
+                    self.collapse_merge();
+                }
+                return true;
+            }
+            self.dropped += 1;
+            self.input.advance();
+        }
+        self.valid = false;
+        false
+    }
+
+    /// should_drop decides about the entry in self.key, which follows the previous one in
+    /// internal key order.
+    fn should_drop(&mut self) -> bool {
+        let (ktyp, seq, ukey) = parse_internal_key(&self.key);
+        if seq == 0 {
+            // Parsing failed.
+            log!(self.log, "Encountered seq=0 in key: {:?}", &self.key);
+            self.last_seq_for_key = MAX_SEQUENCE_NUMBER;
+            self.have_ukey = false;
+            self.current_ukey.clear();
+            return true;
+        }
+
+        if !self.have_ukey || self.cmp.cmp(ukey, &self.current_ukey) != Ordering::Equal {
+            // First occurrence of this key.
+            self.current_ukey.clear();
+            self.current_ukey.extend_from_slice(ukey);
+            self.have_ukey = true;
+            self.last_seq_for_key = MAX_SEQUENCE_NUMBER;
+        }
+
+        let smallest_seq = self.smallest_seq;
+        let cmp = self.cmp.as_ref().as_ref();
+        // The entry is covered by a range tombstone that all snapshots see; or a newer entry of
+        // the same key is visible to all snapshots; or the entry is a deletion that all
+        // snapshots see, and no older entry of the key is below the compacted levels.
+        let drop = self
+            .tombstones
+            .iter()
+            .any(|t| t.seq <= smallest_seq && t.covers(cmp, ukey, seq))
+            || self.last_seq_for_key <= smallest_seq
+            || (ktyp == ValueType::TypeDeletion
+                && seq <= smallest_seq
+                && (self.is_base_level)(ukey));
+        if ktyp != ValueType::TypeMerge {
+            self.last_seq_for_key = seq;
+        }
+        drop
+    }
+
+    /// collapse_merge is called when the current entry is a merge operand that all snapshots
+    /// see. It reads the older entries of the key: if they end with a value or a deletion, or if
+    /// no older entry exists below the compacted levels, the operands are merged into a single
+    /// value that replaces the current entry. Otherwise, the operands are returned unchanged.
+    fn collapse_merge(&mut self) {
+        let (_, seq, ukey) = parse_internal_key(&self.key);
+        let ukey = ukey.to_vec();
+        let mut entries = vec![(self.key.clone(), self.val.clone())];
+        let (mut k, mut v) = (vec![], vec![]);
+        // None while no value or deletion has been found.
+        let mut existing = None;
+        let mut terminator = None;
+
+        loop {
+            self.input.advance();
+            if !self.input.current(&mut k, &mut v) {
+                break;
+            }
+            let (typ, s, uk) = parse_internal_key(&k);
+            if s == 0 || self.cmp.cmp(uk, &ukey) != Ordering::Equal {
+                break;
+            }
+            let cmp = self.cmp.as_ref().as_ref();
+            let smallest_seq = self.smallest_seq;
+            let covered = self
+                .tombstones
+                .iter()
+                .any(|t| t.seq <= smallest_seq && t.covers(cmp, uk, s));
+            if typ == ValueType::TypeMerge && !covered {
+                entries.push((k.clone(), v.clone()));
+                continue;
+            }
+            // The value or deletion is merged into the operands, and dropped.
+            existing = Some(
+                (typ == ValueType::TypeValue && !covered).then(|| match self.expiry {
+                    Some(_) => ttl::unstamped(&v).to_vec(),
+                    None => v.clone(),
+                }),
+            );
+            terminator = Some((k.clone(), v.clone(), s));
+            self.input.advance();
+            break;
+        }
+        self.input_ahead = true;
+
+        if existing.is_none() && (self.is_base_level)(&ukey) {
+            existing = Some(None);
+        }
+        let merged = existing.and_then(|existing| {
+            let operands: Vec<Vec<u8>> = entries.iter().rev().map(|(_, v)| v.clone()).collect();
+            merge_op::full_merge(
+                self.merge_op.as_ref(),
+                &ukey,
+                existing.as_deref(),
+                &operands,
+            )
+            .map_err(|e| log!(self.log, "Keeping merge operands of {:?}: {}", ukey, e))
+            .ok()
+        });
+
+        match merged {
+            Some(val) => {
+                self.dropped += entries.len() - 1 + terminator.map_or(0, |_| 1);
+                self.key = LookupKey::new_full(&ukey, seq, ValueType::TypeValue)
+                    .internal_key()
+                    .to_vec();
+                self.val = match self.expiry {
+                    Some(expiry) => expiry.stamp(&val),
+                    None => val,
+                };
+                // Older entries are shadowed by the merged value.
+                self.last_seq_for_key = seq;
+            }
+            None => {
+                self.pending.extend(entries.into_iter().skip(1));
+                if let Some((k, v, s)) = terminator {
+                    self.pending.push_back((k, v));
+                    self.last_seq_for_key = s;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        cmp::InternalKeyCmp, memtable::MemTable, merge_op::testutil::append_operator,
+        merging_iter::MergingIter, options,
+    };
+
+    fn collect<F: FnMut(UserKey) -> bool>(
+        it: &mut CompactionIterator<F>,
+    ) -> Vec<(String, SequenceNumber)> {
+        let (mut k, mut v) = (vec![], vec![]);
+        let mut out = vec![];
+        it.seek_to_first();
+        while it.current(&mut k, &mut v) {
+            let (_, seq, ukey) = parse_internal_key(&k);
+            out.push((String::from_utf8(ukey.to_vec()).unwrap(), seq));
+            it.advance();
+        }
+        out
+    }
+
+    fn new_iter<F: FnMut(UserKey) -> bool>(
+        smallest_seq: SequenceNumber,
+        tombstones: Vec<RangeTombstone>,
+        is_base_level: F,
+    ) -> CompactionIterator<F> {
+        use ValueType::*;
+        let opt = options::for_test();
+        // Two inputs, e.g. tables from adjacent levels.
+        let (mut a, mut b) = (
+            MemTable::new(opt.cmp.clone()),
+            MemTable::new(opt.cmp.clone()),
+        );
+        a.add(12, TypeValue, b"aaa", b"a12");
+        a.add(5, TypeValue, b"aaa", b"a5");
+        a.add(20, TypeDeletion, b"bbb", b"");
+        a.add(4, TypeDeletion, b"ccc", b"");
+        a.add(30, TypeValue, b"ddd", b"d30");
+        b.add(8, TypeValue, b"aaa", b"a8");
+        b.add(2, TypeValue, b"aaa", b"a2");
+        b.add(3, TypeValue, b"bbb", b"b3");
+        b.add(1, TypeValue, b"ccc", b"c1");
+        b.add(9, TypeValue, b"ddd", b"d9");
+        let input = MergingIter::new(
+            Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone()))),
+            vec![Box::new(a.iter()), Box::new(b.iter())],
+        );
+        CompactionIterator::new(
+            Box::new(input),
+            &opt,
+            smallest_seq,
+            tombstones,
+            is_base_level,
+        )
+    }
+
+    fn owned(v: &[(&str, SequenceNumber)]) -> Vec<(String, SequenceNumber)> {
+        v.iter().map(|&(k, s)| (k.to_string(), s)).collect()
+    }
+
+    #[test]
+    fn test_compaction_iter_no_snapshots() {
+        // All entries are visible to the oldest snapshot: only the newest version of each key
+        // is kept, and deletions are dropped at the base level.
+        let mut it = new_iter(100, vec![], |_| true);
+        assert_eq!(owned(&[("aaa", 12), ("ddd", 30)]), collect(&mut it));
+        assert_eq!(8, it.dropped());
+
+        // Deletions must be kept if older versions may exist below.
+        let mut it = new_iter(100, vec![], |k| k != b"bbb");
+        assert_eq!(
+            owned(&[("aaa", 12), ("bbb", 20), ("ddd", 30)]),
+            collect(&mut it)
+        );
+    }
+
+    #[test]
+    fn test_compaction_iter_snapshots() {
+        // The snapshot at 6 sees aaa@5 and bbb@3, so newer versions must be kept along with
+        // them. ccc was deleted before the snapshot.
+        let mut it = new_iter(6, vec![], |_| true);
+        assert_eq!(
+            owned(&[
+                ("aaa", 12),
+                ("aaa", 8),
+                ("aaa", 5),
+                ("bbb", 20),
+                ("bbb", 3),
+                ("ddd", 30),
+                ("ddd", 9),
+            ]),
+            collect(&mut it)
+        );
+    }
+
+    #[test]
+    fn test_compaction_iter_range_tombstones() {
+        let tombstones = vec![
+            // Visible to all snapshots.
+            RangeTombstone {
+                begin: b"a".to_vec(),
+                end: b"b".to_vec(),
+                seq: 10,
+            },
+            // Not visible to the snapshot at 15.
+            RangeTombstone {
+                begin: b"d".to_vec(),
+                end: b"e".to_vec(),
+                seq: 40,
+            },
+        ];
+        let mut it = new_iter(15, tombstones, |_| true);
+        assert_eq!(
+            owned(&[
+                ("aaa", 12),
+                ("bbb", 20),
+                ("bbb", 3),
+                ("ddd", 30),
+                ("ddd", 9)
+            ]),
+            collect(&mut it)
+        );
+    }
+
+    #[test]
+    fn test_compaction_iter_merge() {
+        use ValueType::*;
+        let mut opt = options::for_test();
+        let mut mt = MemTable::new(opt.cmp.clone());
+        mt.add(9, TypeMerge, b"aaa", b"3");
+        mt.add(8, TypeMerge, b"aaa", b"2");
+        mt.add(7, TypeValue, b"aaa", b"1");
+        mt.add(6, TypeValue, b"aaa", b"0");
+        mt.add(5, TypeMerge, b"bbb", b"2");
+        mt.add(4, TypeMerge, b"bbb", b"1");
+        mt.add(3, TypeMerge, b"ccc", b"1");
+        mt.add(2, TypeDeletion, b"ccc", b"");
+        mt.add(1, TypeValue, b"ccc", b"0");
+
+        let run = |opt: &Options, smallest_seq, base: bool| {
+            let mut it =
+                CompactionIterator::new(Box::new(mt.iter()), opt, smallest_seq, vec![], |_| base);
+            let (mut k, mut v) = (vec![], vec![]);
+            let mut out = vec![];
+            it.seek_to_first();
+            while it.current(&mut k, &mut v) {
+                let (typ, seq, ukey) = parse_internal_key(&k);
+                out.push((
+                    String::from_utf8(ukey.to_vec()).unwrap(),
+                    seq,
+                    typ,
+                    String::from_utf8(v.clone()).unwrap(),
+                ));
+                it.advance();
+            }
+            out
+        };
+        let e = |k: &str, seq, typ, v: &str| (k.to_string(), seq, typ, v.to_string());
+
+        // Without an operator, operands don't shadow the versions they apply to.
+        assert_eq!(
+            vec![
+                e("aaa", 9, TypeMerge, "3"),
+                e("aaa", 8, TypeMerge, "2"),
+                e("aaa", 7, TypeValue, "1"),
+                e("bbb", 5, TypeMerge, "2"),
+                e("bbb", 4, TypeMerge, "1"),
+                e("ccc", 3, TypeMerge, "1"),
+            ],
+            run(&opt, 100, true)
+        );
+
+        opt.merge_operator = Some(append_operator());
+        assert_eq!(
+            vec![
+                e("aaa", 9, TypeValue, "123"),
+                e("bbb", 5, TypeValue, "12"),
+                e("ccc", 3, TypeValue, "1"),
+            ],
+            run(&opt, 100, true)
+        );
+        // bbb may have older versions below; the snapshot at 8 doesn't see aaa@9.
+        assert_eq!(
+            vec![
+                e("aaa", 9, TypeMerge, "3"),
+                e("aaa", 8, TypeValue, "12"),
+                e("bbb", 5, TypeMerge, "2"),
+                e("bbb", 4, TypeMerge, "1"),
+                e("ccc", 3, TypeValue, "1"),
+            ],
+            run(&opt, 8, false)
+        );
+
+        // A range tombstone not visible to the snapshot at 9 doesn't delete the value the
+        // operands apply to.
+        let tombstones = vec![RangeTombstone {
+            begin: b"a".to_vec(),
+            end: b"b".to_vec(),
+            seq: 10,
+        }];
+        let mut it = CompactionIterator::new(Box::new(mt.iter()), &opt, 9, tombstones, |_| true);
+        let (mut k, mut v) = (vec![], vec![]);
+        it.seek_to_first();
+        assert!(it.current(&mut k, &mut v));
+        let (typ, seq, ukey) = parse_internal_key(&k);
+        assert_eq!((b"aaa".as_slice(), 9, TypeValue), (ukey, seq, typ));
+        assert_eq!(b"123", v.as_slice());
+    }
+}
@@ -75,3 +75,48 @@ impl Compressor for SnappyCompressor {
         Ok(snap::raw::Decoder::new().decompress_vec(&block)?)
     }
 }
+
+/// A compressor that compress data with Zstandard
+///
+/// It default id is `2`. Only available with the `zstd` feature.
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy)]
+pub struct ZstdCompressor {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCompressor {
+    /// Returns a compressor using the given zstd compression level.
+    pub fn new(level: i32) -> ZstdCompressor {
+        ZstdCompressor { level }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCompressor {
+    fn default() -> Self {
+        // zstd's default level.
+        ZstdCompressor::new(3)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl CompressorId for ZstdCompressor {
+    const ID: u8 = 2;
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for ZstdCompressor {
+    fn encode(&self, block: Vec<u8>) -> crate::Result<Vec<u8>> {
+        zstd::stream::encode_all(block.as_slice(), self.level).map_err(|e| {
+            crate::Status::new(crate::error::StatusCode::CompressionError, &e.to_string())
+        })
+    }
+
+    fn decode(&self, block: Vec<u8>) -> crate::Result<Vec<u8>> {
+        zstd::stream::decode_all(block.as_slice()).map_err(|e| {
+            crate::Status::new(crate::error::StatusCode::CompressionError, &e.to_string())
+        })
+    }
+}
@@ -1,22 +1,26 @@
 //! db_impl contains the implementation of the database interface and high-level compaction and
 //! maintenance logic.
 
+use integer_encoding::VarInt;
+
 use std::{
     cmp::Ordering,
     io::{self, BufWriter, Write},
-    mem::swap,
+    mem::{self, swap},
     path::{Path, PathBuf},
     rc::Rc,
+    sync::atomic,
 };
 
 use crate::{
     cmp::{Cmp, InternalKeyCmp},
-    db_iter::DBIterator,
+    compaction_iter::CompactionIterator,
+    db_iter::{ColumnFamilyIter, DBIterator},
     env::{Env, FileLock},
     error::{err, Result, StatusCode},
     filter::{BoxedFilterPolicy, InternalFilterPolicy},
     infolog::Logger,
-    key_types::{parse_internal_key, InternalKey, LookupKey, ValueType},
+    key_types::{parse_internal_key, InternalKey, LookupKey, RangeTombstone, UserKey, ValueType},
     log::{LogReader, LogWriter},
     memtable::MemTable,
     merging_iter::MergingIter,
@@ -24,11 +28,13 @@ use crate::{
     snapshot::{Snapshot, SnapshotList},
     table_builder::TableBuilder,
     table_cache::{table_file_name, TableCache},
+    table_reader::{BlockReadStats, Table},
+    ttl::{Expiry, TtlIter},
     types::{
-        parse_file_name, share, FileMetaData, FileNum, FileType, LdbIterator, SequenceNumber,
-        Shared, MAX_SEQUENCE_NUMBER, NUM_LEVELS,
+        parse_file_name, share, FileMetaData, FileNum, FileType, GetResult, LdbIterator, Range,
+        SequenceNumber, Shared, MAX_SEQUENCE_NUMBER, NUM_LEVELS,
     },
-    version::Version,
+    version::{total_size, Version},
     version_edit::VersionEdit,
     version_set::{
         manifest_file_name, read_current_file, set_current_file, Compaction, VersionSet,
@@ -52,11 +58,21 @@ pub struct DB {
 
     log: Option<LogWriter<BufWriter<Box<dyn Write>>>>,
     log_num: Option<FileNum>,
+    // Time of the last log sync, according to Env::now_monotonic().
+    last_log_sync: Option<u64>,
+    // Set if synchronous writes were logged since the last sync.
+    log_sync_pending: bool,
     cache: Shared<TableCache>,
     vset: Shared<VersionSet>,
     snaps: SnapshotList,
 
     cstats: [CompactionStats; NUM_LEVELS],
+
+    // Set by open_for_readonly(); all modifications fail with NotSupported.
+    read_only: bool,
+    // Corrupt tables excluded during recovery, to be renamed once the manifest no longer
+    // references them.
+    excluded_tables: Vec<FileNum>,
 }
 
 unsafe impl Send for DB {}
@@ -70,7 +86,12 @@ impl DB {
         let name = name.as_ref();
         let log = open_info_log(opt.env.as_ref().as_ref(), name);
         opt.log = Some(share(log));
+        DB::new_with_logger(name, opt)
+    }
 
+    /// new_with_logger is like new(), but uses the info log set in opt instead of opening the LOG
+    /// file in the database directory.
+    fn new_with_logger(name: &Path, opt: Options) -> DB {
         let cache = share(TableCache::new(name, opt.clone(), opt.max_open_file - 10));
         let vset = VersionSet::new(name, opt.clone(), cache.clone());
 
@@ -83,16 +104,20 @@ impl DB {
             internal_cmp: Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone()))),
             fpol: InternalFilterPolicy::new(opt.filter_policy.clone()),
 
-            mem: MemTable::new(opt.cmp.clone()),
+            mem: MemTable::new_with_budget(opt.cmp.clone(), opt.memory_budget.clone()),
             imm: None,
 
             opt,
             log: None,
             log_num: None,
+            last_log_sync: None,
+            log_sync_pending: false,
             cache,
             vset: share(vset),
             snaps: SnapshotList::new(),
             cstats: Default::default(),
+            read_only: false,
+            excluded_tables: vec![],
         }
     }
 
@@ -100,12 +125,25 @@ impl DB {
         self.vset.borrow().current()
     }
 
+    /// name returns the directory of the database, as passed to `open()`.
+    pub fn name(&self) -> &Path {
+        &self.name
+    }
+
+    /// options returns the options the database was opened with. The info log is the one
+    /// opened by the database, unless it was opened with a custom logger.
+    pub fn options(&self) -> &Options {
+        &self.opt
+    }
+
     /// Opens or creates a new or existing database. `name` is the name of the directory containing
     /// the database.
     ///
     /// Whether a new database is created and what happens if a database exists at the given path
-    /// depends on the options set (`create_if_missing`, `error_if_exists`).
+    /// depends on the options set (`create_if_missing`, `error_if_exists`). Invalid options (see
+    /// `Options::validate()`) are rejected with `InvalidArgument`.
     pub fn open<P: AsRef<Path>>(name: P, opt: Options) -> Result<DB> {
+        opt.validate()?;
         let name = name.as_ref();
         let mut db = DB::new(name, opt);
         let mut ve = VersionEdit::new();
@@ -119,7 +157,10 @@ impl DB {
                 .env
                 .open_writable_file(Path::new(&log_file_name(&db.name, lognum)))?;
             ve.set_log_num(lognum);
-            db.log = Some(LogWriter::new(BufWriter::new(logfile)));
+            db.log = Some(LogWriter::new(
+                BufWriter::new(logfile),
+                db.opt.checksum_type,
+            ));
             db.log_num = Some(lognum);
         }
 
@@ -127,6 +168,7 @@ impl DB {
             ve.set_log_num(db.log_num.unwrap_or(0));
             db.vset.borrow_mut().log_and_apply(ve)?;
         }
+        db.rename_excluded_tables();
 
         db.delete_obsolete_files()?;
         db.maybe_do_compaction()?;
@@ -134,10 +176,370 @@ impl DB {
         Ok(db)
     }
 
+    /// Opens an existing database without modifying it: no lock is acquired, and the write-ahead
+    /// logs are only replayed into memory. All reads (including iterators) work as usual, while
+    /// writes and compactions fail with `NotSupported`.
+    ///
+    /// As the database is not locked, the state seen is the one at the time of opening; it can be
+    /// refreshed with `catch_up()`, which allows using the handle as a secondary instance
+    /// following a primary that writes to the same database.
+    pub fn open_for_readonly<P: AsRef<Path>>(name: P, mut opt: Options) -> Result<DB> {
+        opt.validate()?;
+        opt.reuse_logs = false;
+        opt.reuse_manifest = false;
+        let name = name.as_ref();
+        let mut db = DB::new_with_logger(name, opt);
+        db.read_only = true;
+
+        if let Err(e) = read_current_file(&db.opt.env, &db.name) {
+            if e.code == StatusCode::NotFound {
+                return err(StatusCode::NotFound, "database does not exist");
+            }
+            return Err(e);
+        }
+        db.catch_up()?;
+        Ok(db)
+    }
+
+    /// bootstrap_from_sstables creates a new database containing the entries of the given table
+    /// files, e.g. ones written by `write_sorted_table()`. The tables are copied into the
+    /// database, and a manifest referencing them is written.
+    ///
+    /// If the tables' key ranges are disjoint, they are placed at the level a memtable flush
+    /// would be written to; otherwise, they all go to level 0, where entries of the same key are
+    /// ordered by sequence number. The database must not exist yet; if copying the tables fails,
+    /// it is left empty.
+    pub fn bootstrap_from_sstables<P: AsRef<Path>>(
+        name: P,
+        files: &[PathBuf],
+        mut opt: Options,
+    ) -> Result<DB> {
+        let name = name.as_ref();
+        if read_current_file(&opt.env, name).is_ok() {
+            return err(StatusCode::AlreadyExists, "database already exists");
+        }
+        if files.is_empty() {
+            return err(StatusCode::InvalidArgument, "no tables to import");
+        }
+        let mut metas = vec![];
+        for file in files {
+            metas.push(read_table_metadata(&opt, file)?.0);
+        }
+
+        let icmp = InternalKeyCmp(opt.cmp.clone());
+        let mut order: Vec<usize> = (0..files.len()).collect();
+        order.sort_by(|&a, &b| icmp.cmp(&metas[a].smallest, &metas[b].smallest));
+        let disjoint = order.windows(2).all(|w| {
+            let largest = parse_internal_key(&metas[w[0]].largest).2;
+            let smallest = parse_internal_key(&metas[w[1]].smallest).2;
+            opt.cmp.cmp(largest, smallest) == Ordering::Less
+        });
+
+        opt.create_if_missing = true;
+        let mut db = DB::open(name, opt)?;
+        let level = if disjoint {
+            let (first, last) = (&metas[order[0]], &metas[order[order.len() - 1]]);
+            db.current().borrow().pick_memtable_output_level(
+                parse_internal_key(&first.smallest).2,
+                parse_internal_key(&last.largest).2,
+            )
+        } else {
+            // Files with higher numbers are newer in level 0.
+            order.sort_by_key(|&i| metas[i].largest_seqno);
+            0
+        };
+
+        let mut ve = VersionEdit::new();
+        let mut copied = vec![];
+        let r = (|| -> Result<()> {
+            for &i in &order {
+                let num = db.vset.borrow_mut().new_file_number();
+                let path = table_file_name(&db.name, num);
+                copy_table_file(db.opt.env.as_ref().as_ref(), &files[i], &path)?;
+                copied.push(path);
+
+                let mut meta = metas[i].clone();
+                meta.num = num;
+                log!(
+                    db.opt.log,
+                    "Imported table {:?} as num={} into L{}: size={}",
+                    files[i],
+                    num,
+                    level,
+                    meta.size
+                );
+                ve.add_file(level, meta);
+            }
+            Ok(())
+        })();
+        if let Err(e) = r {
+            for path in copied {
+                let _ = db.opt.env.delete(&path);
+            }
+            return Err(e);
+        }
+
+        let last_seq = metas.iter().map(|m| m.largest_seqno).max().unwrap_or(0);
+        {
+            let mut vset = db.vset.borrow_mut();
+            vset.last_seq = vset.last_seq.max(last_seq);
+        }
+        db.vset.borrow_mut().log_and_apply(ve)?;
+        db.maybe_do_compaction()?;
+        Ok(db)
+    }
+
+    /// ingest_table adds the externally produced table at `path` (e.g. written by a
+    /// `TableBuilder`) to the database. The table is validated and copied into the database
+    /// directory under a new file number. It is placed in the deepest level such that neither
+    /// that level nor any level above it overlaps its key range; if level 0 overlaps, it is
+    /// added to level 0.
+    ///
+    /// The memtable is flushed first. If the table overlaps existing data, all of its sequence
+    /// numbers must be higher than those in the database, so that its entries shadow older
+    /// ones during reads and compactions; otherwise InvalidArgument is returned.
+    pub fn ingest_table(&mut self, path: &Path) -> Result<()> {
+        self.check_writable()?;
+        let (mut meta, smallest_seqno) = read_table_metadata(&self.opt, path)?;
+        self.flush()?;
+
+        let (smallest, largest) = (
+            parse_internal_key(&meta.smallest).2,
+            parse_internal_key(&meta.largest).2,
+        );
+        let ucmp = self.opt.cmp.clone();
+        let overlaps_tombstone = self.range_tombstones().iter().any(|t| {
+            ucmp.cmp(&t.begin, largest) != Ordering::Greater
+                && ucmp.cmp(smallest, &t.end) == Ordering::Less
+        });
+        let level;
+        {
+            let current = self.current();
+            let current = current.borrow();
+            let overlaps: Vec<bool> = (0..NUM_LEVELS)
+                .map(|l| current.overlap_in_level(l, &smallest, &largest))
+                .collect();
+            level = overlaps
+                .iter()
+                .position(|&o| o)
+                .map_or(NUM_LEVELS - 1, |l| l.saturating_sub(1));
+            // Range tombstones hide older entries just like data does.
+            if (overlaps.contains(&true) || overlaps_tombstone)
+                && smallest_seqno <= self.vset.borrow().last_seq
+            {
+                return err(
+                    StatusCode::InvalidArgument,
+                    "table overlaps existing data but its sequence numbers are not newer",
+                );
+            }
+        }
+
+        let num = self.vset.borrow_mut().new_file_number();
+        let dest = table_file_name(&self.name, num);
+        copy_table_file(self.opt.env.as_ref().as_ref(), path, &dest)?;
+
+        meta.num = num;
+        log!(
+            self.opt.log,
+            "Ingested table {:?} as num={} into L{}: size={}",
+            path,
+            num,
+            level,
+            meta.size
+        );
+        {
+            let mut vset = self.vset.borrow_mut();
+            vset.last_seq = vset.last_seq.max(meta.largest_seqno);
+        }
+        let mut ve = VersionEdit::new();
+        ve.add_file(level, meta);
+        if let Err(e) = self.vset.borrow_mut().log_and_apply(ve) {
+            let _ = self.opt.env.delete(&dest);
+            return Err(e);
+        }
+        self.maybe_do_compaction()
+    }
+
+    /// catch_up brings a database opened with `open_for_readonly()` up to date with the files
+    /// on disk: the manifest is read again, and the write-ahead logs not yet covered by it are
+    /// replayed into a new memtable. The previous state is kept if this fails, e.g. because the
+    /// primary instance deleted a file while it was being read; calling catch_up() again will
+    /// usually succeed then.
+    ///
+    /// Iterators obtained before continue to see the previous state. Snapshots don't: they only
+    /// pin a sequence number, so reads at a snapshot taken before catch_up() use the new state,
+    /// in which the primary may have compacted away versions that the snapshot would see.
+    pub fn catch_up(&mut self) -> Result<()> {
+        if !self.read_only {
+            return err(
+                StatusCode::NotSupported,
+                "catch_up() is only supported on read-only databases",
+            );
+        }
+        let mut vset = VersionSet::new(&self.name, self.opt.clone(), self.cache.clone());
+        vset.recover()?;
+
+        let mut log_files = vec![];
+        for file in &self.opt.env.children(&self.name)? {
+            if let Ok((num, FileType::Log)) = parse_file_name(file) {
+                if num >= vset.log_num || num >= vset.prev_log_num {
+                    log_files.push(num);
+                }
+            }
+        }
+        log_files.sort();
+
+        let mut mem =
+            MemTable::new_with_budget(self.opt.cmp.clone(), self.opt.memory_budget.clone());
+        let mut max_seq = 0;
+        for num in log_files {
+            max_seq = max_seq.max(self.replay_log_file(num, &mut mem)?);
+        }
+        // Sequence numbers handed out in snapshots must not go backwards.
+        vset.last_seq = vset.last_seq.max(max_seq).max(self.vset.borrow().last_seq);
+
+        self.vset = share(vset);
+        self.mem = mem;
+        Ok(())
+    }
+
+    /// replay_log_file reads a log file into `mem`, without writing any tables. It returns the
+    /// highest sequence number found.
+    fn replay_log_file(&self, log_num: FileNum, mem: &mut MemTable) -> Result<SequenceNumber> {
+        let filename = log_file_name(&self.name, log_num);
+        let logfile = self.opt.env.open_sequential_file(Path::new(&filename))?;
+        let mut logreader = LogReader::new(logfile, true, self.opt.checksum_type);
+        let mut scratch = vec![];
+        let mut batch = WriteBatch::new();
+        let mut max_seq = 0;
+
+        while let Ok(true) = logreader.read_record(&mut scratch) {
+            if scratch.len() < 12 {
+                continue;
+            }
+            batch.set_contents(&scratch);
+            batch.insert_into_memtable(batch.sequence(), mem);
+            max_seq = max_seq.max(batch.sequence() + batch.count() as u64 - 1);
+        }
+        Ok(max_seq)
+    }
+
+    /// repair rebuilds the manifest of a database whose manifest is missing or corrupted. All
+    /// readable table files are placed at level 0, and the entries of surviving write-ahead logs
+    /// are written to new tables; afterwards, the database is compacted, so that newer entries
+    /// shadow older ones regardless of the order in which the files are found.
+    ///
+    /// Table files that can't be read are moved to the `lost` subdirectory. Range tombstones are
+    /// only recorded in the manifest, so those are lost as well.
+    pub fn repair<P: AsRef<Path>>(name: P, opt: Options) -> Result<()> {
+        opt.validate()?;
+        let name = name.as_ref();
+        let children = opt.env.children(name).unwrap_or_default();
+        if !children.iter().any(|f| parse_file_name(f).is_ok()) {
+            return err(StatusCode::NotFound, "database does not exist");
+        }
+        let mut db = DB::new(name, opt.clone());
+        db.acquire_lock()?;
+
+        let (mut tables, mut logs) = (vec![], vec![]);
+        let mut next_num = 2;
+        for file in children {
+            if let Ok((num, typ)) = parse_file_name(&file) {
+                next_num = next_num.max(num + 1);
+                match typ {
+                    FileType::Table => tables.push(num),
+                    FileType::Log => logs.push(num),
+                    _ => {}
+                }
+            }
+        }
+        tables.sort();
+        logs.sort();
+
+        let mut files = vec![];
+        for num in tables {
+            let path = table_file_name(name, num);
+            match read_table_metadata(&db.opt, &path) {
+                Ok((mut meta, _)) => {
+                    meta.num = num;
+                    files.push(meta);
+                }
+                Err(e) => {
+                    log!(db.opt.log, "Repair: table {} is unreadable: {}", num, e);
+                    let lost = name.join("lost");
+                    db.opt.env.mkdir(&lost)?;
+                    db.opt
+                        .env
+                        .rename(&path, &lost.join(path.file_name().unwrap()))?;
+                }
+            }
+        }
+
+        let mut ve = VersionEdit::new();
+        let mut last_seq = 0;
+        for &num in &logs {
+            let mut mem = MemTable::new(db.opt.cmp.clone());
+            last_seq = last_seq.max(db.replay_log_file(num, &mut mem)?);
+            for t in mem.range_tombstones() {
+                ve.add_range_tombstone(t.clone());
+            }
+            if mem.len() > 0 {
+                let fmd = build_table(name, &db.opt, mem.iter(), next_num)?;
+                next_num += 1;
+                if fmd.size > 0 {
+                    files.push(fmd);
+                }
+            }
+        }
+        log!(
+            db.opt.log,
+            "Repair: recovered {} tables from {} logs",
+            files.len(),
+            logs.len()
+        );
+
+        for f in files {
+            last_seq = last_seq.max(f.largest_seqno);
+            ve.add_file(0, f);
+        }
+        let manifest_num = next_num;
+        ve.set_comparator_name(db.opt.cmp.id());
+        ve.set_filter_policy_name(db.opt.filter_policy.name());
+        ve.set_log_num(0);
+        ve.set_next_file(manifest_num + 1);
+        ve.set_last_seq(last_seq);
+        {
+            let manifest = manifest_file_name(name, manifest_num);
+            let manifest_file = db.opt.env.open_writable_file(&manifest)?;
+            let mut lw = LogWriter::new(manifest_file, db.opt.checksum_type);
+            lw.add_record(&ve.encode())?;
+            lw.sync(db.opt.env.as_ref().as_ref())?;
+        }
+        set_current_file(&db.opt.env, name, manifest_num)?;
+        // The logs' contents are in tables now.
+        for num in logs {
+            db.opt.env.delete(&log_file_name(name, num))?;
+        }
+        db.release_lock()?;
+        drop(db);
+
+        let mut db = DB::open(name, opt)?;
+        db.compact_range(None, None)
+    }
+
+    /// check_writable returns an error if the database has been opened read-only.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            return err(StatusCode::NotSupported, "database is opened read-only");
+        }
+        Ok(())
+    }
+
     /// initialize_db initializes a new database.
     fn initialize_db(&mut self) -> Result<()> {
         let mut ve = VersionEdit::new();
         ve.set_comparator_name(self.opt.cmp.id());
+        ve.set_filter_policy_name(self.opt.filter_policy.name());
         ve.set_log_num(0);
         ve.set_next_file(2);
         ve.set_last_seq(0);
@@ -145,9 +547,9 @@ impl DB {
         {
             let manifest = manifest_file_name(&self.name, 1);
             let manifest_file = self.opt.env.open_writable_file(Path::new(&manifest))?;
-            let mut lw = LogWriter::new(manifest_file);
+            let mut lw = LogWriter::new(manifest_file, self.opt.checksum_type);
             lw.add_record(&ve.encode())?;
-            lw.flush()?;
+            lw.sync(self.opt.env.as_ref().as_ref())?;
         }
         set_current_file(&self.opt.env, &self.name, 1)
     }
@@ -155,22 +557,23 @@ impl DB {
     /// recover recovers from the existing state on disk. If the wrapped result is `true`, then
     /// log_and_apply() should be called after recovery has finished.
     fn recover(&mut self, ve: &mut VersionEdit) -> Result<bool> {
-        if self.opt.error_if_exists && self.opt.env.exists(self.name.as_ref()).unwrap_or(false) {
+        // A database exists if it has a CURRENT file; an empty directory doesn't count.
+        let exists = self.opt.env.exists(&self.name.join("CURRENT"))?;
+        if exists && self.opt.error_if_exists {
             return err(StatusCode::AlreadyExists, "database already exists");
         }
 
         self.opt.env.mkdir(Path::new(&self.name)).unwrap();
         self.acquire_lock()?;
 
-        if let Err(e) = read_current_file(&self.opt.env, &self.name) {
-            if e.code == StatusCode::NotFound && self.opt.create_if_missing {
-                self.initialize_db()?;
-            } else {
+        if !exists {
+            if !self.opt.create_if_missing {
                 return err(
                     StatusCode::InvalidArgument,
                     "database does not exist and create_if_missing is false",
                 );
             }
+            self.initialize_db()?;
         }
         // If save_manifest is true, we should log_and_apply() later in order to write
         // manifest.
@@ -196,6 +599,9 @@ impl DB {
             log!(self.opt.log, "Missing at least these files: {:?}", expected);
             return err(StatusCode::Corruption, "missing live files (see log)");
         }
+        if self.opt.skip_corrupt_tables && self.exclude_corrupt_tables(ve) {
+            save_manifest = true;
+        }
 
         log_files.sort();
         for i in 0..log_files.len() {
@@ -217,6 +623,51 @@ impl DB {
         Ok(save_manifest)
     }
 
+    /// exclude_corrupt_tables opens all tables of the current version. Tables that fail to open
+    /// with a `Corruption` error are removed from the version by `ve`, and remembered for
+    /// `rename_excluded_tables()`. Other errors, e.g. transient I/O errors, leave the table in
+    /// place. Returns true if a table was excluded.
+    fn exclude_corrupt_tables(&mut self, ve: &mut VersionEdit) -> bool {
+        let current = self.current();
+        for (level, files) in current.borrow().files.iter().enumerate() {
+            for f in files {
+                let num = f.borrow().num;
+                let e = match self.cache.borrow_mut().get_table(num) {
+                    Ok(_) => continue,
+                    Err(e) => e,
+                };
+                if e.code != StatusCode::Corruption {
+                    log!(self.opt.log, "Keeping table num={}: {}", num, e);
+                    continue;
+                }
+                log!(
+                    self.opt.log,
+                    "Excluding corrupt table num={} at level {}: {}",
+                    num,
+                    level,
+                    e
+                );
+                ve.delete_file(level, num);
+                self.excluded_tables.push(num);
+            }
+        }
+        !self.excluded_tables.is_empty()
+    }
+
+    /// rename_excluded_tables renames the tables excluded by exclude_corrupt_tables() so that
+    /// they aren't deleted as obsolete files. It must only be called after the version edit
+    /// excluding them has been written to the manifest.
+    fn rename_excluded_tables(&mut self) {
+        for num in mem::take(&mut self.excluded_tables) {
+            let name = table_file_name(&self.name, num);
+            let mut corrupt = name.clone().into_os_string();
+            corrupt.push(".corrupt");
+            if let Err(e) = self.opt.env.rename(&name, Path::new(&corrupt)) {
+                log!(self.opt.log, "Renaming table num={} failed: {}", num, e);
+            }
+        }
+    }
+
     /// recover_log_file reads a single log file into a memtable, writing new L0 tables if necessary.
     /// If is_last is true, it checks whether the log file can be reused, and sets up
     /// the database's logging handles appropriately if that's the case.
@@ -234,21 +685,29 @@ impl DB {
         let mut logreader = LogReader::new(
             logfile, // checksum=
             true,
+            self.opt.checksum_type,
         );
         log!(self.opt.log, "Recovering log file {:?}", filename);
         let mut scratch = vec![];
-        let mut mem = MemTable::new(cmp.clone());
+        let mut mem = MemTable::new_with_budget(cmp.clone(), self.opt.memory_budget.clone());
         let mut batch = WriteBatch::new();
 
         let mut compactions = 0;
         let mut max_seq = 0;
         let mut save_manifest = false;
-
-        while let Ok(len) = logreader.read(&mut scratch) {
-            if len == 0 {
-                break;
+        let mut damaged = false;
+
+        loop {
+            match logreader.read_record(&mut scratch) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    log!(self.opt.log, "log file {:06} is damaged: {}", log_num, e);
+                    damaged = true;
+                    break;
+                }
             }
-            if len < 12 {
+            if scratch.len() < 12 {
                 // log!(
                 //     self.opt.log,
                 //     "corruption in log file {:06}: record shorter than 12B",
@@ -269,18 +728,25 @@ impl DB {
 
                 self.write_l0_table(&mem, ve, None)?;
                 save_manifest = true;
-                mem = MemTable::new(cmp.clone());
+                mem = MemTable::new_with_budget(cmp.clone(), self.opt.memory_budget.clone());
             }
             batch.clear();
         }
 
-        // Check if we can reuse the last log file.
-        if self.opt.reuse_logs && is_last && compactions == 0 {
+        // Check if we can reuse the last log file. Records appended after a damaged part would be
+        // lost on the next recovery, and big logs are better replaced by a table.
+        let oldsize = self.opt.env.size_of(Path::new(&filename))?;
+        if self.opt.reuse_logs
+            && is_last
+            && compactions == 0
+            && !damaged
+            && oldsize <= self.opt.max_file_size
+        {
             assert!(self.log.is_none());
             log!(self.opt.log, "reusing log file {:?}", filename);
-            let oldsize = self.opt.env.size_of(Path::new(&filename))?;
             let oldfile = self.opt.env.open_appendable_file(Path::new(&filename))?;
-            let lw = LogWriter::new_with_off(BufWriter::new(oldfile), oldsize);
+            let lw =
+                LogWriter::new_with_off(BufWriter::new(oldfile), oldsize, self.opt.checksum_type);
             self.log = Some(lw);
             self.log_num = Some(log_num);
             self.mem = mem;
@@ -327,7 +793,8 @@ impl DB {
 
                 // If we're here, delete this file.
                 if typ == FileType::Table {
-                    self.cache.borrow_mut().evict(num).unwrap();
+                    // The table may not have been opened.
+                    let _ = self.cache.borrow_mut().evict(num);
                 }
                 log!(self.opt.log, "Deleting file type={:?} num={}", typ, num);
                 if let Err(e) = self.opt.env.delete(&self.name.join(&name)) {
@@ -375,6 +842,13 @@ impl DB {
         self.write(wb, false)
     }
 
+    /// Like `put()`, but returns the sequence number assigned to the entry.
+    pub fn put_with_seq(&mut self, k: &[u8], v: &[u8]) -> Result<SequenceNumber> {
+        let mut wb = WriteBatch::new();
+        wb.put(k, v);
+        self.write_seq(wb, false)
+    }
+
     /// Deletes a single entry. Like with `put()`, you can call `flush()` to guarantee that
     /// the operation made it to disk.
     pub fn delete(&mut self, k: &[u8]) -> Result<()> {
@@ -383,71 +857,341 @@ impl DB {
         self.write(wb, false)
     }
 
-    //// Writes an entire WriteBatch. `sync` determines whether the write should be flushed to
-    /// disk.
-    pub fn write(&mut self, mut batch: WriteBatch, sync: bool) -> Result<()> {
+    /// Adds a merge operand for a single key; reads combine it with the current value using
+    /// `Options::merge_operator`. Fails with `InvalidArgument` if no merge operator is set.
+    pub fn merge(&mut self, k: &[u8], operand: &[u8]) -> Result<()> {
+        if self.opt.merge_operator.is_none() {
+            return err(
+                StatusCode::InvalidArgument,
+                "merge() requires Options::merge_operator",
+            );
+        }
+        let mut wb = WriteBatch::new();
+        wb.merge(k, operand);
+        self.write(wb, false)
+    }
+
+    /// get_or_insert_with returns the current value of `key`. If the key doesn't exist, `f()` is
+    /// called to compute a value, which is written and returned.
+    ///
+    /// The lookup and the write can't be interleaved with other operations on this DB handle,
+    /// because both require `&mut self`; this is not atomic with respect to other processes or
+    /// handles opened on the same database.
+    pub fn get_or_insert_with<F: FnOnce() -> Vec<u8>>(
+        &mut self,
+        key: &[u8],
+        f: F,
+    ) -> Result<Vec<u8>> {
+        self.check_writable()?;
+        let seq = self.vset.borrow().last_seq;
+        if let Some(v) = self.get_internal(seq, key)? {
+            return Ok(v);
+        }
+        let val = f();
+        let mut wb = WriteBatch::new();
+        wb.put(key, &val);
+        self.write(wb, false)?;
+        Ok(val)
+    }
+
+    /// put_sorted_iter adds all entries yielded by `it`, which must be sorted in strictly ascending
+    /// key order, by streaming them directly into new table files. Unlike `write()`, no
+    /// `WriteBatch` is materialized and the entries go through neither the log nor the memtable,
+    /// which makes this suitable for bulk loads. The memtable is compacted first so that the new
+    /// entries shadow all earlier ones.
+    ///
+    /// If the input is not sorted, an `InvalidArgument` error is returned and none of the entries
+    /// are added.
+    pub fn put_sorted_iter<K, V, I>(&mut self, it: I) -> Result<()>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        self.check_writable()?;
+        self.make_room_for_write(true)?;
+
+        let first_seq = self.vset.borrow().last_seq + 1;
+        let mut seq = first_seq;
+        let mut outputs: Vec<FileMetaData> = vec![];
+        let (mut key, mut lastkey) = (vec![], vec![]);
+
+        let r = (|| -> Result<()> {
+            let mut builder: Option<TableBuilder<Box<dyn Write>>> = None;
+            for (k, v) in it {
+                let k = k.as_ref();
+                if seq > first_seq
+                    && self.opt.cmp.cmp(k, &lastkey[..lastkey.len() - 8]) != Ordering::Greater
+                {
+                    return err(
+                        StatusCode::InvalidArgument,
+                        "put_sorted_iter: keys are not in strictly ascending order",
+                    );
+                }
+                key.clear();
+                key.extend_from_slice(k);
+                key.extend_from_slice(&(seq << 8 | ValueType::TypeValue as u64).to_le_bytes());
+                seq += 1;
+
+                if builder.is_none() {
+                    let num = self.vset.borrow_mut().new_file_number();
+                    let f = self
+                        .opt
+                        .env
+                        .open_writable_file(&table_file_name(&self.name, num))?;
+                    let f: Box<dyn Write> = Box::new(BufWriter::new(f));
+                    builder = Some(TableBuilder::new(self.opt.clone(), f));
+                    outputs.push(FileMetaData {
+                        num,
+                        smallest: key.clone(),
+                        ..Default::default()
+                    });
+                }
+                let b = builder.as_mut().unwrap();
+                b.add(&key, v.as_ref())?;
+                swap(&mut key, &mut lastkey);
+
+                if b.should_flush() {
+                    let out = outputs.last_mut().unwrap();
+                    out.size = builder
+                        .take()
+                        .unwrap()
+                        .finish_synced(self.opt.env.as_ref().as_ref())?;
+                    out.largest.clone_from(&lastkey);
+                    out.largest_seqno = seq - 1;
+                }
+            }
+            if let Some(b) = builder {
+                let out = outputs.last_mut().unwrap();
+                out.size = b.finish_synced(self.opt.env.as_ref().as_ref())?;
+                out.largest.clone_from(&lastkey);
+                out.largest_seqno = seq - 1;
+            }
+            // Verify that the new tables can be used.
+            for out in &outputs {
+                self.cache.borrow_mut().get_table(out.num)?;
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = r {
+            for out in &outputs {
+                let _ = self.opt.env.delete(&table_file_name(&self.name, out.num));
+            }
+            return Err(e);
+        }
+        if outputs.is_empty() {
+            return Ok(());
+        }
+
+        let mut ve = VersionEdit::new();
+        {
+            let base = self.current();
+            let base = base.borrow();
+            for out in outputs {
+                let level = base.pick_memtable_output_level(
+                    parse_internal_key(&out.smallest).2,
+                    parse_internal_key(&out.largest).2,
+                );
+                log!(
+                    self.opt.log,
+                    "Bulk-loaded table num={} into L{}: size={}",
+                    out.num,
+                    level,
+                    out.size
+                );
+                self.cstats[level].add(CompactionStats {
+                    bytes_written: out.size,
+                    files_out: 1,
+                    ..Default::default()
+                });
+                ve.add_file(level, out);
+            }
+        }
+        self.vset.borrow_mut().last_seq = seq - 1;
+        self.vset.borrow_mut().log_and_apply(ve)?;
+        self.maybe_do_compaction()
+    }
+
+    /// Deletes all entries with keys in [begin, end) by writing a single range tombstone, instead
+    /// of one deletion per key. Like with `delete()`, you can call `flush()` to guarantee that the
+    /// operation made it to disk.
+    pub fn delete_range(&mut self, begin: &[u8], end: &[u8]) -> Result<()> {
+        if self.opt.cmp.cmp(begin, end) != Ordering::Less {
+            return err(
+                StatusCode::InvalidArgument,
+                "delete_range: begin must be smaller than end",
+            );
+        }
+        let mut wb = WriteBatch::new();
+        wb.delete_range(begin, end);
+        self.write(wb, false)
+    }
+
+    /// Writes an entire WriteBatch atomically: either all or none of its operations become
+    /// visible. The batch is appended to the log before being applied to the memtable. If `sync`
+    /// (or the batch's own `set_sync()` flag) is set, the log is synced to disk before returning,
+    /// unless the last sync happened less than `min_wal_sync_interval_micros` ago: then the sync
+    /// is coalesced with those of other writes into one sync by the next write after the
+    /// interval, `sync()` or `flush()`.
+    pub fn write(&mut self, batch: WriteBatch, sync: bool) -> Result<()> {
+        self.write_seq(batch, sync).map(|_| ())
+    }
+
+    /// write_seq writes a WriteBatch and returns the sequence number assigned to its first entry.
+    fn write_seq(&mut self, mut batch: WriteBatch, sync: bool) -> Result<SequenceNumber> {
+        self.check_writable()?;
+        self.check_batch(&batch)?;
         assert!(self.log.is_some());
 
         self.make_room_for_write(false)?;
 
+        if let Some(expiry) = Expiry::from_options(&self.opt) {
+            batch = expiry.stamp_batch(&batch);
+        }
         let entries = batch.count() as u64;
         let log = self.log.as_mut().unwrap();
         let next = self.vset.borrow().last_seq + 1;
 
-        batch.insert_into_memtable(next, &mut self.mem);
         log.add_record(&batch.encode(next))?;
-        if sync {
-            log.flush()?;
+        if sync || batch.is_sync() || self.log_sync_pending {
+            self.sync_log(false)?;
         }
+        batch.insert_into_memtable(next, &mut self.mem);
         self.vset.borrow_mut().last_seq += entries;
+        Ok(next)
+    }
+
+    /// check_batch rejects batches containing keys and values exceeding `max_key_size` and
+    /// `max_value_size`, and keys that couldn't be written to a table with `max_key_length`.
+    /// The bounds of range deletions are not checked.
+    fn check_batch(&self, batch: &WriteBatch) -> Result<()> {
+        for (typ, k, v) in batch.iter_typed() {
+            if typ == ValueType::TypeRangeDeletion {
+                continue;
+            }
+            if let Some(max) = self.opt.max_key_size.filter(|max| k.len() > *max) {
+                return err(
+                    StatusCode::InvalidArgument,
+                    &format!("key of length {} exceeds max_key_size {}", k.len(), max),
+                );
+            }
+            // Tables store the key with 8 bytes of sequence number and type.
+            let klen = k.len() + 8;
+            if let Some(max) = self.opt.max_key_length.filter(|max| klen > *max) {
+                return err(
+                    StatusCode::InvalidArgument,
+                    &format!(
+                        "key of length {} exceeds max_key_length {} (including 8 bytes of tag)",
+                        k.len(),
+                        max
+                    ),
+                );
+            }
+            let vlen = v.map_or(0, |v| v.len());
+            if let Some(max) = self.opt.max_value_size.filter(|max| vlen > *max) {
+                return err(
+                    StatusCode::InvalidArgument,
+                    &format!("value of length {} exceeds max_value_size {}", vlen, max),
+                );
+            }
+        }
         Ok(())
     }
 
-    /// flush makes sure that all pending changes (e.g. from put()) are stored on disk.
+    /// flush makes sure that all pending changes (e.g. from put()) are stored on disk: the log is
+    /// synced, and the memtable is written to a table file which is recorded in the manifest
+    /// before flush returns. Afterwards, reads are served from tables until new writes arrive.
     pub fn flush(&mut self) -> Result<()> {
+        self.check_writable()?;
+        assert!(self.log.is_some());
+        self.sync_log(true)?;
+        self.make_room_for_write(true)
+    }
+
+    /// sync makes all writes so far durable by syncing the log to disk. Unlike flush(), the
+    /// memtable is not written to a table, so calling it frequently doesn't create small tables.
+    pub fn sync(&mut self) -> Result<()> {
+        self.check_writable()?;
         assert!(self.log.is_some());
-        self.log.as_mut().unwrap().flush()
+        self.sync_log(true)
+    }
+
+    /// sync_log syncs the log file. Unless force is set, a sync less than
+    /// `min_wal_sync_interval_micros` after the last one is only marked as pending, to be
+    /// performed by the next call after the interval.
+    fn sync_log(&mut self, force: bool) -> Result<()> {
+        if let (false, Some(last)) = (force, self.last_log_sync) {
+            let next = last.saturating_add(self.opt.min_wal_sync_interval_micros);
+            if self.opt.env.now_monotonic() < next {
+                self.log_sync_pending = true;
+                return Ok(());
+            }
+        }
+        self.log
+            .as_mut()
+            .unwrap()
+            .sync(self.opt.env.as_ref().as_ref())?;
+        self.last_log_sync = Some(self.opt.env.now_monotonic());
+        self.log_sync_pending = false;
+        Ok(())
     }
 }
 
 impl DB {
     // READ //
     fn get_internal(&mut self, seq: SequenceNumber, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.get_detailed_internal(seq, key)? {
+            GetResult::Found(v) => Ok(Some(v)),
+            GetResult::Deleted | GetResult::NotFound => Ok(None),
+        }
+    }
+
+    fn get_detailed_internal(&mut self, seq: SequenceNumber, key: &[u8]) -> Result<GetResult> {
+        let mut buf = vec![];
+        Ok(match self.get_into_internal(seq, key, &mut buf)? {
+            (true, _) => GetResult::Found(buf),
+            (false, true) => GetResult::Deleted,
+            (false, false) => GetResult::NotFound,
+        })
+    }
+
+    /// get_into_internal looks up key at seq. `buf` is cleared, and a found value is stored in
+    /// it. Like MemTable::get_into(), it returns whether a value was found and whether the key
+    /// is deleted.
+    fn get_into_internal(
+        &mut self,
+        seq: SequenceNumber,
+        key: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<(bool, bool)> {
+        buf.clear();
         // Using this lookup key will skip all entries with higher sequenece numbers, because they
         // will compare "lesser" using the InternalKeyCmp
         let lkey = LookupKey::new(key, seq);
 
-        match self.mem.get(&lkey) {
-            (Some(v), _) => return Ok(Some(v)),
-            // deleted entry
-            (None, true) => return Ok(None),
-            // not found entry
-            (None, false) => {}
-        }
-
-        if let Some(imm) = self.imm.as_ref() {
-            match imm.get(&lkey) {
-                (Some(v), _) => return Ok(Some(v)),
-                // deleted entry
-                (None, true) => return Ok(None),
-                // not found entry
-                (None, false) => {}
+        let mut typ = self.mem.get_into(&lkey, buf);
+        if typ.is_none() {
+            if let Some(imm) = self.imm.as_ref() {
+                typ = imm.get_into(&lkey, buf);
             }
         }
+        if let Some(typ) = typ {
+            return self.resolve_entry(typ, seq, key, buf);
+        }
 
         let mut do_compaction = false;
-        let mut result = None;
+        let result;
 
         // Limiting the borrow scope of self.current.
         {
             let current = self.current();
             let mut current = current.borrow_mut();
-            if let Ok(Some((v, st))) = current.get(lkey.internal_key()) {
-                if current.update_stats(st) {
-                    do_compaction = true;
-                }
-                result = Some(v)
+            let (r, st) = current.get_entry_into(lkey.internal_key(), buf)?;
+            if current.update_stats(st) {
+                do_compaction = true;
             }
+            result = r;
         }
 
         if do_compaction {
@@ -455,13 +1199,67 @@ impl DB {
                 log!(self.opt.log, "error while doing compaction in get: {}", e);
             }
         }
-        Ok(result)
-    }
-
-    /// get_at reads the value for a given key at or before snapshot. If returns Ok(None) if the
-    /// entry wasn't found, and Err(_) if an error occurred.
-    pub fn get_at(&mut self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>> {
-        self.get_internal(snapshot.sequence(), key)
+        match result {
+            Some(typ) => self.resolve_entry(typ, seq, key, buf),
+            None => Ok((false, false)),
+        }
+    }
+
+    /// resolve_entry translates the type of the newest entry for key, whose value is in buf, to
+    /// the result of get_into_internal(). For a merge operand, the older entries are needed
+    /// too; they are merged by an iterator at seq. An expired value counts as a deletion.
+    fn resolve_entry(
+        &mut self,
+        typ: ValueType,
+        seq: SequenceNumber,
+        key: &[u8],
+        buf: &mut Vec<u8>,
+    ) -> Result<(bool, bool)> {
+        match typ {
+            ValueType::TypeDeletion => Ok((false, true)),
+            ValueType::TypeMerge => {
+                buf.clear();
+                let ss = self.snaps.new_snapshot(seq);
+                let mut iter = self.new_iter_at(ss)?;
+                iter.seek(key);
+                let mut k = vec![];
+                if iter.current(&mut k, buf) && self.opt.cmp.cmp(&k, key) == Ordering::Equal {
+                    return Ok((true, false));
+                }
+                iter.status()?;
+                buf.clear();
+                Ok((false, true))
+            }
+            _ => match Expiry::from_options(&self.opt) {
+                Some(expiry) if !expiry.resolve(buf) => {
+                    buf.clear();
+                    Ok((false, true))
+                }
+                _ => Ok((true, false)),
+            },
+        }
+    }
+
+    /// get_at reads the value for a given key at or before snapshot. If returns Ok(None) if the
+    /// entry wasn't found, and Err(_) if an error occurred.
+    pub fn get_at(&mut self, snapshot: &Snapshot, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.get_internal(snapshot.sequence(), key)
+    }
+
+    /// get_detailed reads the current value for key, distinguishing a key whose newest entry is
+    /// a deletion (`GetResult::Deleted`) from one that was never written (`GetResult::NotFound`).
+    pub fn get_detailed(&mut self, key: &[u8]) -> Result<GetResult> {
+        let seq = self.vset.borrow().last_seq;
+        self.get_detailed_internal(seq, key)
+    }
+
+    /// get_into reads the current value for key into `buf`, reusing its allocation; this avoids
+    /// allocating a new vector for every lookup of a hot read path. `buf` is cleared first. It
+    /// returns Ok(true) if the key exists, and Ok(false) otherwise.
+    pub fn get_into(&mut self, key: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+        let seq = self.vset.borrow().last_seq;
+        self.get_into_internal(seq, key, buf)
+            .map(|(found, _)| found)
     }
 
     /// get is a simplified version of get_at(), translating errors to None.
@@ -484,19 +1282,102 @@ impl DB {
         self.new_iter_at(snapshot)
     }
 
-    // new_iter at returns a DBIterator at the supplied snapshot.
+    /// prefix_iter returns an iterator positioned at the first entry with a key starting with
+    /// `prefix`, which stops after the last such entry. The bound is computed bytewise, i.e. this
+    /// only works as expected with the default comparator.
+    ///
+    /// If `Options::prefix_extractor` is set and `prefix` is in its domain, tables whose filters
+    /// show that they don't contain the prefix are skipped.
+    pub fn prefix_iter(&mut self, prefix: &[u8]) -> Result<DBIterator> {
+        let mut iter = match self.opt.prefix_extractor {
+            Some(ref extractor) if extractor.in_domain(prefix) => {
+                let snapshot = self.get_snapshot();
+                let stats = BlockReadStats::default();
+                DBIterator::new(
+                    self.opt.cmp.clone(),
+                    self.vset.clone(),
+                    self.prefix_iterators(prefix, &stats)?,
+                    snapshot,
+                    self.range_tombstones(),
+                    stats,
+                    self.opt.merge_operator.clone(),
+                )
+            }
+            _ => self.new_iter()?,
+        };
+        if let Some(bound) = prefix_upper_bound(prefix) {
+            iter.set_upper_bound(bound);
+        }
+        iter.seek(prefix);
+        Ok(iter)
+    }
+
+    /// new_iter_at returns a DBIterator over the state of the database at the supplied snapshot;
+    /// entries written after the snapshot was taken are not returned.
     pub fn new_iter_at(&mut self, ss: Snapshot) -> Result<DBIterator> {
+        let stats = BlockReadStats::default();
         Ok(DBIterator::new(
             self.opt.cmp.clone(),
             self.vset.clone(),
-            self.merge_iterators()?,
+            self.merge_iterators(&stats)?,
             ss,
+            self.range_tombstones(),
+            stats,
+            self.opt.merge_operator.clone(),
         ))
     }
 
+    /// range_tombstones returns the range tombstones of the memtables and the current version.
+    fn range_tombstones(&self) -> Vec<RangeTombstone> {
+        let mut tombstones = self.mem.range_tombstones().to_vec();
+        if let Some(ref imm) = self.imm {
+            tombstones.extend_from_slice(imm.range_tombstones());
+        }
+        tombstones.extend_from_slice(&self.current().borrow().range_tombstones);
+        tombstones
+    }
+
     /// merge_iterators produces a MergingIter merging the entries in the memtable, the immutable
-    /// memtable, and table files from all levels.
-    fn merge_iterators(&mut self) -> Result<MergingIter> {
+    /// memtable, and table files from all levels. Data blocks read from the tables are counted in
+    /// `stats`. With a TTL, expired values appear as deletions.
+    fn merge_iterators(&mut self, stats: &BlockReadStats) -> Result<MergingIter> {
+        let mut iters = self.memtable_iterators();
+
+        // Add iterators for table files.
+        let current = self.current();
+        let current = current.borrow();
+        iters.extend(current.new_iters_with_stats(Some(stats.clone()))?);
+
+        let iters = TtlIter::wrap(iters, Expiry::from_options(&self.opt), true);
+        Ok(MergingIter::new(self.internal_cmp.clone(), iters))
+    }
+
+    /// prefix_iterators is like merge_iterators(), but only includes table files that may contain
+    /// keys starting with `prefix`, according to their key range and filters.
+    fn prefix_iterators(&mut self, prefix: &[u8], stats: &BlockReadStats) -> Result<MergingIter> {
+        let mut iters = self.memtable_iterators();
+
+        // An empty user key is treated as open bound by Version::overlapping_inputs().
+        let bound = prefix_upper_bound(prefix).unwrap_or_default();
+        let begin = LookupKey::new(prefix, MAX_SEQUENCE_NUMBER);
+        let end = LookupKey::new_full(&bound, 0, ValueType::TypeDeletion);
+
+        let current = self.current();
+        let current = current.borrow();
+        for level in 0..NUM_LEVELS {
+            for f in current.overlapping_inputs(level, begin.internal_key(), end.internal_key()) {
+                let table = self.cache.borrow_mut().get_table(f.borrow().num)?;
+                if table.prefix_may_match(prefix) {
+                    iters.push(Box::new(table.iter_with_stats(Some(stats.clone()))));
+                }
+            }
+        }
+
+        let iters = TtlIter::wrap(iters, Expiry::from_options(&self.opt), true);
+        Ok(MergingIter::new(self.internal_cmp.clone(), iters))
+    }
+
+    fn memtable_iterators(&self) -> Vec<Box<dyn LdbIterator>> {
         let mut iters: Vec<Box<dyn LdbIterator>> = vec![];
         if self.mem.len() > 0 {
             iters.push(Box::new(self.mem.iter()));
@@ -506,13 +1387,60 @@ impl DB {
                 iters.push(Box::new(imm.iter()));
             }
         }
+        iters
+    }
+}
 
-        // Add iterators for table files.
-        let current = self.current();
-        let current = current.borrow();
-        iters.extend(current.new_iters()?);
+impl DB {
+    // COLUMN FAMILIES //
+    //
+    // Column families are separate keyspaces within the same database. They are implemented by
+    // prefixing every key with the (varint-encoded) index of its family in
+    // `Options::column_families`. Keys written using put() etc. are not part of any family and
+    // should not be mixed with families in the same database.
+
+    /// family_prefix returns the key prefix for the named column family.
+    fn family_prefix(&self, family: &str) -> Result<Vec<u8>> {
+        match self.opt.column_families.iter().position(|f| f == family) {
+            Some(ix) => Ok(ix.encode_var_vec()),
+            None => err(
+                StatusCode::InvalidArgument,
+                &format!("unknown column family: {}", family),
+            ),
+        }
+    }
 
-        Ok(MergingIter::new(self.internal_cmp.clone(), iters))
+    fn family_key(&self, family: &str, key: &[u8]) -> Result<Vec<u8>> {
+        let mut k = self.family_prefix(family)?;
+        k.extend_from_slice(key);
+        Ok(k)
+    }
+
+    /// put_cf is like put(), but stores the entry in the given column family.
+    pub fn put_cf(&mut self, family: &str, k: &[u8], v: &[u8]) -> Result<()> {
+        let k = self.family_key(family, k)?;
+        self.put(&k, v)
+    }
+
+    /// delete_cf is like delete(), but removes the entry from the given column family.
+    pub fn delete_cf(&mut self, family: &str, k: &[u8]) -> Result<()> {
+        let k = self.family_key(family, k)?;
+        self.delete(&k)
+    }
+
+    /// get_cf reads the value for a key in the given column family. It returns Ok(None) if the
+    /// entry wasn't found, and Err(_) if the family is unknown or an error occurred.
+    pub fn get_cf(&mut self, family: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let k = self.family_key(family, key)?;
+        let seq = self.vset.borrow().last_seq;
+        self.get_internal(seq, &k)
+    }
+
+    /// iter_cf returns an iterator over the entries of a single column family. The returned keys
+    /// don't contain the family prefix.
+    pub fn iter_cf(&mut self, family: &str) -> Result<ColumnFamilyIter> {
+        let prefix = self.family_prefix(family)?;
+        Ok(ColumnFamilyIter::new(self.new_iter()?, prefix))
     }
 }
 
@@ -524,15 +1452,103 @@ impl DB {
     pub fn get_snapshot(&mut self) -> Snapshot {
         self.snaps.new_snapshot(self.vset.borrow().last_seq)
     }
+
+    /// Releases a snapshot obtained from get_snapshot(). Once all clones of a snapshot are
+    /// released, compactions are free to drop entries only visible to it.
+    pub fn release_snapshot(&mut self, s: Snapshot) {
+        drop(s)
+    }
 }
 
 impl DB {
     // STATISTICS //
+
+    /// compaction_stats returns the statistics of the compactions run since the database was
+    /// opened, indexed by the level that was written to.
+    pub fn compaction_stats(&self) -> [CompactionStats; NUM_LEVELS] {
+        self.cstats
+    }
+
     fn add_stats(&mut self, level: usize, cs: CompactionStats) {
         assert!(level < NUM_LEVELS);
         self.cstats[level].add(cs);
     }
 
+    /// get_approximate_sizes returns, for each of the given ranges, the approximate number of
+    /// bytes used on disk by the table files for keys in [start; limit). Entries that are only
+    /// present in the memtable are not counted.
+    pub fn get_approximate_sizes(&self, ranges: &[Range]) -> Vec<u64> {
+        let vset = self.vset.borrow();
+        let v = vset.current();
+        ranges
+            .iter()
+            .map(|r| {
+                let start = LookupKey::new(r.start, MAX_SEQUENCE_NUMBER);
+                let limit = LookupKey::new(r.limit, MAX_SEQUENCE_NUMBER);
+                let start_off = vset.approximate_offset(&v, start.internal_key());
+                let limit_off = vset.approximate_offset(&v, limit.internal_key());
+                limit_off.saturating_sub(start_off) as u64
+            })
+            .collect()
+    }
+
+    /// approximate_memory_usage returns the approximate number of bytes held in memory by the
+    /// memtable, the immutable memtable waiting to be flushed (if any), and the block cache.
+    /// If the block cache is shared with other databases, their blocks are included.
+    pub fn approximate_memory_usage(&self) -> usize {
+        self.mem.approx_mem_usage()
+            + self.imm.as_ref().map_or(0, |imm| imm.approx_mem_usage())
+            + self.opt.block_cache.borrow().usage()
+    }
+
+    /// property returns runtime information about the database. Supported names are:
+    ///
+    /// * `leveldb.num-files-at-level<N>`: the number of table files at level N.
+    /// * `leveldb.stats`: a table with file counts, sizes and compaction statistics per level.
+    /// * `leveldb.sstables`: a summary of the table files at every level.
+    ///
+    /// Unknown names return `None`.
+    pub fn property(&self, name: &str) -> Option<String> {
+        let name = name.strip_prefix("leveldb.")?;
+        let current = self.current();
+        let current = current.borrow();
+
+        if let Some(level) = name.strip_prefix("num-files-at-level") {
+            let level = level.parse::<usize>().ok()?;
+            if level >= NUM_LEVELS {
+                return None;
+            }
+            return Some(current.num_level_files(level).to_string());
+        }
+
+        match name {
+            "stats" => {
+                let mut s = String::from(
+                    "Level  Files  Size(B)  Time(us)  Read(B)  Write(B)\n\
+                     --------------------------------------------------\n",
+                );
+                for (level, files) in current.files.iter().enumerate() {
+                    let cs = &self.cstats[level];
+                    if files.is_empty() && cs.micros == 0 {
+                        continue;
+                    }
+                    s.push_str(&format!(
+                        "{:>5} {:>6} {:>8} {:>9} {:>8} {:>9}\n",
+                        level,
+                        files.len(),
+                        total_size(files.iter()),
+                        cs.micros,
+                        cs.bytes_read,
+                        cs.bytes_written
+                    ));
+                }
+                Some(s)
+            }
+            "sstables" => Some(current.level_summary()),
+            _ => None,
+        }
+    }
+
     /// Trigger a compaction based on where this key is located in the different levels.
     fn record_read_sample(&mut self, k: InternalKey) {
         let current = self.current();
@@ -551,10 +1567,42 @@ impl DB {
     /// if it's the case
     #[allow(clippy::unnecessary_unwrap)]
     fn make_room_for_write(&mut self, force: bool) -> Result<()> {
-        if !force && self.mem.approx_mem_usage() < self.opt.write_buffer_size || self.mem.len() == 0
+        let mut l0_files = self.current().borrow().files[0].len();
+        if !force && l0_files >= self.opt.l0_slowdown_writes_trigger {
+            // Compactions run on this thread, so delaying the write wouldn't shrink level 0.
+            // Instead, the write does a step of the pending compaction work, which compacts level 0
+            // if it is due.
+            self.maybe_do_compaction()?;
+            l0_files = self.current().borrow().files[0].len();
+        }
+
+        // Any non-empty memtable is flushed while the budget is exceeded: the memtables of other
+        // databases can't be flushed from here, and waiting for a larger one could leave the
+        // budget exceeded forever when it is shared by many databases.
+        let over_budget = self
+            .opt
+            .memory_budget
+            .as_ref()
+            .is_some_and(|b| b.load(atomic::Ordering::Relaxed) > self.opt.memory_budget_limit);
+        if !force && !over_budget && self.mem.approx_mem_usage() < self.opt.write_buffer_size
+            || self.mem.len() == 0
         {
             Ok(())
         } else {
+            if l0_files >= self.opt.l0_stop_writes_trigger {
+                log!(
+                    self.opt.log,
+                    "Too many L0 files ({}); compacting before writing",
+                    l0_files
+                );
+                self.compact_levels(&[], &[], 0..1)?;
+            }
+
+            // The old log must not be left with pending syncs.
+            if self.log_sync_pending {
+                self.sync_log(true)?;
+            }
+
             // Create new memtable.
             let logn = self.vset.borrow_mut().new_file_number();
             let logf = self
@@ -565,10 +1613,14 @@ impl DB {
                 self.vset.borrow_mut().reuse_file_number(logn);
                 Err(logf.err().unwrap())
             } else {
-                self.log = Some(LogWriter::new(BufWriter::new(logf.unwrap())));
+                self.log = Some(LogWriter::new(
+                    BufWriter::new(logf.unwrap()),
+                    self.opt.checksum_type,
+                ));
                 self.log_num = Some(logn);
 
-                let mut imm = MemTable::new(self.opt.cmp.clone());
+                let mut imm =
+                    MemTable::new_with_budget(self.opt.cmp.clone(), self.opt.memory_budget.clone());
                 swap(&mut imm, &mut self.mem);
                 self.imm = Some(imm);
                 self.maybe_do_compaction()
@@ -578,7 +1630,9 @@ impl DB {
 
     /// maybe_do_compaction starts a blocking compaction if it makes sense.
     fn maybe_do_compaction(&mut self) -> Result<()> {
-        if self.imm.is_some() {
+        if self.read_only {
+            Ok(())
+        } else if self.imm.is_some() {
             self.compact_memtable()
         } else if self.vset.borrow().needs_compaction() {
             let c = self.vset.borrow_mut().pick_compaction();
@@ -592,12 +1646,21 @@ impl DB {
         }
     }
 
-    /// compact_range triggers an immediate compaction on the specified key range. Repeatedly
-    /// calling this without actually adding new keys is not useful.
+    /// compact_range triggers an immediate compaction on the specified key range. `None` bounds
+    /// extend the range to the beginning respectively the end of the keyspace, so
+    /// `compact_range(None, None)` compacts the whole database. Repeatedly calling this without
+    /// actually adding new keys is not useful.
     ///
     /// Compactions in general will cause the database to find entries more quickly, and take up
     /// less space on disk.
-    pub fn compact_range(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+    pub fn compact_range(&mut self, from: Option<&[u8]>, to: Option<&[u8]>) -> Result<()> {
+        self.check_writable()?;
+        // Empty user keys are treated as open bounds by Version::overlapping_inputs().
+        let (from, to) = (from.unwrap_or_default(), to.unwrap_or_default());
+
+        // Compact memtable.
+        self.make_room_for_write(true)?;
+
         let mut max_level = 1;
         {
             let v = self.vset.borrow().current();
@@ -609,24 +1672,58 @@ impl DB {
             }
         }
 
-        // Compact memtable.
+        self.compact_levels(from, to, 0..max_level + 1)
+    }
+
+    /// compact_to_level compacts the specified key range and pushes the result down to
+    /// `target_level`, instead of leaving it one level below where it started. This is useful when
+    /// deeper levels are placed on slower storage. `None` bounds are treated like in
+    /// `compact_range()`.
+    ///
+    /// Every level above `target_level` is compacted for the given range in turn, so entries from
+    /// intervening levels are merged on the way down; entries in levels below `target_level` are
+    /// left untouched.
+    pub fn compact_to_level(
+        &mut self,
+        from: Option<&[u8]>,
+        to: Option<&[u8]>,
+        target_level: usize,
+    ) -> Result<()> {
+        self.check_writable()?;
+        if target_level == 0 || target_level >= NUM_LEVELS {
+            return err(
+                StatusCode::InvalidArgument,
+                &format!("target level must be between 1 and {}", NUM_LEVELS - 1),
+            );
+        }
+        let (from, to) = (from.unwrap_or_default(), to.unwrap_or_default());
+
         self.make_room_for_write(true)?;
+        self.compact_levels(from, to, 0..target_level)
+    }
 
-        let mut ifrom = LookupKey::new(from, MAX_SEQUENCE_NUMBER)
-            .internal_key()
-            .to_vec();
+    /// compact_levels compacts the range [from, to] of every level in `levels` into the next
+    /// level, in order.
+    fn compact_levels(
+        &mut self,
+        from: &[u8],
+        to: &[u8],
+        levels: std::ops::Range<usize>,
+    ) -> Result<()> {
+        let ifrom = LookupKey::new(from, MAX_SEQUENCE_NUMBER);
         let iend = LookupKey::new_full(to, 0, ValueType::TypeDeletion);
 
-        for l in 0..max_level + 1 {
+        for l in levels {
+            let mut lfrom = ifrom.internal_key().to_vec();
             loop {
                 let c_ = self
                     .vset
                     .borrow_mut()
-                    .compact_range(l, &ifrom, iend.internal_key());
+                    .compact_range(l, &lfrom, iend.internal_key());
                 if let Some(c) = c_ {
-                    // Update ifrom to the largest key of the last file in this compaction.
+                    // Update lfrom to the largest key of the last file in this compaction.
                     let ix = c.num_inputs(0) - 1;
-                    ifrom.clone_from(&c.input(0, ix).largest);
+                    lfrom.clone_from(&c.input(0, ix).largest);
                     self.start_compaction(c)?;
                 } else {
                     break;
@@ -682,6 +1779,7 @@ impl DB {
                 log!(self.opt.log, "Compaction work failed: {}", e);
             }
             self.install_compaction_results(state)?;
+            self.drop_expired_range_tombstones()?;
             log!(
                 self.opt.log,
                 "Compaction finished: {}",
@@ -718,6 +1816,11 @@ impl DB {
         base: Option<&Version>,
     ) -> Result<()> {
         let start_ts = self.opt.env.micros();
+        // Range tombstones are kept in the manifest rather than in tables.
+        for t in memt.range_tombstones() {
+            ve.add_range_tombstone(t.clone());
+        }
+
         let num = self.vset.borrow_mut().new_file_number();
         log!(self.opt.log, "Start write of L0 table {:06}", num);
         let fmd = build_table(&self.name, &self.opt, memt.iter(), num)?;
@@ -747,7 +1850,8 @@ impl DB {
 
         let stats = CompactionStats {
             micros: self.opt.env.micros() - start_ts,
-            written: fmd.size,
+            bytes_written: fmd.size,
+            files_out: 1,
             ..Default::default()
         };
 
@@ -781,14 +1885,20 @@ impl DB {
             cs.compaction.level() + 1
         );
 
-        let mut input = self.vset.borrow().make_input_iterator(&cs.compaction);
+        let input = self.vset.borrow().make_input_iterator(&cs.compaction);
+        let tombstones = self.current().borrow().range_tombstones.clone();
+        // The iterator keeps its own position for the base level checks.
+        let mut base_level = cs.compaction.clone();
+        let mut input = CompactionIterator::new(
+            input,
+            &self.opt,
+            cs.smallest_seq,
+            tombstones,
+            move |k: UserKey| base_level.is_base_level_for(k),
+        );
         input.seek_to_first();
 
         let (mut key, mut val) = (vec![], vec![]);
-        let mut last_seq_for_key = MAX_SEQUENCE_NUMBER;
-
-        let mut have_ukey = false;
-        let mut current_ukey = vec![];
 
         while input.valid() {
             // TODO: Do we need to do a memtable compaction here? Probably not, in the sequential
@@ -797,43 +1907,7 @@ impl DB {
             if cs.compaction.should_stop_before(&key) && cs.builder.is_none() {
                 self.finish_compaction_output(cs, key.clone())?;
             }
-            let (ktyp, seq, ukey) = parse_internal_key(&key);
-            if seq == 0 {
-                // Parsing failed.
-                log!(self.opt.log, "Encountered seq=0 in key: {:?}", &key);
-                last_seq_for_key = MAX_SEQUENCE_NUMBER;
-                have_ukey = false;
-                current_ukey.clear();
-                input.advance();
-                continue;
-            }
-
-            if !have_ukey || self.opt.cmp.cmp(ukey, &current_ukey) != Ordering::Equal {
-                // First occurrence of this key.
-                current_ukey.clear();
-                current_ukey.extend_from_slice(ukey);
-                have_ukey = true;
-                last_seq_for_key = MAX_SEQUENCE_NUMBER;
-            }
-
-            // We can omit the key under the following conditions:
-            if last_seq_for_key <= cs.smallest_seq {
-                last_seq_for_key = seq;
-                input.advance();
-                continue;
-            }
-            // Entry is deletion; no older version is observable by any snapshot; and all entries
-            // in compacted levels with smaller sequence numbers will
-            if ktyp == ValueType::TypeDeletion
-                && seq <= cs.smallest_seq
-                && cs.compaction.is_base_level_for(ukey)
-            {
-                last_seq_for_key = seq;
-                input.advance();
-                continue;
-            }
-
-            last_seq_for_key = seq;
+            let seq = parse_internal_key(&key).1;
 
             if cs.builder.is_none() {
                 let fnum = self.vset.borrow_mut().new_file_number();
@@ -851,9 +1925,11 @@ impl DB {
             if cs.builder.as_ref().unwrap().entries() == 0 {
                 cs.current_output().smallest.clone_from(&key)
             }
+            let output = cs.current_output();
+            output.largest_seqno = output.largest_seqno.max(seq);
             cs.builder.as_mut().unwrap().add(&key, &val)?;
             // NOTE: Adjust max file size based on level.
-            if cs.builder.as_ref().unwrap().size_estimate() > self.opt.max_file_size {
+            if cs.builder.as_ref().unwrap().should_flush() {
                 self.finish_compaction_output(cs, key.clone())?;
             }
             input.advance();
@@ -870,13 +1946,15 @@ impl DB {
 
         for parent in 0..2 {
             for inp in 0..cs.compaction.num_inputs(parent) {
-                stats.read += cs.compaction.input(parent, inp).size;
+                stats.bytes_read += cs.compaction.input(parent, inp).size;
+                stats.files_in += 1;
             }
         }
         for output in &cs.outputs {
-            stats.written += output.size;
+            stats.bytes_written += output.size;
+            stats.files_out += 1;
         }
-        self.cstats[cs.compaction.level()].add(stats);
+        self.add_stats(cs.compaction.level() + 1, stats);
 
         Ok(())
     }
@@ -895,7 +1973,7 @@ impl DB {
         // (it's not good for corruptions, in any case)
         let b = cs.builder.take().unwrap();
         let entries = b.entries();
-        let bytes = b.finish()?;
+        let bytes = b.finish_synced(self.opt.env.as_ref().as_ref())?;
         cs.total_bytes += bytes;
 
         cs.current_output().largest = largest;
@@ -919,6 +1997,33 @@ impl DB {
         Ok(())
     }
 
+    /// drop_expired_range_tombstones removes range tombstones from the current version once no
+    /// table overlaps their range anymore, i.e. all entries covered by them have been compacted
+    /// away. Entries in the memtables are newer than any tombstone in the version.
+    fn drop_expired_range_tombstones(&mut self) -> Result<()> {
+        let mut ve = VersionEdit::new();
+        {
+            let current = self.current();
+            let current = current.borrow();
+            for t in &current.range_tombstones {
+                if !(0..NUM_LEVELS)
+                    .any(|l| current.overlap_in_level(l, &t.begin.as_slice(), &t.end.as_slice()))
+                {
+                    ve.delete_range_tombstone(t.seq);
+                }
+            }
+        }
+        if ve.deleted_range_tombstones.is_empty() {
+            return Ok(());
+        }
+        log!(
+            self.opt.log,
+            "Dropping {} expired range tombstones",
+            ve.deleted_range_tombstones.len()
+        );
+        self.vset.borrow_mut().log_and_apply(ve)
+    }
+
     fn install_compaction_results(&mut self, mut cs: CompactionState) -> Result<()> {
         log!(
             self.opt.log,
@@ -942,6 +2047,9 @@ impl DB {
 
 impl Drop for DB {
     fn drop(&mut self) {
+        if self.log_sync_pending {
+            let _ = self.sync_log(true);
+        }
         self.release_lock().unwrap();
     }
 }
@@ -980,18 +2088,29 @@ impl CompactionState {
     }
 }
 
-#[derive(Debug, Default)]
-struct CompactionStats {
-    micros: u64,
-    read: usize,
-    written: usize,
+/// CompactionStats accumulates the work done by compactions writing to one level. Memtable
+/// flushes and bulk-loaded tables are counted at the level they are written to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// Time spent, in microseconds.
+    pub micros: u64,
+    /// Size of the table files read.
+    pub bytes_read: usize,
+    /// Size of the table files written.
+    pub bytes_written: usize,
+    /// Number of table files read.
+    pub files_in: usize,
+    /// Number of table files written.
+    pub files_out: usize,
 }
 
 impl CompactionStats {
     fn add(&mut self, cs: CompactionStats) {
         self.micros += cs.micros;
-        self.read += cs.read;
-        self.written += cs.written;
+        self.bytes_read += cs.bytes_read;
+        self.bytes_written += cs.bytes_written;
+        self.files_in += cs.files_in;
+        self.files_out += cs.files_out;
     }
 }
 
@@ -1006,6 +2125,7 @@ pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
 
     let (mut kbuf, mut vbuf) = (vec![], vec![]);
     let mut firstkey = None;
+    let mut largest_seqno = 0;
     // lastkey is what remains in kbuf.
 
     // Clean up file if write fails at any point.
@@ -1020,9 +2140,10 @@ pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
             if firstkey.is_none() {
                 firstkey = Some(kbuf.clone());
             }
+            largest_seqno = largest_seqno.max(parse_internal_key(&kbuf).1);
             builder.add(&kbuf, &vbuf)?;
         }
-        builder.finish()?;
+        builder.finish_synced(opt.env.as_ref().as_ref())?;
         Ok(())
     })();
 
@@ -1038,6 +2159,7 @@ pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
         md.size = opt.env.size_of(Path::new(&filename))?;
         md.smallest = f;
         md.largest = kbuf;
+        md.largest_seqno = largest_seqno;
     } else {
         opt.env.delete(Path::new(&filename)).unwrap();
     }
@@ -1045,6 +2167,141 @@ pub fn build_table<I: LdbIterator, P: AsRef<Path>>(
     Ok(md)
 }
 
+/// merge_dbs writes the union of the live data of all `sources` into the new database `dest`.
+/// The sources are opened read-only and are not modified. If a key exists in several sources, the
+/// entry with the highest sequence number wins (on ties, the one from the later source).
+///
+/// `dest` must not exist yet. The merged entries are written directly as tables, without going
+/// through the log and memtable.
+pub fn merge_dbs<P: AsRef<Path>, Q: AsRef<Path>>(
+    sources: &[P],
+    dest: Q,
+    opt: Options,
+) -> Result<()> {
+    let mut iters = vec![];
+    for src in sources {
+        let mut iter = DB::open_for_readonly(src, opt.clone())?.new_iter()?;
+        iter.seek_to_first();
+        iters.push(iter);
+    }
+
+    if read_current_file(&opt.env, dest.as_ref()).is_ok() {
+        return err(
+            StatusCode::AlreadyExists,
+            "destination database already exists",
+        );
+    }
+    let mut dest_opt = opt.clone();
+    dest_opt.create_if_missing = true;
+    let mut dest = DB::open(dest, dest_opt)?;
+
+    let cmp = opt.cmp.clone();
+    let (mut key, mut val) = (vec![], vec![]);
+    let merged = std::iter::from_fn(|| {
+        // Find the smallest current key, and the newest entry for it.
+        let mut winner: Option<(usize, SequenceNumber)> = None;
+        let mut smallest: Option<Vec<u8>> = None;
+        for (i, iter) in iters.iter().enumerate() {
+            if !iter.current(&mut key, &mut val) {
+                continue;
+            }
+            let seq = iter.current_seq().unwrap();
+            let order = match smallest {
+                None => Ordering::Less,
+                Some(ref s) => cmp.cmp(&key, s),
+            };
+            match order {
+                Ordering::Less => {
+                    smallest = Some(key.clone());
+                    winner = Some((i, seq));
+                }
+                Ordering::Equal if seq >= winner.unwrap().1 => winner = Some((i, seq)),
+                _ => {}
+            }
+        }
+        let (ix, _) = winner?;
+        let smallest = smallest.unwrap();
+        let mut entry = (vec![], vec![]);
+        iters[ix].current(&mut entry.0, &mut entry.1);
+        for iter in iters.iter_mut() {
+            if iter.current(&mut key, &mut val) && cmp.cmp(&key, &smallest) == Ordering::Equal {
+                iter.advance();
+            }
+        }
+        Some(entry)
+    });
+    dest.put_sorted_iter(merged)?;
+    dest.flush()
+}
+
+/// read_table_metadata reads a table file and returns its size, smallest and largest keys and
+/// highest sequence number, along with the lowest sequence number. The file number is left at 0.
+/// Tables whose keys are not in the order of opt.cmp (e.g. because they were written using
+/// another comparator) are rejected.
+fn read_table_metadata(opt: &Options, path: &Path) -> Result<(FileMetaData, SequenceNumber)> {
+    let size = opt.env.size_of(path)?;
+    let file = opt.env.open_random_access_file(path)?;
+    let table = Table::new(opt.clone(), Rc::new(file), size)?;
+    let icmp = InternalKeyCmp(opt.cmp.clone());
+    let mut iter = table.iter();
+    let mut meta = FileMetaData {
+        size,
+        ..Default::default()
+    };
+    let mut smallest_seqno = MAX_SEQUENCE_NUMBER;
+    let (mut key, mut val) = (vec![], vec![]);
+    while iter.advance() {
+        iter.current(&mut key, &mut val);
+        if key.len() < 8 {
+            return err(StatusCode::Corruption, "table contains a malformed key");
+        }
+        if meta.smallest.is_empty() {
+            meta.smallest.clone_from(&key);
+        } else if icmp.cmp(&meta.largest, &key) != Ordering::Less {
+            return err(
+                StatusCode::InvalidArgument,
+                "table keys are not ordered by the comparator",
+            );
+        }
+        let seq = parse_internal_key(&key).1;
+        meta.largest_seqno = meta.largest_seqno.max(seq);
+        smallest_seqno = smallest_seqno.min(seq);
+        swap(&mut meta.largest, &mut key);
+    }
+    if meta.smallest.is_empty() {
+        return err(StatusCode::InvalidArgument, "table is empty");
+    }
+    Ok((meta, smallest_seqno))
+}
+
+/// copy_table_file copies the table at `src` to `dest` without reading it into memory at once,
+/// and syncs the copy. If this fails, `dest` is deleted.
+fn copy_table_file(env: &dyn Env, src: &Path, dest: &Path) -> Result<()> {
+    let r = (|| -> Result<()> {
+        let mut from = env.open_sequential_file(src)?;
+        let mut to = env.open_writable_file(dest)?;
+        io::copy(&mut from, &mut to)?;
+        env.sync(to.as_mut())
+    })();
+    if r.is_err() {
+        let _ = env.delete(dest);
+    }
+    r
+}
+
+/// prefix_upper_bound returns the smallest key that is greater than all keys starting with
+/// `prefix`, or None if there is no such key (i.e. prefix consists only of 0xff bytes).
+fn prefix_upper_bound(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut bound = prefix.to_vec();
+    while let Some(last) = bound.pop() {
+        if last < 0xff {
+            bound.push(last + 1);
+            return Some(bound);
+        }
+    }
+    None
+}
+
 fn log_file_name(db: &Path, num: FileNum) -> PathBuf {
     db.join(format!("{:06}.log", num))
 }
@@ -1110,7 +2367,7 @@ pub mod testutil {
 
         let manifest = manifest_file_name(name, 10);
         let manifest_file = opt.env.open_writable_file(Path::new(&manifest)).unwrap();
-        let mut lw = LogWriter::new(manifest_file);
+        let mut lw = LogWriter::new(manifest_file, opt.checksum_type);
         lw.add_record(&ve.encode()).unwrap();
         lw.flush().unwrap();
         set_current_file(&opt.env, name, 10).unwrap();
@@ -1142,16 +2399,29 @@ pub mod testutil {
 
 #[cfg(test)]
 mod tests {
-    use std::mem;
+    use std::{
+        cell::Cell,
+        collections::HashSet,
+        io::Read,
+        mem,
+        sync::{atomic::AtomicUsize, Arc},
+        time::Duration,
+    };
 
     use tests::testutil::{build_db, set_file_to_compact};
 
     use crate::{
+        cache::Cache,
+        env::{self, RandomAccess},
         error::Status,
+        filter::{FixedPrefixExtractor, NoFilterPolicy},
         key_types::{LookupKey, ValueType},
-        mem_env::MemEnv,
-        options,
-        test_util::LdbIteratorIter,
+        mem_env::{FaultOp, MemEnv},
+        merge_op::testutil::append_operator,
+        options::{self, OptionsBuilder},
+        table_builder::write_sorted_table,
+        test_util::{CountingCmp, LdbIteratorIter, ReverseCmp},
+        types::current_key_val,
         version::testutil::make_version,
     };
 
@@ -1334,6 +2604,33 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_db_impl_iter_pins_version() {
+        let (mut db, opt) = build_db();
+        let collect = |iter: &mut DBIterator| LdbIteratorIter::wrap(iter).collect::<Vec<_>>();
+        let want = collect(&mut db.new_iter().unwrap());
+        let tables = |env: &Rc<Box<dyn Env>>| {
+            env.children(Path::new("db"))
+                .unwrap()
+                .into_iter()
+                .filter(|f| matches!(parse_file_name(f), Ok((_, FileType::Table))))
+                .collect::<HashSet<_>>()
+        };
+        let before = tables(&opt.env);
+
+        // The compaction replaces all tables, but the files read by the iterator are kept.
+        let mut iter = db.new_iter().unwrap();
+        db.compact_range(None, None).unwrap();
+        assert!(before.is_subset(&tables(&opt.env)));
+        assert_eq!(want, collect(&mut iter));
+
+        // Once the iterator is gone, they are deleted.
+        drop(iter);
+        db.delete_obsolete_files().unwrap();
+        assert!(before.is_disjoint(&tables(&opt.env)));
+        assert_eq!(want, collect(&mut db.new_iter().unwrap()));
+    }
+
     #[test]
     fn test_db_impl_compact_range() {
         let (mut db, opt) = build_db();
@@ -1343,7 +2640,7 @@ mod tests {
             "children before: {:?}",
             env.children(&Path::new("db").join("")).unwrap()
         );
-        db.compact_range(b"aaa", b"dba").unwrap();
+        db.compact_range(Some(b"aaa"), Some(b"dba")).unwrap();
         eprintln!(
             "children after: {:?}",
             env.children(&Path::new("db").join("")).unwrap()
@@ -1388,6 +2685,41 @@ mod tests {
         assert_eq!(b"val3".to_vec(), db.get(b"fab").unwrap());
     }
 
+    #[test]
+    fn test_db_impl_compaction_io_error() {
+        let env = MemEnv::new();
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(env.clone()));
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for k in &["abc", "abd", "abe"] {
+            db.put(k.as_bytes(), b"val").unwrap();
+        }
+        db.flush().unwrap();
+        db.make_room_for_write(true).unwrap();
+        db.put(b"abc", b"new").unwrap();
+
+        // Tables can neither be created nor opened.
+        env.set_fault(".ldb", FaultOp::Open, StatusCode::IOError);
+        let v = db.vset.borrow().current();
+        for f in v.borrow().files.iter().flatten() {
+            db.cache.borrow_mut().evict(f.borrow().num).unwrap();
+        }
+        assert_eq!(
+            StatusCode::IOError,
+            db.compact_range(None, None).unwrap_err().code
+        );
+
+        env.clear_faults();
+        db.compact_range(None, None).unwrap();
+        assert_eq!(Some(b"new".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"val".to_vec()), db.get(b"abe"));
+        drop(db);
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(b"new".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"val".to_vec()), db.get(b"abd"));
+    }
+
     #[test]
     fn test_db_impl_compact_range_memtable() {
         let (mut db, opt) = build_db();
@@ -1399,7 +2731,7 @@ mod tests {
             "children before: {:?}",
             env.children(Path::new("db")).unwrap()
         );
-        db.compact_range(b"aaa", b"dba").unwrap();
+        db.compact_range(Some(b"aaa"), Some(b"dba")).unwrap();
         eprintln!(
             "children after: {:?}",
             env.children(Path::new("db")).unwrap()
@@ -1453,52 +2785,1778 @@ mod tests {
     }
 
     #[test]
-    fn test_db_impl_locking() {
-        let opt = options::for_test();
-        let _db = DB::open("db", opt.clone()).unwrap();
-        let want_err = Status::new(
-            StatusCode::LockError,
-            "database lock is held by another instance",
-        );
-        assert_eq!(want_err, DB::open("db", opt.clone()).err().unwrap());
+    fn test_db_impl_compact_range_whole_keyspace() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = 1 << 10;
+        opt.block_size = 256;
+        let mut db = DB::open("db", opt).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("key{:05}", i).into_bytes())
+            .collect();
+        for k in &keys {
+            db.put(k, b"a value that is not too short").unwrap();
+        }
+        for k in keys.iter().step_by(2) {
+            db.delete(k).unwrap();
+        }
+
+        let num_tables = |db: &DB| {
+            db.opt
+                .env
+                .children(&db.name)
+                .unwrap()
+                .iter()
+                .filter(|f| matches!(parse_file_name(f), Ok((_, FileType::Table))))
+                .count()
+        };
+        let before = num_tables(&db);
+        db.compact_range(None, None).unwrap();
+        let after = num_tables(&db);
+        // The remaining keys fit into a single table.
+        assert!(before > 1);
+        assert_eq!(1, after);
+
+        for (i, k) in keys.iter().enumerate() {
+            if i % 2 == 0 {
+                assert_eq!(None, db.get(k));
+            } else {
+                assert_eq!(Some(b"a value that is not too short".to_vec()), db.get(k));
+            }
+        }
     }
 
     #[test]
-    fn test_db_impl_build_table() {
-        let mut opt = options::for_test();
-        opt.block_size = 128;
-        let mt = build_memtable();
+    fn test_db_impl_delete_range() {
+        fn visible(db: &mut DB) -> Vec<Vec<u8>> {
+            let mut iter = db.new_iter().unwrap();
+            iter.collect_kv().into_iter().map(|(k, _)| k).collect()
+        }
 
-        let f = build_table("db", &opt, mt.iter(), 123).unwrap();
-        let path = &Path::new("db").join("000123.ldb");
+        let opt = options::for_test();
+        let keys: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("k{:02}", i).into_bytes())
+            .collect();
+        let want: Vec<Vec<u8>> = keys
+            .iter()
+            .filter(|k| k.as_slice() < b"k20".as_slice() || k.as_slice() >= b"k80".as_slice())
+            .cloned()
+            .collect();
 
-        assert_eq!(
-            LookupKey::new("aabc".as_bytes(), 6).internal_key(),
-            f.smallest.as_slice()
-        );
-        assert_eq!(
-            LookupKey::new("test123".as_bytes(), 7).internal_key(),
-            f.largest.as_slice()
-        );
-        assert_eq!(379, f.size);
-        assert_eq!(123, f.num);
-        assert!(opt.env.exists(path).unwrap());
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for k in &keys {
+                db.put(k, k).unwrap();
+            }
+            let ss = db.get_snapshot();
+            assert!(db.delete_range(b"k80", b"k20").is_err());
+            db.delete_range(b"k20", b"k80").unwrap();
+
+            for k in &keys {
+                assert_eq!(want.contains(k), db.get(k).is_some(), "{:?}", k);
+            }
+            assert_eq!(want, visible(&mut db));
+
+            // The snapshot was taken before the deletion.
+            assert_eq!(Some(b"k50".to_vec()), db.get_at(&ss, b"k50").unwrap());
+            assert_eq!(100, db.new_iter_at(ss).unwrap().count_remaining());
+
+            // Entries written after the tombstone are not affected by it.
+            db.put(b"k50", b"new").unwrap();
+            assert_eq!(Some(b"new".to_vec()), db.get(b"k50"));
+            db.delete(b"k50").unwrap();
+        }
 
         {
-            // Read table back in.
-            let mut tc = TableCache::new("db", opt.clone(), 100);
-            let tbl = tc.get_table(123).unwrap();
-            assert_eq!(mt.len(), LdbIteratorIter::wrap(&mut tbl.iter()).count());
+            // Recovered from the log.
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            assert_eq!(None, db.get(b"k50"));
+            assert_eq!(want, visible(&mut db));
+
+            // Write the memtable to a table; the tombstone goes to the manifest.
+            db.make_room_for_write(true).unwrap();
+            assert_eq!(1, db.current().borrow().range_tombstones.len());
+            for k in &keys {
+                assert_eq!(want.contains(k), db.get(k).is_some(), "{:?}", k);
+            }
+            assert_eq!(want, visible(&mut db));
         }
 
         {
-            // Corrupt table; make sure it doesn't load fully.
-            let mut buf = vec![];
-            opt.env
-                .open_sequential_file(path)
+            // Recovered from the manifest.
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            assert_eq!(1, db.current().borrow().range_tombstones.len());
+            assert_eq!(want, visible(&mut db));
+
+            // Compaction drops the covered entries, but the tombstone is still needed: the
+            // remaining tables overlap its range.
+            db.compact_range(None, None).unwrap();
+            assert_eq!(want, visible(&mut db));
+            assert_eq!(1, db.current().borrow().range_tombstones.len());
+            let mut iter = db.current().borrow().new_iters().unwrap();
+            assert_eq!(
+                want.len(),
+                iter.iter_mut()
+                    .map(|it| it.count_remaining())
+                    .sum::<usize>()
+            );
+        }
+    }
+
+    #[test]
+    fn test_db_impl_delete_range_expires() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        for i in 0..10 {
+            db.put(format!("k{}", i).as_bytes(), b"v").unwrap();
+        }
+        db.delete_range(b"k", b"l").unwrap();
+        db.compact_range(None, None).unwrap();
+
+        // All tables are gone, so is the tombstone.
+        assert_eq!(0, db.current().borrow().range_tombstones.len());
+        for l in 0..NUM_LEVELS {
+            assert_eq!(0, db.current().borrow().num_level_files(l));
+        }
+        assert_eq!(0, db.new_iter().unwrap().count_remaining());
+
+        db.put(b"k1", b"w").unwrap();
+        assert_eq!(Some(b"w".to_vec()), db.get(b"k1"));
+    }
+
+    #[test]
+    fn test_db_impl_compact_to_level() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = 1 << 10;
+        opt.block_size = 256;
+        let mut db = DB::open("db", opt).unwrap();
+
+        let keys: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!("key{:05}", i).into_bytes())
+            .collect();
+        for k in &keys {
+            db.put(k, k).unwrap();
+        }
+
+        assert!(db.compact_to_level(None, None, 0).is_err());
+        assert!(db.compact_to_level(None, None, NUM_LEVELS).is_err());
+
+        db.compact_to_level(Some(b"key00000"), Some(b"key00199"), 3)
+            .unwrap();
+        {
+            let v = db.current();
+            let v = v.borrow();
+            for l in 0..NUM_LEVELS {
+                if l == 3 {
+                    assert!(v.num_level_files(l) > 0);
+                } else {
+                    assert_eq!(0, v.num_level_files(l), "files at level {}", l);
+                }
+            }
+        }
+
+        for k in &keys {
+            assert_eq!(Some(k.clone()), db.get(k));
+        }
+    }
+
+    #[test]
+    fn test_db_impl_put_sorted_iter() {
+        let n = 100_000;
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..n)
+            .map(|i| {
+                (
+                    format!("key{:08}", i).into_bytes(),
+                    format!("val{}", i).into_bytes(),
+                )
+            })
+            .collect();
+
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        db.put(b"key00000005", b"old").unwrap();
+        db.put_sorted_iter(entries.iter().map(|(k, v)| (k, v)))
+            .unwrap();
+
+        assert_eq!(n as u64, db.vset.borrow().last_seq - 1);
+        assert_eq!(0, db.mem.len());
+        for (k, v) in entries.iter().step_by(997) {
+            assert_eq!(Some(v.clone()), db.get(k));
+        }
+        assert_eq!(Some(b"val5".to_vec()), db.get(b"key00000005"));
+        assert_eq!(
+            n,
+            LdbIteratorIter::wrap(&mut db.new_iter().unwrap()).count()
+        );
+
+        // Unsorted input is rejected and leaves the database unchanged.
+        let last_seq = db.vset.borrow().last_seq;
+        let r = db.put_sorted_iter(vec![(b"zzz", b"1"), (b"aaa", b"2")]);
+        assert_eq!(StatusCode::InvalidArgument, r.unwrap_err().code);
+        assert_eq!(last_seq, db.vset.borrow().last_seq);
+        assert_eq!(None, db.get(b"zzz"));
+    }
+
+    #[test]
+    fn test_db_impl_column_families() {
+        let mut opt = options::for_test();
+        opt.column_families = vec!["a".to_string(), "b".to_string()];
+        let mut db = DB::open("db", opt).unwrap();
+
+        db.put_cf("a", b"key1", b"a1").unwrap();
+        db.put_cf("a", b"key2", b"a2").unwrap();
+        db.put_cf("b", b"key2", b"b2").unwrap();
+        db.put_cf("b", b"key3", b"b3").unwrap();
+
+        assert_eq!(Some(b"a2".to_vec()), db.get_cf("a", b"key2").unwrap());
+        assert_eq!(Some(b"b2".to_vec()), db.get_cf("b", b"key2").unwrap());
+        assert_eq!(None, db.get_cf("a", b"key3").unwrap());
+        assert_eq!(None, db.get(b"key1"));
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.put_cf("c", b"key1", b"c1").unwrap_err().code
+        );
+
+        let a: Vec<_> = LdbIteratorIter::wrap(&mut db.iter_cf("a").unwrap()).collect();
+        assert_eq!(
+            vec![
+                (b"key1".to_vec(), b"a1".to_vec()),
+                (b"key2".to_vec(), b"a2".to_vec())
+            ],
+            a
+        );
+        let b: Vec<_> = LdbIteratorIter::wrap(&mut db.iter_cf("b").unwrap()).collect();
+        assert_eq!(
+            vec![
+                (b"key2".to_vec(), b"b2".to_vec()),
+                (b"key3".to_vec(), b"b3".to_vec())
+            ],
+            b
+        );
+
+        // Deletes only affect their own family.
+        db.delete_cf("b", b"key2").unwrap();
+        assert_eq!(None, db.get_cf("b", b"key2").unwrap());
+        assert_eq!(Some(b"a2".to_vec()), db.get_cf("a", b"key2").unwrap());
+        let mut it = db.iter_cf("b").unwrap();
+        assert_eq!(Some((b"key3".to_vec(), b"b3".to_vec())), it.next());
+        assert_eq!(None, it.next());
+
+        let mut it = db.iter_cf("a").unwrap();
+        it.seek(b"key2");
+        assert_eq!(
+            Some((b"key2".to_vec(), b"a2".to_vec())),
+            current_key_val(&it)
+        );
+        assert!(it.prev());
+        assert_eq!(
+            Some((b"key1".to_vec(), b"a1".to_vec())),
+            current_key_val(&it)
+        );
+        assert!(!it.prev());
+        assert!(!it.valid());
+    }
+
+    #[test]
+    fn test_db_impl_get_approximate_sizes() {
+        let (db, _) = build_db();
+
+        let ranges = [
+            Range {
+                start: b"aaa",
+                limit: b"aaa",
+            },
+            Range {
+                start: b"aaa",
+                limit: b"bab",
+            },
+            Range {
+                start: b"aaa",
+                limit: b"fab",
+            },
+            Range {
+                start: b"aaa",
+                limit: b"zzz",
+            },
+        ];
+        let sizes = db.get_approximate_sizes(&ranges);
+        assert_eq!(ranges.len(), sizes.len());
+        assert_eq!(0, sizes[0]);
+        for i in 1..sizes.len() {
+            assert!(sizes[i - 1] < sizes[i]);
+        }
+
+        // A range after all keys is empty, and an inverted range doesn't underflow.
+        let sizes = db.get_approximate_sizes(&[
+            Range {
+                start: b"zzz",
+                limit: b"zzzz",
+            },
+            Range {
+                start: b"fab",
+                limit: b"aaa",
+            },
+        ]);
+        assert_eq!(vec![0, 0], sizes);
+    }
+
+    #[test]
+    fn test_db_impl_approximate_memory_usage() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = 1 << 20;
+        let mut db = DB::open("memusage", opt.clone()).unwrap();
+        let empty = db.approximate_memory_usage();
+        assert!(empty < 4096);
+
+        // 1000 entries of about 110 bytes each.
+        let val = [b'x'; 100];
+        for i in 0..1000 {
+            db.put(format!("key{:06}", i).as_bytes(), &val).unwrap();
+        }
+        let full = db.approximate_memory_usage();
+        assert!(full >= empty + 110 * 1000);
+        assert!(full < empty + 4 * 110 * 1000);
+
+        // An immutable memtable is counted as well.
+        let mut imm = MemTable::new(opt.cmp.clone());
+        swap(&mut imm, &mut db.mem);
+        db.imm = Some(imm);
+        assert_eq!(
+            full + db.mem.approx_mem_usage(),
+            db.approximate_memory_usage()
+        );
+
+        // After flushing, the memtables are empty; reading the table fills the block cache.
+        db.mem = db.imm.take().unwrap();
+        db.flush().unwrap();
+        assert!(db.approximate_memory_usage() < 4096);
+        for i in 0..1000 {
+            assert!(db.get(format!("key{:06}", i).as_bytes()).is_some());
+        }
+        let cached = db.approximate_memory_usage();
+        assert_eq!(
+            db.mem.approx_mem_usage() + opt.block_cache.borrow().usage(),
+            cached
+        );
+        assert!(cached >= 100 * 1000 / 2);
+        assert!(cached < 4 * 110 * 1000);
+    }
+
+    #[test]
+    fn test_db_impl_property() {
+        let (db, _) = build_db();
+        let (v, _) = make_version();
+
+        for l in 0..NUM_LEVELS {
+            assert_eq!(
+                Some(v.files[l].len().to_string()),
+                db.property(&format!("leveldb.num-files-at-level{}", l))
+            );
+        }
+        assert_eq!(None, db.property("leveldb.num-files-at-level7"));
+        assert_eq!(None, db.property("leveldb.num-files-at-levelx"));
+        assert_eq!(None, db.property("leveldb.unknown"));
+        assert_eq!(None, db.property("num-files-at-level0"));
+
+        let stats = db.property("leveldb.stats").unwrap();
+        // Header, separator and levels 0 to 3.
+        assert_eq!(6, stats.lines().count());
+        assert!(stats.lines().nth(2).unwrap().starts_with("    0      2 "));
+        assert_eq!(
+            Some(db.vset.borrow().current_summary()),
+            db.property("leveldb.sstables")
+        );
+    }
+
+    /// SyncCountingEnv wraps a MemEnv, counts opened files, flushes of log files and reads from
+    /// random-access files, and uses a manual clock.
+    #[derive(Default)]
+    struct SyncCountingEnv {
+        env: MemEnv,
+        opens: Rc<Cell<usize>>,
+        syncs: Rc<Cell<usize>>,
+        reads: Rc<Cell<usize>>,
+        now: Rc<Cell<u64>>,
+    }
+
+    impl SyncCountingEnv {
+        fn count_open(&self) {
+            self.opens.set(self.opens.get() + 1);
+        }
+    }
+
+    struct CountingReader(Box<dyn RandomAccess>, Rc<Cell<usize>>);
+
+    impl RandomAccess for CountingReader {
+        fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<usize> {
+            self.1.set(self.1.get() + 1);
+            self.0.read_at(off, dst)
+        }
+    }
+
+    struct CountingWriter(Box<dyn Write>, Rc<Cell<usize>>);
+
+    impl Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            self.1.set(self.1.get() + 1);
+            self.0.flush()
+        }
+    }
+
+    impl Env for SyncCountingEnv {
+        fn open_sequential_file(&self, p: &Path) -> Result<Box<dyn Read>> {
+            self.count_open();
+            self.env.open_sequential_file(p)
+        }
+        fn open_random_access_file(&self, p: &Path) -> Result<Box<dyn RandomAccess>> {
+            self.count_open();
+            let f = self.env.open_random_access_file(p)?;
+            Ok(Box::new(CountingReader(f, self.reads.clone())))
+        }
+        fn open_writable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
+            self.count_open();
+            let f = self.env.open_writable_file(p)?;
+            if matches!(
+                parse_file_name(p.file_name().unwrap()),
+                Ok((_, FileType::Log))
+            ) {
+                Ok(Box::new(CountingWriter(f, self.syncs.clone())))
+            } else {
+                Ok(f)
+            }
+        }
+        fn open_appendable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
+            self.count_open();
+            self.env.open_appendable_file(p)
+        }
+        fn sync(&self, w: &mut dyn Write) -> Result<()> {
+            self.env.sync(w)
+        }
+        fn exists(&self, p: &Path) -> Result<bool> {
+            self.env.exists(p)
+        }
+        fn children(&self, p: &Path) -> Result<Vec<PathBuf>> {
+            self.env.children(p)
+        }
+        fn size_of(&self, p: &Path) -> Result<usize> {
+            self.env.size_of(p)
+        }
+        fn delete(&self, p: &Path) -> Result<()> {
+            self.env.delete(p)
+        }
+        fn mkdir(&self, p: &Path) -> Result<()> {
+            self.env.mkdir(p)
+        }
+        fn rmdir(&self, p: &Path) -> Result<()> {
+            self.env.rmdir(p)
+        }
+        fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+            self.env.rename(from, to)
+        }
+        fn lock(&self, p: &Path) -> Result<FileLock> {
+            self.env.lock(p)
+        }
+        fn unlock(&self, l: FileLock) -> Result<()> {
+            self.env.unlock(l)
+        }
+        fn new_logger(&self, p: &Path) -> Result<env::Logger> {
+            self.env.new_logger(p)
+        }
+        fn micros(&self) -> u64 {
+            self.now.get()
+        }
+        fn now_monotonic(&self) -> u64 {
+            self.now.get()
+        }
+        fn sleep_for(&self, micros: u32) {
+            self.now.set(self.now.get() + micros as u64);
+        }
+    }
+
+    #[test]
+    fn test_db_impl_custom_env() {
+        let opens = Rc::new(Cell::new(0));
+        let env = SyncCountingEnv {
+            opens: opens.clone(),
+            ..Default::default()
+        };
+        let opt = OptionsBuilder::new().env(Box::new(env)).build();
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        // At least the manifest, CURRENT and the log were opened through the custom env.
+        let after_open = opens.get();
+        assert!(after_open >= 3);
+
+        db.put(b"abc", b"def").unwrap();
+        db.flush().unwrap();
+        assert!(opens.get() > after_open);
+        drop(db);
+
+        let before = opens.get();
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+        assert!(opens.get() > before);
+    }
+
+    #[test]
+    fn test_db_impl_default_rename_noreplace() {
+        // SyncCountingEnv doesn't override rename_noreplace.
+        let env = SyncCountingEnv::default();
+        let (src, dst, other) = (Path::new("src"), Path::new("dst"), Path::new("other"));
+        env.open_writable_file(src)
+            .unwrap()
+            .write_all(b"src")
+            .unwrap();
+        env.open_writable_file(dst).unwrap();
+
+        assert_eq!(
+            StatusCode::AlreadyExists,
+            env.rename_noreplace(src, dst).unwrap_err().code
+        );
+        assert_eq!(b"src".to_vec(), env.read_to_vec(src).unwrap());
+        env.rename_noreplace(src, other).unwrap();
+        assert!(!env.exists(src).unwrap());
+        assert_eq!(b"src".to_vec(), env.read_to_vec(other).unwrap());
+        assert_eq!(
+            StatusCode::NotFound,
+            env.rename_noreplace(src, Path::new("new"))
+                .unwrap_err()
+                .code
+        );
+    }
+
+    #[test]
+    fn test_db_impl_skip_corrupt_tables() {
+        let env = MemEnv::new();
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(env.clone()));
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for prefix in ["a", "b", "c"] {
+            for i in 0..3 {
+                db.put(format!("{}{}", prefix, i).as_bytes(), b"val")
+                    .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        // Find the table containing the "b" keys.
+        let num = db
+            .current()
+            .borrow()
+            .files
+            .iter()
+            .flatten()
+            .find(|f| parse_internal_key(&f.borrow().smallest).2 == b"b0")
+            .unwrap()
+            .borrow()
+            .num;
+        drop(db);
+        let path = table_file_name("db", num);
+        let fname = path.file_name().unwrap().to_str().unwrap().to_string();
+        let mut lenient = opt.clone();
+        lenient.skip_corrupt_tables = true;
+
+        // Other errors than corruption don't exclude the table.
+        env.set_fault(&fname, FaultOp::Open, StatusCode::IOError);
+        drop(DB::open("db", lenient.clone()).unwrap());
+        env.clear_faults();
+        let db = DB::open("db", opt.clone()).unwrap();
+        assert!(db.vset.borrow().live_files().contains(&num));
+        drop(db);
+
+        // Break the footer.
+        let mut buf = opt.env.read_to_vec(&path).unwrap();
+        let len = buf.len();
+        buf[len - 8..].fill(0);
+        opt.env
+            .open_writable_file(&path)
+            .unwrap()
+            .write_all(&buf)
+            .unwrap();
+
+        // Without skip_corrupt_tables, the table is only noticed when it is read.
+        let db = DB::open("db", opt.clone()).unwrap();
+        assert!(db.vset.borrow().live_files().contains(&num));
+        drop(db);
+
+        // If the manifest can't be written, the table is neither excluded nor renamed.
+        env.set_fault("MANIFEST", FaultOp::Write, StatusCode::IOError);
+        assert!(DB::open("db", lenient.clone()).is_err());
+        env.clear_faults();
+        assert!(opt.env.exists(&path).unwrap());
+        let db = DB::open("db", opt.clone()).unwrap();
+        assert!(db.vset.borrow().live_files().contains(&num));
+        drop(db);
+
+        let mut db = DB::open("db", lenient).unwrap();
+        assert!(!db.vset.borrow().live_files().contains(&num));
+        for k in ["a0", "a2", "c0", "c2"] {
+            assert_eq!(Some(b"val".to_vec()), db.get(k.as_bytes()));
+        }
+        assert_eq!(None, db.get(b"b0"));
+        assert_eq!(
+            6,
+            LdbIteratorIter::wrap(&mut db.new_iter().unwrap()).count()
+        );
+        drop(db);
+
+        // The exclusion was recorded in the manifest, and the file was kept.
+        let mut corrupt = path.into_os_string();
+        corrupt.push(".corrupt");
+        assert!(opt.env.exists(Path::new(&corrupt)).unwrap());
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(
+            6,
+            LdbIteratorIter::wrap(&mut db.new_iter().unwrap()).count()
+        );
+    }
+
+    #[test]
+    fn test_db_impl_size_limits() {
+        let mut opt = options::for_test();
+        opt.max_key_size = Some(4);
+        opt.max_value_size = Some(8);
+        let mut db = DB::open("db", opt).unwrap();
+
+        db.put(b"abcd", b"12345678").unwrap();
+        let e = db.put(b"abcde", b"1").unwrap_err();
+        assert_eq!(StatusCode::InvalidArgument, e.code);
+        assert!(e.err.ends_with("key of length 5 exceeds max_key_size 4"));
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.delete(b"abcde").unwrap_err().code
+        );
+        let e = db.put(b"abc", b"123456789").unwrap_err();
+        assert_eq!(StatusCode::InvalidArgument, e.code);
+        assert!(e
+            .err
+            .ends_with("value of length 9 exceeds max_value_size 8"));
+
+        // A batch with one bad entry is rejected as a whole.
+        let mut wb = WriteBatch::new();
+        wb.put(b"xyz", b"1");
+        wb.put(b"xyz", b"123456789");
+        assert!(db.write(wb, false).is_err());
+        assert_eq!(None, db.get(b"xyz"));
+        assert_eq!(1, db.vset.borrow().last_seq);
+
+        // Empty keys are valid; they are stored with a tag in the memtable.
+        db.put(b"", b"empty").unwrap();
+        assert_eq!(Some(b"empty".to_vec()), db.get(b""));
+        drop(db);
+
+        // Keys too long for max_key_length are rejected when written instead of failing the
+        // flush.
+        let mut opt = options::for_test();
+        opt.max_key_length = Some(12);
+        let mut db = DB::open("db2", opt).unwrap();
+        db.put(b"abcd", b"1").unwrap();
+        let e = db.put(b"abcde", b"1").unwrap_err();
+        assert_eq!(StatusCode::InvalidArgument, e.code);
+        assert!(e.err.contains("exceeds max_key_length 12"));
+        db.flush().unwrap();
+        assert_eq!(Some(b"1".to_vec()), db.get(b"abcd"));
+    }
+
+    #[test]
+    fn test_db_impl_name_and_options() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = 12345;
+        let db = DB::open("dbs/first", opt).unwrap();
+        assert_eq!(Path::new("dbs/first"), db.name());
+        assert_eq!(12345, db.options().write_buffer_size);
+
+        let opt = db.options().clone();
+        let db2 = DB::open(Path::new("dbs").join("second"), opt).unwrap();
+        assert_eq!(Path::new("dbs/second"), db2.name());
+        assert_eq!(Path::new("dbs/first"), db.name());
+    }
+
+    #[test]
+    fn test_db_impl_write_batch() {
+        let opt = options::for_test();
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"c", b"c0").unwrap();
+        let before = db.get_snapshot();
+
+        let mut wb = WriteBatch::new();
+        wb.put(b"a", b"a1");
+        wb.put(b"b", b"b1");
+        wb.delete(b"c");
+        wb.set_sync(true);
+        db.write(wb, false).unwrap();
+
+        // All operations of the batch became visible at once.
+        let collect = |iter: &mut DBIterator| LdbIteratorIter::wrap(iter).collect::<Vec<_>>();
+        assert_eq!(
+            vec![(b"c".to_vec(), b"c0".to_vec())],
+            collect(&mut db.new_iter_at(before).unwrap())
+        );
+        let want = vec![
+            (b"a".to_vec(), b"a1".to_vec()),
+            (b"b".to_vec(), b"b1".to_vec()),
+        ];
+        assert_eq!(want, collect(&mut db.new_iter().unwrap()));
+
+        // Simulate a crash: whatever wasn't synced to the log is lost.
+        db.put(b"d", b"unsynced").unwrap();
+        mem::forget(db.log.take());
+        drop(db);
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(want, collect(&mut db.new_iter().unwrap()));
+        assert_eq!(None, db.get(b"d"));
+    }
+
+    #[test]
+    fn test_db_impl_put_with_seq() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        let mut seqs = vec![];
+        for k in &["abc", "abd", "abe", "abc"] {
+            seqs.push(db.put_with_seq(k.as_bytes(), b"val").unwrap());
+        }
+        assert!(seqs.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(*seqs.last().unwrap(), db.vset.borrow().last_seq);
+        db.sync_log(true).unwrap();
+
+        // The sequence numbers match the order in which the batches appear in the log.
+        let logfile = db
+            .opt
+            .env
+            .open_sequential_file(Path::new(&log_file_name(&db.name, db.log_num.unwrap())))
+            .unwrap();
+        let mut logreader = LogReader::new(logfile, true, db.opt.checksum_type);
+        let (mut scratch, mut batch) = (vec![], WriteBatch::new());
+        let mut logged = vec![];
+        while logreader.read_record(&mut scratch).unwrap() {
+            batch.set_contents(&scratch);
+            logged.push(batch.sequence());
+        }
+        assert_eq!(seqs, logged);
+    }
+
+    #[test]
+    fn test_db_impl_memory_budget() {
+        let budget = Arc::new(AtomicUsize::new(0));
+        let mut opt = options::for_test();
+        opt.memory_budget = Some(budget.clone());
+        opt.memory_budget_limit = 64 * 1024;
+        let mut db1 = DB::open("db1", opt.clone()).unwrap();
+        let mut db2 = DB::open("db2", opt.clone()).unwrap();
+        let mut db3 = DB::open("db3", opt.clone()).unwrap();
+        let val = [b'x'; 1000];
+        let num_files =
+            |db: &DB| -> usize { db.current().borrow().files.iter().map(Vec::len).sum() };
+
+        for i in 0..40 {
+            db1.put(format!("key{:03}", i).as_bytes(), &val).unwrap();
+        }
+        assert!(budget.load(atomic::Ordering::Relaxed) > 40 * val.len());
+        let mut i = 0;
+        while budget.load(atomic::Ordering::Relaxed) <= opt.memory_budget_limit {
+            db2.put(format!("key{:03}", i).as_bytes(), &val).unwrap();
+            i += 1;
+        }
+        // Neither memtable is anywhere near write_buffer_size.
+        assert!(db1.mem.approx_mem_usage() < opt.write_buffer_size / 2);
+        assert_eq!(0, num_files(&db2));
+
+        // The next write to any DB flushes its memtable, and releases its memory; an empty
+        // memtable is not flushed.
+        db3.put(b"key000", &val).unwrap();
+        assert_eq!(0, num_files(&db3));
+        db2.put(b"trigger", &val).unwrap();
+        assert_eq!(1, num_files(&db2));
+        assert_eq!(0, num_files(&db1));
+        assert_eq!(
+            db1.mem.approx_mem_usage() + db2.mem.approx_mem_usage() + db3.mem.approx_mem_usage(),
+            budget.load(atomic::Ordering::Relaxed)
+        );
+        assert_eq!(Some(val.to_vec()), db2.get(b"key000"));
+
+        drop(db1);
+        drop(db2);
+        drop(db3);
+        assert_eq!(0, budget.load(atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn test_db_impl_memory_budget_many_dbs() {
+        let budget = Arc::new(AtomicUsize::new(0));
+        let mut opt = options::for_test();
+        opt.memory_budget = Some(budget.clone());
+        opt.memory_budget_limit = 64 * 1024;
+        // Each memtable holds only a small share of the budget.
+        let mut dbs: Vec<DB> = (0..32)
+            .map(|i| DB::open(format!("db{}", i), opt.clone()).unwrap())
+            .collect();
+        let val = [b'x'; 1000];
+
+        let mut i = 0;
+        while budget.load(atomic::Ordering::Relaxed) <= opt.memory_budget_limit {
+            dbs[i % 32]
+                .put(format!("key{:03}", i).as_bytes(), &val)
+                .unwrap();
+            i += 1;
+        }
+        // Writing to every DB once brings the budget back under the limit.
+        let mut recovered = false;
+        for (j, db) in dbs.iter_mut().enumerate() {
+            db.put(format!("more{:03}", j).as_bytes(), &val).unwrap();
+            recovered |= budget.load(atomic::Ordering::Relaxed) <= opt.memory_budget_limit;
+        }
+        assert!(recovered);
+        assert_eq!(Some(val.to_vec()), dbs[0].get(b"key000"));
+        assert_eq!(Some(val.to_vec()), dbs[31].get(b"more031"));
+    }
+
+    #[test]
+    fn test_db_impl_merge_dbs() {
+        let opt = options::for_test();
+        {
+            let mut db1 = DB::open("db1", opt.clone()).unwrap();
+            for (k, v) in [("a", "a1"), ("b", "b1"), ("c", "c1")] {
+                db1.put(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            let mut db2 = DB::open("db2", opt.clone()).unwrap();
+            for (k, v) in [
+                ("c", "c2"),
+                ("d", "d2"),
+                ("e", "e2"),
+                ("b", "b2"),
+                ("z", "z2"),
+            ] {
+                db2.put(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            db2.delete(b"e").unwrap();
+            db2.compact_range(None, None).unwrap();
+        }
+
+        merge_dbs(&["db1", "db2"], "merged", opt.clone()).unwrap();
+        assert_eq!(
+            StatusCode::AlreadyExists,
+            merge_dbs(&["db1", "db2"], "merged", opt.clone())
+                .unwrap_err()
+                .code
+        );
+
+        let mut db = DB::open("merged", opt).unwrap();
+        let mut iter = db.new_iter().unwrap();
+        let entries: Vec<_> = LdbIteratorIter::wrap(&mut iter).collect();
+        let expected: Vec<_> = [
+            ("a", "a1"),
+            ("b", "b2"),
+            ("c", "c1"),
+            ("d", "d2"),
+            ("z", "z2"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+        .collect();
+        assert_eq!(expected, entries);
+        // The merged data lives in tables.
+        let v = db.vset.borrow().current();
+        assert!(v.borrow().files.iter().any(|l| !l.is_empty()));
+    }
+
+    #[test]
+    fn test_db_impl_recover_seq_from_tables() {
+        let opt = options::for_test();
+        let last_seq;
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for k in &["abc", "abd", "abe", "abf"] {
+                db.put(k.as_bytes(), b"val").unwrap();
+            }
+            db.compact_range(None, None).unwrap();
+            last_seq = db.vset.borrow().last_seq;
+
+            // Simulate a manifest whose last sequence number is stale.
+            db.vset.borrow_mut().last_seq = 1;
+            db.vset
+                .borrow_mut()
+                .log_and_apply(VersionEdit::new())
+                .unwrap();
+        }
+        for f in opt.env.children(Path::new("db")).unwrap() {
+            if let Ok((_, FileType::Log)) = parse_file_name(&f) {
+                opt.env.delete(&Path::new("db").join(f)).unwrap();
+            }
+        }
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert!(db.vset.borrow().last_seq >= last_seq);
+        assert!(db.put_with_seq(b"abc", b"new").unwrap() > last_seq);
+        assert_eq!(Some(b"new".to_vec()), db.get(b"abc"));
+    }
+
+    #[test]
+    fn test_db_impl_recover_seq_from_range_tombstones() {
+        let opt = options::for_test();
+        let last_seq;
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for k in &["abc", "abd", "abe", "abf"] {
+                db.put(k.as_bytes(), b"val").unwrap();
+            }
+            db.compact_range(None, None).unwrap();
+            // The tombstone has the largest sequence number, but no table contains it.
+            db.delete_range(b"abc", b"abz").unwrap();
+            db.flush().unwrap();
+            assert_eq!(1, db.current().borrow().range_tombstones.len());
+            last_seq = db.vset.borrow().last_seq;
+
+            db.vset.borrow_mut().last_seq = 1;
+            db.vset
+                .borrow_mut()
+                .log_and_apply(VersionEdit::new())
+                .unwrap();
+        }
+        for f in opt.env.children(Path::new("db")).unwrap() {
+            if let Ok((_, FileType::Log)) = parse_file_name(&f) {
+                opt.env.delete(&Path::new("db").join(f)).unwrap();
+            }
+        }
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert!(db.vset.borrow().last_seq >= last_seq);
+        assert!(db.put_with_seq(b"abc", b"new").unwrap() > last_seq);
+        assert_eq!(Some(b"new".to_vec()), db.get(b"abc"));
+    }
+
+    #[test]
+    fn test_db_impl_copy_table_file() {
+        let env = MemEnv::new();
+        let (src, dest) = (Path::new("src.ldb"), Path::new("dest.ldb"));
+        let contents: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        env.open_writable_file(src)
+            .unwrap()
+            .write_all(&contents)
+            .unwrap();
+
+        copy_table_file(&env, src, dest).unwrap();
+        assert_eq!(contents, env.read_to_vec(dest).unwrap());
+
+        // A failed copy is deleted.
+        env.delete(dest).unwrap();
+        env.set_fault("dest", FaultOp::Write, StatusCode::IOError);
+        assert_eq!(
+            StatusCode::IOError,
+            copy_table_file(&env, src, dest).err().unwrap().code
+        );
+        env.clear_faults();
+        assert!(!env.exists(dest).unwrap());
+    }
+
+    #[test]
+    fn test_db_impl_prefix_upper_bound() {
+        assert_eq!(Some(b"abd".to_vec()), prefix_upper_bound(b"abc"));
+        assert_eq!(Some(b"ac".to_vec()), prefix_upper_bound(b"ab\xff"));
+        assert_eq!(Some(vec![1]), prefix_upper_bound(&[0, 0xff, 0xff]));
+        assert_eq!(None, prefix_upper_bound(&[0xff, 0xff]));
+        assert_eq!(None, prefix_upper_bound(b""));
+    }
+
+    #[test]
+    fn test_db_impl_prefix_iter() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        for (i, user) in ["user:1:", "user:2:", "user:10:", "usr:2:"]
+            .iter()
+            .enumerate()
+        {
+            for field in &["name", "age", "mail"] {
+                let key = format!("{}{}", user, field);
+                db.put(key.as_bytes(), format!("{}", i).as_bytes()).unwrap();
+            }
+        }
+        // Move some entries to tables.
+        db.compact_range(Some(b"user:1:"), Some(b"user:2:"))
+            .unwrap();
+        db.put(b"user:2:zip", b"1").unwrap();
+        db.delete(b"user:2:mail").unwrap();
+
+        let mut iter = db.prefix_iter(b"user:2:").unwrap();
+        let mut keys = vec![];
+        while iter.valid() {
+            let (k, v) = current_key_val(&iter).unwrap();
+            assert!(k.starts_with(b"user:2:"));
+            keys.push((String::from_utf8(k).unwrap(), v));
+            iter.advance();
+        }
+        assert_eq!(
+            vec![
+                ("user:2:age".to_string(), b"1".to_vec()),
+                ("user:2:name".to_string(), b"1".to_vec()),
+                ("user:2:zip".to_string(), b"1".to_vec()),
+            ],
+            keys
+        );
+
+        let iter = db.prefix_iter(b"user:3").unwrap();
+        assert!(!iter.valid());
+        let iter = db.prefix_iter(b"usr:").unwrap();
+        assert_eq!(b"usr:2:age", current_key_val(&iter).unwrap().0.as_slice());
+    }
+
+    #[test]
+    fn test_db_impl_prefix_iter_skips_tables() {
+        let mut opt = options::for_test();
+        opt.prefix_extractor = Some(Rc::new(Box::new(FixedPrefixExtractor::new(2))));
+
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            // The first table's key range includes the second table's, but not its prefix.
+            db.put(b"aa1", b"1").unwrap();
+            db.put(b"ae1", b"1").unwrap();
+            db.make_room_for_write(true).unwrap();
+            db.put(b"ac1", b"2").unwrap();
+            db.put(b"ac2", b"2").unwrap();
+            db.make_room_for_write(true).unwrap();
+            assert_eq!(2, db.current().borrow().files.iter().flatten().count());
+        }
+
+        opt.block_cache = share(Cache::new(128));
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        let mut iter = db.prefix_iter(b"ac").unwrap();
+        let mut entries = vec![];
+        while let Some(kv) = current_key_val(&iter) {
+            entries.push(kv);
+            iter.advance();
+        }
+        assert_eq!(
+            vec![
+                (b"ac1".to_vec(), b"2".to_vec()),
+                (b"ac2".to_vec(), b"2".to_vec())
+            ],
+            entries
+        );
+        // Only the data block of the table containing "ac" keys was read.
+        assert_eq!(1, opt.block_cache.borrow().count());
+
+        let iter = db.prefix_iter(b"ab").unwrap();
+        assert!(!iter.valid());
+        assert_eq!(1, opt.block_cache.borrow().count());
+
+        // Tables written without prefix extractor have whole-key filters, which are not used.
+        opt.prefix_extractor = None;
+        let mut db = DB::open("db2", opt.clone()).unwrap();
+        db.put(b"aa1", b"1").unwrap();
+        db.put(b"ae1", b"1").unwrap();
+        db.make_room_for_write(true).unwrap();
+        drop(db);
+        opt.prefix_extractor = Some(Rc::new(Box::new(FixedPrefixExtractor::new(2))));
+        let mut db = DB::open("db2", opt).unwrap();
+        let t = db
+            .current()
+            .borrow()
+            .files
+            .iter()
+            .flatten()
+            .next()
+            .unwrap()
+            .borrow()
+            .num;
+        let table = db.cache.borrow_mut().get_table(t).unwrap();
+        assert!(table.prefix_may_match(b"ac"));
+        assert!(!db.prefix_iter(b"ac").unwrap().valid());
+    }
+
+    #[test]
+    fn test_db_impl_bootstrap_from_sstables() {
+        let opt = options::for_test();
+        let env = opt.env.as_ref().as_ref();
+        env.mkdir(Path::new("tables")).unwrap();
+        let t1 = PathBuf::from("tables/a.ldb");
+        let t2 = PathBuf::from("tables/b.ldb");
+        let t3 = PathBuf::from("tables/c.ldb");
+        write_sorted_table(env, &t1, &[(b"aaa", b"1"), (b"abc", b"2")], 1, &opt).unwrap();
+        write_sorted_table(env, &t2, &[(b"xxx", b"3"), (b"xyz", b"4")], 3, &opt).unwrap();
+        write_sorted_table(env, &t3, &[(b"abc", b"5")], 10, &opt).unwrap();
+
+        let mut db =
+            DB::bootstrap_from_sstables("db", &[t2.clone(), t1.clone()], opt.clone()).unwrap();
+        let mut iter = db.new_iter().unwrap();
+        assert_eq!(
+            vec![
+                (b"aaa".to_vec(), b"1".to_vec()),
+                (b"abc".to_vec(), b"2".to_vec()),
+                (b"xxx".to_vec(), b"3".to_vec()),
+                (b"xyz".to_vec(), b"4".to_vec()),
+            ],
+            LdbIteratorIter::wrap(&mut iter).collect::<Vec<_>>()
+        );
+        // Disjoint tables are placed below level 0, and the source files are kept.
+        assert_eq!(0, db.current().borrow().num_level_files(0));
+        assert!(env.exists(&t1).unwrap());
+        assert_eq!(4, db.vset.borrow().last_seq);
+
+        // New writes get newer sequence numbers, and everything survives reopening.
+        db.put(b"abc", b"new").unwrap();
+        drop(db);
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"new".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"4".to_vec()), db.get(b"xyz"));
+        drop(db);
+
+        assert_eq!(
+            StatusCode::AlreadyExists,
+            DB::bootstrap_from_sstables("db", std::slice::from_ref(&t1), opt.clone())
+                .err()
+                .unwrap()
+                .code
+        );
+
+        // Overlapping tables go to level 0, where the newest entry wins.
+        let mut db = DB::bootstrap_from_sstables("db2", &[t3, t1.clone()], opt.clone()).unwrap();
+        assert_eq!(2, db.current().borrow().num_level_files(0));
+        assert_eq!(Some(b"5".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"1".to_vec()), db.get(b"aaa"));
+
+        let t4 = PathBuf::from("tables/garbage.ldb");
+        env.open_writable_file(&t4)
+            .unwrap()
+            .write_all(b"not a table")
+            .unwrap();
+        assert!(DB::bootstrap_from_sstables("db3", &[t1, t4], opt.clone()).is_err());
+        assert!(!env.exists(Path::new("db3")).unwrap());
+    }
+
+    #[test]
+    fn test_db_impl_reuse_logs() {
+        let opt = options::for_test();
+        let env = opt.env.clone();
+        let logs = || -> Vec<PathBuf> {
+            env.children(Path::new("db"))
+                .unwrap()
+                .into_iter()
+                .filter(|f| parse_file_name(f).map(|(_, t)| t) == Ok(FileType::Log))
+                .collect()
+        };
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"aaa", b"1").unwrap();
+        drop(db);
+        let first_logs = logs();
+        assert_eq!(1, first_logs.len());
+
+        // The log is appended to instead of being replaced.
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(first_logs, logs());
+        assert_eq!(1, db.mem.len());
+        db.put(b"bbb", b"2").unwrap();
+        drop(db);
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(first_logs, logs());
+        assert_eq!(Some(b"1".to_vec()), db.get(b"aaa"));
+        assert_eq!(Some(b"2".to_vec()), db.get(b"bbb"));
+        drop(db);
+
+        // A damaged log is not reused: records written afterwards must not end up behind the
+        // damaged one.
+        let path = Path::new("db").join(&first_logs[0]);
+        let mut contents = env.read_to_vec(&path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        env.open_writable_file(&path)
+            .unwrap()
+            .write_all(&contents)
+            .unwrap();
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_ne!(first_logs, logs());
+        assert_eq!(Some(b"1".to_vec()), db.get(b"aaa"));
+        assert_eq!(None, db.get(b"bbb"));
+        db.put(b"ccc", b"3").unwrap();
+        drop(db);
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"1".to_vec()), db.get(b"aaa"));
+        assert_eq!(Some(b"3".to_vec()), db.get(b"ccc"));
+    }
+
+    #[test]
+    fn test_db_impl_recover_empty_log_record() {
+        let opt = options::for_test();
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"aaa", b"1").unwrap();
+        // An empty record, as written by other implementations, doesn't end recovery; the
+        // records after it are replayed.
+        db.log.as_mut().unwrap().add_empty_record().unwrap();
+        db.put(b"bbb", b"2").unwrap();
+        drop(db);
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(b"1".to_vec()), db.get(b"aaa"));
+        assert_eq!(Some(b"2".to_vec()), db.get(b"bbb"));
+    }
+
+    #[test]
+    fn test_db_impl_open_create_if_missing_error_if_exists() {
+        let mut opt = options::for_test();
+        let env = opt.env.clone();
+
+        opt.create_if_missing = false;
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            DB::open("db", opt.clone()).err().unwrap().code
+        );
+        assert!(!env.exists(&Path::new("db").join("CURRENT")).unwrap());
+
+        opt.create_if_missing = true;
+        opt.error_if_exists = true;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"abc", b"def").unwrap();
+        drop(db);
+
+        // The database exists now.
+        assert_eq!(
+            StatusCode::AlreadyExists,
+            DB::open("db", opt.clone()).err().unwrap().code
+        );
+        opt.create_if_missing = false;
+        opt.error_if_exists = false;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+        drop(db);
+
+        // An empty directory is not a database.
+        env.mkdir(Path::new("empty")).unwrap();
+        opt.create_if_missing = true;
+        opt.error_if_exists = true;
+        assert!(DB::open("empty", opt.clone()).is_ok());
+    }
+
+    #[test]
+    fn test_db_impl_ingest_table() {
+        let mut opt = options::for_test();
+        opt.create_if_missing = true;
+        let env = opt.env.as_ref().as_ref();
+        env.mkdir(Path::new("tables")).unwrap();
+        let t1 = PathBuf::from("tables/a.ldb");
+        let t2 = PathBuf::from("tables/b.ldb");
+        let t3 = PathBuf::from("tables/c.ldb");
+        write_sorted_table(env, &t1, &[(b"xxx", b"1"), (b"xyz", b"2")], 1, &opt).unwrap();
+        write_sorted_table(env, &t2, &[(b"abc", b"3")], 1, &opt).unwrap();
+        write_sorted_table(env, &t3, &[(b"abc", b"4"), (b"abd", b"5")], 100, &opt).unwrap();
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"aaa", b"old").unwrap();
+        db.put(b"abc", b"old").unwrap();
+
+        // A table not overlapping anything goes to the last level.
+        db.ingest_table(&t1).unwrap();
+        assert_eq!(1, db.current().borrow().num_level_files(NUM_LEVELS - 1));
+        assert_eq!(Some(b"1".to_vec()), db.get(b"xxx"));
+        assert_eq!(Some(b"2".to_vec()), db.get(b"xyz"));
+        // The memtable was flushed.
+        assert_eq!(0, db.mem.len());
+
+        // Overlapping tables must be newer than the database contents.
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.ingest_table(&t2).err().unwrap().code
+        );
+        db.ingest_table(&t3).unwrap();
+        assert_eq!(Some(b"4".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"5".to_vec()), db.get(b"abd"));
+        assert_eq!(Some(b"old".to_vec()), db.get(b"aaa"));
+        assert_eq!(101, db.vset.borrow().last_seq);
+
+        // New writes get newer sequence numbers, and everything survives reopening.
+        db.put(b"abd", b"new").unwrap();
+        drop(db);
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"new".to_vec()), db.get(b"abd"));
+        assert_eq!(Some(b"4".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"2".to_vec()), db.get(b"xyz"));
+
+        let t4 = PathBuf::from("tables/garbage.ldb");
+        env.open_writable_file(&t4)
+            .unwrap()
+            .write_all(b"not a table")
+            .unwrap();
+        assert_eq!(
+            StatusCode::Corruption,
+            db.ingest_table(&t4).err().unwrap().code
+        );
+    }
+
+    #[test]
+    fn test_db_impl_ingest_table_range_tombstone() {
+        let opt = options::for_test();
+        let env = opt.env.as_ref().as_ref();
+        env.mkdir(Path::new("tables")).unwrap();
+        let old = PathBuf::from("tables/old.ldb");
+        let new = PathBuf::from("tables/new.ldb");
+        write_sorted_table(env, &old, &[(b"bbb", b"1"), (b"ccc", b"2")], 1, &opt).unwrap();
+        write_sorted_table(env, &new, &[(b"bbb", b"3")], 100, &opt).unwrap();
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"bbb", b"old").unwrap();
+        db.put(b"ccc", b"old").unwrap();
+        db.delete_range(b"a", b"z").unwrap();
+        db.put(b"yyy", b"new").unwrap();
+        // The covered entries are compacted away; the tombstone is kept for yyy's table.
+        db.compact_range(None, None).unwrap();
+        assert_eq!(1, db.current().borrow().range_tombstones.len());
+        assert!(
+            !(0..NUM_LEVELS).any(|l| db.current().borrow().overlap_in_level(
+                l,
+                &&b"bbb"[..],
+                &&b"ccc"[..]
+            ))
+        );
+
+        // The tombstone would hide all entries of the older table.
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.ingest_table(&old).err().unwrap().code
+        );
+        db.ingest_table(&new).unwrap();
+        assert_eq!(Some(b"3".to_vec()), db.get(b"bbb"));
+        assert_eq!(None, db.get(b"ccc"));
+    }
+
+    #[test]
+    fn test_db_impl_repair() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = 1 << 10;
+        opt.block_size = 256;
+        let keys: Vec<Vec<u8>> = (0..300)
+            .map(|i| format!("key{:04}", i).into_bytes())
+            .collect();
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for k in &keys {
+                db.put(k, b"old").unwrap();
+            }
+            db.compact_range(None, None).unwrap();
+            // Newer versions in level 0 and in the log.
+            for k in &keys[..100] {
+                db.put(k, b"new").unwrap();
+            }
+            db.flush().unwrap();
+            db.put(&keys[200], b"logged").unwrap();
+            db.delete(&keys[250]).unwrap();
+            db.sync_log(true).unwrap();
+        }
+
+        let env = opt.env.clone();
+        for f in env.children(Path::new("db")).unwrap() {
+            if let Ok((_, FileType::Descriptor | FileType::Current)) = parse_file_name(&f) {
+                env.delete(&Path::new("db").join(f)).unwrap();
+            }
+        }
+        // A garbage table is moved away.
+        env.open_writable_file(&table_file_name("db", 999))
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+        assert!(DB::open_for_readonly("db", opt.clone()).is_err());
+
+        DB::repair("db", opt.clone()).unwrap();
+        assert!(env
+            .exists(&Path::new("db").join("lost").join("000999.ldb"))
+            .unwrap());
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for (i, k) in keys.iter().enumerate() {
+            let want = match i {
+                0..=99 => Some(b"new".to_vec()),
+                200 => Some(b"logged".to_vec()),
+                250 => None,
+                _ => Some(b"old".to_vec()),
+            };
+            assert_eq!(want, db.get(k), "key {}", i);
+        }
+        // New writes don't reuse sequence numbers.
+        db.put(&keys[0], b"newest").unwrap();
+        assert_eq!(Some(b"newest".to_vec()), db.get(&keys[0]));
+
+        assert_eq!(
+            StatusCode::NotFound,
+            DB::repair("otherdb", opt).unwrap_err().code
+        );
+    }
+
+    #[test]
+    fn test_db_impl_open_for_readonly() {
+        let opt = options::for_test();
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            db.put(b"abc", b"def").unwrap();
+            db.put(b"xyz", b"123").unwrap();
+            db.compact_range(None, None).unwrap();
+            // Only in the log.
+            db.put(b"mno", b"456").unwrap();
+            db.delete(b"xyz").unwrap();
+            db.sync_log(true).unwrap();
+        }
+        let files_before = opt.env.children(Path::new("db")).unwrap();
+
+        let mut db = DB::open_for_readonly("db", opt.clone()).unwrap();
+        assert!(db.lock.is_none());
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"456".to_vec()), db.get(b"mno"));
+        assert_eq!(None, db.get(b"xyz"));
+        let mut iter = db.new_iter().unwrap();
+        let keys: Vec<_> = LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect();
+        assert_eq!(vec![b"abc".to_vec(), b"mno".to_vec()], keys);
+
+        for r in [
+            db.put(b"abc", b"xxx"),
+            db.delete(b"abc"),
+            db.write(WriteBatch::new(), true),
+            db.flush(),
+            db.compact_range(None, None),
+        ] {
+            assert_eq!(StatusCode::NotSupported, r.unwrap_err().code);
+        }
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+
+        // Nothing was changed on disk, and the database can still be opened for writing.
+        drop(db);
+        assert_eq!(files_before, opt.env.children(Path::new("db")).unwrap());
+        let db2 = DB::open_for_readonly("db", opt.clone()).unwrap();
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"456".to_vec()), db.get(b"mno"));
+        drop(db2);
+
+        assert_eq!(
+            StatusCode::NotFound,
+            DB::open_for_readonly("otherdb", opt).err().unwrap().code
+        );
+    }
+
+    #[test]
+    fn test_db_impl_flush_writes_table() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        let num_files = |db: &DB| -> usize {
+            (0..NUM_LEVELS)
+                .map(|l| {
+                    db.property(&format!("leveldb.num-files-at-level{}", l))
+                        .unwrap()
+                        .parse::<usize>()
+                        .unwrap()
+                })
+                .sum()
+        };
+        db.put(b"abc", b"def").unwrap();
+        db.put(b"xyz", b"123").unwrap();
+        assert_eq!(0, num_files(&db));
+
+        db.flush().unwrap();
+        assert_eq!(1, num_files(&db));
+        assert_eq!(0, db.mem.len());
+        assert!(db.imm.is_none());
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+        assert_eq!(Some(b"123".to_vec()), db.get(b"xyz"));
+
+        // Flushing an empty memtable doesn't write a table.
+        db.flush().unwrap();
+        assert_eq!(1, num_files(&db));
+
+        // The table is recorded in the manifest.
+        let opt = db.opt.clone();
+        drop(db);
+        let db = DB::open_for_readonly("db", opt).unwrap();
+        assert_eq!(1, num_files(&db));
+        assert_eq!(0, db.mem.len());
+    }
+
+    #[test]
+    fn test_db_impl_catch_up() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = 1024;
+        opt.block_size = 256;
+        let mut primary = DB::open("db", opt.clone()).unwrap();
+        primary.put(b"abc", b"def").unwrap();
+        primary.flush().unwrap();
+
+        let mut secondary = DB::open_for_readonly("db", opt.clone()).unwrap();
+        assert_eq!(Some(b"def".to_vec()), secondary.get(b"abc"));
+        assert_eq!(None, secondary.get(b"xyz"));
+        let snapshot = secondary.get_snapshot();
+
+        // New writes in the log only.
+        primary.put(b"xyz", b"123").unwrap();
+        primary.delete(b"abc").unwrap();
+        primary.sync_log(true).unwrap();
+        assert_eq!(Some(b"def".to_vec()), secondary.get(b"abc"));
+        secondary.catch_up().unwrap();
+        assert_eq!(None, secondary.get(b"abc"));
+        assert_eq!(Some(b"123".to_vec()), secondary.get(b"xyz"));
+        assert_eq!(
+            Some(b"def".to_vec()),
+            secondary.get_at(&snapshot, b"abc").unwrap()
+        );
+
+        // New tables and manifest edits, with the old log files deleted.
+        for i in 0..200u32 {
+            primary
+                .put(format!("key{:04}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        primary.compact_range(None, None).unwrap();
+        primary.put(b"mno", b"456").unwrap();
+        primary.flush().unwrap();
+        secondary.catch_up().unwrap();
+        let num_files = |db: &DB| {
+            let current = db.current();
+            let current = current.borrow();
+            (0..NUM_LEVELS)
+                .map(|l| current.num_level_files(l))
+                .sum::<usize>()
+        };
+        assert!(num_files(&secondary) > 0);
+        assert_eq!(num_files(&primary), num_files(&secondary));
+        assert_eq!(Some(b"value".to_vec()), secondary.get(b"key0000"));
+        assert_eq!(Some(b"value".to_vec()), secondary.get(b"key0199"));
+        assert_eq!(Some(b"456".to_vec()), secondary.get(b"mno"));
+        assert_eq!(Some(b"123".to_vec()), secondary.get(b"xyz"));
+        assert_eq!(None, secondary.get(b"abc"));
+
+        assert_eq!(
+            StatusCode::NotSupported,
+            primary.catch_up().unwrap_err().code
+        );
+    }
+
+    #[test]
+    fn test_db_impl_changed_filter_policy() {
+        let opt = options::for_test();
+        let read_info_log = |opt: &Options| {
+            let mut s = String::new();
+            opt.env
+                .open_sequential_file(&Path::new("db").join("LOG"))
                 .unwrap()
-                .read_to_end(&mut buf)
+                .read_to_string(&mut s)
                 .unwrap();
+            s
+        };
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            for i in 0..100 {
+                db.put(format!("key{}", i).as_bytes(), b"value").unwrap();
+            }
+            db.compact_range(None, None).unwrap();
+        }
+
+        let mut opt2 = opt.clone();
+        opt2.filter_policy = Rc::new(Box::new(NoFilterPolicy::new()));
+        {
+            let mut db = DB::open("db", opt2.clone()).unwrap();
+            assert!(read_info_log(&opt2).contains("filter policy changed"));
+            for i in 0..100 {
+                assert_eq!(
+                    Some(b"value".to_vec()),
+                    db.get(format!("key{}", i).as_bytes())
+                );
+            }
+            db.put(b"key100", b"value").unwrap();
+            db.compact_range(None, None).unwrap();
+            assert_eq!(Some(b"value".to_vec()), db.get(b"key100"));
+        }
+
+        // The new policy has been recorded; no more warnings.
+        let mut db = DB::open("db", opt2.clone()).unwrap();
+        assert!(!read_info_log(&opt2).contains("filter policy changed"));
+        assert_eq!(Some(b"value".to_vec()), db.get(b"key50"));
+    }
+
+    #[test]
+    fn test_db_impl_changed_comparator() {
+        let opt = options::for_test();
+        {
+            let mut db = DB::open("db", opt.clone()).unwrap();
+            db.put(b"key", b"value").unwrap();
+        }
+
+        let mut opt2 = opt.clone();
+        opt2.cmp = Rc::new(Box::new(
+            CountingCmp::new(Rc::default()).with_id("test.OtherComparator"),
+        ));
+        match DB::open("db", opt2) {
+            Err(e) => {
+                assert_eq!(StatusCode::InvalidArgument, e.code);
+                assert!(e.err.contains("leveldb.BytewiseComparator"));
+                assert!(e.err.contains("test.OtherComparator"));
+            }
+            Ok(_) => panic!("opened database with a different comparator"),
+        }
+
+        // The original comparator still works.
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(b"value".to_vec()), db.get(b"key"));
+    }
+
+    /// keys collects the keys returned by iter.
+    fn keys<It: LdbIterator>(iter: &mut It) -> Vec<Vec<u8>> {
+        LdbIteratorIter::wrap(iter).map(|(k, _)| k).collect()
+    }
+
+    #[test]
+    fn test_db_impl_reverse_comparator() {
+        let mut opt = options::for_test();
+        opt.cmp = Rc::new(Box::new(ReverseCmp));
+        let want: Vec<Vec<u8>> = ["c", "bb", "b", "a"]
+            .iter()
+            .map(|k| k.as_bytes().to_vec())
+            .collect();
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        for k in &["b", "a", "c", "bb"] {
+            db.put(k.as_bytes(), b"val").unwrap();
+        }
+        // The memtable is ordered by the comparator.
+        let mem_keys: Vec<Vec<u8>> = keys(&mut db.mem.iter())
+            .iter()
+            .map(|k| parse_internal_key(k).2.to_vec())
+            .collect();
+        assert_eq!(want, mem_keys);
+        assert_eq!(want, keys(&mut db.new_iter().unwrap()));
+
+        // So are the tables written from it.
+        db.make_room_for_write(true).unwrap();
+        let files: Vec<FileMetaData> = db
+            .current()
+            .borrow()
+            .files
+            .iter()
+            .flatten()
+            .map(|f| f.borrow().clone())
+            .collect();
+        assert_eq!(1, files.len());
+        let path = table_file_name(&db.name, files[0].num);
+        let src = opt.env.open_random_access_file(Path::new(&path)).unwrap();
+        let table = Table::new(opt.clone(), Rc::new(src), files[0].size).unwrap();
+        let table_keys: Vec<Vec<u8>> = keys(&mut table.iter())
+            .iter()
+            .map(|k| parse_internal_key(k).2.to_vec())
+            .collect();
+        assert_eq!(want, table_keys);
+        drop(db);
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(want, keys(&mut db.new_iter().unwrap()));
+        assert_eq!(Some(b"val".to_vec()), db.get(b"bb"));
+    }
+
+    #[test]
+    fn test_db_impl_throttled_log_sync() {
+        let syncs = Rc::new(Cell::new(0));
+        let now = Rc::new(Cell::new(0));
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(SyncCountingEnv {
+            syncs: syncs.clone(),
+            now: now.clone(),
+            ..Default::default()
+        }));
+        opt.min_wal_sync_interval_micros = 1000;
+        let mut db = DB::open("db", opt).unwrap();
+
+        let start = now.get();
+        let base = syncs.get();
+        for i in 0..100 {
+            let mut wb = WriteBatch::new();
+            wb.put(format!("key{}", i).as_bytes(), b"value");
+            db.write(wb, true).unwrap();
+            db.put(format!("nosync{}", i).as_bytes(), b"value").unwrap();
+            now.set(now.get() + 100);
+        }
+        // Syncs within the interval were coalesced without waiting, bounding the number of
+        // syncs by the elapsed time.
+        let elapsed = now.get() - start;
+        assert_eq!(100 * 100, elapsed);
+        let count = syncs.get() - base;
+        assert!(count > 1);
+        assert!(count as u64 <= elapsed / 1000 + 1);
+
+        // An explicit sync happens right away.
+        db.sync().unwrap();
+        assert_eq!(count + 1, syncs.get() - base);
+
+        // A pending sync is performed by the next write after the interval, even without sync.
+        db.write(WriteBatch::new(), true).unwrap();
+        assert_eq!(count + 1, syncs.get() - base);
+        now.set(now.get() + 1000);
+        db.put(b"later", b"value").unwrap();
+        assert_eq!(count + 2, syncs.get() - base);
+        db.put(b"later", b"value").unwrap();
+        assert_eq!(count + 2, syncs.get() - base);
+
+        // So does flush().
+        db.write(WriteBatch::new(), true).unwrap();
+        assert_eq!(count + 2, syncs.get() - base);
+        db.flush().unwrap();
+        assert_eq!(count + 3, syncs.get() - base);
+        assert_eq!(Some(b"value".to_vec()), db.get(b"key99"));
+    }
+
+    #[test]
+    fn test_db_impl_sync() {
+        let syncs = Rc::new(Cell::new(0));
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(SyncCountingEnv {
+            syncs: syncs.clone(),
+            ..Default::default()
+        }));
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        db.put(b"abc", b"def").unwrap();
+        let before = syncs.get();
+
+        // The log is synced, but the memtable stays in place.
+        db.sync().unwrap();
+        assert_eq!(before + 1, syncs.get());
+        assert_eq!(1, db.mem.len());
+        assert_eq!(0, db.current().borrow().files[0].len());
+        drop(db);
+
+        let mut db = DB::open("db", opt).unwrap();
+        assert_eq!(Some(b"def".to_vec()), db.get(b"abc"));
+    }
+
+    #[test]
+    fn test_db_impl_locking() {
+        let opt = options::for_test();
+        let _db = DB::open("db", opt.clone()).unwrap();
+        let want_err = Status::new(
+            StatusCode::LockError,
+            "database lock is held by another instance",
+        );
+        assert_eq!(want_err, DB::open("db", opt.clone()).err().unwrap());
+    }
+
+    #[test]
+    fn test_db_impl_build_table() {
+        let mut opt = options::for_test();
+        opt.block_size = 128;
+        let mt = build_memtable();
+
+        let f = build_table("db", &opt, mt.iter(), 123).unwrap();
+        let path = &Path::new("db").join("000123.ldb");
+
+        assert_eq!(
+            LookupKey::new("aabc".as_bytes(), 6).internal_key(),
+            f.smallest.as_slice()
+        );
+        assert_eq!(
+            LookupKey::new("test123".as_bytes(), 7).internal_key(),
+            f.largest.as_slice()
+        );
+        assert_eq!(379, f.size);
+        assert_eq!(123, f.num);
+        assert!(opt.env.exists(path).unwrap());
+
+        {
+            // Read table back in.
+            let mut tc = TableCache::new("db", opt.clone(), 100);
+            let tbl = tc.get_table(123).unwrap();
+            assert_eq!(mt.len(), LdbIteratorIter::wrap(&mut tbl.iter()).count());
+        }
+
+        {
+            // Corrupt table; make sure it doesn't load fully.
+            let mut buf = opt.env.read_to_vec(path).unwrap();
             buf[150] += 1;
             opt.env
                 .open_writable_file(path)
@@ -1506,6 +4564,8 @@ mod tests {
                 .write_all(&buf)
                 .unwrap();
 
+            // Data block checksums are only verified with paranoid checks.
+            opt.paranoid_checks = true;
             let mut tc = TableCache::new("db", opt.clone(), 100);
             let tbl = tc.get_table(123).unwrap();
             // The last two entries are skipped due to the corruption above.
@@ -1580,6 +4640,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_db_impl_snapshot_survives_compaction() {
+        for release in [false, true] {
+            let opt = options::for_test();
+            let mut db = DB::open("db", opt).unwrap();
+
+            db.put(b"k", b"v1").unwrap();
+            let ss = db.get_snapshot();
+            let seq = ss.sequence();
+            db.flush().unwrap();
+            db.put(b"k", b"v2").unwrap();
+            db.flush().unwrap();
+
+            assert_eq!(Some(b"v1".to_vec()), db.get_at(&ss, b"k").unwrap());
+            assert_eq!(Some(b"v2".to_vec()), db.get(b"k"));
+
+            if release {
+                db.release_snapshot(ss);
+                db.compact_range(None, None).unwrap();
+                // The old version is no longer needed by any snapshot and has been dropped.
+                assert_eq!(None, db.get_internal(seq, b"k").unwrap());
+            } else {
+                db.compact_range(None, None).unwrap();
+                assert_eq!(Some(b"v1".to_vec()), db.get_at(&ss, b"k").unwrap());
+            }
+            assert_eq!(Some(b"v2".to_vec()), db.get(b"k"));
+        }
+    }
+
     #[test]
     fn test_db_impl_delete() {
         let mut db = build_db().0;
@@ -1599,6 +4688,360 @@ mod tests {
         assert!(db.get(b"xyz").is_some());
     }
 
+    #[test]
+    fn test_db_impl_iter_merges_all_sources() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        // Deepest level: all keys.
+        for k in ["a", "b", "c", "d", "e"] {
+            db.put(k.as_bytes(), format!("{}-table1", k).as_bytes())
+                .unwrap();
+        }
+        db.compact_range(None, None).unwrap();
+        // A newer table.
+        db.put(b"b", b"b-table2").unwrap();
+        db.delete(b"c").unwrap();
+        db.flush().unwrap();
+        // The immutable memtable, which isn't compacted yet.
+        db.put(b"c", b"c-imm").unwrap();
+        db.put(b"d", b"d-imm").unwrap();
+        db.put(b"f", b"f-imm").unwrap();
+        let mut imm = MemTable::new(db.opt.cmp.clone());
+        swap(&mut imm, &mut db.mem);
+        db.imm = Some(imm);
+        // The active memtable.
+        db.put(b"d", b"d-mem").unwrap();
+        db.delete(b"e").unwrap();
+        db.put(b"g", b"g-mem").unwrap();
+        let num_tables: usize = {
+            let v = db.current();
+            let v = v.borrow();
+            (0..NUM_LEVELS).map(|l| v.num_level_files(l)).sum()
+        };
+        assert_eq!(2, num_tables);
+
+        let want: Vec<(Vec<u8>, Vec<u8>)> = [
+            ("a", "a-table1"),
+            ("b", "b-table2"),
+            ("c", "c-imm"),
+            ("d", "d-mem"),
+            ("f", "f-imm"),
+            ("g", "g-mem"),
+        ]
+        .iter()
+        .map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec()))
+        .collect();
+        let mut iter = db.new_iter().unwrap();
+        assert_eq!(want, LdbIteratorIter::wrap(&mut iter).collect::<Vec<_>>());
+
+        // Backwards as well.
+        let mut backwards = vec![];
+        iter.seek(b"g");
+        while let Some(kv) = current_key_val(&iter) {
+            backwards.push(kv);
+            iter.prev();
+        }
+        backwards.reverse();
+        assert_eq!(want, backwards);
+    }
+
+    #[test]
+    fn test_db_impl_get_uses_filter() {
+        // Returns the number of file reads made by looking up an absent key, after opening all
+        // tables.
+        let absent_key_reads = |filter_policy: BoxedFilterPolicy| {
+            let reads = Rc::new(Cell::new(0));
+            let mut opt = options::for_test();
+            opt.env = Rc::new(Box::new(SyncCountingEnv {
+                reads: reads.clone(),
+                ..Default::default()
+            }));
+            opt.filter_policy = filter_policy;
+            let mut db = DB::open("db", opt).unwrap();
+            for i in 0..100 {
+                db.put(format!("key{:03}", i).as_bytes(), b"val").unwrap();
+            }
+            db.compact_range(None, None).unwrap();
+            let files = db.vset.borrow().live_files();
+            for num in files {
+                db.cache.borrow_mut().get_table(num).unwrap();
+            }
+
+            reads.set(0);
+            assert_eq!(None, db.get(b"key050x"));
+            reads.get()
+        };
+        // The filter rules out the data block.
+        assert_eq!(0, absent_key_reads(options::for_test().filter_policy));
+        assert!(absent_key_reads(Rc::new(Box::new(NoFilterPolicy::new()))) > 0);
+    }
+
+    #[test]
+    fn test_db_impl_get_detailed() {
+        let mut db = build_db().0;
+
+        db.put(b"xyy", b"123").unwrap();
+        db.delete(b"xyy").unwrap();
+
+        assert_eq!(
+            GetResult::Found(b"val2".to_vec()),
+            db.get_detailed(b"aab").unwrap()
+        );
+        assert_eq!(GetResult::NotFound, db.get_detailed(b"aad").unwrap());
+        // Deleted in the memtable and in a table, respectively.
+        assert_eq!(GetResult::Deleted, db.get_detailed(b"xyy").unwrap());
+        assert_eq!(GetResult::Deleted, db.get_detailed(b"gca").unwrap());
+
+        db.delete_range(b"aaa", b"aac").unwrap();
+        assert_eq!(GetResult::Deleted, db.get_detailed(b"aab").unwrap());
+        assert_eq!(GetResult::Deleted, db.get_detailed(b"aabb").unwrap());
+    }
+
+    #[test]
+    fn test_db_impl_empty_value() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        let check = |db: &mut DB| {
+            assert_eq!(Some(vec![]), db.get(b"empty"));
+            assert_eq!(GetResult::Found(vec![]), db.get_detailed(b"empty").unwrap());
+            assert_eq!(None, db.get(b"deleted"));
+            assert_eq!(GetResult::Deleted, db.get_detailed(b"deleted").unwrap());
+            let mut buf = b"junk".to_vec();
+            assert!(db.get_into(b"empty", &mut buf).unwrap());
+            assert!(buf.is_empty());
+
+            // Iteration returns the empty value but skips the deleted key.
+            let entries = LdbIteratorIter::wrap(&mut db.new_iter().unwrap()).collect::<Vec<_>>();
+            assert_eq!(
+                vec![
+                    (b"empty".to_vec(), vec![]),
+                    (b"nonempty".to_vec(), b"x".to_vec())
+                ],
+                entries
+            );
+        };
+
+        db.put(b"deleted", b"").unwrap();
+        db.delete(b"deleted").unwrap();
+        db.put(b"empty", b"").unwrap();
+        db.put(b"nonempty", b"x").unwrap();
+        // From the memtable, and from a table.
+        check(&mut db);
+        db.flush().unwrap();
+        assert_eq!(0, db.mem.len());
+        check(&mut db);
+    }
+
+    #[test]
+    fn test_db_impl_merge() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            db.merge(b"a", b"y").unwrap_err().code
+        );
+        drop(db);
+
+        let mut opt = options::for_test();
+        opt.merge_operator = Some(append_operator());
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        let check = |db: &mut DB, a: &[u8]| {
+            let expected = vec![
+                (b"a".to_vec(), a.to_vec()),
+                (b"b".to_vec(), b"pq".to_vec()),
+                (b"c".to_vec(), b"n".to_vec()),
+                (b"d".to_vec(), b"v".to_vec()),
+            ];
+            for (k, v) in expected.iter() {
+                assert_eq!(Some(v), db.get(k).as_ref());
+            }
+            let mut iter = db.new_iter().unwrap();
+            assert_eq!(
+                expected,
+                LdbIteratorIter::wrap(&mut iter).collect::<Vec<_>>()
+            );
+
+            // Backwards, and changing direction on a merged entry.
+            let (mut k, mut v) = (vec![], vec![]);
+            let mut backwards = vec![];
+            iter.seek_to_last();
+            while iter.current(&mut k, &mut v) {
+                backwards.push((k.clone(), v.clone()));
+                iter.prev();
+            }
+            backwards.reverse();
+            assert_eq!(expected, backwards);
+            iter.seek(b"b");
+            assert!(iter.advance());
+            assert!(iter.prev());
+            assert!(iter.current(&mut k, &mut v));
+            assert_eq!((b"b".to_vec(), b"pq".to_vec()), (k.clone(), v.clone()));
+            assert!(iter.prev());
+            assert!(iter.current(&mut k, &mut v));
+            assert_eq!((b"a".to_vec(), a.to_vec()), (k, v));
+        };
+
+        db.put(b"a", b"x").unwrap();
+        db.merge(b"a", b"y").unwrap();
+        db.merge(b"a", b"z").unwrap();
+        db.merge(b"b", b"p").unwrap();
+        db.merge(b"b", b"q").unwrap();
+        db.put(b"c", b"old").unwrap();
+        db.delete(b"c").unwrap();
+        db.merge(b"c", b"n").unwrap();
+        db.put(b"d", b"v").unwrap();
+        check(&mut db, b"xyz");
+
+        // Operands in a table, and more in the memtable.
+        db.flush().unwrap();
+        check(&mut db, b"xyz");
+        let snapshot = db.get_snapshot();
+        db.merge(b"a", b"w").unwrap();
+        check(&mut db, b"xyzw");
+
+        // The compaction merges the operands that all snapshots see.
+        db.compact_range(None, None).unwrap();
+        check(&mut db, b"xyzw");
+        assert_eq!(Some(b"xyz".to_vec()), db.get_at(&snapshot, b"a").unwrap());
+        drop(snapshot);
+        db.compact_range(None, None).unwrap();
+        check(&mut db, b"xyzw");
+        let mut iter = db.merge_iterators(&BlockReadStats::default()).unwrap();
+        assert_eq!(4, LdbIteratorIter::wrap(&mut iter).count());
+        drop(iter);
+
+        // Operands are replayed from the log.
+        db.merge(b"a", b"!").unwrap();
+        drop(db);
+        let mut db = DB::open("db", opt).unwrap();
+        check(&mut db, b"xyzw!");
+    }
+
+    #[test]
+    fn test_db_impl_ttl() {
+        let env = MemEnv::new();
+        let mut opt = options::for_test();
+        opt.env = Rc::new(Box::new(env.clone()));
+        opt.ttl = Some(Duration::from_secs(10));
+        opt.merge_operator = Some(append_operator());
+        let secs = |s: u64| s * 1_000_000;
+        let collect = |db: &mut DB| -> Vec<(Vec<u8>, Vec<u8>)> {
+            LdbIteratorIter::wrap(&mut db.new_iter().unwrap()).collect()
+        };
+
+        let mut db = DB::open("db", opt.clone()).unwrap();
+        env.set_micros(secs(1));
+        db.put(b"a", b"old").unwrap();
+        env.set_micros(secs(5));
+        db.put(b"b", b"new").unwrap();
+        db.put(b"c", b"x").unwrap();
+        db.merge(b"c", b"y").unwrap();
+        assert_eq!(Some(b"old".to_vec()), db.get(b"a"));
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), b"old".to_vec()),
+                (b"b".to_vec(), b"new".to_vec()),
+                (b"c".to_vec(), b"xy".to_vec()),
+            ],
+            collect(&mut db)
+        );
+
+        // a is 11 seconds old, from the memtable and from a table.
+        env.set_micros(secs(12));
+        let want = vec![
+            (b"b".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"xy".to_vec()),
+        ];
+        for _ in 0..2 {
+            assert_eq!(None, db.get(b"a"));
+            assert_eq!(GetResult::Deleted, db.get_detailed(b"a").unwrap());
+            assert_eq!(Some(b"new".to_vec()), db.get(b"b"));
+            assert_eq!(Some(b"xy".to_vec()), db.get(b"c"));
+            assert_eq!(want, collect(&mut db));
+            db.flush().unwrap();
+        }
+
+        // The compaction drops a, and merges c into a value that expires like a new one.
+        db.compact_range(None, None).unwrap();
+        let current = db.current();
+        let mut raw = MergingIter::new(
+            db.internal_cmp.clone(),
+            current.borrow().new_iters().unwrap(),
+        );
+        let keys: Vec<_> = LdbIteratorIter::wrap(&mut raw)
+            .map(|(k, _)| parse_internal_key(&k).2.to_vec())
+            .collect();
+        assert_eq!(vec![b"b".to_vec(), b"c".to_vec()], keys);
+        assert_eq!(want, collect(&mut db));
+
+        env.set_micros(secs(20));
+        assert_eq!(None, db.get(b"b"));
+        assert_eq!(Some(b"xy".to_vec()), db.get(b"c"));
+        env.set_micros(secs(23));
+        assert!(collect(&mut db).is_empty());
+    }
+
+    #[test]
+    fn test_db_impl_get_into() {
+        let mut db = build_db().0;
+        db.put(b"xyz", b"memtable value").unwrap();
+
+        let mut buf = Vec::with_capacity(64);
+        let (ptr, cap) = (buf.as_ptr(), buf.capacity());
+        for _ in 0..1000 {
+            // From a table, from the memtable, and a missing key.
+            assert!(db.get_into(b"aab", &mut buf).unwrap());
+            assert_eq!(b"val2", buf.as_slice());
+            assert!(db.get_into(b"xyz", &mut buf).unwrap());
+            assert_eq!(b"memtable value", buf.as_slice());
+            assert!(!db.get_into(b"aad", &mut buf).unwrap());
+            assert!(buf.is_empty());
+        }
+        // The buffer was never reallocated.
+        assert_eq!((ptr, cap), (buf.as_ptr(), buf.capacity()));
+
+        // A buffer that is too small grows.
+        let mut small = vec![];
+        assert!(db.get_into(b"xyz", &mut small).unwrap());
+        assert_eq!(b"memtable value", small.as_slice());
+        assert!(db.get_into(b"aab", &mut small).unwrap());
+        assert_eq!(b"val2", small.as_slice());
+    }
+
+    #[test]
+    fn test_db_impl_get_or_insert_with() {
+        let mut db = build_db().0;
+        let calls = Cell::new(0);
+        let compute = || {
+            calls.set(calls.get() + 1);
+            b"computed".to_vec()
+        };
+
+        // Missing key: the value is computed once and stored.
+        assert_eq!(
+            b"computed".to_vec(),
+            db.get_or_insert_with(b"xyz", compute).unwrap()
+        );
+        assert_eq!(1, calls.get());
+        assert_eq!(Some(b"computed".to_vec()), db.get(b"xyz"));
+
+        // Present keys, in the memtable and in a table: the closure is not called.
+        assert_eq!(
+            b"computed".to_vec(),
+            db.get_or_insert_with(b"xyz", compute).unwrap()
+        );
+        assert_eq!(
+            b"val2".to_vec(),
+            db.get_or_insert_with(b"aab", compute).unwrap()
+        );
+        assert_eq!(1, calls.get());
+
+        // A deleted key counts as missing.
+        db.delete(b"aab").unwrap();
+        assert_eq!(
+            b"computed".to_vec(),
+            db.get_or_insert_with(b"aab", compute).unwrap()
+        );
+        assert_eq!(2, calls.get());
+    }
+
     #[test]
     fn test_db_impl_compact_single_file() {
         let mut db = build_db().0;
@@ -1688,12 +5131,85 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_db_impl_memtable_rotation() {
+        let mut opt = options::for_test();
+        opt.write_buffer_size = opt.block_size;
+        let mut db = DB::open("db", opt.clone()).unwrap();
+
+        let key = |i: usize| format!("key{:04}", i * 7 % 500);
+        let val = [b'v'; 50];
+        let mut log_nums = HashSet::new();
+        for i in 0..500 {
+            db.put(key(i).as_bytes(), &val).unwrap();
+            log_nums.insert(db.log_num.unwrap());
+            // The memtable is rotated before it grows much beyond the write buffer size.
+            assert!(db.mem.approx_mem_usage() < opt.write_buffer_size + 200);
+            if i % 50 == 0 {
+                for j in 0..=i {
+                    assert_eq!(Some(val.to_vec()), db.get(key(j).as_bytes()));
+                }
+            }
+        }
+        // Every rotation started a new log, and the full memtables were written to tables.
+        assert!(log_nums.len() > 5);
+        assert!(db.imm.is_none());
+        assert!(
+            db.current()
+                .borrow()
+                .files
+                .iter()
+                .map(Vec::len)
+                .sum::<usize>()
+                > 5
+        );
+        for i in 0..500 {
+            assert_eq!(Some(val.to_vec()), db.get(key(i).as_bytes()));
+        }
+    }
+
+    #[test]
+    fn test_db_impl_l0_write_triggers() {
+        // With a compaction trigger above the slowdown trigger, level 0 is never compacted on its
+        // own, so its files pile up until the stop trigger. Otherwise, writes at the slowdown
+        // trigger compact level 0 before it reaches the stop trigger.
+        for (compaction_trigger, want_max_l0) in [(100, 5), (2, 3)] {
+            let mut opt = options::for_test();
+            opt.write_buffer_size = opt.block_size;
+            opt.l0_compaction_trigger = compaction_trigger;
+            opt.l0_slowdown_writes_trigger = 3;
+            opt.l0_stop_writes_trigger = 5;
+            let mut db = DB::open("db", opt).unwrap();
+
+            // Keys are written out of order, so that every memtable overlaps the previous ones and
+            // is written to level 0.
+            let val = [b'v'; 100];
+            let mut max_l0 = 0;
+            for i in 0..2000 {
+                let key = format!("key{:05}", i * 7919 % 2000);
+                db.put(key.as_bytes(), &val).unwrap();
+                max_l0 = max_l0.max(db.current().borrow().num_level_files(0));
+            }
+            assert_eq!(want_max_l0, max_l0, "{}", compaction_trigger);
+            assert!(db.current().borrow().num_level_files(1) > 0);
+
+            for i in 0..2000 {
+                assert_eq!(
+                    Some(val.to_vec()),
+                    db.get(format!("key{:05}", i).as_bytes())
+                );
+            }
+        }
+    }
+
     #[test]
     fn test_db_impl_compaction() {
         let mut db = build_db().0;
         let v = db.current();
         v.borrow_mut().compaction_score = Some(2.0);
         v.borrow_mut().compaction_level = Some(1);
+        // A referenced version keeps its files alive.
+        drop(v);
 
         db.maybe_do_compaction().unwrap();
 
@@ -1721,6 +5237,33 @@ mod tests {
         assert_eq!(2, v.borrow().files[2].len());
     }
 
+    #[test]
+    fn test_db_impl_compaction_stats() {
+        let mut db = build_db().0;
+        assert_eq!(
+            [CompactionStats::default(); NUM_LEVELS],
+            db.compaction_stats()
+        );
+        let v = db.current();
+        v.borrow_mut().compaction_score = Some(2.0);
+        v.borrow_mut().compaction_level = Some(1);
+        db.maybe_do_compaction().unwrap();
+
+        // Counted at the level the compaction wrote to.
+        let stats = db.compaction_stats();
+        assert_eq!(CompactionStats::default(), stats[1]);
+        assert_eq!(345, stats[2].bytes_written);
+        assert_eq!(1, stats[2].files_out);
+        assert!(stats[2].files_in >= 2);
+        assert!(stats[2].bytes_read > 0);
+
+        // A memtable flush counts as one output file.
+        db.put(b"zzz", b"val").unwrap();
+        db.compact_range(Some(b"zzz"), Some(b"zzz")).unwrap();
+        let written: usize = db.compaction_stats().iter().map(|s| s.files_out).sum();
+        assert!(written >= 2);
+    }
+
     #[test]
     fn test_db_impl_compaction_trivial() {
         let (mut v, opt) = make_version();
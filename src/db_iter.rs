@@ -1,21 +1,54 @@
-use std::{cmp::Ordering, mem, rc::Rc};
+use std::{
+    cmp::Ordering,
+    mem,
+    rc::Rc,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc,
+    },
+};
 
 use crate::{
-    cmp::Cmp,
-    key_types::{parse_internal_key, truncate_to_userkey, LookupKey, ValueType},
+    cmp::{Cmp, InternalKeyCmp},
+    error::{err, Result, Status, StatusCode},
+    key_types::{parse_internal_key, truncate_to_userkey, LookupKey, RangeTombstone, ValueType},
+    merge_op::{self, BoxedMergeOperator},
     merging_iter::MergingIter,
     snapshot::Snapshot,
-    types::{Direction, LdbIterator, Shared},
+    table_reader::BlockReadStats,
+    types::{Direction, LdbIterator, SequenceNumber, Shared, MAX_SEQUENCE_NUMBER},
+    version::Version,
     version_set::VersionSet,
 };
 
 const READ_BYTES_PERIOD: isize = 1048576;
+/// How many iterator steps are taken between two checks of the cancellation token.
+const CANCEL_CHECK_PERIOD: usize = 64;
+
+/// A CancelToken can be shared with another thread in order to abort a running scan. Once it is
+/// set to true, the DBIterator it is attached to stops and reports an `Aborted` status.
+pub type CancelToken = Arc<AtomicBool>;
+
+/// IterStats describes the work done by a DBIterator so far; see `DBIterator::stats()`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IterStats {
+    /// Number of data blocks loaded from tables (from the block cache or from disk).
+    pub blocks_read: usize,
+    /// Size of these blocks, as stored in the table files.
+    pub bytes_read: usize,
+    /// Number of entries passed over without being returned: deletions, entries covered by range
+    /// tombstones, older versions of a key and entries written after the iterator's snapshot.
+    pub keys_skipped: usize,
+}
 
 /// DBIterator is an iterator over the contents of a database.
 pub struct DBIterator {
     // A user comparator.
     cmp: Rc<Box<dyn Cmp>>,
     vset: Shared<VersionSet>,
+    // The version the table iterators were created from. Holding onto it keeps its files from
+    // being deleted by compactions that run while iterating.
+    _version: Shared<Version>,
     iter: MergingIter,
     // By holding onto a snapshot, we make sure that the iterator iterates over the state at the
     // point of its creation.
@@ -29,7 +62,31 @@ pub struct DBIterator {
     // buffer for reading internal keys
     keybuf: Vec<u8>,
     savedval: Vec<u8>,
+    // sequence number of the entry in savedkey/savedval.
+    savedseq: SequenceNumber,
     valbuf: Vec<u8>,
+
+    merge_op: Option<BoxedMergeOperator>,
+    // Operands of the current key, oldest first.
+    operands: Vec<Vec<u8>>,
+    // Set if the current entry (moving forward) is the result of merging operands, which is
+    // stored in savedkey/savedval. The inner iterator is positioned after the operands then.
+    merged: bool,
+    error: Option<Status>,
+
+    cancel: Option<CancelToken>,
+    steps: usize,
+    aborted: bool,
+
+    // Exclusive upper bound on user keys, as the internal key sorting before all entries of the
+    // bound's user key.
+    upper_bound: Option<Vec<u8>>,
+    internal_cmp: InternalKeyCmp,
+    // Range tombstones visible in the snapshot; entries covered by them are skipped.
+    range_tombstones: Vec<RangeTombstone>,
+
+    block_stats: BlockReadStats,
+    keys_skipped: usize,
 }
 
 impl DBIterator {
@@ -38,9 +95,15 @@ impl DBIterator {
         vset: Shared<VersionSet>,
         iter: MergingIter,
         ss: Snapshot,
+        mut range_tombstones: Vec<RangeTombstone>,
+        block_stats: BlockReadStats,
+        merge_op: Option<BoxedMergeOperator>,
     ) -> DBIterator {
+        range_tombstones.retain(|t| t.seq <= ss.sequence());
+        let version = vset.borrow().current();
         DBIterator {
-            cmp,
+            cmp: cmp.clone(),
+            _version: version,
             vset,
             iter,
             ss,
@@ -51,10 +114,133 @@ impl DBIterator {
             savedkey: vec![],
             keybuf: vec![],
             savedval: vec![],
+            savedseq: 0,
             valbuf: vec![],
+
+            merge_op,
+            operands: vec![],
+            merged: false,
+            error: None,
+
+            cancel: None,
+            steps: 0,
+            aborted: false,
+
+            upper_bound: None,
+            internal_cmp: InternalKeyCmp(cmp.clone()),
+            range_tombstones,
+
+            block_stats,
+            keys_skipped: 0,
         }
     }
 
+    /// stats returns counters describing the work done by this iterator so far. For example, a
+    /// high number of skipped keys shows that a scan is slowed down by deletions or overwritten
+    /// values which haven't been compacted yet.
+    pub fn stats(&self) -> IterStats {
+        IterStats {
+            blocks_read: self.block_stats.blocks(),
+            bytes_read: self.block_stats.bytes(),
+            keys_skipped: self.keys_skipped,
+        }
+    }
+
+    /// entry_type returns the type of an entry, treating entries covered by a range tombstone as
+    /// deleted.
+    fn entry_type(&self, typ: ValueType, seq: u64, ukey: &[u8]) -> ValueType {
+        if self
+            .range_tombstones
+            .iter()
+            .any(|t| t.covers(self.cmp.as_ref().as_ref(), ukey, seq))
+        {
+            ValueType::TypeDeletion
+        } else {
+            typ
+        }
+    }
+
+    /// set_upper_bound makes the iterator stop (become invalid) once it reaches a key that is equal
+    /// to or greater than `bound`. Entries at or after the bound are never looked at, even if they
+    /// are deleted or otherwise hidden.
+    pub fn set_upper_bound(&mut self, bound: Vec<u8>) {
+        let ikey = LookupKey::new(&bound, MAX_SEQUENCE_NUMBER);
+        self.upper_bound = Some(ikey.internal_key().to_vec());
+    }
+
+    /// set_cancel_token attaches a token to this iterator. When the token is set, the iterator
+    /// becomes invalid within a few steps, and `status()` returns an `Aborted` error.
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// status returns an `Aborted` error if the iteration was cancelled using the cancel token,
+    /// the error of the merge operator if merge operands couldn't be merged, and Ok otherwise.
+    pub fn status(&self) -> Result<()> {
+        if self.aborted {
+            err(StatusCode::Aborted, "iteration cancelled")
+        } else if let Some(ref e) = self.error {
+            Err(e.clone())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// seek_for_prev positions the iterator at the greatest key that is less than or equal to
+    /// `key`, or makes it invalid if there is no such key. Unlike `seek()`, this finds the floor
+    /// of `key`.
+    pub fn seek_for_prev(&mut self, key: &[u8]) {
+        self.seek(key);
+        let (mut k, mut v) = (vec![], vec![]);
+        if self.current(&mut k, &mut v) {
+            if self.cmp.cmp(&k, key) != Ordering::Equal {
+                // Landed on a bigger key; the previous one (if any) is the floor.
+                self.prev();
+            }
+            return;
+        }
+
+        // There is no key >= `key`; the last key is the floor.
+        self.seek_to_last();
+    }
+
+    /// current_seq returns the sequence number of the current entry.
+    pub(crate) fn current_seq(&self) -> Option<SequenceNumber> {
+        if !self.valid() {
+            return None;
+        }
+        if self.dir == Direction::Forward && !self.merged {
+            let mut key = vec![];
+            self.iter.current(&mut key, &mut vec![]);
+            Some(parse_internal_key(&key).1)
+        } else {
+            Some(self.savedseq)
+        }
+    }
+
+    /// check_cancelled returns true if the iterator has been cancelled. The token is only looked
+    /// at every CANCEL_CHECK_PERIOD calls.
+    fn check_cancelled(&mut self) -> bool {
+        if self.aborted {
+            return true;
+        }
+        if let Some(ref token) = self.cancel {
+            self.steps += 1;
+            if self.steps < CANCEL_CHECK_PERIOD {
+                return false;
+            }
+            self.steps = 0;
+            if token.load(atomic::Ordering::Relaxed) {
+                self.aborted = true;
+                self.valid = false;
+                self.savedkey.clear();
+                self.savedval.clear();
+                return true;
+            }
+        }
+        false
+    }
+
     /// record_read_sample records a read sample using the current contents of self.keybuf, which
     /// should be an InternalKey.
     fn record_read_sample(&mut self, len: usize) {
@@ -70,23 +256,32 @@ impl DBIterator {
     fn find_next_user_entry(&mut self, mut skipping: bool) -> bool {
         assert!(self.iter.valid());
         assert!(self.dir == Direction::Forward);
+        self.merged = false;
 
         while self.iter.valid() {
             self.iter.current(&mut self.keybuf, &mut self.savedval);
+            if let Some(ref bound) = self.upper_bound {
+                if self.internal_cmp.cmp(&self.keybuf, bound) != Ordering::Less {
+                    break;
+                }
+            }
             let len = self.keybuf.len() + self.savedval.len();
             self.record_read_sample(len);
             let (typ, seq, ukey) = parse_internal_key(&self.keybuf);
 
             // Skip keys with a sequence number after our snapshot.
             if seq <= self.ss.sequence() {
+                let typ = self.entry_type(typ, seq, ukey);
                 if typ == ValueType::TypeDeletion {
                     // Mark current (deleted) key to be skipped.
                     self.savedkey.clear();
                     self.savedkey.extend_from_slice(ukey);
                     skipping = true;
-                } else if typ == ValueType::TypeValue {
+                } else if typ == ValueType::TypeValue || typ == ValueType::TypeMerge {
                     if skipping && self.cmp.cmp(ukey, &self.savedkey) <= Ordering::Equal {
                         // Entry hidden, because it's smaller than the key to be skipped.
+                    } else if typ == ValueType::TypeMerge {
+                        return self.merge_forward();
                     } else {
                         self.valid = true;
                         self.savedkey.clear();
@@ -94,6 +289,7 @@ impl DBIterator {
                     }
                 }
             }
+            self.keys_skipped += 1;
             self.iter.advance();
         }
         self.savedkey.clear();
@@ -101,12 +297,77 @@ impl DBIterator {
         false
     }
 
+    /// merge_forward collects the merge operands of the key in self.keybuf, starting with the
+    /// current entry (whose value is in self.savedval), together with the value they apply to.
+    /// The merged value is stored in savedkey/savedval.
+    fn merge_forward(&mut self) -> bool {
+        let (_, seq, ukey) = parse_internal_key(&self.keybuf);
+        self.savedkey.clear();
+        self.savedkey.extend_from_slice(ukey);
+        self.savedseq = seq;
+        self.operands.clear();
+        self.operands.push(mem::take(&mut self.savedval));
+
+        // Older entries of the same key follow; all of them are in the snapshot.
+        let mut existing = None;
+        loop {
+            self.iter.advance();
+            if !self.iter.current(&mut self.keybuf, &mut self.valbuf) {
+                break;
+            }
+            let (typ, seq, ukey) = parse_internal_key(&self.keybuf);
+            if self.cmp.cmp(ukey, &self.savedkey) != Ordering::Equal {
+                break;
+            }
+            self.keys_skipped += 1;
+            match self.entry_type(typ, seq, ukey) {
+                ValueType::TypeMerge => self.operands.push(mem::take(&mut self.valbuf)),
+                ValueType::TypeDeletion => break,
+                _ => {
+                    existing = Some(mem::take(&mut self.valbuf));
+                    break;
+                }
+            }
+        }
+        self.operands.reverse();
+        self.merged = self.merge_operands(existing.as_deref());
+        self.merged
+    }
+
+    /// merge_operands applies self.operands to existing, storing the result in savedval. On
+    /// failure, the iterator becomes invalid and status() returns the error.
+    fn merge_operands(&mut self, existing: Option<&[u8]>) -> bool {
+        match merge_op::full_merge(
+            self.merge_op.as_ref(),
+            &self.savedkey,
+            existing,
+            &self.operands,
+        ) {
+            Ok(v) => {
+                self.savedval = v;
+                self.valid = true;
+            }
+            Err(e) => {
+                self.error = Some(e);
+                self.valid = false;
+                self.savedkey.clear();
+                self.savedval.clear();
+            }
+        }
+        self.operands.clear();
+        self.valid
+    }
+
     /// find_prev_user_entry, on a backwards-moving iterator, stores the newest non-deleted version
     /// of the entry with the key == self.savedkey that is in the current snapshot, into
     /// savedkey/savedval.
     fn find_prev_user_entry(&mut self) -> bool {
         assert!(self.dir == Direction::Reverse);
         let mut value_type = ValueType::TypeDeletion;
+        let mut entries_read = 0;
+        // Whether savedval holds a value that the operands in self.operands apply to.
+        let mut has_base = false;
+        self.operands.clear();
 
         // The iterator should be already set to the previous entry if this is a direction change
         // (i.e. first prev() call after advance()). savedkey is set to the key of that entry.
@@ -131,33 +392,59 @@ impl DBIterator {
                     // We found a non-deleted entry for a previous key (in the previous iteration)
                     break;
                 }
-                value_type = typ;
-                if value_type == ValueType::TypeDeletion {
-                    self.savedkey.clear();
-                    self.savedval.clear();
-                } else {
-                    self.savedkey.clear();
-                    self.savedkey.extend_from_slice(ukey);
-                    mem::swap(&mut self.savedval, &mut self.valbuf);
+                value_type = self.entry_type(typ, seq, ukey);
+                match value_type {
+                    ValueType::TypeDeletion => {
+                        self.savedkey.clear();
+                        self.savedval.clear();
+                        self.operands.clear();
+                        has_base = false;
+                    }
+                    ValueType::TypeMerge => {
+                        // Operands are read oldest first.
+                        self.savedkey.clear();
+                        self.savedkey.extend_from_slice(ukey);
+                        self.operands.push(mem::take(&mut self.valbuf));
+                        self.savedseq = seq;
+                    }
+                    _ => {
+                        self.savedkey.clear();
+                        self.savedkey.extend_from_slice(ukey);
+                        mem::swap(&mut self.savedval, &mut self.valbuf);
+                        self.savedseq = seq;
+                        self.operands.clear();
+                        has_base = true;
+                    }
                 }
             }
+            entries_read += 1;
             self.iter.prev();
         }
 
         if value_type == ValueType::TypeDeletion {
+            self.keys_skipped += entries_read;
             self.valid = false;
             self.savedkey.clear();
             self.savedval.clear();
             self.dir = Direction::Forward;
         } else {
+            // All entries but the returned one were skipped.
+            self.keys_skipped += entries_read - 1;
             self.valid = true;
+            if !self.operands.is_empty() {
+                let existing = has_base.then(|| mem::take(&mut self.savedval));
+                self.merge_operands(existing.as_deref());
+            }
         }
-        true
+        self.valid
     }
 }
 
 impl LdbIterator for DBIterator {
     fn advance(&mut self) -> bool {
+        if self.check_cancelled() {
+            return false;
+        }
         if !self.valid() {
             self.seek_to_first();
             return self.valid();
@@ -170,15 +457,20 @@ impl LdbIterator for DBIterator {
             } else {
                 self.iter.advance();
             }
-            if !self.iter.valid() {
-                self.valid = false;
-                self.savedkey.clear();
-                return false;
-            }
+        } else if self.merged {
+            // savedkey holds the merged key, and the inner iterator is already past its operands.
+            self.merged = false;
         } else {
-            // Save current user key.
+            // Save current user key, and move past the current entry (which has been returned
+            // already).
             assert!(self.iter.current(&mut self.savedkey, &mut self.savedval));
             truncate_to_userkey(&mut self.savedkey);
+            self.iter.advance();
+        }
+        if !self.iter.valid() {
+            self.valid = false;
+            self.savedkey.clear();
+            return false;
         }
         self.find_next_user_entry(
             // skipping=
@@ -189,8 +481,8 @@ impl LdbIterator for DBIterator {
         if !self.valid() {
             return false;
         }
-        // If direction is forward, savedkey and savedval are not used.
-        if self.dir == Direction::Forward {
+        // If direction is forward, savedkey and savedval are not used unless merged.
+        if self.dir == Direction::Forward && !self.merged {
             self.iter.current(key, val);
             truncate_to_userkey(key);
             true
@@ -203,7 +495,7 @@ impl LdbIterator for DBIterator {
         }
     }
     fn prev(&mut self) -> bool {
-        if !self.valid() {
+        if self.check_cancelled() || !self.valid() {
             return false;
         }
 
@@ -212,8 +504,17 @@ impl LdbIterator for DBIterator {
             // find_prev_user_entry() wants savedkey to be the key of the entry that is supposed to
             // be left in savedkey/savedval, which is why we have to go to the previous entry before
             // calling it.
-            self.iter.current(&mut self.savedkey, &mut self.savedval);
-            truncate_to_userkey(&mut self.savedkey);
+            if self.merged {
+                // savedkey holds the merged key; the inner iterator is after its operands, at
+                // the end if it is invalid.
+                if !self.iter.valid() {
+                    self.iter.seek_to_last();
+                }
+                self.merged = false;
+            } else {
+                self.iter.current(&mut self.savedkey, &mut self.savedval);
+                truncate_to_userkey(&mut self.savedkey);
+            }
             loop {
                 self.iter.prev();
                 if !self.iter.valid() {
@@ -239,6 +540,7 @@ impl LdbIterator for DBIterator {
     }
     fn seek(&mut self, to: &[u8]) {
         self.dir = Direction::Forward;
+        self.merged = false;
         self.savedkey.clear();
         self.savedval.clear();
         self.savedkey
@@ -255,6 +557,7 @@ impl LdbIterator for DBIterator {
     }
     fn seek_to_first(&mut self) {
         self.dir = Direction::Forward;
+        self.merged = false;
         self.savedval.clear();
         self.iter.seek_to_first();
         if self.iter.valid() {
@@ -266,24 +569,125 @@ impl LdbIterator for DBIterator {
             self.valid = false;
         }
     }
+    fn seek_to_last(&mut self) {
+        self.dir = Direction::Reverse;
+        self.merged = false;
+        self.savedkey.clear();
+        self.savedval.clear();
+        match self.upper_bound {
+            Some(ref bound) => {
+                // Start at the last entry before the bound.
+                self.iter.seek(bound);
+                if self.iter.valid() {
+                    self.iter.prev();
+                } else {
+                    self.iter.seek_to_last();
+                }
+            }
+            None => self.iter.seek_to_last(),
+        }
+        self.find_prev_user_entry();
+    }
     fn reset(&mut self) {
         self.iter.reset();
         self.valid = false;
+        self.merged = false;
         self.savedkey.clear();
         self.savedval.clear();
         self.keybuf.clear();
     }
 }
 
+/// ColumnFamilyIter is an iterator over the entries of one column family, as returned by
+/// `DB::iter_cf()`. It stops at the end of the family, and returns keys without the family prefix.
+pub struct ColumnFamilyIter {
+    iter: DBIterator,
+    prefix: Vec<u8>,
+    valid: bool,
+
+    keybuf: Vec<u8>,
+    valbuf: Vec<u8>,
+}
+
+impl ColumnFamilyIter {
+    pub fn new(iter: DBIterator, prefix: Vec<u8>) -> ColumnFamilyIter {
+        ColumnFamilyIter {
+            iter,
+            prefix,
+            valid: false,
+            keybuf: vec![],
+            valbuf: vec![],
+        }
+    }
+
+    /// update reads the current entry of the underlying iterator and checks whether it is still
+    /// part of the column family.
+    fn update(&mut self) -> bool {
+        self.valid = self.iter.valid()
+            && self.iter.current(&mut self.keybuf, &mut self.valbuf)
+            && self.keybuf.starts_with(&self.prefix);
+        self.valid
+    }
+}
+
+impl LdbIterator for ColumnFamilyIter {
+    fn advance(&mut self) -> bool {
+        if !self.valid {
+            self.iter.seek(&self.prefix);
+        } else {
+            self.iter.advance();
+        }
+        self.update()
+    }
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        if !self.valid {
+            return false;
+        }
+        key.clear();
+        key.extend_from_slice(&self.keybuf[self.prefix.len()..]);
+        val.clear();
+        val.extend_from_slice(&self.valbuf);
+        true
+    }
+    fn seek(&mut self, key: &[u8]) {
+        let mut k = self.prefix.clone();
+        k.extend_from_slice(key);
+        self.iter.seek(&k);
+        self.update();
+    }
+    fn reset(&mut self) {
+        self.iter.reset();
+        self.valid = false;
+    }
+    fn valid(&self) -> bool {
+        self.valid
+    }
+    fn prev(&mut self) -> bool {
+        if !self.valid {
+            return false;
+        }
+        self.iter.prev();
+        self.update()
+    }
+}
+
 fn random_period() -> isize {
     rand::random::<isize>() % 2 * READ_BYTES_PERIOD
 }
 
 #[cfg(test)]
 mod tests {
-    use std::collections::{HashMap, HashSet};
+    use std::{
+        cell::Cell,
+        collections::{HashMap, HashSet},
+    };
 
-    use crate::{db_impl, options, test_util::LdbIteratorIter, types::current_key_val, DB};
+    use crate::{
+        db_impl, options,
+        test_util::{CountingCmp, LdbIteratorIter},
+        types::current_key_val,
+        DB,
+    };
 
     use super::*;
     use db_impl::testutil::*;
@@ -473,6 +877,262 @@ mod tests {
         }
     }
 
+    #[test]
+    fn db_iter_snapshot_overlapping_versions() {
+        let opt = options::for_test();
+        let mut db = DB::open("db", opt).unwrap();
+
+        db.put(b"a", b"a1").unwrap();
+        db.put(b"b", b"b1").unwrap();
+        db.put(b"c", b"c1").unwrap();
+        db.flush().unwrap();
+        db.put(b"b", b"b2").unwrap();
+
+        let ss = db.get_snapshot();
+        // None of these are visible at ss, whether in a table or in the memtable.
+        db.put(b"a", b"a3").unwrap();
+        db.delete(b"b").unwrap();
+        db.flush().unwrap();
+        db.put(b"c", b"c3").unwrap();
+        db.put(b"d", b"d3").unwrap();
+
+        let expected = vec![
+            (b"a".to_vec(), b"a1".to_vec()),
+            (b"b".to_vec(), b"b2".to_vec()),
+            (b"c".to_vec(), b"c1".to_vec()),
+        ];
+        let mut iter = db.new_iter_at(ss.clone()).unwrap();
+        assert_eq!(
+            expected,
+            LdbIteratorIter::wrap(&mut iter).collect::<Vec<_>>()
+        );
+
+        // Backwards, and after seeking.
+        iter.seek(b"c");
+        assert_eq!(Some(expected[2].clone()), current_key_val(&iter));
+        assert!(iter.prev());
+        assert_eq!(Some(expected[1].clone()), current_key_val(&iter));
+        assert!(iter.prev());
+        assert_eq!(Some(expected[0].clone()), current_key_val(&iter));
+        assert!(!iter.prev());
+        assert!(!iter.valid());
+
+        // The current state is unaffected.
+        let mut iter = db.new_iter().unwrap();
+        assert_eq!(
+            vec![
+                (b"a".to_vec(), b"a3".to_vec()),
+                (b"c".to_vec(), b"c3".to_vec()),
+                (b"d".to_vec(), b"d3".to_vec()),
+            ],
+            LdbIteratorIter::wrap(&mut iter).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn db_iter_seek_for_prev() {
+        let opt = options::for_test();
+        let mut db = DB::open("db", opt).unwrap();
+        for k in ["b", "d", "f", "h"] {
+            db.put(k.as_bytes(), k.as_bytes()).unwrap();
+        }
+        db.flush().unwrap();
+        let ss = db.get_snapshot();
+        db.delete(b"d").unwrap();
+        db.put(b"e", b"e").unwrap();
+
+        let mut iter = db.new_iter().unwrap();
+        // Between two keys, on a deleted key, and on an existing key.
+        for (target, floor) in [("c", "b"), ("d", "b"), ("g", "f"), ("f", "f"), ("e", "e")] {
+            iter.seek_for_prev(target.as_bytes());
+            assert_eq!(
+                Some((floor.as_bytes().to_vec(), floor.as_bytes().to_vec())),
+                current_key_val(&iter)
+            );
+        }
+        // The iterator can move on from there.
+        iter.seek_for_prev(b"c");
+        assert!(iter.advance());
+        assert_eq!(b"e".to_vec(), current_key_val(&iter).unwrap().0);
+        iter.seek_for_prev(b"c");
+        assert!(!iter.prev());
+
+        // Above all keys.
+        iter.seek_for_prev(b"z");
+        assert_eq!(b"h".to_vec(), current_key_val(&iter).unwrap().0);
+        assert!(iter.prev());
+        assert_eq!(b"f".to_vec(), current_key_val(&iter).unwrap().0);
+
+        // Below all keys.
+        iter.seek_for_prev(b"a");
+        assert!(!iter.valid());
+
+        // At the snapshot, d is still there and e doesn't exist yet.
+        let mut iter = db.new_iter_at(ss).unwrap();
+        iter.seek_for_prev(b"e");
+        assert_eq!(b"d".to_vec(), current_key_val(&iter).unwrap().0);
+    }
+
+    #[test]
+    fn db_iter_seek_for_prev_multiple_tables() {
+        let (mut db, _) = build_db();
+        let mut iter = db.new_iter().unwrap();
+
+        // Present and absent targets; the keys are spread over tables in several levels.
+        for (target, floor) in [
+            ("aaa", "aaa"),
+            ("aab", "aab"),
+            ("aay", "aax"),
+            ("abz", "aba"),
+            ("bab", "bab"),
+            ("bb", "bab"),
+            ("cab", "cab"),
+            ("cb", "cab"),
+            ("cba", "cba"),
+            ("gca", "gba"),
+            ("h", "gda"),
+            ("zzz", "iba"),
+        ] {
+            iter.seek_for_prev(target.as_bytes());
+            assert_eq!(
+                floor.as_bytes().to_vec(),
+                current_key_val(&iter).unwrap().0,
+                "target {}",
+                target
+            );
+        }
+        iter.seek_for_prev(b"a");
+        assert!(!iter.valid());
+
+        // Seeking to the end works without scanning the database, and iteration continues
+        // backwards from there.
+        iter.seek_to_last();
+        assert_eq!(
+            (b"iba".to_vec(), b"val2".to_vec()),
+            current_key_val(&iter).unwrap()
+        );
+        assert!(iter.prev());
+        assert_eq!(b"iaa".to_vec(), current_key_val(&iter).unwrap().0);
+
+        let mut iter = db.new_iter().unwrap();
+        iter.set_upper_bound(b"bb".to_vec());
+        iter.seek_to_last();
+        assert_eq!(b"bab".to_vec(), current_key_val(&iter).unwrap().0);
+        iter.seek_for_prev(b"zzz");
+        assert_eq!(b"bab".to_vec(), current_key_val(&iter).unwrap().0);
+        iter.set_upper_bound(b"a".to_vec());
+        iter.seek_to_last();
+        assert!(!iter.valid());
+    }
+
+    #[test]
+    fn db_iter_stats() {
+        let mut db = DB::open("db", options::for_test()).unwrap();
+        for version in 0..5 {
+            for k in 0..10 {
+                let val = format!("val{}", version);
+                db.put(format!("key{}", k).as_bytes(), val.as_bytes())
+                    .unwrap();
+            }
+        }
+        db.delete(b"key3").unwrap();
+
+        let mut iter = db.new_iter().unwrap();
+        assert_eq!(IterStats::default(), iter.stats());
+        assert_eq!(9, LdbIteratorIter::wrap(&mut iter).count());
+        // Four stale versions of each of nine keys; the deletion and five versions of key3.
+        assert_eq!(4 * 9 + 6, iter.stats().keys_skipped);
+        // Everything is still in the memtable.
+        assert_eq!(0, iter.stats().blocks_read);
+
+        let (mut db, _) = build_db();
+        let mut iter = db.new_iter().unwrap();
+        LdbIteratorIter::wrap(&mut iter).count();
+        let stats = iter.stats();
+        assert!(stats.blocks_read >= 9);
+        assert!(stats.bytes_read > 0);
+        assert!(stats.keys_skipped > 0);
+    }
+
+    #[test]
+    fn db_iter_upper_bound() {
+        let cmps = Rc::new(Cell::new(0));
+        let mut opt = options::for_test();
+        opt.cmp = Rc::new(Box::new(CountingCmp::new(cmps.clone())));
+        let mut db = DB::open("db", opt).unwrap();
+        for i in 0..500 {
+            db.put(format!("key{:03}", i).as_bytes(), b"val").unwrap();
+        }
+        for i in 50..500 {
+            db.delete(format!("key{:03}", i).as_bytes()).unwrap();
+        }
+        let expected: Vec<_> = (0..50)
+            .map(|i| format!("key{:03}", i).into_bytes())
+            .collect();
+
+        // Checking the bound on each returned key: all deletions after it are read, too.
+        cmps.set(0);
+        let mut iter = db.new_iter().unwrap();
+        let keys: Vec<_> = LdbIteratorIter::wrap(&mut iter)
+            .map(|(k, _)| k)
+            .take_while(|k| k.as_slice() < b"key050".as_slice())
+            .collect();
+        assert_eq!(expected, keys);
+        let naive_cmps = cmps.get();
+
+        cmps.set(0);
+        let mut iter = db.new_iter().unwrap();
+        iter.set_upper_bound(b"key050".to_vec());
+        let keys: Vec<_> = LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect();
+        assert_eq!(expected, keys);
+        assert!(cmps.get() < naive_cmps, "{} >= {}", cmps.get(), naive_cmps);
+
+        // The bound itself is excluded, and seeking beyond it finds nothing.
+        let mut iter = db.new_iter().unwrap();
+        iter.set_upper_bound(b"key049".to_vec());
+        let keys: Vec<_> = LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect();
+        assert_eq!(expected[..49], keys[..]);
+        iter.seek(b"key049");
+        assert!(!iter.valid());
+        iter.seek(b"key048");
+        assert_eq!(b"key048", current_key_val(&iter).unwrap().0.as_slice());
+        assert!(!iter.advance());
+    }
+
+    #[test]
+    fn db_iter_cancel() {
+        let opt = options::for_test();
+        let mut db = DB::open("db", opt).unwrap();
+        for i in 0..1000 {
+            db.put(format!("key{:04}", i).as_bytes(), b"value").unwrap();
+        }
+
+        let token = CancelToken::default();
+        let mut iter = db.new_iter().unwrap();
+        iter.set_cancel_token(token.clone());
+
+        let mut seen = 0;
+        while iter.advance() {
+            seen += 1;
+            if seen == 100 {
+                token.store(true, atomic::Ordering::Relaxed);
+            }
+        }
+        assert!((100..100 + CANCEL_CHECK_PERIOD).contains(&seen));
+        assert!(!iter.valid());
+        assert_eq!(StatusCode::Aborted, iter.status().unwrap_err().code);
+
+        // A cancelled iterator stays cancelled.
+        assert!(!iter.advance());
+        assert!(iter.next().is_none());
+
+        // Without cancellation, the full range is returned.
+        let mut iter = db.new_iter().unwrap();
+        iter.set_cancel_token(CancelToken::default());
+        assert_eq!(1000, LdbIteratorIter::wrap(&mut iter).count());
+        assert!(iter.status().is_ok());
+    }
+
     #[test]
     fn db_iter_allow_empty_key() {
         let opt = options::for_test();
@@ -3,11 +3,11 @@ use crate::env_common::{micros, sleep_for};
 use crate::error::{err, Result, Status, StatusCode};
 use fs2::FileExt;
 
+use std::cell::Cell;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::io::{self, ErrorKind, Read, Write};
-use std::iter::FromIterator;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
@@ -32,6 +32,28 @@ impl Default for PosixDiskEnv {
     }
 }
 
+thread_local! {
+    // Set while PosixDiskEnv::sync() flushes a writer, asking the SyncableFiles below it to sync.
+    static SYNC_ON_FLUSH: Cell<bool> = const { Cell::new(false) };
+}
+
+/// A SyncableFile is a file opened for writing. Flushing it only syncs it to disk (contents and
+/// metadata) if done by `PosixDiskEnv::sync()`, which may reach it through wrapping writers.
+struct SyncableFile(File);
+
+impl Write for SyncableFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        if SYNC_ON_FLUSH.with(Cell::get) {
+            self.0.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
 /// map_err_with_name annotates an io::Error with information about the operation and the file.
 fn map_err_with_name(method: &'static str, f: &Path, e: io::Error) -> Status {
     let mut s = Status::from(e);
@@ -39,6 +61,34 @@ fn map_err_with_name(method: &'static str, f: &Path, e: io::Error) -> Status {
     s
 }
 
+/// rename_noreplace_atomic renames `old` to `new` using renameat2(2), which atomically fails if
+/// `new` exists.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn rename_noreplace_atomic(old: &Path, new: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let to_cstring = |p: &Path| {
+        CString::new(p.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))
+    };
+    let (old, new) = (to_cstring(old)?, to_cstring(new)?);
+    let r = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            old.as_ptr(),
+            libc::AT_FDCWD,
+            new.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if r == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
 // Note: We're using Ok(f()?) in several locations below in order to benefit from the automatic
 // error conversion using std::convert::From.
 impl Env for PosixDiskEnv {
@@ -61,7 +111,7 @@ impl Env for PosixDiskEnv {
             .map_err(|e| map_err_with_name("open (randomaccess)", p, e))
     }
     fn open_writable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
-        Ok(Box::new(
+        Ok(Box::new(SyncableFile(
             fs::OpenOptions::new()
                 .create(true)
                 .truncate(true)
@@ -69,17 +119,25 @@ impl Env for PosixDiskEnv {
                 .append(false)
                 .open(p)
                 .map_err(|e| map_err_with_name("open (write)", p, e))?,
-        ))
+        )))
     }
     fn open_appendable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
-        Ok(Box::new(
+        Ok(Box::new(SyncableFile(
             fs::OpenOptions::new()
                 .create(true)
                 .truncate(false)
                 .append(true)
                 .open(p)
                 .map_err(|e| map_err_with_name("open (append)", p, e))?,
-        ))
+        )))
+    }
+    /// sync flushes `w` and syncs the files of this Env written through it with
+    /// `File::sync_all()`.
+    fn sync(&self, w: &mut dyn Write) -> Result<()> {
+        SYNC_ON_FLUSH.with(|s| s.set(true));
+        let r = w.flush();
+        SYNC_ON_FLUSH.with(|s| s.set(false));
+        Ok(r?)
     }
 
     fn exists(&self, p: &Path) -> Result<bool> {
@@ -87,17 +145,12 @@ impl Env for PosixDiskEnv {
     }
     fn children(&self, p: &Path) -> Result<Vec<PathBuf>> {
         let dir_reader = fs::read_dir(p).map_err(|e| map_err_with_name("children", p, e))?;
-        let filenames = dir_reader
+        dir_reader
             .map(|r| {
-                if r.is_err() {
-                    Path::new("").to_owned()
-                } else {
-                    let direntry = r.unwrap();
-                    Path::new(&direntry.file_name()).to_owned()
-                }
+                r.map(|direntry| PathBuf::from(direntry.file_name()))
+                    .map_err(|e| map_err_with_name("children", p, e))
             })
-            .filter(|s| !s.as_os_str().is_empty());
-        Ok(Vec::from_iter(filenames))
+            .collect()
     }
     fn size_of(&self, p: &Path) -> Result<usize> {
         let meta = fs::metadata(p).map_err(|e| map_err_with_name("size_of", p, e))?;
@@ -116,6 +169,30 @@ impl Env for PosixDiskEnv {
     fn rename(&self, old: &Path, new: &Path) -> Result<()> {
         fs::rename(old, new).map_err(|e| map_err_with_name("rename", old, e))
     }
+    fn rename_noreplace(&self, old: &Path, new: &Path) -> Result<()> {
+        #[cfg(all(target_os = "linux", target_env = "gnu"))]
+        {
+            match rename_noreplace_atomic(old, new) {
+                Ok(()) => return Ok(()),
+                // Not supported by the kernel or file system; fall back to the racy check below.
+                Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) => {}
+                Err(e) => return Err(map_err_with_name("rename_noreplace", old, e)),
+            }
+        }
+        if !old.exists() {
+            return err(
+                StatusCode::NotFound,
+                &format!("rename_noreplace: file not found: {}", path_to_str(old)),
+            );
+        }
+        if new.exists() {
+            return err(
+                StatusCode::AlreadyExists,
+                &format!("rename_noreplace: destination exists: {}", path_to_str(new)),
+            );
+        }
+        self.rename(old, new)
+    }
 
     fn lock(&self, p: &Path) -> Result<FileLock> {
         let mut locks = self.locks.lock().unwrap();
@@ -227,6 +304,89 @@ mod tests {
 
         assert!(env.open_sequential_file(name).is_ok());
         assert!(env.open_random_access_file(name).is_ok());
+        assert_eq!(b"123xyz".to_vec(), env.read_to_vec(name).unwrap());
+
+        assert!(env.delete(name).is_ok());
+        assert!(env.read_to_vec(name).is_err());
+    }
+
+    #[test]
+    fn test_rename_noreplace() {
+        let env = PosixDiskEnv::new();
+        let (src, dst) = (Path::new("testfile.rn_src"), Path::new("testfile.rn_dst"));
+        env.open_writable_file(src)
+            .unwrap()
+            .write_all(b"src")
+            .unwrap();
+        env.open_writable_file(dst)
+            .unwrap()
+            .write_all(b"dst")
+            .unwrap();
+
+        assert_eq!(
+            StatusCode::AlreadyExists,
+            env.rename_noreplace(src, dst).unwrap_err().code
+        );
+        assert_eq!(b"dst".to_vec(), env.read_to_vec(dst).unwrap());
+        env.delete(dst).unwrap();
+
+        env.rename_noreplace(src, dst).unwrap();
+        assert!(!env.exists(src).unwrap());
+        assert_eq!(b"src".to_vec(), env.read_to_vec(dst).unwrap());
+        assert_eq!(
+            StatusCode::NotFound,
+            env.rename_noreplace(src, dst).unwrap_err().code
+        );
+        env.delete(dst).unwrap();
+    }
+
+    #[test]
+    fn test_sync() {
+        let env = PosixDiskEnv::new();
+        let name = Path::new("testfile.sync");
+
+        // The writers are wrapped in buffers, so the data only reaches the file if sync flushes
+        // it through to the SyncableFile, which syncs it then.
+        let mut f = io::BufWriter::new(env.open_writable_file(name).unwrap());
+        f.write_all(b"some data").unwrap();
+        assert_eq!(0, env.size_of(name).unwrap());
+        env.sync(&mut f).unwrap();
+        assert_eq!(9, env.size_of(name).unwrap());
+
+        let mut f = io::BufWriter::new(env.open_appendable_file(name).unwrap());
+        f.write_all(b"+more").unwrap();
+        assert_eq!(9, env.size_of(name).unwrap());
+        env.sync(&mut f).unwrap();
+        assert_eq!(14, env.size_of(name).unwrap());
+        assert_eq!(b"some data+more".to_vec(), env.read_to_vec(name).unwrap());
+
+        assert!(env.delete(name).is_ok());
+    }
+
+    #[test]
+    fn test_append_preserves_contents() {
+        let env = PosixDiskEnv::new();
+        let name = Path::new("testfile.append");
+
+        env.open_writable_file(name)
+            .unwrap()
+            .write_all(b"original")
+            .unwrap();
+        env.open_appendable_file(name)
+            .unwrap()
+            .write_all(b"+appended")
+            .unwrap();
+        assert_eq!(
+            b"original+appended".to_vec(),
+            env.read_to_vec(name).unwrap()
+        );
+
+        // Opening for writing starts over.
+        env.open_writable_file(name)
+            .unwrap()
+            .write_all(b"new")
+            .unwrap();
+        assert_eq!(b"new".to_vec(), env.read_to_vec(name).unwrap());
 
         assert!(env.delete(name).is_ok());
     }
@@ -257,6 +417,13 @@ mod tests {
             env.unlock(r.unwrap()).unwrap();
         }
 
+        {
+            let _guard = env.lock_guarded(name).unwrap();
+            assert!(env.lock(name).is_err());
+        }
+        // Dropping the guard released the lock.
+        env.unlock(env.lock(name).unwrap()).unwrap();
+
         assert!(env.delete(name).is_ok());
     }
 
@@ -273,6 +440,8 @@ mod tests {
             )
             .is_ok());
         assert_eq!(env.children(dirname).unwrap().len(), 1);
+        assert_eq!(env.children_with_ext(dirname, "txt").unwrap().len(), 1);
+        assert!(env.children_with_ext(dirname, "ldb").unwrap().is_empty());
         assert!(env.rmdir(dirname).is_ok());
     }
 }
@@ -1,7 +1,10 @@
 //! An `env` is an abstraction layer that allows the database to run both on different platforms as
 //! well as persisting data on disk or in memory.
 
-use crate::error::Result;
+use crate::{
+    env_common,
+    error::{err, Result, StatusCode},
+};
 
 use std::fs::File;
 use std::io::prelude::*;
@@ -33,27 +36,115 @@ pub struct FileLock {
     pub id: String,
 }
 
+/// FileLockGuard holds a lock acquired with `Env::lock_guarded()`, which is released when the
+/// guard is dropped. Errors from releasing the lock are ignored then; use `unlock()` to see them.
+pub struct FileLockGuard<'a> {
+    lock: Option<FileLock>,
+    unlock: Option<Box<dyn FnOnce(FileLock) -> Result<()> + 'a>>,
+}
+
+impl<'a> FileLockGuard<'a> {
+    /// new creates a guard for `lock`, which is released by calling `unlock`.
+    pub fn new<F: FnOnce(FileLock) -> Result<()> + 'a>(lock: FileLock, unlock: F) -> Self {
+        FileLockGuard {
+            lock: Some(lock),
+            unlock: Some(Box::new(unlock)),
+        }
+    }
+
+    /// unlock releases the lock now.
+    pub fn unlock(mut self) -> Result<()> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<()> {
+        match (self.lock.take(), self.unlock.take()) {
+            (Some(lock), Some(unlock)) => unlock(lock),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for FileLockGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
 pub trait Env {
     fn open_sequential_file(&self, _: &Path) -> Result<Box<dyn Read>>;
     fn open_random_access_file(&self, _: &Path) -> Result<Box<dyn RandomAccess>>;
+    /// Opens a file for writing, creating it if necessary and truncating existing contents. Use
+    /// this for new files only; existing files (e.g. a reused manifest) must be opened with
+    /// `open_appendable_file()`.
     fn open_writable_file(&self, _: &Path) -> Result<Box<dyn Write>>;
+    /// Opens a file for appending, creating it if necessary and preserving existing contents.
     fn open_appendable_file(&self, _: &Path) -> Result<Box<dyn Write>>;
+    /// Flushes `w` (a writer returned by this Env, possibly wrapped in a buffer) and makes the
+    /// data written to it durable, so that it survives a crash of the process or the machine.
+    ///
+    /// The default implementation only flushes `w`, which suffices for Envs whose writers don't
+    /// buffer data outside of durable storage.
+    fn sync(&self, w: &mut dyn Write) -> Result<()> {
+        Ok(w.flush()?)
+    }
 
     fn exists(&self, _: &Path) -> Result<bool>;
     fn children(&self, _: &Path) -> Result<Vec<PathBuf>>;
+    /// Returns the children of `dir` whose name has the extension `ext` (e.g. "ldb", with or
+    /// without the leading dot).
+    fn children_with_ext(&self, dir: &Path, ext: &str) -> Result<Vec<PathBuf>> {
+        let ext = ext.trim_start_matches('.');
+        let mut children = self.children(dir)?;
+        children.retain(|c| c.extension().is_some_and(|e| e == ext));
+        Ok(children)
+    }
     fn size_of(&self, _: &Path) -> Result<usize>;
 
+    /// Reads the whole file at the given path.
+    fn read_to_vec(&self, p: &Path) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        self.open_sequential_file(p)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
     fn delete(&self, _: &Path) -> Result<()>;
     fn mkdir(&self, _: &Path) -> Result<()>;
     fn rmdir(&self, _: &Path) -> Result<()>;
+    /// Renames a file, replacing the destination if it exists.
     fn rename(&self, _: &Path, _: &Path) -> Result<()>;
+    /// Renames a file like `rename()`, but fails with `AlreadyExists` instead of replacing an
+    /// existing destination. A missing source results in `NotFound`.
+    ///
+    /// The default implementation checks whether the destination exists before renaming. This is
+    /// not atomic: a destination created in between is replaced. Override it if the platform
+    /// offers an atomic alternative.
+    fn rename_noreplace(&self, old: &Path, new: &Path) -> Result<()> {
+        if self.exists(new)? {
+            return err(
+                StatusCode::AlreadyExists,
+                &format!("rename_noreplace: destination exists: {}", new.display()),
+            );
+        }
+        self.rename(old, new)
+    }
 
     fn lock(&self, _: &Path) -> Result<FileLock>;
     fn unlock(&self, l: FileLock) -> Result<()>;
+    /// Like `lock()`, but returns a guard that releases the lock when dropped.
+    fn lock_guarded(&self, p: &Path) -> Result<FileLockGuard<'_>> {
+        let lock = self.lock(p)?;
+        Ok(FileLockGuard::new(lock, move |l| self.unlock(l)))
+    }
 
     fn new_logger(&self, _: &Path) -> Result<Logger>;
 
     fn micros(&self) -> u64;
+    /// Returns a timestamp in microseconds for measuring intervals. Unlike `micros()`, it never
+    /// goes backwards; it has no meaning across processes.
+    fn now_monotonic(&self) -> u64 {
+        env_common::monotonic_micros()
+    }
     fn sleep_for(&self, micros: u32);
 }
 
@@ -1,4 +1,4 @@
-use std::{thread, time};
+use std::{sync::OnceLock, thread, time};
 
 pub fn micros() -> u64 {
     loop {
@@ -11,6 +11,13 @@ pub fn micros() -> u64 {
     }
 }
 
+/// monotonic_micros returns the microseconds elapsed since the first call in this process. Unlike
+/// micros(), it doesn't jump when the system clock is adjusted.
+pub fn monotonic_micros() -> u64 {
+    static START: OnceLock<time::Instant> = OnceLock::new();
+    START.get_or_init(time::Instant::now).elapsed().as_micros() as u64
+}
+
 pub fn sleep_for(micros: u32) {
-    thread::sleep(time::Duration::new(0, micros * 1000));
+    thread::sleep(time::Duration::from_micros(micros as u64));
 }
@@ -10,6 +10,7 @@ use std::{io, sync};
 pub enum StatusCode {
     OK,
 
+    Aborted,
     AlreadyExists,
     Corruption,
     CompressionError,
@@ -84,6 +85,7 @@ impl From<io::Error> for Status {
     fn from(e: io::Error) -> Status {
         let c = match e.kind() {
             io::ErrorKind::NotFound => StatusCode::NotFound,
+            io::ErrorKind::AlreadyExists => StatusCode::AlreadyExists,
             io::ErrorKind::InvalidData => StatusCode::Corruption,
             io::ErrorKind::InvalidInput => StatusCode::InvalidArgument,
             io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
@@ -32,6 +32,9 @@ impl FilterPolicy for BoxedFilterPolicy {
     }
 }
 
+/// The name returned by `NoFilterPolicy`.
+pub const NO_FILTER_POLICY_NAME: &str = "_";
+
 /// Used for tables that don't have filter blocks but need a type parameter.
 #[derive(Clone)]
 pub struct NoFilterPolicy;
@@ -44,7 +47,7 @@ impl NoFilterPolicy {
 
 impl FilterPolicy for NoFilterPolicy {
     fn name(&self) -> &'static str {
-        "_"
+        NO_FILTER_POLICY_NAME
     }
     fn create_filter(&self, _: &[u8], _: &[usize]) -> Vec<u8> {
         vec![]
@@ -209,6 +212,97 @@ impl<FP: FilterPolicy> FilterPolicy for InternalFilterPolicy<FP> {
     }
 }
 
+/// A PrefixExtractor defines the prefix of a key. If set in `Options::prefix_extractor`, filters
+/// contain the prefixes of keys instead of the keys themselves, which allows them to rule out
+/// blocks for prefix scans (see `DB::prefix_iter()`), not only for point lookups.
+///
+/// `transform()` must return a prefix of its argument, and all keys starting with a key `k` for
+/// which `in_domain(k)` holds must have the same prefix as `k`.
+pub trait PrefixExtractor {
+    /// Returns a string identifying this extractor and its parameters. It is stored in every
+    /// table, and filters of tables written with a different extractor are not used.
+    fn name(&self) -> String;
+    /// Returns the prefix of key. Only called for keys for which `in_domain()` is true.
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8];
+    /// Returns whether key has a prefix. Other keys are added to filters as they are.
+    fn in_domain(&self, key: &[u8]) -> bool;
+}
+
+pub type BoxedPrefixExtractor = Rc<Box<dyn PrefixExtractor>>;
+
+/// FixedPrefixExtractor uses the first `len` bytes of a key as prefix.
+#[derive(Clone)]
+pub struct FixedPrefixExtractor {
+    len: usize,
+}
+
+impl FixedPrefixExtractor {
+    pub fn new(len: usize) -> FixedPrefixExtractor {
+        FixedPrefixExtractor { len }
+    }
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn name(&self) -> String {
+        format!("leveldb.FixedPrefix.{}", self.len)
+    }
+    fn transform<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        &key[0..self.len]
+    }
+    fn in_domain(&self, key: &[u8]) -> bool {
+        key.len() >= self.len
+    }
+}
+
+/// A filter policy wrapping another policy; it adds the prefix of each key (as defined by a
+/// PrefixExtractor) to the filter instead of the key itself. Keys outside of the extractor's
+/// domain are added unchanged.
+#[derive(Clone)]
+pub struct PrefixFilterPolicy<FP: FilterPolicy> {
+    inner: FP,
+    extractor: BoxedPrefixExtractor,
+}
+
+impl<FP: FilterPolicy> PrefixFilterPolicy<FP> {
+    pub fn new(inner: FP, extractor: BoxedPrefixExtractor) -> PrefixFilterPolicy<FP> {
+        PrefixFilterPolicy { inner, extractor }
+    }
+
+    fn prefix<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        if self.extractor.in_domain(key) {
+            self.extractor.transform(key)
+        } else {
+            key
+        }
+    }
+}
+
+impl<FP: FilterPolicy> FilterPolicy for PrefixFilterPolicy<FP> {
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn create_filter(&self, keys: &[u8], key_offsets: &[usize]) -> Vec<u8> {
+        let mut mod_keys = Vec::with_capacity(keys.len());
+        let mut mod_key_offsets = Vec::with_capacity(key_offsets.len());
+
+        offset_data_iterate(keys, key_offsets, |key| {
+            let prefix = self.prefix(key);
+            // Keys arrive sorted, so identical prefixes are adjacent.
+            let last = mod_key_offsets.last().map(|&off| &mod_keys[off..]);
+            if last != Some(prefix) {
+                mod_key_offsets.push(mod_keys.len());
+                mod_keys.extend_from_slice(prefix);
+            }
+        });
+        self.inner.create_filter(&mod_keys, &mod_key_offsets)
+    }
+
+    fn key_may_match(&self, key: &[u8], filter: &[u8]) -> bool {
+        self.inner.key_may_match(self.prefix(key), filter)
+    }
+}
+
 /// offset_data_iterate iterates over the entries in data that are indexed by the offsets given in
 /// offsets. This is e.g. the internal format of a FilterBlock.
 fn offset_data_iterate<F: FnMut(&[u8])>(data: &[u8], offsets: &[usize], mut f: F) {
@@ -295,6 +389,26 @@ mod tests {
         assert_eq!(create_filter(), create_internalkey_filter());
     }
 
+    #[test]
+    fn test_filter_prefix() {
+        let extractor: BoxedPrefixExtractor = Rc::new(Box::new(FixedPrefixExtractor::new(3)));
+        let fp = PrefixFilterPolicy::new(BloomPolicy::new(_BITS_PER_KEY), extractor);
+        assert_eq!(BloomPolicy::new(_BITS_PER_KEY).name(), fp.name());
+
+        let (data, offs) = input_data();
+        let f = fp.create_filter(&data, &offs);
+
+        offset_data_iterate(&data, &offs, |key| {
+            assert!(fp.key_may_match(key, &f));
+        });
+        // Any key with a matching prefix matches, as do the prefixes themselves.
+        assert!(fp.key_may_match(b"abc", &f));
+        assert!(fp.key_may_match(b"abc999", &f));
+        assert!(fp.key_may_match(b"xxx", &f));
+        assert!(!fp.key_may_match(b"abd123def456", &f));
+        assert!(!fp.key_may_match(b"zzz", &f));
+    }
+
     #[test]
     fn test_filter_bloom_hash() {
         let d1 = vec![0x62];
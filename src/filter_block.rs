@@ -121,7 +121,11 @@ impl FilterBlockReader {
         assert!(data.len() >= 5);
 
         let fbase = data[data.len() - 1] as u32;
-        let offset = u32::decode_fixed(&data[data.len() - 5..data.len() - 1]).unwrap() as usize;
+        let mut offset = u32::decode_fixed(&data[data.len() - 5..data.len() - 1]).unwrap() as usize;
+        if offset > data.len() - 5 {
+            // Malformed block; treat it as containing no filters, so that all keys may match.
+            offset = data.len() - 5;
+        }
 
         FilterBlockReader {
             policy: pol,
@@ -145,16 +149,27 @@ impl FilterBlockReader {
 
     /// blk_offset is the offset of the block containing key. Returns whether the key matches the
     /// filter for the block at blk_offset.
+    ///
+    /// The filter for the block is the one covering the `2^base_lg` bytes range (2 KiB by default)
+    /// that the block starts in. Offsets without a filter may match any key.
     pub fn key_may_match(&self, blk_offset: usize, key: &[u8]) -> bool {
-        if get_filter_index(blk_offset, self.filter_base_lg2) > self.num() {
+        let ix = get_filter_index(blk_offset, self.filter_base_lg2);
+        if ix >= self.num() {
             return true;
         }
 
-        let filter_begin = self.offset_of(get_filter_index(blk_offset, self.filter_base_lg2));
-        let filter_end = self.offset_of(get_filter_index(blk_offset, self.filter_base_lg2) + 1);
-
-        assert!(filter_begin < filter_end);
-        assert!(filter_end <= self.offsets_offset);
+        // The end of the last filter is the start of the offsets array, which is stored right
+        // after the last filter offset.
+        let filter_begin = self.offset_of(ix);
+        let filter_end = self.offset_of(ix + 1);
+        if filter_begin > filter_end || filter_end > self.offsets_offset {
+            // Corrupted offsets.
+            return true;
+        }
+        if filter_begin == filter_end {
+            // No block starts in this range, so there are no keys.
+            return false;
+        }
 
         self.policy
             .key_may_match(key, &self.block[filter_begin..filter_end])
@@ -241,4 +256,45 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_filter_block_multiple_ranges() {
+        let policy: BoxedFilterPolicy = Rc::new(Box::new(BloomPolicy::new(32)));
+        let mut bld = FilterBlockBuilder::new(policy.clone());
+        let key = |block: usize, i: usize| format!("block{}-key{}", block, i).into_bytes();
+
+        // Two blocks starting in the first 2 KiB, one in the second range, and one at 10000,
+        // leaving ranges 2 to 3 empty.
+        let block_offsets = [0, 1000, 2500, 10000];
+        for (block, &offset) in block_offsets.iter().enumerate() {
+            bld.start_block(offset);
+            for i in 0..10 {
+                bld.add_key(&key(block, i));
+            }
+        }
+        let reader = FilterBlockReader::new_owned(policy, bld.finish());
+        assert_eq!(5, reader.num());
+
+        for (block, &offset) in block_offsets.iter().enumerate() {
+            for (other, &other_offset) in block_offsets.iter().enumerate() {
+                // Blocks starting in the same range share their filter.
+                let same_filter = get_filter_index(other_offset, FILTER_BASE_LOG2)
+                    == get_filter_index(offset, FILTER_BASE_LOG2);
+                for i in 0..10 {
+                    assert_eq!(
+                        same_filter,
+                        reader.key_may_match(offset, &key(other, i)),
+                        "block {} key {:?}",
+                        block,
+                        key(other, i)
+                    );
+                }
+            }
+        }
+        // Ranges in which no block starts have empty filters.
+        assert!(!reader.key_may_match(5000, &key(0, 0)));
+        assert!(!reader.key_may_match(7000, &key(3, 0)));
+        // Offsets beyond the last filter may match anything.
+        assert!(reader.key_may_match(20000, b"unknown"));
+    }
 }
@@ -9,10 +9,53 @@ use integer_encoding::{FixedInt, FixedIntWriter, VarInt, VarIntWriter};
 // TODO: At some point, convert those into actual types with conversion between them, That's a lot
 // of boilerpalce, but increases type safety.
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone, Copy, PartialOrd, PartialEq)]
 pub enum ValueType {
     TypeDeletion = 0,
     TypeValue = 1,
+    /// A range tombstone; its key is the beginning and its value the end of the deleted range.
+    TypeRangeDeletion = 2,
+    /// A merge operand, to be combined with older entries of the key by the merge operator.
+    TypeMerge = 3,
+}
+
+/// A RangeTombstone deletes all entries with a user key in [begin, end) that are older than the
+/// tombstone itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeTombstone {
+    pub begin: Vec<u8>,
+    pub end: Vec<u8>,
+    pub seq: SequenceNumber,
+}
+
+impl RangeTombstone {
+    /// covers returns true if the tombstone deletes the entry for `ukey` with sequence number
+    /// `seq`.
+    pub fn covers(&self, ucmp: &dyn Cmp, ukey: UserKey, seq: SequenceNumber) -> bool {
+        seq < self.seq && self.contains(ucmp, ukey)
+    }
+
+    /// contains returns true if `ukey` lies within the deleted range.
+    pub fn contains(&self, ucmp: &dyn Cmp, ukey: UserKey) -> bool {
+        ucmp.cmp(&self.begin, ukey) != Ordering::Greater
+            && ucmp.cmp(ukey, &self.end) == Ordering::Less
+    }
+}
+
+/// max_covering_seq returns the sequence number of the newest tombstone from `tombstones` that
+/// covers `ukey` and is visible at `snapshot`, if any. An entry for `ukey` is deleted if its
+/// sequence number is lower than the returned one.
+pub fn max_covering_seq<'a, I: Iterator<Item = &'a RangeTombstone>>(
+    tombstones: I,
+    ucmp: &dyn Cmp,
+    ukey: UserKey,
+    snapshot: SequenceNumber,
+) -> Option<SequenceNumber> {
+    tombstones
+        .filter(|t| t.seq <= snapshot && t.contains(ucmp, ukey))
+        .map(|t| t.seq)
+        .max()
 }
 
 /// A MemtableKey consists of the following elements: [keylen, key, tag, (vallen, value)] where
@@ -91,6 +134,8 @@ pub fn parse_tag(tag: u64) -> (ValueType, u64) {
     match typ {
         0 => (ValueType::TypeDeletion, seq),
         1 => (ValueType::TypeValue, seq),
+        2 => (ValueType::TypeRangeDeletion, seq),
+        3 => (ValueType::TypeMerge, seq),
         _ => (ValueType::TypeValue, seq),
     }
 }
@@ -34,6 +34,7 @@ mod block_builder;
 mod blockhandle;
 mod cache;
 mod cmp;
+mod compaction_iter;
 mod disk_env;
 mod env_common;
 mod error;
@@ -43,8 +44,10 @@ mod key_types;
 mod log;
 mod mem_env;
 mod memtable;
+mod merge_op;
 mod merging_iter;
 mod options;
+mod reverse_iter;
 mod skipmap;
 mod snapshot;
 mod table_block;
@@ -52,6 +55,7 @@ mod table_builder;
 mod table_cache;
 mod table_reader;
 mod test_util;
+mod ttl;
 mod types;
 mod version;
 mod version_edit;
@@ -64,17 +68,23 @@ mod db_iter;
 pub mod compressor;
 pub mod env;
 
-pub use cmp::{Cmp, DefaultCmp};
+pub use cmp::{Cmp, DefaultCmp, FixedPrefixCmp};
 pub use compressor::{Compressor, CompressorId};
-pub use db_impl::DB;
-pub use db_iter::DBIterator;
+pub use db_impl::{merge_dbs, CompactionStats, DB};
+pub use db_iter::{CancelToken, ColumnFamilyIter, DBIterator, IterStats};
 
 pub use disk_env::PosixDiskEnv;
 pub use env::Env;
 pub use error::{Result, Status};
-pub use filter::{BloomPolicy, FilterPolicy};
-pub use mem_env::MemEnv;
-pub use options::{in_memory, CompressorList, Options};
+pub use filter::{BloomPolicy, FilterPolicy, FixedPrefixExtractor, PrefixExtractor};
+pub use key_types::ValueType;
+pub use log::{dump_log, LogOp};
+pub use mem_env::{FaultOp, MemEnv};
+pub use merge_op::{BoxedMergeOperator, MergeOperator};
+pub use options::{in_memory, ChecksumType, CompressorList, Options, OptionsBuilder};
+pub use reverse_iter::ReverseIterator;
 pub use skipmap::SkipMap;
-pub use types::LdbIterator;
+pub use table_builder::write_sorted_table;
+pub use table_reader::dump_table;
+pub use types::{FileMetaData, GetResult, LdbIterator};
 pub use write_batch::WriteBatch;
@@ -3,9 +3,19 @@
 //! A record is a bytestring: [checksum: uint32, length: uint16, type: uint8, data: [u8]]
 //! checksum is the crc32 sum of type and data; type is one of RecordType::{Full/First/Middle/Last}
 
-use crate::error::{err, Result, StatusCode};
-
-use std::io::{Read, Write};
+use crate::{
+    env::Env,
+    error::{err, Result, StatusCode},
+    key_types::ValueType,
+    options::{ChecksumType, Options},
+    types::SequenceNumber,
+    write_batch::WriteBatch,
+};
+
+use std::{
+    io::{self, Read, Write},
+    path::Path,
+};
 
 use crc::{crc32, Hasher32};
 use integer_encoding::{FixedInt, FixedIntWriter};
@@ -29,36 +39,30 @@ pub struct LogWriter<W: Write> {
 }
 
 impl<W: Write> LogWriter<W> {
-    pub fn new(writer: W) -> LogWriter<W> {
+    pub fn new(writer: W, checksum: ChecksumType) -> LogWriter<W> {
         LogWriter {
             dst: writer,
             current_block_offset: 0,
             block_size: BLOCK_SIZE,
-            digest: crc32::Digest::new(crc32::CASTAGNOLI),
+            digest: checksum.digest(),
         }
     }
 
     /// new_with_off opens a writer starting at some offset of an existing log file. The file must
     /// have the default block size.
-    pub fn new_with_off(write: W, off: usize) -> LogWriter<W> {
-        let mut w = LogWriter::new(write);
+    pub fn new_with_off(write: W, off: usize, checksum: ChecksumType) -> LogWriter<W> {
+        let mut w = LogWriter::new(write, checksum);
         w.current_block_offset = off % BLOCK_SIZE;
         w
     }
 
+    /// add_record appends r to the log. Nothing is written for an empty record.
     pub fn add_record(&mut self, r: &[u8]) -> Result<usize> {
         let mut record = r;
         let mut first_frag = true;
         let mut result = Ok(0);
         while result.is_ok() && !record.is_empty() {
-            assert!(self.block_size > HEADER_SIZE);
-            let space_left = self.block_size - self.current_block_offset;
-
-            // Fill up block; go to the next block;
-            if space_left < HEADER_SIZE {
-                self.dst.write_all(&vec![0, 0, 0, 0, 0, 0][0..space_left])?;
-                self.current_block_offset = 0;
-            }
+            self.skip_block_trailer()?;
 
             let avail_for_data = self.block_size - self.current_block_offset - HEADER_SIZE;
 
@@ -87,6 +91,26 @@ impl<W: Write> LogWriter<W> {
         result
     }
 
+    /// add_empty_record writes an empty Full fragment, which other implementations write for
+    /// empty records.
+    #[cfg(test)]
+    pub fn add_empty_record(&mut self) -> Result<usize> {
+        self.skip_block_trailer()?;
+        self.emit_record(RecordType::Full, &[], 0)
+    }
+
+    /// skip_block_trailer fills up the current block and goes to the next one if no header fits
+    /// into it anymore.
+    fn skip_block_trailer(&mut self) -> Result<()> {
+        assert!(self.block_size > HEADER_SIZE);
+        let space_left = self.block_size - self.current_block_offset;
+        if space_left < HEADER_SIZE {
+            self.dst.write_all(&[0, 0, 0, 0, 0, 0][0..space_left])?;
+            self.current_block_offset = 0;
+        }
+        Ok(())
+    }
+
     fn emit_record(&mut self, t: RecordType, data: &[u8], len: usize) -> Result<usize> {
         assert!(len < 256 * 256);
 
@@ -110,6 +134,12 @@ impl<W: Write> LogWriter<W> {
         self.dst.flush()?;
         Ok(())
     }
+
+    /// sync makes all records written so far durable, using `env` (which must have created the
+    /// underlying writer).
+    pub fn sync(&mut self, env: &dyn Env) -> Result<()> {
+        env.sync(&mut self.dst)
+    }
 }
 
 pub struct LogReader<R: Read> {
@@ -120,34 +150,62 @@ pub struct LogReader<R: Read> {
     blocksize: usize,
     head_scratch: [u8; 7],
     checksums: bool,
+    // Set after a corrupted fragment: the rest of its record is dropped up to the next record.
+    skip_fragments: bool,
 }
 
 impl<R: Read> LogReader<R> {
-    pub fn new(src: R, chksum: bool) -> LogReader<R> {
+    /// new creates a reader verifying the checksum of each record if `chksum` is set, using the
+    /// algorithm the log was written with.
+    pub fn new(src: R, chksum: bool, checksum: ChecksumType) -> LogReader<R> {
         LogReader {
             src,
             blk_off: 0,
             blocksize: BLOCK_SIZE,
             checksums: chksum,
             head_scratch: [0; 7],
-            digest: crc32::Digest::new(crc32::CASTAGNOLI),
+            digest: checksum.digest(),
+            skip_fragments: false,
         }
     }
 
-    // EOF is signalled by Ok(0)
+    // EOF is signalled by Ok(0), as is an empty record; use read_record() to tell them apart.
     pub fn read(&mut self, dst: &mut Vec<u8>) -> Result<usize> {
+        self.read_record(dst)?;
+        Ok(dst.len())
+    }
+
+    /// records returns an iterator over the records of the log. Unlike read(), it distinguishes
+    /// an empty record from the end of the log. A record with a bad checksum is returned as
+    /// `Corruption` error, and iteration continues with the next record; it stops after other
+    /// errors.
+    pub fn records(self) -> LogRecordIter<R> {
+        LogRecordIter {
+            reader: self,
+            done: false,
+        }
+    }
+
+    /// read_record reads the next record into dst, returning false at the end of the log. After
+    /// a `Corruption` error, the remaining fragments of the damaged record are dropped; a Middle
+    /// or Last fragment without a preceding First fragment is a `Corruption` error, too.
+    pub fn read_record(&mut self, dst: &mut Vec<u8>) -> Result<bool> {
         let mut checksum: u32;
         let mut length: u16;
         let mut typ: u8;
         let mut dst_offset: usize = 0;
+        let mut in_record = false;
 
         dst.clear();
 
         loop {
             if self.blocksize - self.blk_off < HEADER_SIZE {
-                //skip to next block
-                self.src
-                    .read_exact(&mut self.head_scratch[0..self.blocksize - self.blk_off])?;
+                //skip to next block; the trailer is missing if the log ends here.
+                let trailer = &mut self.head_scratch[0..self.blocksize - self.blk_off];
+                match self.src.read_exact(trailer) {
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+                    r => r?,
+                }
 
                 self.blk_off = 0;
             }
@@ -156,7 +214,7 @@ impl<R: Read> LogReader<R> {
 
             // EOF
             if bytes_read == 0 {
-                return Ok(0);
+                return Ok(false);
             }
 
             self.blk_off += bytes_read;
@@ -174,17 +232,33 @@ impl<R: Read> LogReader<R> {
             if self.checksums
                 && !self.check_integrity(typ, &dst[dst_offset..dst_offset + bytes_read], checksum)
             {
+                self.skip_fragments = true;
                 return err(StatusCode::Corruption, "Invalid Checksum");
             }
 
+            if typ == RecordType::Full as u8 || typ == RecordType::First as u8 {
+                self.skip_fragments = false;
+                in_record = true;
+            } else if !in_record {
+                dst.clear();
+                if self.skip_fragments {
+                    continue;
+                }
+                self.skip_fragments = true;
+                return err(
+                    StatusCode::Corruption,
+                    "fragment without the start of its record",
+                );
+            }
+
             dst_offset += length as usize;
 
             if typ == RecordType::Full as u8 {
-                return Ok(dst_offset);
+                return Ok(true);
             } else if typ == RecordType::First as u8 || typ == RecordType::Middle as u8 {
                 continue;
             } else if typ == RecordType::Last as u8 {
-                return Ok(dst_offset);
+                return Ok(true);
             }
         }
     }
@@ -197,6 +271,33 @@ impl<R: Read> LogReader<R> {
     }
 }
 
+/// LogRecordIter iterates over the records of a log; see `LogReader::records()`.
+pub struct LogRecordIter<R: Read> {
+    reader: LogReader<R>,
+    done: bool,
+}
+
+impl<R: Read> Iterator for LogRecordIter<R> {
+    type Item = Result<Vec<u8>>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut record = vec![];
+        match self.reader.read_record(&mut record) {
+            Ok(true) => Some(Ok(record)),
+            Ok(false) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = e.code != StatusCode::Corruption;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 const MASK_DELTA: u32 = 0xa282ead8;
 
 pub fn mask_crc(c: u32) -> u32 {
@@ -208,6 +309,75 @@ pub fn unmask_crc(c: u32) -> u32 {
     rot.wrapping_shr(17) | rot.wrapping_shl(15)
 }
 
+/// An operation decoded from a log record: its type, the key and the value, which is None for a
+/// deletion, the merge operand for a merge, and the end of the range for a range deletion.
+pub type LogOp = (ValueType, Vec<u8>, Option<Vec<u8>>);
+
+/// dump_log opens the log file at `path` and returns an iterator over the WriteBatches stored in
+/// it, each as base sequence number and the contained operations. Iteration stops after the first
+/// error. Records too short to be a WriteBatch are skipped, like during recovery, unless
+/// `opt.paranoid_checks` is set.
+pub fn dump_log(
+    env: &dyn Env,
+    path: &Path,
+    opt: &Options,
+) -> Result<impl Iterator<Item = Result<(SequenceNumber, Vec<LogOp>)>>> {
+    let file = env.open_sequential_file(path)?;
+    Ok(LogDump {
+        reader: LogReader::new(file, true, opt.checksum_type),
+        paranoid: opt.paranoid_checks,
+        scratch: vec![],
+        batch: WriteBatch::new(),
+        done: false,
+    })
+}
+
+struct LogDump {
+    reader: LogReader<Box<dyn Read>>,
+    paranoid: bool,
+    scratch: Vec<u8>,
+    batch: WriteBatch,
+    done: bool,
+}
+
+impl Iterator for LogDump {
+    type Item = Result<(SequenceNumber, Vec<LogOp>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.reader.read_record(&mut self.scratch) {
+                Ok(true) => {}
+                Ok(false) => {
+                    self.done = true;
+                    break;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+            if self.scratch.len() < 12 {
+                if self.paranoid {
+                    self.done = true;
+                    return Some(err(
+                        StatusCode::Corruption,
+                        "log record shorter than WriteBatch header",
+                    ));
+                }
+                continue;
+            }
+
+            self.batch.set_contents(&self.scratch);
+            let ops = self
+                .batch
+                .iter_typed()
+                .map(|(t, k, v)| (t, k.to_vec(), v.map(|v| v.to_vec())))
+                .collect();
+            return Some(Ok((self.batch.sequence(), ops)));
+        }
+        None
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
@@ -215,9 +385,14 @@ mod tests {
     use crc::crc32::checksum_castagnoli;
 
     use crate::{
+        env::Env,
         error::{err, StatusCode},
-        log::{mask_crc, unmask_crc, LogReader, HEADER_SIZE},
+        log::{dump_log, mask_crc, unmask_crc, LogReader, HEADER_SIZE},
+        mem_env::MemEnv,
+        options::{self, ChecksumType},
+        write_batch::WriteBatch,
     };
+    use std::path::Path;
 
     use super::LogWriter;
 
@@ -241,7 +416,7 @@ mod tests {
             "and my second",
             "and my third",
         ];
-        let mut lw = LogWriter::new(Vec::new());
+        let mut lw = LogWriter::new(Vec::new(), ChecksumType::Crc32c);
 
         let total_len = data.iter().fold(0, |l, d| l + d.len());
 
@@ -263,7 +438,7 @@ mod tests {
         let mut dst = vec![0; 1024];
 
         {
-            let mut lw = LogWriter::new(Cursor::new(dst.as_mut_slice()));
+            let mut lw = LogWriter::new(Cursor::new(dst.as_mut_slice()), ChecksumType::Crc32c);
             for d in data {
                 let _ = lw.add_record(d.as_bytes());
             }
@@ -275,8 +450,11 @@ mod tests {
         // cursors and stuff is required.
         {
             let offset = data[0].len() + super::HEADER_SIZE;
-            let mut lw =
-                LogWriter::new_with_off(Cursor::new(&mut dst.as_mut_slice()[offset..]), offset);
+            let mut lw = LogWriter::new_with_off(
+                Cursor::new(&mut dst.as_mut_slice()[offset..]),
+                offset,
+                ChecksumType::Crc32c,
+            );
             for d in &data[1..] {
                 let _ = lw.add_record(d.as_bytes());
             }
@@ -291,7 +469,7 @@ mod tests {
             "123456789012".as_bytes().to_vec(), // spans two blocks of 17
             "0101010101010101010101".as_bytes().to_vec(),
         ]; // spans three blocks of 17
-        let mut lw = LogWriter::new(Vec::new());
+        let mut lw = LogWriter::new(Vec::new(), ChecksumType::Crc32c);
         lw.block_size = HEADER_SIZE + 10;
 
         for e in data.iter() {
@@ -303,7 +481,7 @@ mod tests {
         // Corrput first record.
         lw.dst[2] += 1;
 
-        let mut lr = LogReader::new(lw.dst.as_slice(), true);
+        let mut lr = LogReader::new(lw.dst.as_slice(), true, ChecksumType::Crc32c);
         lr.blocksize = super::HEADER_SIZE + 10;
         let mut dst = Vec::with_capacity(128);
 
@@ -330,4 +508,168 @@ mod tests {
 
         assert_eq!(i, data.len());
     }
+
+    #[test]
+    fn test_reader_records() {
+        let data = [
+            b"abcdefghi".to_vec(),
+            vec![],
+            b"0101010101010101010101".to_vec(),
+        ];
+        let mut lw = LogWriter::new(Vec::new(), ChecksumType::Crc32c);
+        lw.block_size = HEADER_SIZE + 10;
+        // This writer writes nothing for empty records, but other implementations do.
+        assert_eq!(0, lw.add_record(&[]).unwrap());
+        assert!(lw.dst.is_empty());
+        for e in data.iter() {
+            if e.is_empty() {
+                lw.add_empty_record().unwrap();
+            } else {
+                lw.add_record(e).unwrap();
+            }
+        }
+
+        let reader = |src| {
+            let mut lr = LogReader::new(src, true, ChecksumType::Crc32c);
+            lr.blocksize = HEADER_SIZE + 10;
+            lr.records()
+        };
+        // The empty record is returned, too.
+        let records: Vec<_> = reader(Cursor::new(lw.dst.clone()))
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(data.to_vec(), records);
+
+        // Corrupt the first record; the others are still returned.
+        lw.dst[HEADER_SIZE] += 1;
+        let records: Vec<_> = reader(Cursor::new(lw.dst.clone())).collect();
+        assert_eq!(
+            vec![
+                err(StatusCode::Corruption, "Invalid Checksum"),
+                Ok(data[1].clone()),
+                Ok(data[2].clone())
+            ],
+            records
+        );
+    }
+
+    #[test]
+    fn test_reader_corrupt_fragments() {
+        let data = [
+            b"0101010101010101010101".to_vec(),
+            b"abc".to_vec(),
+            b"defghijklmnopqrstuvwxyz".to_vec(),
+        ];
+        let mut lw = LogWriter::new(Vec::new(), ChecksumType::Crc32c);
+        lw.block_size = HEADER_SIZE + 10;
+        for e in data.iter() {
+            lw.add_record(e).unwrap();
+        }
+        let records = |contents: &[u8]| -> Vec<crate::error::Result<Vec<u8>>> {
+            let mut lr = LogReader::new(Cursor::new(contents.to_vec()), true, ChecksumType::Crc32c);
+            lr.blocksize = HEADER_SIZE + 10;
+            lr.records().collect()
+        };
+
+        // A corrupted First fragment is reported once; its Middle and Last fragments are dropped.
+        let mut contents = lw.dst.clone();
+        contents[HEADER_SIZE] += 1;
+        assert_eq!(
+            vec![
+                err(StatusCode::Corruption, "Invalid Checksum"),
+                Ok(data[1].clone()),
+                Ok(data[2].clone())
+            ],
+            records(&contents)
+        );
+
+        // Likewise for a corrupted Middle fragment.
+        let mut contents = lw.dst.clone();
+        contents[HEADER_SIZE + 10 + HEADER_SIZE] += 1;
+        assert_eq!(
+            vec![
+                err(StatusCode::Corruption, "Invalid Checksum"),
+                Ok(data[1].clone()),
+                Ok(data[2].clone())
+            ],
+            records(&contents)
+        );
+
+        // A log starting within a record begins with an error, not with a partial record.
+        let records = records(&lw.dst[HEADER_SIZE + 10..]);
+        assert_eq!(
+            StatusCode::Corruption,
+            records[0].as_ref().unwrap_err().code
+        );
+        assert_eq!(vec![Ok(data[1].clone()), Ok(data[2].clone())], records[1..]);
+    }
+
+    #[test]
+    fn test_reader_checksum_types() {
+        let types = [ChecksumType::Crc32c, ChecksumType::Crc32];
+        for write_type in types {
+            let mut lw = LogWriter::new(Vec::new(), write_type);
+            lw.add_record(b"hello").unwrap();
+
+            for read_type in types {
+                let mut lr = LogReader::new(lw.dst.as_slice(), true, read_type);
+                let mut dst = vec![];
+                if read_type == write_type {
+                    assert_eq!(5, lr.read(&mut dst).unwrap());
+                    assert_eq!(b"hello".to_vec(), dst);
+                } else {
+                    assert_eq!(StatusCode::Corruption, lr.read(&mut dst).unwrap_err().code);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_dump_log() {
+        use crate::key_types::ValueType::*;
+        let env = MemEnv::new();
+        let path = Path::new("000001.log");
+        {
+            let mut lw =
+                LogWriter::new(env.open_writable_file(path).unwrap(), ChecksumType::Crc32c);
+            let mut b = WriteBatch::new();
+            b.put(b"abc", b"def");
+            b.delete(b"xyz");
+            b.merge(b"abc", b"+");
+            b.delete_range(b"a", b"b");
+            lw.add_record(&b.encode(10)).unwrap();
+            // Empty records (written by other implementations) are skipped without ending the
+            // dump.
+            lw.add_empty_record().unwrap();
+            b.clear();
+            b.put(b"ghi", b"jkl");
+            lw.add_record(&b.encode(12)).unwrap();
+            lw.flush().unwrap();
+        }
+
+        let dump: Vec<_> = dump_log(&env, path, &options::for_test())
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    10,
+                    vec![
+                        (TypeValue, b"abc".to_vec(), Some(b"def".to_vec())),
+                        (TypeDeletion, b"xyz".to_vec(), None),
+                        (TypeMerge, b"abc".to_vec(), Some(b"+".to_vec())),
+                        (TypeRangeDeletion, b"a".to_vec(), Some(b"b".to_vec())),
+                    ]
+                ),
+                (
+                    12,
+                    vec![(TypeValue, b"ghi".to_vec(), Some(b"jkl".to_vec()))]
+                ),
+            ],
+            dump
+        );
+
+        assert!(dump_log(&env, Path::new("000002.log"), &options::for_test()).is_err());
+    }
 }
@@ -5,7 +5,10 @@ use std::{
     io::{self, Read, Write},
     ops::Deref,
     path::{Path, PathBuf},
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
 use crate::{
@@ -120,6 +123,83 @@ impl RandomAccess for MemFile {
     }
 }
 
+/// FaultOp is the kind of operation that a fault injected with `MemEnv::set_fault()` applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultOp {
+    /// Opening a file, including `read_to_vec()`.
+    Open,
+    Read,
+    Write,
+    SizeOf,
+}
+
+struct Fault {
+    path_substr: String,
+    op: FaultOp,
+    code: StatusCode,
+    once: bool,
+}
+
+/// Faults is the list of faults configured on a MemFS, shared with all files opened from it.
+#[derive(Clone, Default)]
+struct Faults(Arc<Mutex<Vec<Fault>>>);
+
+impl Faults {
+    /// check returns the configured error if a fault matches the operation on the given path. A
+    /// fault that is set to fire once is removed afterwards.
+    fn check(&self, p: &str, op: FaultOp) -> Result<()> {
+        let mut faults = self.0.lock()?;
+        if let Some(i) = faults
+            .iter()
+            .position(|f| f.op == op && p.contains(&f.path_substr))
+        {
+            let code = if faults[i].once {
+                faults.remove(i).code
+            } else {
+                faults[i].code.clone()
+            };
+            return err(code, &format!("injected fault: {:?}: {}", op, p));
+        }
+        Ok(())
+    }
+}
+
+/// A FaultyFile wraps a reader, writer or MemFile, and fails reads and writes for which a fault
+/// is configured.
+struct FaultyFile<T> {
+    inner: T,
+    path: String,
+    faults: Faults,
+}
+
+impl<T: Read> Read for FaultyFile<T> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        self.faults
+            .check(&self.path, FaultOp::Read)
+            .map_err(io::Error::other)?;
+        self.inner.read(dst)
+    }
+}
+
+impl<T: Write> Write for FaultyFile<T> {
+    fn write(&mut self, src: &[u8]) -> io::Result<usize> {
+        self.faults
+            .check(&self.path, FaultOp::Write)
+            .map_err(io::Error::other)?;
+        self.inner.write(src)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: RandomAccess> RandomAccess for FaultyFile<T> {
+    fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<usize> {
+        self.faults.check(&self.path, FaultOp::Read)?;
+        self.inner.read_at(off, dst)
+    }
+}
+
 struct MemFSEntry {
     f: MemFile,
     locked: bool,
@@ -127,20 +207,33 @@ struct MemFSEntry {
 
 /// MemFS implements a completely in-memory file system, both for testing and temporary in-memory
 /// databases. It supports full concurrency.
+#[derive(Clone)]
 pub struct MemFS {
     store: Arc<Mutex<HashMap<String, MemFSEntry>>>,
+    faults: Faults,
 }
 
 impl MemFS {
     fn new() -> MemFS {
         MemFS {
             store: Arc::new(Mutex::new(HashMap::new())),
+            faults: Faults::default(),
+        }
+    }
+
+    /// faulty wraps a file opened at `p` so that it is subject to the configured faults.
+    fn faulty<T>(&self, p: &Path, inner: T) -> FaultyFile<T> {
+        FaultyFile {
+            inner,
+            path: path_to_string(p),
+            faults: self.faults.clone(),
         }
     }
 
     /// Open a file. The caller can use the MemFile either inside a MemFileReader or as
     /// RandomAccess.
     fn open(&self, p: &Path, create: bool) -> Result<MemFile> {
+        self.faults.check(&path_to_string(p), FaultOp::Open)?;
         let mut fs = self.store.lock().unwrap();
         match fs.entry(path_to_string(p)) {
             Entry::Occupied(o) => Ok(o.get().f.clone()),
@@ -166,7 +259,7 @@ impl MemFS {
         if truncate {
             f.0.lock().unwrap().clear();
         }
-        Ok(Box::new(MemFileWriter::new(f, append)))
+        Ok(Box::new(self.faulty(p, MemFileWriter::new(f, append))))
     }
     fn exists_(&self, p: &Path) -> Result<bool> {
         let fs = self.store.lock()?;
@@ -188,6 +281,7 @@ impl MemFS {
         Ok(children)
     }
     fn size_of_(&self, p: &Path) -> Result<usize> {
+        self.faults.check(&path_to_string(p), FaultOp::SizeOf)?;
         let mut fs = self.store.lock()?;
         match fs.entry(path_to_string(p)) {
             Entry::Occupied(o) => Ok(o.get().f.0.lock()?.len()),
@@ -224,6 +318,24 @@ impl MemFS {
             ),
         }
     }
+    fn rename_noreplace_(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut fs = self.store.lock()?;
+        if !fs.contains_key(&path_to_string(from)) {
+            return err(
+                StatusCode::NotFound,
+                &format!("rename_noreplace: file not found: {}", path_to_str(from)),
+            );
+        }
+        if fs.contains_key(&path_to_string(to)) {
+            return err(
+                StatusCode::AlreadyExists,
+                &format!("rename_noreplace: destination exists: {}", path_to_str(to)),
+            );
+        }
+        let v = fs.remove(&path_to_string(from)).unwrap();
+        fs.insert(path_to_string(to), v);
+        Ok(())
+    }
     fn lock_(&self, p: &Path) -> Result<FileLock> {
         let mut fs = self.store.lock()?;
         match fs.entry(path_to_string(p)) {
@@ -277,11 +389,46 @@ impl MemFS {
 
 /// MemEnv is an in-memory environment that can be used for testing and ephemeral databases. The
 /// performance will be better than what a disk environment delivers.
-pub struct MemEnv(MemFS);
+///
+/// Clones of a MemEnv share the same files, so a test can keep a handle to inject faults after
+/// passing the environment to a database.
+#[derive(Clone)]
+pub struct MemEnv(MemFS, Arc<AtomicU64>);
 
 impl MemEnv {
     pub fn new() -> MemEnv {
-        MemEnv(MemFS::new())
+        MemEnv(MemFS::new(), Arc::new(AtomicU64::new(0)))
+    }
+
+    /// set_micros makes `micros()` return `now` instead of the system time, allowing tests to
+    /// control the clock. Passing 0 switches back to the system time.
+    pub fn set_micros(&self, now: u64) {
+        self.1.store(now, Ordering::Relaxed)
+    }
+
+    /// set_fault makes all `op` operations on files whose path contains `path_substr` fail with
+    /// `code`, until `clear_faults()` is called.
+    pub fn set_fault(&self, path_substr: &str, op: FaultOp, code: StatusCode) {
+        self.add_fault(path_substr, op, code, false)
+    }
+
+    /// set_fault_once is like set_fault, but only the next matching operation fails.
+    pub fn set_fault_once(&self, path_substr: &str, op: FaultOp, code: StatusCode) {
+        self.add_fault(path_substr, op, code, true)
+    }
+
+    /// clear_faults removes all configured faults.
+    pub fn clear_faults(&self) {
+        self.0.faults.0.lock().unwrap().clear();
+    }
+
+    fn add_fault(&self, path_substr: &str, op: FaultOp, code: StatusCode, once: bool) {
+        self.0.faults.0.lock().unwrap().push(Fault {
+            path_substr: path_substr.to_string(),
+            op,
+            code,
+            once,
+        });
     }
 }
 
@@ -294,12 +441,12 @@ impl Default for MemEnv {
 impl Env for MemEnv {
     fn open_sequential_file(&self, p: &Path) -> Result<Box<dyn Read>> {
         let f = self.0.open(p, false)?;
-        Ok(Box::new(MemFileReader::new(f, 0)))
+        Ok(Box::new(self.0.faulty(p, MemFileReader::new(f, 0))))
     }
     fn open_random_access_file(&self, p: &Path) -> Result<Box<dyn RandomAccess>> {
         self.0
             .open(p, false)
-            .map(|m| Box::new(m) as Box<dyn RandomAccess>)
+            .map(|m| Box::new(self.0.faulty(p, m)) as Box<dyn RandomAccess>)
     }
     fn open_writable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
         self.0.open_w(p, true, true)
@@ -307,7 +454,6 @@ impl Env for MemEnv {
     fn open_appendable_file(&self, p: &Path) -> Result<Box<dyn Write>> {
         self.0.open_w(p, true, false)
     }
-
     fn exists(&self, p: &Path) -> Result<bool> {
         self.0.exists_(p)
     }
@@ -317,6 +463,11 @@ impl Env for MemEnv {
     fn size_of(&self, p: &Path) -> Result<usize> {
         self.0.size_of_(p)
     }
+    fn read_to_vec(&self, p: &Path) -> Result<Vec<u8>> {
+        let f = self.0.open(p, false)?;
+        let buf = f.0.lock()?.clone();
+        Ok(buf)
+    }
 
     fn delete(&self, p: &Path) -> Result<()> {
         self.0.delete_(p)
@@ -338,6 +489,9 @@ impl Env for MemEnv {
     fn rename(&self, old: &Path, new: &Path) -> Result<()> {
         self.0.rename_(old, new)
     }
+    fn rename_noreplace(&self, old: &Path, new: &Path) -> Result<()> {
+        self.0.rename_noreplace_(old, new)
+    }
 
     fn lock(&self, p: &Path) -> Result<FileLock> {
         self.0.lock_(p)
@@ -347,7 +501,10 @@ impl Env for MemEnv {
     }
 
     fn micros(&self) -> u64 {
-        micros()
+        match self.1.load(Ordering::Relaxed) {
+            0 => micros(),
+            now => now,
+        }
     }
     fn sleep_for(&self, micros: u32) {
         sleep_for(micros)
@@ -605,6 +762,55 @@ mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_memenv_lock_guarded() {
+        let env = MemEnv::new();
+        let p = Path::new("/a/lock");
+        {
+            let _guard = env.lock_guarded(p).unwrap();
+            assert!(env.lock(p).is_err());
+            assert!(env.lock_guarded(p).is_err());
+        }
+        // The lock was released when the guard went out of scope.
+        let lock = env.lock(p).unwrap();
+        env.unlock(lock).unwrap();
+
+        let guard = env.lock_guarded(p).unwrap();
+        assert!(guard.unlock().is_ok());
+        assert!(env.lock_guarded(p).is_ok());
+    }
+
+    #[test]
+    fn test_memenv_children_with_ext() {
+        let me = MemEnv::new();
+        let dir = Path::new("db");
+        for name in [
+            "000001.log",
+            "000002.ldb",
+            "000003.ldb",
+            "CURRENT",
+            "000004.ldb.bak",
+            "ldb",
+        ] {
+            me.open_writable_file(&dir.join(name)).unwrap();
+        }
+        me.open_writable_file(Path::new("other/000005.ldb"))
+            .unwrap();
+
+        let mut tables = me.children_with_ext(dir, "ldb").unwrap();
+        tables.sort();
+        assert_eq!(
+            vec![PathBuf::from("000002.ldb"), PathBuf::from("000003.ldb")],
+            tables
+        );
+        assert_eq!(2, me.children_with_ext(dir, ".ldb").unwrap().len());
+        assert_eq!(
+            vec![PathBuf::from("000001.log")],
+            me.children_with_ext(dir, "log").unwrap()
+        );
+        assert!(me.children_with_ext(dir, "sst").unwrap().is_empty());
+    }
+
     #[cfg(unix)]
     #[test]
     fn test_memenv_all() {
@@ -639,6 +845,120 @@ mod tests {
         me.sleep_for(10);
     }
 
+    #[test]
+    fn test_memenv_read_to_vec() {
+        let me = MemEnv::new();
+        let p = Path::new("/a/file");
+        assert_eq!(StatusCode::NotFound, me.read_to_vec(p).err().unwrap().code);
+
+        me.open_writable_file(p)
+            .unwrap()
+            .write_all(b"hello world")
+            .unwrap();
+        assert_eq!(b"hello world".to_vec(), me.read_to_vec(p).unwrap());
+
+        // The returned buffer is a copy, not a view of the file.
+        let mut buf = me.read_to_vec(p).unwrap();
+        buf.clear();
+        me.open_appendable_file(p).unwrap().write_all(b"!").unwrap();
+        assert_eq!(b"hello world!".to_vec(), me.read_to_vec(p).unwrap());
+    }
+
+    #[test]
+    fn test_memenv_rename_noreplace() {
+        let me = MemEnv::new();
+        let (src, dst, other) = (
+            Path::new("/a/src"),
+            Path::new("/a/dst"),
+            Path::new("/a/other"),
+        );
+        me.open_writable_file(src)
+            .unwrap()
+            .write_all(b"src")
+            .unwrap();
+        me.open_writable_file(dst)
+            .unwrap()
+            .write_all(b"dst")
+            .unwrap();
+
+        // rename_noreplace leaves an existing destination alone.
+        assert_eq!(
+            StatusCode::AlreadyExists,
+            me.rename_noreplace(src, dst).unwrap_err().code
+        );
+        assert_eq!(b"src".to_vec(), me.read_to_vec(src).unwrap());
+        assert_eq!(b"dst".to_vec(), me.read_to_vec(dst).unwrap());
+
+        me.rename_noreplace(src, other).unwrap();
+        assert!(!me.exists(src).unwrap());
+        assert_eq!(b"src".to_vec(), me.read_to_vec(other).unwrap());
+        assert_eq!(
+            StatusCode::NotFound,
+            me.rename_noreplace(src, Path::new("/a/new"))
+                .unwrap_err()
+                .code
+        );
+
+        // rename replaces the destination.
+        me.rename(other, dst).unwrap();
+        assert!(!me.exists(other).unwrap());
+        assert_eq!(b"src".to_vec(), me.read_to_vec(dst).unwrap());
+        assert_eq!(
+            StatusCode::NotFound,
+            me.rename(other, dst).unwrap_err().code
+        );
+    }
+
+    #[test]
+    fn test_memenv_faults() {
+        let me = MemEnv::new();
+        let (p, other) = (Path::new("/a/000005.ldb"), Path::new("/a/000006.log"));
+
+        me.set_fault_once(".ldb", FaultOp::Open, StatusCode::IOError);
+        assert_eq!(
+            StatusCode::IOError,
+            me.open_writable_file(p).err().unwrap().code
+        );
+        assert!(!me.exists(p).unwrap());
+        me.open_writable_file(other)
+            .unwrap()
+            .write_all(b"abc")
+            .unwrap();
+        // The fault only fired once.
+        let mut w = me.open_writable_file(p).unwrap();
+        w.write_all(b"abc").unwrap();
+
+        me.set_fault(".ldb", FaultOp::Write, StatusCode::IOError);
+        assert!(w.write_all(b"def").is_err());
+        assert!(w.write_all(b"def").is_err());
+        me.open_appendable_file(other)
+            .unwrap()
+            .write_all(b"def")
+            .unwrap();
+
+        me.set_fault(".ldb", FaultOp::Read, StatusCode::Corruption);
+        me.set_fault("000005", FaultOp::SizeOf, StatusCode::PermissionDenied);
+        let mut buf = [0; 3];
+        assert_eq!(
+            StatusCode::Corruption,
+            me.open_random_access_file(p)
+                .unwrap()
+                .read_at(0, &mut buf)
+                .unwrap_err()
+                .code
+        );
+        assert!(me.open_sequential_file(p).unwrap().read(&mut buf).is_err());
+        assert_eq!(
+            StatusCode::PermissionDenied,
+            me.size_of(p).unwrap_err().code
+        );
+        assert_eq!(6, me.size_of(other).unwrap());
+
+        me.clear_faults();
+        w.write_all(b"def").unwrap();
+        assert_eq!(b"abcdef".to_vec(), me.read_to_vec(p).unwrap());
+    }
+
     #[cfg(windows)]
     #[test]
     fn test_memenv_all() {
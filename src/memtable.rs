@@ -1,9 +1,16 @@
-use std::rc::Rc;
+use std::{
+    rc::Rc,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use crate::{
     cmp::{Cmp, MemtableKeyCmp},
     key_types::{
-        build_memtable_key, parse_internal_key, parse_memtable_key, LookupKey, UserKey, ValueType,
+        build_memtable_key, max_covering_seq, parse_internal_key, parse_memtable_key, parse_tag,
+        LookupKey, RangeTombstone, UserKey, ValueType,
     },
     skipmap::{SkipMap, SkipMapIter},
     types::{current_key_val, LdbIterator, SequenceNumber},
@@ -13,56 +20,134 @@ use crate::{
 /// MemTable uses MemtablKeys internally, that is, it stores key and value in the [Skipmap] key.
 pub struct MemTable {
     map: SkipMap,
+    // The user comparator, and the range tombstones written to this memtable. They are kept
+    // separately from the map, and are not returned by iterators.
+    user_cmp: Rc<Box<dyn Cmp>>,
+    range_tombstones: Vec<RangeTombstone>,
+    range_tombstones_mem: usize,
+    // A shared counter of memory used by memtables (see Options::memory_budget), and how much
+    // this memtable has added to it.
+    budget: Option<Arc<AtomicUsize>>,
+    charged: usize,
 }
 
 impl MemTable {
     /// Returns a new MemTable.
     /// This wraps opt.cmp inside a MemtableKey-specific comparator.
     pub fn new(cmp: Rc<Box<dyn Cmp>>) -> MemTable {
-        MemTable::new_raw(Rc::new(Box::new(MemtableKeyCmp(cmp))))
+        MemTable::new_raw(Rc::new(Box::new(MemtableKeyCmp(cmp.clone()))), cmp)
+    }
+
+    /// Like new(), but the memory used by the memtable is also added to `budget` (starting with the
+    /// first entry), and subtracted again once the memtable is dropped.
+    pub fn new_with_budget(cmp: Rc<Box<dyn Cmp>>, budget: Option<Arc<AtomicUsize>>) -> MemTable {
+        let mut mt = MemTable::new(cmp);
+        mt.budget = budget;
+        mt
     }
 
     /// Doesn't wrap the comparator in a MemtableKeyCmp.
-    fn new_raw(cmp: Rc<Box<dyn Cmp>>) -> MemTable {
+    fn new_raw(cmp: Rc<Box<dyn Cmp>>, user_cmp: Rc<Box<dyn Cmp>>) -> MemTable {
         MemTable {
             map: SkipMap::new(cmp),
+            user_cmp,
+            range_tombstones: vec![],
+            range_tombstones_mem: 0,
+            budget: None,
+            charged: 0,
         }
     }
 
+    /// charge adds the memory used since the last call to the budget, if there is one.
+    fn charge(&mut self) {
+        if let Some(ref budget) = self.budget {
+            let usage = self.approx_mem_usage();
+            budget.fetch_add(usage - self.charged, Ordering::Relaxed);
+            self.charged = usage;
+        }
+    }
+
+    /// Returns the number of entries, including range tombstones.
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.map.len() + self.range_tombstones.len()
     }
 
     pub fn approx_mem_usage(&self) -> usize {
-        self.map.approx_memory()
+        self.map.approx_memory() + self.range_tombstones_mem
     }
 
     pub fn add(&mut self, seq: SequenceNumber, t: ValueType, key: UserKey, value: &[u8]) {
         self.map
-            .insert(build_memtable_key(key, value, t, seq), Vec::new())
+            .insert(build_memtable_key(key, value, t, seq), Vec::new());
+        self.charge();
+    }
+
+    /// add_range_tombstone records the deletion of all entries in [begin, end) older than `seq`.
+    pub fn add_range_tombstone(&mut self, seq: SequenceNumber, begin: UserKey, end: UserKey) {
+        self.range_tombstones_mem +=
+            std::mem::size_of::<RangeTombstone>() + begin.len() + end.len();
+        self.range_tombstones.push(RangeTombstone {
+            begin: begin.to_vec(),
+            end: end.to_vec(),
+            seq,
+        });
+        self.charge();
+    }
+
+    /// Returns the range tombstones written to this memtable.
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
     }
 
     /// get returns the value for the given entry and whether the entry is marked as deleted. This
-    /// is to distinguish between not-found and found-delete.
+    /// is to distinguish between not-found and found-delete. An entry covered by a range tombstone
+    /// in this memtable counts as deleted, as does a key without entry covered by a tombstone
+    /// (all older entries are covered as well).
+    ///
+    /// A merge operand is returned like a value; use get_into() to tell them apart.
     pub fn get(&self, key: &LookupKey) -> (Option<Vec<u8>>, bool) {
+        let mut buf = vec![];
+        match self.get_into(key, &mut buf) {
+            Some(ValueType::TypeDeletion) => (None, true),
+            Some(_) => (Some(buf), false),
+            None => (None, false),
+        }
+    }
+
+    /// get_into is like get(), but appends a found value to `buf` instead of allocating a new
+    /// vector. It returns the type of the newest entry -- `TypeValue`, `TypeMerge` (whose operand
+    /// is appended to `buf`) or `TypeDeletion` --, or None if there is no entry for the key.
+    pub fn get_into(&self, key: &LookupKey, buf: &mut Vec<u8>) -> Option<ValueType> {
         let mut iter = self.map.iter();
         let k = key.memtable_key();
         iter.seek(k);
 
+        let (_, seq, ukey) = parse_internal_key(key.internal_key());
+        let tombstone_seq = max_covering_seq(
+            self.range_tombstones.iter(),
+            self.user_cmp.as_ref().as_ref(),
+            ukey,
+            seq,
+        );
+
         if let Some((foundkey, _)) = current_key_val(&iter) {
             let (fkeylen, fkeyoff, tag, vallen, valoff) = parse_memtable_key(&foundkey);
 
             // Compare user key -- if equal, proceed
             // We only care about use key equality here
             if key.user_key() == &foundkey[fkeyoff..fkeyoff + fkeylen] {
-                if tag & 0xff == ValueType::TypeValue as u64 {
-                    return (Some(foundkey[valoff..valoff + vallen].to_vec()), false);
-                } else {
-                    return (None, true);
+                if tombstone_seq.is_some_and(|s| tag >> 8 < s) {
+                    return Some(ValueType::TypeDeletion);
+                }
+                let (typ, _) = parse_tag(tag);
+                if typ == ValueType::TypeDeletion {
+                    return Some(typ);
                 }
+                buf.extend_from_slice(&foundkey[valoff..valoff + vallen]);
+                return Some(typ);
             }
         }
-        (None, false)
+        tombstone_seq.map(|_| ValueType::TypeDeletion)
     }
 
     pub fn iter(&self) -> MemtableIterator {
@@ -72,11 +157,19 @@ impl MemTable {
     }
 }
 
+impl Drop for MemTable {
+    fn drop(&mut self) {
+        if let Some(ref budget) = self.budget {
+            budget.fetch_sub(self.charged, Ordering::Relaxed);
+        }
+    }
+}
+
 /// MemtableIterator is an iterator over a MemTable. It is mostly concerned with converting to and
 /// from the MemtableKey format used in the inner map; all-taking or -returning methods deal with
 /// InternalKeys.
 ///
-/// This iterator skips deleted entries.
+/// Like the iterators over tables, this iterator returns deletion entries too.
 pub struct MemtableIterator {
     skipmapiter: SkipMapIter,
 }
@@ -94,24 +187,12 @@ impl LdbIterator for MemtableIterator {
     }
 
     fn prev(&mut self) -> bool {
-        // Make sure this is actually needed (skipping deleted values?).
-        let (mut key, mut val) = (vec![], vec![]);
-        loop {
-            if !self.skipmapiter.prev() {
-                return false;
-            }
-            if self.skipmapiter.current(&mut key, &mut val) {
-                let (_, _, tag, _, _) = parse_memtable_key(&key);
+        // Deletion entries must not be skipped: DBIterator relies on them to hide older values.
+        self.skipmapiter.prev()
+    }
 
-                if tag & 0xff == ValueType::TypeValue as u64 {
-                    return true;
-                } else {
-                    continue;
-                }
-            } else {
-                return false;
-            }
-        }
+    fn seek_to_last(&mut self) {
+        self.skipmapiter.seek_to_last()
     }
 
     fn valid(&self) -> bool {
@@ -163,7 +244,7 @@ mod tests {
     use crate::{
         key_types::{parse_tag, truncate_to_userkey},
         options,
-        test_util::{test_iterator_properties, LdbIteratorIter},
+        test_util::{test_iterator_properties, test_iterator_seek_past_end, LdbIteratorIter},
     };
 
     use super::*;
@@ -230,10 +311,10 @@ mod tests {
             panic!("found");
         }
 
-        if let Some(v) = mt.get(&LookupKey::new("abf".as_bytes(), 110)).0 {
-            println!("{:?}", v);
-            panic!("found");
-        }
+        assert_eq!(
+            (None, false),
+            mt.get(&LookupKey::new("abf".as_bytes(), 110))
+        );
 
         // Bigger sequence number falls back to next smaller
         if let Some(v) = mt.get(&LookupKey::new("abc".as_bytes(), 116)).0 {
@@ -263,6 +344,54 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_memtable_empty_value() {
+        let mut mt = MemTable::new(options::for_test().cmp);
+        mt.add(1, ValueType::TypeValue, b"abc", b"");
+        mt.add(2, ValueType::TypeValue, b"abd", b"");
+        mt.add(3, ValueType::TypeDeletion, b"abd", b"");
+
+        // An empty value is a value, not a deletion.
+        assert_eq!((Some(vec![]), false), mt.get(&LookupKey::new(b"abc", 5)));
+        assert_eq!((Some(vec![]), false), mt.get(&LookupKey::new(b"abd", 2)));
+        assert_eq!((None, true), mt.get(&LookupKey::new(b"abd", 5)));
+    }
+
+    #[test]
+    fn test_memtable_range_tombstone() {
+        let mut mt = get_memtable();
+        mt.add_range_tombstone(121, b"abc", b"abf");
+        assert_eq!(6, mt.len());
+
+        // abc@120 is covered, abd@121 is not (same sequence number).
+        assert_eq!((None, true), mt.get(&LookupKey::new("abc".as_bytes(), 130)));
+        assert_eq!(
+            (Some(b"124".to_vec()), false),
+            mt.get(&LookupKey::new("abd".as_bytes(), 130))
+        );
+        // Keys without entries in the range count as deleted; the end is exclusive.
+        assert_eq!(
+            (None, true),
+            mt.get(&LookupKey::new("abcc".as_bytes(), 130))
+        );
+        assert_eq!(
+            (Some(b"126".to_vec()), false),
+            mt.get(&LookupKey::new("abf".as_bytes(), 130))
+        );
+        // The tombstone is invisible to older lookups.
+        assert_eq!(
+            (Some(b"123".to_vec()), false),
+            mt.get(&LookupKey::new("abc".as_bytes(), 120))
+        );
+        assert_eq!(
+            (None, false),
+            mt.get(&LookupKey::new("abcc".as_bytes(), 120))
+        );
+
+        // Iterators don't return tombstones.
+        assert_eq!(5, LdbIteratorIter::wrap(&mut mt.iter()).count());
+    }
+
     #[test]
     fn test_memtable_iterator_init() {
         let mt = get_memtable();
@@ -397,5 +526,6 @@ mod tests {
         }
 
         test_iterator_properties(mt.iter());
+        test_iterator_seek_past_end(mt.iter(), LookupKey::new(b"abg", 1000).internal_key());
     }
 }
@@ -0,0 +1,105 @@
+//! Merge operators combine the operands written by `DB::merge()` with the value they were applied
+//! to.
+
+use std::rc::Rc;
+
+use crate::error::{err, Result, StatusCode};
+
+/// A MergeOperator defines the meaning of `DB::merge()`: instead of replacing a value, a merge
+/// stores an operand, and reads combine all operands written since the last put or deletion of a
+/// key with the value written by that put.
+///
+/// The operator must be the same every time a database is opened, as operands may be merged
+/// at any time, e.g. during a compaction.
+pub trait MergeOperator {
+    /// Returns a string identifying the operator.
+    fn name(&self) -> &'static str;
+    /// Returns the result of applying `operands` (oldest first) to `existing`, the value of `key`
+    /// before the first operand was written. `existing` is None if the key didn't exist or was
+    /// deleted. Returns None if the operands can't be merged, which is reported as corruption.
+    fn full_merge(
+        &self,
+        key: &[u8],
+        existing: Option<&[u8]>,
+        operands: &[Vec<u8>],
+    ) -> Option<Vec<u8>>;
+}
+
+pub type BoxedMergeOperator = Rc<Box<dyn MergeOperator>>;
+
+/// full_merge applies operands (oldest first) to existing using op, failing if there is no
+/// operator or the operator fails.
+pub fn full_merge(
+    op: Option<&BoxedMergeOperator>,
+    key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &[Vec<u8>],
+) -> Result<Vec<u8>> {
+    let Some(op) = op else {
+        return err(
+            StatusCode::NotSupported,
+            "found merge operands, but no merge operator is set",
+        );
+    };
+    match op.full_merge(key, existing, operands) {
+        Some(v) => Ok(v),
+        None => err(
+            StatusCode::Corruption,
+            &format!("merge operator {} failed for key {:?}", op.name(), key),
+        ),
+    }
+}
+
+#[cfg(test)]
+pub mod testutil {
+    use super::*;
+
+    /// AppendOperator concatenates the operands to the existing value.
+    pub struct AppendOperator;
+
+    impl MergeOperator for AppendOperator {
+        fn name(&self) -> &'static str {
+            "test.Append"
+        }
+        fn full_merge(
+            &self,
+            _: &[u8],
+            existing: Option<&[u8]>,
+            operands: &[Vec<u8>],
+        ) -> Option<Vec<u8>> {
+            let mut v = existing.unwrap_or_default().to_vec();
+            for o in operands {
+                v.extend_from_slice(o);
+            }
+            Some(v)
+        }
+    }
+
+    pub fn append_operator() -> BoxedMergeOperator {
+        Rc::new(Box::new(AppendOperator))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testutil::*;
+    use super::*;
+
+    #[test]
+    fn test_merge_op_full_merge() {
+        let op = append_operator();
+        let operands = vec![b"b".to_vec(), b"c".to_vec()];
+        assert_eq!(
+            b"abc".to_vec(),
+            full_merge(Some(&op), b"k", Some(b"a"), &operands).unwrap()
+        );
+        assert_eq!(
+            b"bc".to_vec(),
+            full_merge(Some(&op), b"k", None, &operands).unwrap()
+        );
+        assert_eq!(
+            StatusCode::NotSupported,
+            full_merge(None, b"k", None, &operands).unwrap_err().code
+        );
+    }
+}
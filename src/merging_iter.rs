@@ -6,8 +6,6 @@ use crate::{
 };
 
 /// Warning: This module is kinda messy. The original implementation is not that much better thought :-);
-///
-/// Issue: 1) prev() may not work correctly at the beginning of merging iterator;
 #[derive(PartialEq)]
 enum SL {
     Smallest,
@@ -19,6 +17,8 @@ pub struct MergingIter {
     current: Option<usize>,
     direction: Direction,
     cmp: Rc<Box<dyn Cmp>>,
+    // Set after a seek past the last entry of all children.
+    past_end: bool,
 }
 
 impl MergingIter {
@@ -29,6 +29,7 @@ impl MergingIter {
             current: None,
             direction: Direction::Forward,
             cmp,
+            past_end: false,
         }
     }
 
@@ -81,8 +82,8 @@ impl MergingIter {
                                 if self.iters[i].valid() {
                                     self.iters[i].prev();
                                 } else {
-                                    // seek to last.
-                                    while self.iters[i].advance() {}
+                                    // All entries are smaller than key.
+                                    self.iters[i].seek_to_last();
                                 }
                             }
                         }
@@ -112,27 +113,38 @@ impl MergingIter {
             Ordering::Greater
         };
 
-        let mut next_ix = 0;
+        let mut next_ix: Option<usize> = None;
         let (mut current, mut smallest, mut valscratch) = (vec![], vec![], vec![]);
 
-        for i in 1..self.iters.len() {
+        for i in 0..self.iters.len() {
             if self.iters[i].current(&mut current, &mut valscratch) {
-                if self.iters[next_ix].current(&mut smallest, &mut valscratch) {
-                    if self.cmp.cmp(&current, &smallest) == ord {
-                        next_ix = i;
+                match next_ix {
+                    Some(ix) => {
+                        if self.iters[ix].current(&mut smallest, &mut valscratch)
+                            && self.cmp.cmp(&current, &smallest) == ord
+                        {
+                            next_ix = Some(i);
+                        }
                     }
-                } else {
-                    next_ix = i;
+                    None => next_ix = Some(i),
                 }
             }
         }
 
-        self.current = Some(next_ix);
+        if next_ix.is_none() {
+            // All children are exhausted, i.e. we moved past the first or last entry. Like after
+            // reset(), the next advance() starts again at the first entry.
+            self.direction = Direction::Forward;
+        }
+        self.current = next_ix;
     }
 }
 
 impl LdbIterator for MergingIter {
     fn advance(&mut self) -> bool {
+        if self.past_end {
+            return false;
+        }
         if let Some(current) = self.current {
             self.update_direction(Direction::Forward);
             if !self.iters[current].advance() {
@@ -160,13 +172,26 @@ impl LdbIterator for MergingIter {
         for i in 0..self.iters.len() {
             self.iters[i].seek(key);
         }
+        self.direction = Direction::Forward;
         self.find_smallest();
+        // Every child is past its end now.
+        self.past_end = self.current.is_none() && !self.iters.is_empty();
     }
     fn reset(&mut self) {
         for i in 0..self.iters.len() {
             self.iters[i].reset();
         }
         self.current = None;
+        self.direction = Direction::Forward;
+        self.past_end = false;
+    }
+    fn seek_to_last(&mut self) {
+        for i in 0..self.iters.len() {
+            self.iters[i].seek_to_last();
+        }
+        self.direction = Direction::Reverse;
+        self.past_end = false;
+        self.find_largest();
     }
     fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
         if let Some(ix) = self.current {
@@ -176,6 +201,16 @@ impl LdbIterator for MergingIter {
         }
     }
     fn prev(&mut self) -> bool {
+        if self.past_end {
+            // All children were seeked past their end, so prev() moves each to its last entry.
+            self.past_end = false;
+            for i in 0..self.iters.len() {
+                self.iters[i].prev();
+            }
+            self.direction = Direction::Reverse;
+            self.find_largest();
+            return self.valid();
+        }
         if let Some(current) = self.current {
             if self.iters[current].valid() {
                 self.update_direction(Direction::Reverse);
@@ -196,8 +231,8 @@ mod tests {
     use crate::cmp::DefaultCmp;
     use crate::skipmap;
     use crate::test_util;
-    use crate::test_util::test_iterator_properties;
     use crate::test_util::LdbIteratorIter;
+    use crate::test_util::{test_iterator_properties, test_iterator_seek_past_end};
     use crate::types;
 
     use super::*;
@@ -245,12 +280,68 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_merging_count_collect() {
+        let skm = tests::make_skipmap();
+        let mut miter = MergingIter::new(
+            Rc::new(Box::new(DefaultCmp)),
+            vec![Box::new(skm.iter()), Box::new(skm.iter())],
+        );
+        assert_eq!(52, miter.count_remaining());
+        assert!(!miter.valid());
+
+        let mut miter = MergingIter::new(
+            Rc::new(Box::new(DefaultCmp)),
+            vec![Box::new(skm.iter()), Box::new(skm.iter())],
+        );
+        let entries = miter.collect_kv();
+        assert_eq!(52, entries.len());
+        assert_eq!(entries[0], entries[1]);
+        assert_eq!(b"abz", entries[51].0.as_slice());
+        assert!(!miter.valid());
+    }
+
     #[test]
     fn test_merging_zero() {
         let mut miter = MergingIter::new(Rc::new(Box::new(DefaultCmp)), vec![]);
         assert_eq!(0, LdbIteratorIter::wrap(&mut miter).count());
     }
 
+    #[test]
+    fn test_merging_empty_child() {
+        let val = "def".as_bytes();
+        let empty = TestLdbIter::new(vec![]);
+        let iter = TestLdbIter::new(vec![(b("aba"), val), (b("abb"), val), (b("abc"), val)]);
+        let mut miter = MergingIter::new(
+            Rc::new(Box::new(DefaultCmp)),
+            vec![Box::new(empty), Box::new(iter)],
+        );
+        let want = vec![
+            (b"aba".to_vec(), val.to_vec()),
+            (b"abb".to_vec(), val.to_vec()),
+            (b"abc".to_vec(), val.to_vec()),
+        ];
+        assert_eq!(want, miter.collect_kv());
+        assert!(!miter.valid());
+
+        // After a reset, the next advance() re-initializes over the empty child.
+        miter.reset();
+        assert_eq!(want, miter.collect_kv());
+
+        miter.reset();
+        miter.seek(b"abb");
+        assert_eq!(
+            Some((b"abb".to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+        assert!(miter.advance());
+        assert_eq!(
+            Some((b"abc".to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+        assert!(!miter.advance());
+    }
+
     #[test]
     fn test_merging_behavior() {
         let val = "def".as_bytes();
@@ -261,6 +352,14 @@ mod tests {
             vec![Box::new(iter), Box::new(iter2)],
         );
         test_iterator_properties(miter);
+
+        let iter = TestLdbIter::new(vec![(b("aba"), val), (b("abc"), val)]);
+        let iter2 = TestLdbIter::new(vec![(b("abb"), val), (b("abd"), val)]);
+        let miter = MergingIter::new(
+            Rc::new(Box::new(DefaultCmp)),
+            vec![Box::new(iter), Box::new(iter2)],
+        );
+        test_iterator_seek_past_end(miter, b"abe");
     }
 
     #[test]
@@ -305,6 +404,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_merging_prev_before_first() {
+        let val = "def".as_bytes();
+        let iter = TestLdbIter::new(vec![(b("abb"), val), (b("abd"), val)]);
+        let iter2 = TestLdbIter::new(vec![(b("aba"), val), (b("abc"), val), (b("abe"), val)]);
+
+        let mut miter = MergingIter::new(
+            Rc::new(Box::new(DefaultCmp)),
+            vec![Box::new(iter), Box::new(iter2)],
+        );
+
+        // -> aba -> abb
+        assert!(miter.advance());
+        assert!(miter.advance());
+        assert_eq!(
+            Some((b("abb").to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+        // aba <-
+        assert!(miter.prev());
+        assert_eq!(
+            Some((b("aba").to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+        // Moving before the first entry invalidates the iterator.
+        assert!(!miter.prev());
+        assert!(!miter.valid());
+        assert!(!miter.prev());
+        assert!(!miter.valid());
+        assert_eq!(None, current_key_val(&miter));
+
+        // Advancing again starts over at the first entry.
+        assert!(miter.advance());
+        assert_eq!(
+            Some((b("aba").to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+        assert!(miter.advance());
+        assert_eq!(
+            Some((b("abb").to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+        assert!(miter.advance());
+        assert_eq!(
+            Some((b("abc").to_vec(), val.to_vec())),
+            current_key_val(&miter)
+        );
+    }
+
     fn b(s: &'static str) -> &'static [u8] {
         s.as_bytes()
     }
@@ -365,4 +513,31 @@ mod tests {
             Some((b("aba").to_vec(), val.to_vec()))
         );
     }
+
+    #[test]
+    fn test_merging_seek_to_last() {
+        let val = "def".as_bytes();
+        let it1 = TestLdbIter::new(vec![(b("aba"), val), (b("abc"), val), (b("abe"), val)]);
+        let it2 = TestLdbIter::new(vec![(b("abb"), val), (b("abd"), val)]);
+        let it3 = TestLdbIter::new(vec![]);
+
+        let mut iter = MergingIter::new(
+            Rc::new(Box::new(DefaultCmp)),
+            vec![Box::new(it1), Box::new(it2), Box::new(it3)],
+        );
+        iter.seek_to_last();
+        assert_eq!(b("abe").to_vec(), current_key_val(&iter).unwrap().0);
+        for k in ["abd", "abc", "abb", "aba"] {
+            assert!(iter.prev());
+            assert_eq!(b(k).to_vec(), current_key_val(&iter).unwrap().0);
+        }
+        assert!(!iter.prev());
+
+        // Switching direction after seeking to the end.
+        iter.seek_to_last();
+        assert!(iter.prev());
+        assert!(iter.advance());
+        assert_eq!(b("abe").to_vec(), current_key_val(&iter).unwrap().0);
+        assert!(!iter.advance());
+    }
 }
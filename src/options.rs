@@ -1,4 +1,10 @@
-use std::{io, rc::Rc};
+use crc::crc32;
+use std::{
+    io,
+    rc::Rc,
+    sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
+};
 
 use crate::{
     block::Block,
@@ -7,10 +13,11 @@ use crate::{
     compressor::{self, Compressor, CompressorId},
     disk_env::PosixDiskEnv,
     env::Env,
-    error::StatusCode,
-    filter::{self, BoxedFilterPolicy},
+    error::{err, StatusCode},
+    filter::{self, BoxedFilterPolicy, BoxedPrefixExtractor},
     infolog::{self, Logger},
     mem_env::MemEnv,
+    merge_op::BoxedMergeOperator,
     types::{share, Shared},
     Result, Status,
 };
@@ -23,16 +30,41 @@ const BLOCK_CACHE_CAPACITY: usize = 8 * MB;
 const WRITE_BUFFER_SIZE: usize = 4 * MB;
 const DEFAULT_BITS_PER_KEY: u32 = 10; // NOTE: This may need to be optimized.
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub enum CompressionType {
     CompressionNone = 0,
     CompressionSnappy = 1,
+    CompressionZstd = 2,
+}
+
+/// ChecksumType selects the checksum algorithm protecting log records (including the manifest)
+/// and table blocks. It can't be changed for an existing database: data written with another
+/// algorithm fails verification with a `Corruption` error.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ChecksumType {
+    /// CRC32C (Castagnoli), as used by LevelDB.
+    #[default]
+    Crc32c,
+    /// CRC32 as defined by IEEE 802.3.
+    Crc32,
+}
+
+impl ChecksumType {
+    /// digest returns a new digest computing this type of checksum.
+    pub(crate) fn digest(self) -> crc32::Digest {
+        match self {
+            ChecksumType::Crc32c => crc32::Digest::new(crc32::CASTAGNOLI),
+            ChecksumType::Crc32 => crc32::Digest::new(crc32::IEEE),
+        }
+    }
 }
 
 pub fn int_to_compressiontype(i: u32) -> Option<CompressionType> {
     match i {
         0 => Some(CompressionType::CompressionNone),
         1 => Some(CompressionType::CompressionSnappy),
+        2 => Some(CompressionType::CompressionZstd),
         _ => None,
     }
 }
@@ -43,18 +75,30 @@ pub fn int_to_compressiontype(i: u32) -> Option<CompressionType> {
 /// Note: Compression is not yet implemented.
 #[derive(Clone)]
 pub struct Options {
+    /// The order of user keys, used by the memtable as well as by tables. It can't be changed
+    /// for an existing database.
     pub cmp: Rc<Box<dyn Cmp>>,
     pub env: Rc<Box<dyn Env>>,
     pub log: Option<Shared<Logger>>,
     pub create_if_missing: bool,
     pub error_if_exists: bool,
+    /// Verify the checksums of table data blocks when reading them (index blocks are always
+    /// verified), and treat malformed records in `dump_log()` as errors.
     pub paranoid_checks: bool,
+    /// When opening a database, open all tables, and remove those that fail validation (e.g.
+    /// with a corrupt footer or index) from the database instead of failing later reads. The
+    /// tables' entries are lost; the files are kept, renamed to `<file>.corrupt`.
+    pub skip_corrupt_tables: bool,
     pub write_buffer_size: usize,
     pub max_open_file: usize,
     pub max_file_size: usize,
     pub block_cache: Shared<Cache<Block>>,
     pub block_size: usize,
     pub block_restart_interval: usize,
+    /// Also start a new restart point when a key shares less than a few bytes with the previous
+    /// one. Such keys gain little from prefix compression, while the extra restart point speeds
+    /// up seeks within the block.
+    pub adaptive_restart: bool,
     /// Compressor id in compressor list
     ///
     /// Note: you have to open a database with the same compression type as it was written to, in otder
@@ -65,6 +109,76 @@ pub struct Options {
     pub reuse_logs: bool,
     pub reuse_manifest: bool,
     pub filter_policy: BoxedFilterPolicy,
+    /// If set, filters contain key prefixes as defined by the extractor instead of whole keys.
+    /// This allows `DB::prefix_iter()` to skip tables not containing the prefix, while point
+    /// lookups only benefit from the filter if there are few keys per prefix.
+    pub prefix_extractor: Option<BoxedPrefixExtractor>,
+    /// Required to use `DB::merge()`, and to read databases containing merge operands.
+    pub merge_operator: Option<BoxedMergeOperator>,
+    /// If set, values written by `put()` expire once they are older than this, according to
+    /// `Env::micros()`: reads treat them as deleted, and compactions drop them. The write time is
+    /// stored in front of each value, so a database must always be opened with or always without
+    /// a TTL. Merge operands don't expire.
+    pub ttl: Option<Duration>,
+    /// Minimum time between two syncs of the write-ahead log, measured with
+    /// `Env::now_monotonic()`. The syncs of synchronous writes arriving earlier are coalesced:
+    /// such writes return without waiting, and one sync by the next write after the interval,
+    /// `DB::sync()` or `DB::flush()` makes all of them durable. 0 syncs immediately.
+    pub min_wal_sync_interval_micros: u64,
+    /// Names of the column families used with `DB::put_cf()` and friends. A family is identified
+    /// by its position in this list, so the order must not change between opening a database.
+    pub column_families: Vec<String>,
+    /// Treat level-0 files as non-overlapping, like the files of higher levels. This makes reads
+    /// cheaper, but is only correct if keys are written in strictly increasing order (e.g. an
+    /// append-only log); otherwise reads may return stale values or miss entries.
+    pub assume_disjoint_l0: bool,
+    /// Store the first key of each data block in the index of new tables (table format version
+    /// 2). This allows `get()` to rule out a block without reading it, at the cost of a bigger
+    /// index. Tables written with this option can't be read by older versions.
+    pub index_first_key: bool,
+    /// The maximum length of a key stored in a table, as enforced by the table and block
+    /// builders; this includes tables written directly with a `TableBuilder`. For database
+    /// entries, the length includes the 8-byte tag (sequence number and type), so writes of user
+    /// keys longer than the limit minus 8 are rejected too. Very long keys defeat prefix
+    /// compression and bloat the index; adding a longer key to a table fails with
+    /// `InvalidArgument`. No limit by default.
+    ///
+    /// Unlike `max_key_size`, this is a format limit of tables, not a limit on user keys.
+    pub max_key_length: Option<usize>,
+    /// The maximum size of a user key written by `DB::write()` (and `put()`, `delete()`,
+    /// `merge()`); larger keys are rejected with `InvalidArgument`. No limit by default.
+    ///
+    /// Unlike `max_key_length`, this counts only the user key (without the 8-byte tag) and is
+    /// only checked for writes to a database, not for tables written with a `TableBuilder`. If
+    /// both are set, a user key must be at most `max_key_size` bytes and at most
+    /// `max_key_length - 8` bytes.
+    pub max_key_size: Option<usize>,
+    /// The maximum size of a value or merge operand written by `DB::write()`; larger ones are
+    /// rejected with `InvalidArgument`. No limit by default.
+    pub max_value_size: Option<usize>,
+    /// A counter of the memory used by memtables, which can be shared by several databases.
+    /// When it exceeds `memory_budget_limit`, the memtable of the database being written to is
+    /// flushed, even if it is smaller than `write_buffer_size`.
+    pub memory_budget: Option<Arc<AtomicUsize>>,
+    /// The number of bytes `memory_budget` may reach before memtables are flushed early. While it
+    /// is exceeded, every write to a database with a non-empty memtable flushes it, so the budget
+    /// recovers even if it is shared by many databases. Unlimited by default.
+    pub memory_budget_limit: usize,
+    /// If set, data blocks in new tables are padded so that each starts at a multiple of this
+    /// many bytes, as required for direct I/O. Use the block size of the device (e.g. 4096).
+    pub block_alignment: Option<usize>,
+    /// The checksum algorithm of logs and tables.
+    pub checksum_type: ChecksumType,
+    /// Number of level-0 files at which a compaction of level 0 is started.
+    pub l0_compaction_trigger: usize,
+    /// Number of level-0 files at which each write first does a step of the pending compaction
+    /// work. As compactions run on the writing thread, this takes the place of delaying writes:
+    /// level 0 is compacted if it has reached `l0_compaction_trigger`.
+    pub l0_slowdown_writes_trigger: usize,
+    /// Number of level-0 files at which writes stop until level 0 has been compacted. As
+    /// compactions run on the writing thread, the write that needs a new memtable compacts level
+    /// 0 before continuing.
+    pub l0_stop_writes_trigger: usize,
 }
 
 #[cfg(feature = "fs")]
@@ -82,19 +196,229 @@ impl Default for Options {
             create_if_missing: true,
             error_if_exists: false,
             paranoid_checks: false,
+            skip_corrupt_tables: false,
             write_buffer_size: WRITE_BUFFER_SIZE,
             max_open_file: 1 << 10,
             max_file_size: 2 << 20,
-            // 2000 elements by default
-            block_cache: share(Cache::new(BLOCK_CACHE_CAPACITY / BLOCK_MAX_SIZE)),
+            // 8 MB by default
+            block_cache: share(Cache::with_capacity_bytes(BLOCK_CACHE_CAPACITY)),
             block_size: BLOCK_MAX_SIZE,
             block_restart_interval: 16,
+            adaptive_restart: false,
             reuse_logs: true,
             reuse_manifest: true,
             compressor: 0,
             compressor_list: Rc::new(CompressorList::default()),
             filter_policy: Rc::new(Box::new(filter::BloomPolicy::new(DEFAULT_BITS_PER_KEY))),
+            prefix_extractor: None,
+            merge_operator: None,
+            ttl: None,
+            min_wal_sync_interval_micros: 0,
+            column_families: vec![],
+            assume_disjoint_l0: false,
+            index_first_key: false,
+            max_key_length: None,
+            max_key_size: None,
+            max_value_size: None,
+            memory_budget: None,
+            memory_budget_limit: usize::MAX,
+            block_alignment: None,
+            checksum_type: ChecksumType::default(),
+            l0_compaction_trigger: 4,
+            l0_slowdown_writes_trigger: 8,
+            l0_stop_writes_trigger: 12,
+        }
+    }
+}
+
+impl Options {
+    /// validate checks that the options can be used to open a database, returning an
+    /// `InvalidArgument` error describing the first problem found.
+    pub fn validate(&self) -> Result<()> {
+        if self.block_size == 0 {
+            return err(StatusCode::InvalidArgument, "block_size must be positive");
+        }
+        if self.block_restart_interval == 0 {
+            return err(
+                StatusCode::InvalidArgument,
+                "block_restart_interval must be positive",
+            );
+        }
+        if self.write_buffer_size < self.block_size {
+            return err(
+                StatusCode::InvalidArgument,
+                "write_buffer_size must not be smaller than block_size",
+            );
+        }
+        if self.max_file_size == 0 {
+            return err(
+                StatusCode::InvalidArgument,
+                "max_file_size must be positive",
+            );
+        }
+        // Ten file descriptors are reserved for files other than tables.
+        if self.max_open_file <= 10 {
+            return err(
+                StatusCode::InvalidArgument,
+                "max_open_file must be greater than 10",
+            );
+        }
+        if self.block_alignment == Some(0) {
+            return err(
+                StatusCode::InvalidArgument,
+                "block_alignment must be positive if set",
+            );
+        }
+        if self.l0_compaction_trigger == 0 {
+            return err(
+                StatusCode::InvalidArgument,
+                "l0_compaction_trigger must be positive",
+            );
+        }
+        if self.l0_stop_writes_trigger < self.l0_slowdown_writes_trigger {
+            return err(
+                StatusCode::InvalidArgument,
+                "l0_stop_writes_trigger must not be smaller than l0_slowdown_writes_trigger",
+            );
+        }
+        if !self.compressor_list.is_set(self.compressor) {
+            return err(
+                StatusCode::InvalidArgument,
+                &format!("compressor {} is not in compressor_list", self.compressor),
+            );
         }
+        Ok(())
+    }
+}
+
+/// OptionsBuilder constructs `Options` with fluent setters, starting from the defaults. Fields
+/// without a setter keep their default value.
+///
+/// ```
+/// # use my_leveldb::OptionsBuilder;
+/// let opt = OptionsBuilder::new()
+///     .create_if_missing(false)
+///     .write_buffer_size(1 << 20)
+///     .build();
+/// assert!(!opt.create_if_missing);
+/// ```
+#[derive(Clone, Default)]
+pub struct OptionsBuilder {
+    opt: Options,
+}
+
+impl OptionsBuilder {
+    pub fn new() -> OptionsBuilder {
+        OptionsBuilder::default()
+    }
+
+    pub fn comparator(mut self, cmp: Box<dyn Cmp>) -> Self {
+        self.opt.cmp = Rc::new(cmp);
+        self
+    }
+
+    pub fn env(mut self, env: Box<dyn Env>) -> Self {
+        self.opt.env = Rc::new(env);
+        self
+    }
+
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.opt.create_if_missing = create_if_missing;
+        self
+    }
+
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.opt.error_if_exists = error_if_exists;
+        self
+    }
+
+    pub fn paranoid_checks(mut self, paranoid_checks: bool) -> Self {
+        self.opt.paranoid_checks = paranoid_checks;
+        self
+    }
+
+    pub fn skip_corrupt_tables(mut self, skip: bool) -> Self {
+        self.opt.skip_corrupt_tables = skip;
+        self
+    }
+
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.opt.write_buffer_size = size;
+        self
+    }
+
+    pub fn max_open_files(mut self, n: usize) -> Self {
+        self.opt.max_open_file = n;
+        self
+    }
+
+    pub fn max_file_size(mut self, size: usize) -> Self {
+        self.opt.max_file_size = size;
+        self
+    }
+
+    /// block_cache_capacity sets the size of a new block cache, in bytes.
+    pub fn block_cache_capacity(mut self, bytes: usize) -> Self {
+        self.opt.block_cache = share(Cache::with_capacity_bytes(bytes));
+        self
+    }
+
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.opt.block_size = size;
+        self
+    }
+
+    pub fn block_restart_interval(mut self, interval: usize) -> Self {
+        self.opt.block_restart_interval = interval;
+        self
+    }
+
+    pub fn adaptive_restart(mut self, adaptive: bool) -> Self {
+        self.opt.adaptive_restart = adaptive;
+        self
+    }
+
+    pub fn compressor(mut self, id: u8) -> Self {
+        self.opt.compressor = id;
+        self
+    }
+
+    pub fn filter_policy(mut self, policy: BoxedFilterPolicy) -> Self {
+        self.opt.filter_policy = policy;
+        self
+    }
+
+    pub fn merge_operator(mut self, op: BoxedMergeOperator) -> Self {
+        self.opt.merge_operator = Some(op);
+        self
+    }
+
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.opt.ttl = Some(ttl);
+        self
+    }
+
+    pub fn reuse_logs(mut self, reuse_logs: bool) -> Self {
+        self.opt.reuse_logs = reuse_logs;
+        self
+    }
+
+    pub fn checksum_type(mut self, checksum_type: ChecksumType) -> Self {
+        self.opt.checksum_type = checksum_type;
+        self
+    }
+
+    /// l0_triggers sets the number of level-0 files at which a compaction is started, writes
+    /// are slowed down, and writes stop, respectively.
+    pub fn l0_triggers(mut self, compaction: usize, slowdown: usize, stop: usize) -> Self {
+        self.opt.l0_compaction_trigger = compaction;
+        self.opt.l0_slowdown_writes_trigger = slowdown;
+        self.opt.l0_stop_writes_trigger = stop;
+        self
+    }
+
+    pub fn build(self) -> Options {
+        self.opt
     }
 }
 
@@ -107,6 +431,8 @@ impl Default for Options {
 /// list.set(compressor::NoneCompressor);
 /// list.set(compressor::SnappyCompressor);
 /// ```
+///
+/// With the `zstd` feature, `compressor::ZstdCompressor` is registered as well.
 pub struct CompressorList([Option<Box<dyn Compressor>>; 256]);
 
 impl CompressorList {
@@ -147,6 +473,8 @@ impl Default for CompressorList {
         let mut list = Self::new();
         list.set(compressor::NoneCompressor);
         list.set(compressor::SnappyCompressor);
+        #[cfg(feature = "zstd")]
+        list.set(compressor::ZstdCompressor::default());
         list
     }
 }
@@ -168,3 +496,82 @@ pub fn for_test() -> Options {
         ..Default::default()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn test_options_builder() {
+        let opt = OptionsBuilder::new()
+            .comparator(Box::new(DefaultCmp))
+            .env(Box::new(MemEnv::new()))
+            .create_if_missing(false)
+            .error_if_exists(true)
+            .paranoid_checks(true)
+            .skip_corrupt_tables(true)
+            .write_buffer_size(1 << 20)
+            .max_open_files(100)
+            .max_file_size(1 << 21)
+            .block_cache_capacity(1 << 16)
+            .block_size(1024)
+            .block_restart_interval(8)
+            .compressor(compressor::NoneCompressor::ID)
+            .filter_policy(Rc::new(Box::new(filter::BloomPolicy::new(20))))
+            .reuse_logs(false)
+            .checksum_type(ChecksumType::Crc32)
+            .build();
+
+        assert_eq!("leveldb.BytewiseComparator", opt.cmp.id());
+        assert!(!opt.create_if_missing);
+        assert!(opt.error_if_exists);
+        assert!(opt.paranoid_checks);
+        assert!(opt.skip_corrupt_tables);
+        assert_eq!(1 << 20, opt.write_buffer_size);
+        assert_eq!(100, opt.max_open_file);
+        assert_eq!(1 << 21, opt.max_file_size);
+        assert_eq!(1 << 16, opt.block_cache.borrow().cap());
+        assert_eq!(1024, opt.block_size);
+        assert_eq!(8, opt.block_restart_interval);
+        assert_eq!(0, opt.compressor);
+        assert!(!opt.reuse_logs);
+        assert_eq!(ChecksumType::Crc32, opt.checksum_type);
+        assert!(opt.validate().is_ok());
+
+        // Other fields keep their defaults.
+        let default = Options::default();
+        assert_eq!(default.reuse_manifest, opt.reuse_manifest);
+        assert_eq!(default.max_key_length, opt.max_key_length);
+        assert!(!opt.env.exists(Path::new("db")).unwrap());
+    }
+
+    #[test]
+    fn test_options_validate() {
+        assert!(Options::default().validate().is_ok());
+        assert!(for_test().validate().is_ok());
+
+        let cases: [(fn(&mut Options), &str); 9] = [
+            (|o| o.block_size = 0, "block_size"),
+            (|o| o.block_restart_interval = 0, "block_restart_interval"),
+            (
+                |o| o.write_buffer_size = o.block_size - 1,
+                "write_buffer_size",
+            ),
+            (|o| o.max_file_size = 0, "max_file_size"),
+            (|o| o.max_open_file = 10, "max_open_file"),
+            (|o| o.block_alignment = Some(0), "block_alignment"),
+            (|o| o.l0_compaction_trigger = 0, "l0_compaction_trigger"),
+            (|o| o.l0_stop_writes_trigger = 7, "l0_stop_writes_trigger"),
+            (|o| o.compressor = 200, "compressor 200"),
+        ];
+        for (modify, field) in cases {
+            let mut opt = for_test();
+            modify(&mut opt);
+            let e = opt.validate().unwrap_err();
+            assert_eq!(StatusCode::InvalidArgument, e.code);
+            assert!(e.err.contains(field), "{}: {}", field, e.err);
+        }
+    }
+}
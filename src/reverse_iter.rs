@@ -0,0 +1,173 @@
+//! ReverseIterator presents any LdbIterator in descending key order.
+
+use std::{cmp::Ordering, rc::Rc};
+
+use crate::{cmp::Cmp, types::LdbIterator};
+
+/// ReverseIterator wraps an `LdbIterator` and yields its entries in reverse order. The first call
+/// to `advance()` positions it at the last entry of the wrapped iterator; `advance()` and `prev()`
+/// are mapped to the wrapped iterator's `prev()` and `advance()`, respectively.
+///
+/// `seek(key)` positions the iterator at `key` or the next smaller key (i.e. the next key in
+/// descending order), according to `cmp`, which must be the comparator ordering the wrapped
+/// iterator.
+pub struct ReverseIterator {
+    cmp: Rc<Box<dyn Cmp>>,
+    iter: Box<dyn LdbIterator>,
+    keybuf: Vec<u8>,
+    valbuf: Vec<u8>,
+}
+
+impl ReverseIterator {
+    pub fn new(cmp: Rc<Box<dyn Cmp>>, iter: Box<dyn LdbIterator>) -> ReverseIterator {
+        ReverseIterator {
+            cmp,
+            iter,
+            keybuf: vec![],
+            valbuf: vec![],
+        }
+    }
+
+    /// Position the wrapped iterator at its last entry.
+    fn seek_wrapped_to_last(&mut self) -> bool {
+        self.iter.seek_to_last();
+        self.iter.valid()
+    }
+}
+
+impl LdbIterator for ReverseIterator {
+    fn advance(&mut self) -> bool {
+        if !self.iter.valid() {
+            return self.seek_wrapped_to_last();
+        }
+        self.iter.prev()
+    }
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        self.iter.current(key, val)
+    }
+    fn seek(&mut self, key: &[u8]) {
+        self.iter.seek(key);
+        if !self.iter.valid() {
+            // All entries are smaller than key.
+            self.seek_wrapped_to_last();
+            return;
+        }
+        if self.iter.current(&mut self.keybuf, &mut self.valbuf)
+            && self.cmp.cmp(&self.keybuf, key) != Ordering::Equal
+        {
+            // Landed on a bigger key; step back to the next smaller one (or become invalid).
+            self.iter.prev();
+        }
+    }
+    fn reset(&mut self) {
+        self.iter.reset();
+    }
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+    fn prev(&mut self) -> bool {
+        if !self.iter.valid() {
+            return false;
+        }
+        self.iter.advance()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        options,
+        skipmap::{tests::make_skipmap, SkipMap},
+        test_util::{test_iterator_properties, LdbIteratorIter},
+        types::current_key_val,
+    };
+
+    #[test]
+    fn test_reverse_iter_order() {
+        let skm = make_skipmap();
+        let mut forward: Vec<_> = LdbIteratorIter::wrap(&mut skm.iter()).collect();
+        forward.reverse();
+
+        let mut iter = ReverseIterator::new(options::for_test().cmp, Box::new(skm.iter()));
+        assert!(!iter.valid());
+        let reversed: Vec<_> = LdbIteratorIter::wrap(&mut iter).collect();
+        assert_eq!(forward, reversed);
+        assert!(!iter.valid());
+
+        // Iterating again after exhaustion starts over from the last entry.
+        assert!(iter.advance());
+        assert_eq!(b"abz", current_key_val(&iter).unwrap().0.as_slice());
+        iter.reset();
+        assert!(!iter.valid());
+        let reversed2: Vec<_> = LdbIteratorIter::wrap(&mut iter).collect();
+        assert_eq!(forward, reversed2);
+    }
+
+    #[test]
+    fn test_reverse_iter_seek_prev() {
+        let skm = make_skipmap();
+        let mut iter = ReverseIterator::new(options::for_test().cmp, Box::new(skm.iter()));
+
+        iter.seek(b"abc");
+        assert_eq!(b"abc", current_key_val(&iter).unwrap().0.as_slice());
+        assert!(iter.advance());
+        assert_eq!(b"abb", current_key_val(&iter).unwrap().0.as_slice());
+        assert!(iter.prev());
+        assert_eq!(b"abc", current_key_val(&iter).unwrap().0.as_slice());
+
+        iter.seek(b"abcc");
+        assert_eq!(b"abc", current_key_val(&iter).unwrap().0.as_slice());
+        iter.seek(b"zzz");
+        assert_eq!(b"abz", current_key_val(&iter).unwrap().0.as_slice());
+        assert!(!iter.prev());
+        assert!(!iter.valid());
+        iter.seek(b"a");
+        assert!(!iter.valid());
+    }
+
+    /// CaseInsensitiveCmp compares keys ignoring ASCII case.
+    struct CaseInsensitiveCmp;
+
+    impl Cmp for CaseInsensitiveCmp {
+        fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+            a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+        }
+        fn find_shortest_sep(&self, a: &[u8], _: &[u8]) -> Vec<u8> {
+            a.to_vec()
+        }
+        fn find_short_succ(&self, key: &[u8]) -> Vec<u8> {
+            key.to_vec()
+        }
+        fn id(&self) -> &'static str {
+            "test.CaseInsensitiveComparator"
+        }
+    }
+
+    #[test]
+    fn test_reverse_iter_seek_cmp() {
+        let cmp: Rc<Box<dyn Cmp>> = Rc::new(Box::new(CaseInsensitiveCmp));
+        let mut skm = SkipMap::new(cmp.clone());
+        for k in &["abc", "abd", "abe"] {
+            skm.insert(k.as_bytes().to_vec(), b"def".to_vec());
+        }
+        let mut iter = ReverseIterator::new(cmp, Box::new(skm.iter()));
+        // A key equal according to the comparator is found, even if it differs bytewise.
+        iter.seek(b"ABD");
+        assert_eq!(b"abd", current_key_val(&iter).unwrap().0.as_slice());
+        iter.seek(b"ABDD");
+        assert_eq!(b"abd", current_key_val(&iter).unwrap().0.as_slice());
+    }
+
+    #[test]
+    fn test_reverse_iter_properties() {
+        let mut skm = SkipMap::new(options::for_test().cmp);
+        for k in &["abf", "abe", "abd", "abc"] {
+            skm.insert(k.as_bytes().to_vec(), b"def".to_vec());
+        }
+        test_iterator_properties(ReverseIterator::new(
+            options::for_test().cmp,
+            Box::new(skm.iter()),
+        ));
+    }
+}
@@ -14,6 +14,9 @@ use crate::{
 
 const MAX_HEIGHT: usize = 12;
 const BRANCHING_FACTOR: u32 = 4;
+/// The seed of the random number generator determining node heights, unless one is given to
+/// `SkipMap::new_with_seed()`.
+const DEFAULT_SEED: u64 = 0xdeadbeef;
 
 /// A Node in a skipmap contains links to the next node and others are further away (skips);
 /// `skips[0]` is the immediate element after, that is, the element contained in `next`.
@@ -37,6 +40,17 @@ pub struct InnerSkipMap {
     cmp: Rc<Box<dyn Cmp>>,
 }
 
+impl Drop for InnerSkipMap {
+    fn drop(&mut self) {
+        // Unlink the nodes one by one; dropping the chain recursively overflows the stack for large
+        // maps.
+        let mut next = self.head.next.take();
+        while let Some(mut n) = next {
+            next = n.next.take();
+        }
+    }
+}
+
 pub struct SkipMap {
     map: Rc<RefCell<InnerSkipMap>>,
 }
@@ -49,6 +63,12 @@ impl SkipMap {
 
     /// Returns a SkipMap that uses the comparator from opt
     pub fn new(cmp: Rc<Box<dyn Cmp>>) -> SkipMap {
+        SkipMap::new_with_seed(cmp, DEFAULT_SEED)
+    }
+
+    /// Like new(), but node heights are chosen by a random number generator seeded with `seed`.
+    /// Maps with the same seed and the same inserts have the same structure.
+    pub fn new_with_seed(cmp: Rc<Box<dyn Cmp>>, seed: u64) -> SkipMap {
         let mut s = Vec::new();
         s.resize(MAX_HEIGHT, None);
 
@@ -60,7 +80,7 @@ impl SkipMap {
                     key: Vec::new(),
                     value: Vec::new(),
                 }),
-                rand: StdRng::seed_from_u64(0xdeadbeef),
+                rand: StdRng::seed_from_u64(seed),
                 len: 0,
                 approx_mem: size_of::<Self>() + MAX_HEIGHT * size_of::<Option<*mut Node>>(),
                 cmp,
@@ -92,6 +112,18 @@ impl SkipMap {
         SkipMapIter {
             map: self.map.clone(),
             current: self.map.borrow().head.as_ref() as *const Node,
+            past_end: false,
+        }
+    }
+
+    /// Returns an iterator over the entries with keys in `[lower, upper)`. A bound of `None` leaves
+    /// that end of the range open. If `lower` is not smaller than `upper`, the range is empty.
+    pub fn range(&self, lower: Option<&[u8]>, upper: Option<&[u8]>) -> SkipMapRangeIter {
+        SkipMapRangeIter {
+            iter: self.iter(),
+            cmp: self.map.borrow().cmp.clone(),
+            lower: lower.map(|k| k.to_vec()),
+            upper: upper.map(|k| k.to_vec()),
         }
     }
 }
@@ -192,6 +224,29 @@ impl InnerSkipMap {
         }
     }
 
+    /// Returns the node with the greatest key, or None if the map is empty.
+    fn get_last(&self) -> Option<&Node> {
+        let mut current: *const Node = self.head.as_ref() as *const Node;
+        let mut level = self.head.skips.len() - 1;
+        loop {
+            unsafe {
+                if let Some(next) = (*current).skips[level] {
+                    current = next;
+                    continue;
+                }
+            }
+            if level == 0 {
+                break;
+            }
+            level -= 1;
+        }
+        if current == self.head.as_ref() {
+            None
+        } else {
+            unsafe { Some(&(*current)) }
+        }
+    }
+
     fn insert(&mut self, key: Vec<u8>, val: Vec<u8>) {
         assert!(!key.is_empty());
 
@@ -277,7 +332,9 @@ impl InnerSkipMap {
         };
     }
 
-    // Runs through the skipmap and prints everything including addresses
+    // Runs through the skipmap and prints everything including addresses. Only available in tests,
+    // so that it can't end up printing to stdout in production.
+    #[cfg(test)]
     fn dbg_print(&self) {
         let mut current: *const Node = self.head.as_ref() as *const Node;
         loop {
@@ -303,10 +360,15 @@ impl InnerSkipMap {
 pub struct SkipMapIter {
     map: Rc<RefCell<InnerSkipMap>>,
     current: *const Node,
+    // Set after a seek past the last entry.
+    past_end: bool,
 }
 
 impl LdbIterator for SkipMapIter {
     fn advance(&mut self) -> bool {
+        if self.past_end {
+            return false;
+        }
         // we first go to the next element, then return that -- in order to skip the head node
         let r = unsafe {
             (*self.current)
@@ -326,14 +388,17 @@ impl LdbIterator for SkipMapIter {
 
     fn reset(&mut self) {
         self.current = self.map.borrow().head.as_ref();
+        self.past_end = false;
     }
 
     fn seek(&mut self, key: &[u8]) {
         if let Some(node) = self.map.borrow().get_greater_or_equal(key) {
             self.current = node as *const Node;
+            self.past_end = false;
             return;
         }
         self.reset();
+        self.past_end = true;
     }
 
     fn valid(&self) -> bool {
@@ -353,7 +418,22 @@ impl LdbIterator for SkipMapIter {
             false
         }
     }
+    fn seek_to_last(&mut self) {
+        self.reset();
+        if let Some(last) = self.map.borrow().get_last() {
+            self.current = last as *const Node;
+        }
+    }
+
     fn prev(&mut self) -> bool {
+        if self.past_end {
+            self.past_end = false;
+            if let Some(last) = self.map.borrow().get_last() {
+                self.current = last as *const Node;
+                return true;
+            }
+            return false;
+        }
         // Going after the original implementation here; we just seek to the node before current().
         if self.valid() {
             if let Some(prev) = self
@@ -372,18 +452,134 @@ impl LdbIterator for SkipMapIter {
     }
 }
 
+/// SkipMapRangeIter is a `SkipMapIter` restricted to the keys in `[lower, upper)`; see
+/// `SkipMap::range()`.
+pub struct SkipMapRangeIter {
+    iter: SkipMapIter,
+    cmp: Rc<Box<dyn Cmp>>,
+    lower: Option<Vec<u8>>,
+    upper: Option<Vec<u8>>,
+}
+
+impl SkipMapRangeIter {
+    fn current_key(&self) -> Option<&[u8]> {
+        if self.iter.valid() {
+            unsafe { Some(&(*self.iter.current).key) }
+        } else {
+            None
+        }
+    }
+
+    /// Invalidates the underlying iterator if it has moved outside of the range.
+    fn check_bounds(&mut self) -> bool {
+        let in_range = match self.current_key() {
+            None => return false,
+            Some(key) => {
+                let above_lower = match self.lower.as_ref() {
+                    Some(l) => self.cmp.cmp(key, l) != Ordering::Less,
+                    None => true,
+                };
+                let below_upper = match self.upper.as_ref() {
+                    Some(u) => self.cmp.cmp(key, u) == Ordering::Less,
+                    None => true,
+                };
+                above_lower && below_upper
+            }
+        };
+        if !in_range {
+            self.iter.reset();
+        }
+        in_range
+    }
+}
+
+impl LdbIterator for SkipMapRangeIter {
+    fn advance(&mut self) -> bool {
+        if self.iter.valid() {
+            self.iter.advance();
+        } else if let Some(lower) = self.lower.as_ref() {
+            self.iter.seek(lower);
+        } else {
+            self.iter.advance();
+        }
+        self.check_bounds()
+    }
+
+    fn reset(&mut self) {
+        self.iter.reset();
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        match self.lower.as_ref() {
+            Some(lower) if self.cmp.cmp(key, lower) == Ordering::Less => self.iter.seek(lower),
+            _ => self.iter.seek(key),
+        }
+        self.check_bounds();
+    }
+
+    fn valid(&self) -> bool {
+        self.iter.valid()
+    }
+
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        self.iter.current(key, val)
+    }
+
+    fn prev(&mut self) -> bool {
+        self.iter.prev() && self.check_bounds()
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use time_test::time_test;
 
     use crate::{
         options,
-        test_util::{test_iterator_properties, LdbIteratorIter},
+        test_util::{test_iterator_properties, test_iterator_seek_past_end, LdbIteratorIter},
         types::*,
     };
 
     use super::*;
 
+    /// heights returns the height of every node, in order.
+    fn heights(skm: &SkipMap) -> Vec<usize> {
+        let map = skm.map.borrow();
+        let mut heights = vec![];
+        let mut current = map.head.next.as_deref();
+        while let Some(node) = current {
+            heights.push(node.skips.len());
+            current = node.next.as_deref();
+        }
+        heights
+    }
+
+    #[test]
+    fn test_skipmap_new_with_seed() {
+        let build = |seed| {
+            let mut skm = SkipMap::new_with_seed(options::for_test().cmp, seed);
+            for i in 0..200 {
+                let key = format!("key{:03}", i * 37 % 200);
+                skm.insert(key.into_bytes(), b"val".to_vec());
+            }
+            skm
+        };
+        let (a, b, c) = (build(42), build(42), build(43));
+        assert_eq!(200, heights(&a).len());
+        assert_eq!(heights(&a), heights(&b));
+        assert_ne!(heights(&a), heights(&c));
+        assert!(heights(&a).iter().any(|&h| h > 1));
+
+        // new() uses a fixed seed as well.
+        let mut d = SkipMap::new(options::for_test().cmp);
+        let mut e = SkipMap::new_with_seed(options::for_test().cmp, DEFAULT_SEED);
+        for i in 0..50 {
+            d.insert(vec![i + 1], vec![]);
+            e.insert(vec![i + 1], vec![]);
+        }
+        assert_eq!(heights(&d), heights(&e));
+    }
+
     pub fn make_skipmap() -> SkipMap {
         let mut skm = SkipMap::new(options::for_test().cmp);
         let keys = vec![
@@ -542,6 +738,35 @@ pub mod tests {
         assert_eq!(i, 26);
     }
 
+    #[test]
+    fn test_skipmap_iterator_count_collect() {
+        let skm = make_skipmap();
+        let mut iter = skm.iter();
+        assert_eq!(26, iter.count_remaining());
+        assert!(!iter.valid());
+
+        let mut iter = skm.iter();
+        let entries = iter.collect_kv();
+        assert_eq!(26, entries.len());
+        assert_eq!((b"aba".to_vec(), b"def".to_vec()), entries[0]);
+        assert_eq!(b"abz", entries[25].0.as_slice());
+        assert!(!iter.valid());
+
+        // Both start at the current entry.
+        let mut iter = skm.iter();
+        iter.seek(b"abx");
+        assert_eq!(3, iter.count_remaining());
+        iter.seek(b"abx");
+        assert_eq!(
+            vec![b"abx".to_vec(), b"aby".to_vec(), b"abz".to_vec()],
+            iter.collect_kv()
+                .into_iter()
+                .map(|(k, _)| k)
+                .collect::<Vec<_>>()
+        );
+        assert!(!iter.valid());
+    }
+
     #[test]
     fn test_skipmap_iterator_seek_valid() {
         let skm = make_skipmap();
@@ -578,6 +803,35 @@ pub mod tests {
             skm.insert(k.as_bytes().to_vec(), "def".as_bytes().to_vec());
         }
         test_iterator_properties(skm.iter());
+        test_iterator_seek_past_end(skm.iter(), b"abe");
+    }
+
+    #[test]
+    fn test_skipmap_iterator_seek_to_first_last() {
+        let skm = make_skipmap();
+        let mut iter = skm.iter();
+
+        iter.seek_to_last();
+        assert_eq!(b"abz".to_vec(), current_key_val(&iter).unwrap().0);
+        assert!(iter.prev());
+        assert_eq!(b"aby".to_vec(), current_key_val(&iter).unwrap().0);
+        iter.seek_to_last();
+        assert!(!iter.advance());
+        assert!(!iter.valid());
+
+        iter.seek_to_first();
+        assert_eq!(b"aba".to_vec(), current_key_val(&iter).unwrap().0);
+
+        // After a seek past the end, seek_to_last() still works.
+        iter.seek(b"zzz");
+        iter.seek_to_last();
+        assert_eq!(b"abz".to_vec(), current_key_val(&iter).unwrap().0);
+
+        let empty = SkipMap::new(options::for_test().cmp);
+        let mut iter = empty.iter();
+        iter.seek_to_last();
+        assert!(!iter.valid());
+        assert!(!iter.advance());
     }
 
     #[test]
@@ -597,6 +851,53 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_skipmap_range() {
+        let skm = make_skipmap();
+        let keys = |lower: Option<&str>, upper: Option<&str>| -> Vec<Vec<u8>> {
+            let mut iter = skm.range(lower.map(str::as_bytes), upper.map(str::as_bytes));
+            LdbIteratorIter::wrap(&mut iter).map(|(k, _)| k).collect()
+        };
+        let b =
+            |ks: &[&str]| -> Vec<Vec<u8>> { ks.iter().map(|k| k.as_bytes().to_vec()).collect() };
+
+        // Lower bound is inclusive, upper bound is exclusive.
+        assert_eq!(b(&["abc", "abd", "abe"]), keys(Some("abc"), Some("abf")));
+        assert_eq!(b(&["abd", "abe"]), keys(Some("abcc"), Some("abef")));
+        // Open bounds.
+        assert_eq!(b(&["aba", "abb"]), keys(None, Some("abc")));
+        assert_eq!(b(&["aby", "abz"]), keys(Some("aby"), None));
+        assert_eq!(26, keys(None, None).len());
+        // Empty ranges.
+        assert!(keys(Some("abc"), Some("abc")).is_empty());
+        assert!(keys(Some("abf"), Some("abc")).is_empty());
+        assert!(keys(Some("b"), None).is_empty());
+        assert!(keys(None, Some("aba")).is_empty());
+    }
+
+    #[test]
+    fn test_skipmap_range_seek_prev() {
+        let skm = make_skipmap();
+        let mut iter = skm.range(Some("abc".as_bytes()), Some("abf".as_bytes()));
+
+        iter.seek("aba".as_bytes());
+        assert_eq!(current_key_val(&iter).unwrap().0, "abc".as_bytes());
+        assert!(!iter.prev());
+        assert!(!iter.valid());
+
+        iter.seek("abe".as_bytes());
+        assert_eq!(current_key_val(&iter).unwrap().0, "abe".as_bytes());
+        assert!(iter.prev());
+        assert_eq!(current_key_val(&iter).unwrap().0, "abd".as_bytes());
+        assert!(iter.advance());
+        assert!(!iter.advance());
+
+        iter.seek("abf".as_bytes());
+        assert!(!iter.valid());
+
+        test_iterator_properties(skm.range(Some("abc".as_bytes()), Some("abg".as_bytes())));
+    }
+
     #[test]
     fn test_skipmap_iterator_concurrent_insert() {
         time_test!();
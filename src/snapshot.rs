@@ -86,7 +86,7 @@ impl SnapshotList {
             .iter()
             .fold(
                 MAX_SEQUENCE_NUMBER,
-                |s, (seq, _)| if *seq < s { *seq } else { s },
+                |s, (_, seq)| if *seq < s { *seq } else { s },
             );
         if oldest == MAX_SEQUENCE_NUMBER {
             0
@@ -102,11 +102,11 @@ impl SnapshotList {
             .borrow()
             .map
             .iter()
-            .fold(0, |s, (seq, _)| if *seq > s { *seq } else { s })
+            .fold(0, |s, (_, seq)| if *seq > s { *seq } else { s })
     }
 
     pub fn empty(&self) -> bool {
-        self.inner.borrow().oldest == 0
+        self.inner.borrow().map.is_empty()
     }
 
     pub fn delete(&mut self, id: SnapshotHandle) {
@@ -148,5 +148,20 @@ mod tests {
             assert_eq!(l.oldest(), 1);
         }
         assert_eq!(l.oldest(), 0);
+        assert!(l.empty());
+    }
+
+    #[test]
+    fn test_snapshot_list_sequence_numbers() {
+        // Snapshot handles and sequence numbers are independent.
+        let mut l = SnapshotList::new();
+        let a = l.new_snapshot(20);
+        let b = l.new_snapshot(30);
+        assert_eq!(l.oldest(), 20);
+        assert_eq!(l.newest(), 30);
+        drop(a);
+        assert_eq!(l.oldest(), 30);
+        drop(b);
+        assert!(l.empty());
     }
 }
@@ -1,4 +1,4 @@
-use crc::crc32::{self, Hasher32};
+use crc::Hasher32;
 use integer_encoding::FixedInt;
 
 use crate::{
@@ -9,6 +9,7 @@ use crate::{
     filter,
     filter_block::FilterBlockReader,
     log::unmask_crc,
+    options::ChecksumType,
     table_builder, Options,
 };
 
@@ -36,11 +37,13 @@ pub fn read_filter_block(
 
 /// Reads a table block from a random-access source.
 /// A table block consists of [bytes..., compress(1B), checksum(4B)]; the handle only refers to
-/// the location and length of [bytes...].
+/// the location and length of [bytes...]. The checksum is only verified if `verify_checksum` is
+/// set.
 pub fn read_table_block(
     opt: Options,
     f: &dyn RandomAccess,
     location: &BlockHandle,
+    verify_checksum: bool,
 ) -> Result<Block> {
     // The block is denoted by offset and length in BlockHandle. A block in an encoded
     // table is followed by 1B compression type and 4B checksum.
@@ -61,11 +64,14 @@ pub fn read_table_block(
         ),
     )?;
 
-    if !verify_table_block(
-        &buf,
-        compress[0],
-        unmask_crc(u32::decode_fixed(&cksum).unwrap()),
-    ) {
+    if verify_checksum
+        && !verify_table_block(
+            &buf,
+            compress[0],
+            unmask_crc(u32::decode_fixed(&cksum).unwrap()),
+            opt.checksum_type,
+        )
+    {
         return err(
             StatusCode::Corruption,
             &format!(
@@ -83,8 +89,8 @@ pub fn read_table_block(
 }
 
 /// Verify checksum of block
-fn verify_table_block(data: &[u8], compression: u8, want: u32) -> bool {
-    let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+fn verify_table_block(data: &[u8], compression: u8, want: u32, typ: ChecksumType) -> bool {
+    let mut digest = typ.digest();
     digest.write(data);
     digest.write(&[compression; 1]);
     digest.sum32() == want
@@ -1,6 +1,11 @@
-use crc::{crc32, Hasher32};
+use crc::Hasher32;
 use integer_encoding::FixedIntWriter;
-use std::{cmp::Ordering, io::Write, rc::Rc};
+use std::{
+    cmp::Ordering,
+    io::{BufWriter, Write},
+    path::Path,
+    rc::Rc,
+};
 
 use crate::{
     block::BlockContents,
@@ -8,18 +13,31 @@ use crate::{
     blockhandle::BlockHandle,
     cmp::InternalKeyCmp,
     compressor::{self, Compressor, CompressorId},
-    error::{self, Result},
-    filter::{InternalFilterPolicy, NoFilterPolicy},
+    env::Env,
+    error::{self, err, Result, StatusCode},
+    filter::{InternalFilterPolicy, NoFilterPolicy, PrefixFilterPolicy},
     filter_block::FilterBlockBuilder,
-    key_types::InternalKey,
+    key_types::{InternalKey, LookupKey, ValueType},
     log::mask_crc,
     options::Options,
+    types::{parse_file_name, FileMetaData, FileType, SequenceNumber},
 };
 
 pub const FOOTER_LENGTH: usize = 40;
 pub const FULL_FOOTER_LENGTH: usize = FOOTER_LENGTH + 8;
 pub const MAGIC_FOOTER_NUMBER: u64 = 0xdb4775248b80fb57;
 pub const MAGIC_FOOTER_ENCODED: [u8; 8] = [0x57, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdb];
+/// Magic number of tables with format version 2.
+pub const MAGIC_FOOTER_ENCODED_V2: [u8; 8] = [0x58, 0xfb, 0x80, 0x8b, 0x24, 0x75, 0x47, 0xdb];
+
+/// The original table format.
+pub const FORMAT_VERSION_DEFAULT: u32 = 1;
+/// In this format, the value of an index entry is the block handle followed by the first key of
+/// the data block.
+pub const FORMAT_VERSION_FIRST_KEY_INDEX: u32 = 2;
+
+/// Metaindex key under which the name of the prefix extractor used for the filter block is stored.
+pub const PREFIX_EXTRACTOR_META_KEY: &str = "prefix_extractor";
 
 pub const TABLE_BLOCK_COMPRESS_LEN: usize = 1;
 pub const TABLE_BLOCK_CKSUM_LEN: usize = 4;
@@ -29,29 +47,38 @@ pub const TABLE_BLOCK_CKSUM_LEN: usize = 4;
 pub struct Footer {
     pub meta_index: BlockHandle,
     pub index: BlockHandle,
+    pub version: u32,
 }
 
 /// A Table footer contains a pointer to the metaindex block, another pointer to the index block,
 /// and a magic number;
 /// [ { table data ... , METAINDEX blockhandle, INDEX blockhandle, PADDING bytes } = 40 bytes,
 /// MAGIC_FOOTER_ENCODED ]
+///
+/// The magic number also encodes the format version of the table.
 impl Footer {
     pub fn new(metaix: BlockHandle, index: BlockHandle) -> Footer {
         Footer {
             meta_index: metaix,
             index,
+            version: FORMAT_VERSION_DEFAULT,
         }
     }
 
     pub fn decode(from: &[u8]) -> Option<Footer> {
-        assert!(from.len() >= FOOTER_LENGTH);
-        assert_eq!(&from[FOOTER_LENGTH..], &MAGIC_FOOTER_ENCODED);
+        assert!(from.len() >= FULL_FOOTER_LENGTH);
+        let version = match &from[FOOTER_LENGTH..FULL_FOOTER_LENGTH] {
+            m if m == MAGIC_FOOTER_ENCODED => FORMAT_VERSION_DEFAULT,
+            m if m == MAGIC_FOOTER_ENCODED_V2 => FORMAT_VERSION_FIRST_KEY_INDEX,
+            _ => return None,
+        };
         let (meta, metalen) = BlockHandle::decode(&from[0..])?;
         let (ix, _) = BlockHandle::decode(&from[metalen..])?;
 
         Some(Footer {
             meta_index: meta,
             index: ix,
+            version,
         })
     }
 
@@ -64,8 +91,13 @@ impl Footer {
         for item in to.iter_mut().take(FOOTER_LENGTH).skip(s1 + s2) {
             *item = 0;
         }
+        let magic = if self.version == FORMAT_VERSION_FIRST_KEY_INDEX {
+            &MAGIC_FOOTER_ENCODED_V2
+        } else {
+            &MAGIC_FOOTER_ENCODED
+        };
         to[FOOTER_LENGTH..FULL_FOOTER_LENGTH]
-            .copy_from_slice(&MAGIC_FOOTER_ENCODED[..(FULL_FOOTER_LENGTH - FOOTER_LENGTH)]);
+            .copy_from_slice(&magic[..(FULL_FOOTER_LENGTH - FOOTER_LENGTH)]);
     }
 }
 
@@ -84,10 +116,15 @@ pub struct TableBuilder<Dst: Write> {
     offset: usize,
     num_entries: usize,
     prev_block_last_key: Vec<u8>,
+    // Only maintained if opt.index_first_key is set.
+    data_block_first_key: Vec<u8>,
 
     data_block: Option<BlockBuilder>,
     index_block: Option<BlockBuilder>,
     filter_block: Option<FilterBlockBuilder>,
+
+    // The size at which should_flush() returns true; taken from opt.max_file_size.
+    target_size: usize,
 }
 
 impl<Dst: Write> TableBuilder<Dst> {
@@ -102,20 +139,29 @@ impl<Dst: Write> TableBuilder<Dst> {
 impl<Dst: Write> TableBuilder<Dst> {
     /// Create a new table builder.
     /// The comparator in opt will be wrapped in a InternalKeyCmp, and the filter policy
-    /// in an InternalFilterPolicy.
+    /// in an InternalFilterPolicy (and a PrefixFilterPolicy, if opt.prefix_extractor is set).
     pub fn new(mut opt: Options, dst: Dst) -> TableBuilder<Dst> {
         opt.cmp = Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone())));
+        if let Some(ref extractor) = opt.prefix_extractor {
+            opt.filter_policy = Rc::new(Box::new(PrefixFilterPolicy::new(
+                opt.filter_policy,
+                extractor.clone(),
+            )));
+        }
         opt.filter_policy = Rc::new(Box::new(InternalFilterPolicy::new(opt.filter_policy)));
         TableBuilder::new_raw(opt, dst)
     }
 
-    /// Like new(), but doesn't wrap the comparator in an InternalKeyCmp (for testing)
+    /// Like new(), but doesn't wrap the comparator in an InternalKeyCmp (for testing). If
+    /// opt.prefix_extractor is set, opt.filter_policy is expected to apply it.
     pub fn new_raw(opt: Options, dst: Dst) -> TableBuilder<Dst> {
         TableBuilder {
+            target_size: opt.max_file_size,
             opt: opt.clone(),
             dst,
             offset: 0,
             prev_block_last_key: vec![],
+            data_block_first_key: vec![],
             num_entries: 0,
             data_block: Some(BlockBuilder::new(opt.clone())),
             filter_block: Some(FilterBlockBuilder::new(opt.filter_policy.clone())),
@@ -127,6 +173,8 @@ impl<Dst: Write> TableBuilder<Dst> {
         self.num_entries
     }
 
+    /// size_estimate returns the approximate size of the table if it was finished now: the bytes
+    /// written so far, the pending data, index and filter blocks, and the footer.
     pub fn size_estimate(&self) -> usize {
         let mut size = 0;
         if let Some(ref b) = self.data_block {
@@ -141,9 +189,18 @@ impl<Dst: Write> TableBuilder<Dst> {
         size + self.offset + FULL_FOOTER_LENGTH
     }
 
+    /// should_flush returns true once the table has reached the target size (`max_file_size` of
+    /// the options passed at construction). Callers writing a sequence of tables should then
+    /// finish this table and continue with a new one.
+    pub fn should_flush(&self) -> bool {
+        self.size_estimate() >= self.target_size
+    }
+
     /// Add a key to the table. The key as to be lexically greater or equal to the last one added.
     pub fn add(&mut self, key: InternalKey, val: &[u8]) -> error::Result<()> {
         assert!(self.data_block.is_some());
+        // Reject the key before any state is changed.
+        self.data_block.as_ref().unwrap().check_key(key)?;
         if !self.prev_block_last_key.is_empty() {
             assert!(self.opt.cmp.cmp(&self.prev_block_last_key, key) == Ordering::Less);
         }
@@ -154,15 +211,46 @@ impl<Dst: Write> TableBuilder<Dst> {
 
         let dblock = &mut self.data_block.as_mut().unwrap();
 
+        if self.opt.index_first_key && dblock.entries() == 0 {
+            self.data_block_first_key.clear();
+            self.data_block_first_key.extend_from_slice(key);
+        }
+
         if let Some(ref mut fblock) = self.filter_block {
             fblock.add_key(key);
         }
 
         self.num_entries += 1;
-        dblock.add(key, val);
+        dblock.add(key, val)
+    }
+
+    /// add_all adds all entries of `it`, which must be sorted by key. Unlike add(), it returns an
+    /// `InvalidArgument` error instead of panicking if a key is not greater than the previous one;
+    /// the entries before it have been added then.
+    pub fn add_all<I: Iterator<Item = (Vec<u8>, Vec<u8>)>>(&mut self, it: I) -> Result<()> {
+        for (k, v) in it {
+            if let Some(last) = self.last_key() {
+                if self.opt.cmp.cmp(last, &k) != Ordering::Less {
+                    return err(
+                        StatusCode::InvalidArgument,
+                        "keys are not sorted or contain duplicates",
+                    );
+                }
+            }
+            self.add(&k, &v)?;
+        }
         Ok(())
     }
 
+    /// last_key returns the key added last, or None if the table is empty.
+    fn last_key(&self) -> Option<&[u8]> {
+        match self.data_block.as_ref() {
+            Some(b) if b.entries() > 0 => Some(b.last_key()),
+            _ if !self.prev_block_last_key.is_empty() => Some(&self.prev_block_last_key),
+            _ => None,
+        }
+    }
+
     /// Writes an index entry for the current data_block where `next_key` is the first key of the
     /// next block.
     /// Calls write_block() for writing the block to disk.
@@ -181,11 +269,19 @@ impl<Dst: Write> TableBuilder<Dst> {
         let mut handle_enc = [0u8; 16];
         let enc_len = handle.encode_to(&mut handle_enc);
 
-        self.index_block
-            .as_mut()
-            .unwrap()
-            .add(&sep, &handle_enc[0..enc_len]);
+        if self.opt.index_first_key {
+            let mut entry = Vec::with_capacity(enc_len + self.data_block_first_key.len());
+            entry.extend_from_slice(&handle_enc[0..enc_len]);
+            entry.extend_from_slice(&self.data_block_first_key);
+            self.index_block.as_mut().unwrap().add(&sep, &entry)?;
+        } else {
+            self.index_block
+                .as_mut()
+                .unwrap()
+                .add(&sep, &handle_enc[0..enc_len])?;
+        }
         self.data_block = Some(BlockBuilder::new(self.opt.clone()));
+        self.pad_to_alignment()?;
 
         if let Some(ref mut fblock) = self.filter_block {
             fblock.start_block(self.offset);
@@ -194,6 +290,20 @@ impl<Dst: Write> TableBuilder<Dst> {
         Ok(())
     }
 
+    /// pad_to_alignment writes zero bytes until the offset is a multiple of
+    /// `opt.block_alignment`, so that the next block starts aligned. Block handles point past the
+    /// padding, which is therefore never read.
+    fn pad_to_alignment(&mut self) -> Result<()> {
+        if let Some(align) = self.opt.block_alignment.filter(|&a| a > 1) {
+            let padding = (align - self.offset % align) % align;
+            if padding > 0 {
+                self.dst.write_all(&vec![0; padding])?;
+                self.offset += padding;
+            }
+        }
+        Ok(())
+    }
+
     #[allow(clippy::borrowed_box)]
     fn write_block(
         &mut self,
@@ -203,7 +313,7 @@ impl<Dst: Write> TableBuilder<Dst> {
         let (ctype, compressor) = compressor_id_pair;
         let data = compressor.encode(block)?;
 
-        let mut digest = crc32::Digest::new(crc32::CASTAGNOLI);
+        let mut digest = self.opt.checksum_type.digest();
 
         digest.write(&data);
         digest.write(&[ctype; TABLE_BLOCK_COMPRESS_LEN]);
@@ -218,7 +328,19 @@ impl<Dst: Write> TableBuilder<Dst> {
         Ok(handle)
     }
 
+    /// finish writes the rest of the table and flushes it. It returns the size of the table.
     pub fn finish(mut self) -> Result<usize> {
+        self.finish_in_place()
+    }
+
+    /// finish_synced finishes the table like finish(), and then syncs it to disk using env.
+    pub fn finish_synced(mut self, env: &dyn Env) -> Result<usize> {
+        let size = self.finish_in_place()?;
+        env.sync(&mut self.dst)?;
+        Ok(size)
+    }
+
+    fn finish_in_place(&mut self) -> Result<usize> {
         assert!(self.data_block.is_some());
         let compressor_list = self.opt.compressor_list.clone();
         let compressor_id_pair = (
@@ -238,7 +360,10 @@ impl<Dst: Write> TableBuilder<Dst> {
         }
 
         // Create metaindex block
-        let mut meta_ix_block = BlockBuilder::new(self.opt.clone());
+        // Metaindex keys are fixed names, not user data.
+        let mut meta_opt = self.opt.clone();
+        meta_opt.max_key_length = None;
+        let mut meta_ix_block = BlockBuilder::new(meta_opt);
 
         if self.filter_block.is_some() {
             // if there's a filter block, write the filter block and add it to the metaindex block.
@@ -256,7 +381,15 @@ impl<Dst: Write> TableBuilder<Dst> {
             let mut handle_enc = [0u8; 16];
             let enc_len = fblock_handle.encode_to(&mut handle_enc);
 
-            meta_ix_block.add(filter_key.as_bytes(), &handle_enc[0..enc_len]);
+            meta_ix_block.add(filter_key.as_bytes(), &handle_enc[0..enc_len])?;
+
+            // Filters built from prefixes are only usable with the same extractor.
+            if let Some(ref extractor) = self.opt.prefix_extractor {
+                meta_ix_block.add(
+                    PREFIX_EXTRACTOR_META_KEY.as_bytes(),
+                    extractor.name().as_bytes(),
+                )?;
+            }
         }
 
         // write metaindex block
@@ -268,24 +401,116 @@ impl<Dst: Write> TableBuilder<Dst> {
         let ix_handle = self.write_block(index_cont, compressor_id_pair)?;
 
         // write footer.
-        let footer = Footer::new(meta_ix_handle, ix_handle);
+        let mut footer = Footer::new(meta_ix_handle, ix_handle);
+        if self.opt.index_first_key {
+            footer.version = FORMAT_VERSION_FIRST_KEY_INDEX;
+        }
         let mut buf = [0; FULL_FOOTER_LENGTH];
         footer.encode(&mut buf);
 
-        self.offset += self.dst.write(&buf[..])?;
+        self.dst.write_all(&buf[..])?;
+        self.offset += buf.len();
         self.dst.flush()?;
         Ok(self.offset)
     }
 }
 
+/// write_sorted_table writes `entries`, which must be sorted by key (according to opt.cmp) and
+/// free of duplicates, to a new table at `path`. The entries are stored as values with sequence
+/// numbers counting up from `start_seq`. The returned metadata describes the table; its number
+/// is taken from `path` if that is a table file name, and 0 otherwise.
+pub fn write_sorted_table<P: AsRef<Path>>(
+    env: &dyn Env,
+    path: P,
+    entries: &[(&[u8], &[u8])],
+    start_seq: SequenceNumber,
+    opt: &Options,
+) -> Result<FileMetaData> {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|&(k, v)| (k, v, ValueType::TypeValue))
+        .collect();
+    write_table_entries(env, path.as_ref(), &entries, start_seq, opt)
+}
+
+/// write_table_entries implements write_sorted_table(), allowing for entries of any type.
+pub(crate) fn write_table_entries(
+    env: &dyn Env,
+    path: &Path,
+    entries: &[(&[u8], &[u8], ValueType)],
+    start_seq: SequenceNumber,
+    opt: &Options,
+) -> Result<FileMetaData> {
+    if entries.is_empty() {
+        return err(StatusCode::InvalidArgument, "no entries to write");
+    }
+    for w in entries.windows(2) {
+        if opt.cmp.cmp(w[0].0, w[1].0) != Ordering::Less {
+            return err(
+                StatusCode::InvalidArgument,
+                "entries are not sorted or contain duplicates",
+            );
+        }
+    }
+
+    let keys: Vec<LookupKey> = entries
+        .iter()
+        .zip(start_seq..)
+        .map(|(&(k, _, typ), seq)| LookupKey::new_full(k, seq, typ))
+        .collect();
+
+    let r = (|| -> Result<usize> {
+        let f = BufWriter::new(env.open_writable_file(path)?);
+        let mut builder = TableBuilder::new(opt.clone(), f);
+        for (key, &(_, v, _)) in keys.iter().zip(entries) {
+            builder.add(key.internal_key(), v)?;
+        }
+        builder.finish_synced(env)
+    })();
+    let size = match r {
+        Ok(size) => size,
+        Err(e) => {
+            let _ = env.delete(path);
+            return Err(e);
+        }
+    };
+
+    let num = match path.file_name().map(parse_file_name) {
+        Some(Ok((num, FileType::Table))) => num,
+        _ => 0,
+    };
+    Ok(FileMetaData {
+        num,
+        size,
+        smallest: keys[0].internal_key().to_vec(),
+        largest: keys[keys.len() - 1].internal_key().to_vec(),
+        largest_seqno: start_seq + entries.len() as u64 - 1,
+        ..Default::default()
+    })
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::{io, path::Path, rc::Rc};
+
     use crate::{
         blockhandle::BlockHandle,
         compressor::{self, CompressorId},
+        error::StatusCode,
+        key_types::{parse_internal_key, LookupKey, ValueType},
         options,
-        table_builder::{Footer, TableBuilder},
+        table_block::read_table_block,
+        table_builder::{
+            write_sorted_table, Footer, TableBuilder, FORMAT_VERSION_DEFAULT,
+            FORMAT_VERSION_FIRST_KEY_INDEX,
+        },
+        table_reader::{
+            testutil::{build_raw_table, numbered_entries, open_raw_table, table_entries},
+            Table,
+        },
+        test_util::LdbIteratorIter,
+        types::{current_key_val, LdbIterator},
     };
 
     #[test]
@@ -299,6 +524,18 @@ mod tests {
         assert_eq!(f2.meta_index.size(), 4);
         assert_eq!(f2.index.offset(), 55);
         assert_eq!(f2.index.size(), 5);
+        assert_eq!(f2.version, FORMAT_VERSION_DEFAULT);
+
+        let mut f3 = f2.clone();
+        f3.version = FORMAT_VERSION_FIRST_KEY_INDEX;
+        f3.encode(&mut buf[..]);
+        assert_eq!(
+            FORMAT_VERSION_FIRST_KEY_INDEX,
+            Footer::decode(&buf).unwrap().version
+        );
+
+        buf[47] = 0;
+        assert!(Footer::decode(&buf).is_none());
     }
 
     #[test]
@@ -357,4 +594,206 @@ mod tests {
             b.add(k.as_bytes(), v.as_bytes()).unwrap();
         }
     }
+
+    #[test]
+    fn test_table_builder_add_all() {
+        let mut opt = options::for_test();
+        opt.block_size = 32;
+        let data = numbered_entries(100, b"value");
+        let (_, table) = build_raw_table(opt.clone(), data.clone());
+        assert_eq!(data, table_entries(&table));
+
+        let mut d = vec![];
+        let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+        b.add_all(data.clone().into_iter()).unwrap();
+        assert_eq!(100, b.entries());
+        // Later keys are checked against those added before, across blocks.
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            b.add_all(data[50..51].iter().cloned()).unwrap_err().code
+        );
+
+        // Out of order, and duplicate keys.
+        for bad in [["b", "a"], ["a", "a"]] {
+            let mut d = vec![];
+            let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+            let entries = bad.iter().map(|k| (k.as_bytes().to_vec(), vec![]));
+            assert_eq!(
+                StatusCode::InvalidArgument,
+                b.add_all(entries).unwrap_err().code
+            );
+            assert_eq!(1, b.entries());
+        }
+    }
+
+    /// FailingWriter accepts `limit` bytes, at most 7 per call, and fails afterwards.
+    struct FailingWriter {
+        buf: Vec<u8>,
+        limit: usize,
+    }
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(7).min(self.limit - self.buf.len());
+            if n == 0 && !buf.is_empty() {
+                return Err(io::Error::other("disk full"));
+            }
+            self.buf.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_table_builder_write_errors() {
+        let mut opt = options::for_test();
+        opt.block_size = 32;
+        let data: Vec<(Vec<u8>, Vec<u8>)> = (0..20)
+            .map(|i| (format!("key{:03}", i).into_bytes(), b"value".to_vec()))
+            .collect();
+        let build = |limit| {
+            let mut b = TableBuilder::new_raw(opt.clone(), FailingWriter { buf: vec![], limit });
+            b.add_all(data.clone().into_iter())?;
+            b.finish()
+        };
+
+        // Short writes are completed.
+        let size = build(usize::MAX).unwrap();
+        // Failures while adding entries, and while writing the index or footer.
+        for limit in [0, 100, size - 60, size - 1] {
+            assert_eq!(StatusCode::IOError, build(limit).unwrap_err().code);
+        }
+    }
+
+    #[test]
+    fn test_table_builder_max_key_length() {
+        let mut d = Vec::with_capacity(512);
+        let mut opt = options::for_test();
+        opt.max_key_length = Some(16);
+        let mut b = TableBuilder::new(opt, &mut d);
+
+        b.add(LookupKey::new(b"abc", 1).internal_key(), b"def")
+            .unwrap();
+        // 12 bytes of user key plus 8 bytes of tag.
+        let long = LookupKey::new(b"abcdefghijkl", 1);
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            b.add(long.internal_key(), b"def").unwrap_err().code
+        );
+        assert_eq!(1, b.entries());
+
+        b.add(LookupKey::new(b"abd", 1).internal_key(), b"def")
+            .unwrap();
+        assert_eq!(2, b.entries());
+        assert!(b.finish().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_table_builder_should_flush() {
+        let mut opt = options::for_test();
+        opt.max_file_size = 4096;
+        opt.block_size = 256;
+
+        let data = numbered_entries(2000, b"some value");
+        let mut tables = vec![];
+        let mut data_iter = data.iter().peekable();
+        while data_iter.peek().is_some() {
+            let mut d = vec![];
+            {
+                let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+                for (k, v) in data_iter.by_ref() {
+                    b.add(k, v).unwrap();
+                    if b.should_flush() {
+                        break;
+                    }
+                }
+                b.finish().unwrap();
+            }
+            tables.push(d);
+        }
+        let n = tables.len();
+        assert!(n > 5);
+
+        // Every table is near the target size and can be read on its own.
+        let mut all = vec![];
+        for (i, t) in tables.into_iter().enumerate() {
+            if i < n - 1 {
+                assert!(t.len() >= opt.max_file_size - opt.block_size);
+                assert!(t.len() < opt.max_file_size + opt.block_size);
+            }
+            all.extend(table_entries(&open_raw_table(opt.clone(), t)));
+        }
+        assert_eq!(data, all);
+    }
+
+    #[test]
+    fn test_table_builder_block_alignment() {
+        let mut opt = options::for_test();
+        opt.block_size = 256;
+        opt.block_alignment = Some(4096);
+
+        let data = numbered_entries(500, b"some value");
+        let (d, table) = build_raw_table(opt.clone(), data.clone());
+
+        let footer = Footer::decode(&d[d.len() - super::FULL_FOOTER_LENGTH..]).unwrap();
+        let mut index = read_table_block(opt.clone(), &d, &footer.index, true)
+            .unwrap()
+            .iter();
+        let mut blocks = 0;
+        while index.advance() {
+            let (_, h) = current_key_val(&index).unwrap();
+            let handle = BlockHandle::decode(&h).unwrap().0;
+            assert_eq!(0, handle.offset() % 4096);
+            blocks += 1;
+        }
+        assert!(blocks > 5);
+
+        assert_eq!(data, table_entries(&table));
+        assert_eq!(
+            Some(b"some value".to_vec()),
+            table.get(b"key000321").unwrap().map(|(_, v)| v)
+        );
+    }
+
+    #[test]
+    fn test_write_sorted_table() {
+        let opt = options::for_test();
+        let env = opt.env.clone();
+        let path = Path::new("000012.ldb");
+        let entries: &[(&[u8], &[u8])] = &[(b"abc", b"1"), (b"abd", b"2"), (b"xyz", b"3")];
+
+        let md = write_sorted_table(env.as_ref().as_ref(), path, entries, 100, &opt).unwrap();
+        assert_eq!(12, md.num);
+        assert_eq!(env.size_of(path).unwrap(), md.size);
+        assert_eq!(LookupKey::new(b"abc", 100).internal_key(), &md.smallest[..]);
+        assert_eq!(LookupKey::new(b"xyz", 102).internal_key(), &md.largest[..]);
+        assert_eq!(102, md.largest_seqno);
+
+        let src = env.open_random_access_file(path).unwrap();
+        let table = Table::new(opt.clone(), Rc::new(src), md.size).unwrap();
+        let mut iter = table.iter();
+        let got: Vec<_> = LdbIteratorIter::wrap(&mut iter)
+            .map(|(k, v)| {
+                let (typ, seq, ukey) = parse_internal_key(&k);
+                assert_eq!(ValueType::TypeValue, typ);
+                (ukey.to_vec(), seq, v)
+            })
+            .collect();
+        let want: Vec<_> = entries
+            .iter()
+            .zip(100..)
+            .map(|(&(k, v), seq)| (k.to_vec(), seq, v.to_vec()))
+            .collect();
+        assert_eq!(want, got);
+
+        let unsorted: &[(&[u8], &[u8])] = &[(b"b", b"1"), (b"a", b"2")];
+        assert_eq!(
+            StatusCode::InvalidArgument,
+            write_sorted_table(env.as_ref().as_ref(), "x.ldb", unsorted, 1, &opt)
+                .unwrap_err()
+                .code
+        );
+    }
 }
@@ -56,6 +56,19 @@ impl TableCache {
         tbl.get(key)
     }
 
+    /// get_into is like get(), but stores the entry in the supplied buffers; see
+    /// `Table::get_into()`.
+    pub fn get_into(
+        &mut self,
+        file_num: FileNum,
+        key: InternalKey,
+        kbuf: &mut Vec<u8>,
+        vbuf: &mut Vec<u8>,
+    ) -> Result<bool> {
+        let tbl = self.get_table(file_num)?;
+        tbl.get_into(key, kbuf, vbuf)
+    }
+
     /// Return a table from cache, or open the backing file, then cache and return it.
     pub fn get_table(&mut self, file_num: FileNum) -> Result<Table> {
         let key = filenum_to_key(file_num);
@@ -1,25 +1,31 @@
 use integer_encoding::FixedIntWriter;
 
-use std::{cmp::Ordering, rc::Rc};
+use std::{cell::Cell, cmp::Ordering, io::Write, path::Path, rc::Rc};
 
 use crate::{
     block::{Block, BlockIter},
     blockhandle::BlockHandle,
     cache::{CacheID, CacheKey},
-    cmp::InternalKeyCmp,
-    env::RandomAccess,
+    cmp::{Cmp, InternalKeyCmp},
+    env::{Env, RandomAccess},
     error::{self, err, Result},
-    filter::InternalFilterPolicy,
+    filter::{InternalFilterPolicy, PrefixFilterPolicy, NO_FILTER_POLICY_NAME},
     filter_block::FilterBlockReader,
-    key_types::InternalKey,
+    key_types::{parse_internal_key, InternalKey, LookupKey},
     options::Options,
     table_block,
-    table_builder::{self, Footer},
-    types::{current_key_val, LdbIterator},
+    table_builder::{self, Footer, FORMAT_VERSION_FIRST_KEY_INDEX, PREFIX_EXTRACTOR_META_KEY},
+    types::{current_key_val, LdbIterator, MAX_SEQUENCE_NUMBER},
 };
 
 /// Reads the table footer.
 fn read_footer(f: &dyn RandomAccess, size: usize) -> Result<Footer> {
+    if size < table_builder::FULL_FOOTER_LENGTH {
+        return err(
+            error::StatusCode::Corruption,
+            "file is too short to be a table",
+        );
+    }
     let mut buf = vec![0; table_builder::FULL_FOOTER_LENGTH];
     f.read_at(size - table_builder::FULL_FOOTER_LENGTH, &mut buf)?;
     match Footer::decode(&buf) {
@@ -31,6 +37,110 @@ fn read_footer(f: &dyn RandomAccess, size: usize) -> Result<Footer> {
     }
 }
 
+/// dump_table writes a human-readable description of the table file at `path` (containing
+/// internal keys, like the tables of a database) to `out`: the footer, the index entries, and the
+/// entries of every data block with their sequence number and type. Non-printable bytes of keys
+/// and values are written as hex escapes.
+pub fn dump_table(env: &dyn Env, path: &Path, opt: &Options, out: &mut dyn Write) -> Result<()> {
+    let size = env.size_of(path)?;
+    let file = Rc::new(env.open_random_access_file(path)?);
+    let table = Table::new(opt.clone(), file, size)?;
+    let footer = &table.footer;
+    writeln!(
+        out,
+        "footer: version={} metaindex={} index={}",
+        footer.version,
+        fmt_handle(&footer.meta_index),
+        fmt_handle(&footer.index)
+    )?;
+
+    let mut handles = vec![];
+    let mut index_iter = table.indexblock.iter();
+    let (mut key, mut val) = (vec![], vec![]);
+    writeln!(out, "index:")?;
+    while index_iter.advance() {
+        index_iter.current(&mut key, &mut val);
+        let handle = BlockHandle::decode(&val)
+            .ok_or_else(|| error::Status::new(error::StatusCode::Corruption, "bad index entry"))?
+            .0;
+        writeln!(
+            out,
+            "  {} -> {}",
+            fmt_internal_key(&key),
+            fmt_handle(&handle)
+        )?;
+        handles.push(handle);
+    }
+
+    for handle in handles {
+        let block = table_block::read_table_block(
+            table.opt.clone(),
+            table.file.as_ref().as_ref(),
+            &handle,
+            true,
+        )?;
+        writeln!(out, "data block {}:", fmt_handle(&handle))?;
+        let mut block_iter = block.iter();
+        while block_iter.advance() {
+            block_iter.current(&mut key, &mut val);
+            writeln!(out, "  {} => {}", fmt_internal_key(&key), fmt_bytes(&val))?;
+        }
+    }
+    Ok(())
+}
+
+fn fmt_handle(h: &BlockHandle) -> String {
+    format!("(offset={}, size={})", h.offset(), h.size())
+}
+
+fn fmt_internal_key(k: &[u8]) -> String {
+    let (typ, seq, ukey) = parse_internal_key(k);
+    format!("'{}' @ {} : {:?}", fmt_bytes(ukey), seq, typ)
+}
+
+fn fmt_bytes(b: &[u8]) -> String {
+    b.escape_ascii().to_string()
+}
+
+/// BlockStat describes a single data block of a table, as returned by `Table::block_stats()`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockStat {
+    /// Offset of the block in the table file.
+    pub offset: usize,
+    /// Size of the uncompressed block contents.
+    pub raw_size: usize,
+    /// Size of the block as stored in the file, excluding the compression type and checksum.
+    pub compressed_size: usize,
+    pub num_entries: usize,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+}
+
+/// BlockReadStats counts the data blocks loaded by the table iterators it is attached to (see
+/// `Table::iter_with_stats()`). Clones share the counters.
+#[derive(Clone, Default)]
+pub struct BlockReadStats {
+    blocks: Rc<Cell<usize>>,
+    bytes: Rc<Cell<usize>>,
+}
+
+impl BlockReadStats {
+    fn record(&self, size: usize) {
+        self.blocks.set(self.blocks.get() + 1);
+        self.bytes.set(self.bytes.get() + size);
+    }
+
+    /// blocks returns the number of data blocks loaded (from the block cache or the file).
+    pub fn blocks(&self) -> usize {
+        self.blocks.get()
+    }
+
+    /// bytes returns the total size of the data blocks loaded, as stored in the file.
+    pub fn bytes(&self) -> usize {
+        self.bytes.get()
+    }
+}
+
 #[derive(Clone)]
 pub struct Table {
     file: Rc<Box<dyn RandomAccess>>,
@@ -38,6 +148,8 @@ pub struct Table {
     cache_id: CacheID,
 
     opt: Options,
+    // Set if the table contains internal keys; opt.cmp is an InternalKeyCmp in that case.
+    user_cmp: Option<Rc<Box<dyn Cmp>>>,
 
     footer: Footer,
     indexblock: Block,
@@ -49,14 +161,29 @@ impl Table {
     pub fn new_raw(opt: Options, file: Rc<Box<dyn RandomAccess>>, size: usize) -> Result<Table> {
         let footer = read_footer(file.as_ref().as_ref(), size)?;
 
-        let indexblock =
-            table_block::read_table_block(opt.clone(), file.as_ref().as_ref(), &footer.index)?;
-        let metaindexblock =
-            table_block::read_table_block(opt.clone(), file.as_ref().as_ref(), &footer.meta_index)?;
-
-        // Open filter block for reading
-        let filter_block_reader =
-            Table::read_filter_block(&metaindexblock, file.as_ref().as_ref(), &opt)?;
+        // The index and metaindex blocks are always verified; data blocks only if
+        // opt.paranoid_checks is set.
+        let indexblock = table_block::read_table_block(
+            opt.clone(),
+            file.as_ref().as_ref(),
+            &footer.index,
+            true,
+        )?;
+
+        // The metaindex block only points to the filter block; without a filter policy, there is
+        // nothing to look up and reading it can be skipped.
+        let filter_block_reader = if opt.filter_policy.name() == NO_FILTER_POLICY_NAME {
+            None
+        } else {
+            let metaindexblock = table_block::read_table_block(
+                opt.clone(),
+                file.as_ref().as_ref(),
+                &footer.meta_index,
+                true,
+            )?;
+            // Open filter block for reading
+            Table::read_filter_block(&metaindexblock, file.as_ref().as_ref(), &opt)?
+        };
 
         let cache_id = opt.block_cache.borrow_mut().new_cache_id();
 
@@ -66,6 +193,7 @@ impl Table {
             file_size: size,
             cache_id,
             opt,
+            user_cmp: None,
             footer,
             filters: filter_block_reader,
             indexblock,
@@ -83,6 +211,22 @@ impl Table {
             .to_vec();
 
         let mut metaindexiter = mataix.iter();
+
+        // A filter built from key prefixes can only be used with the same prefix extractor, and
+        // a filter built from whole keys only without one.
+        metaindexiter.seek(PREFIX_EXTRACTOR_META_KEY.as_bytes());
+        let recorded_extractor = match current_key_val(&metaindexiter) {
+            Some((key, val)) if key == PREFIX_EXTRACTOR_META_KEY.as_bytes() => Some(val),
+            _ => None,
+        };
+        let configured_extractor = options
+            .prefix_extractor
+            .as_ref()
+            .map(|e| e.name().into_bytes());
+        if recorded_extractor != configured_extractor {
+            return Ok(None);
+        }
+
         metaindexiter.seek(&filter_name);
 
         if let Some((_key, val)) = current_key_val(&metaindexiter) {
@@ -100,11 +244,34 @@ impl Table {
 
     /// Creates a new table reader operating on internal keys (i.e., InternalKey). This means that
     /// a different comparator (internal_key_cmp) and a different filter policy
-    /// (InternalFilterPolicy) are used.
+    /// (InternalFilterPolicy, wrapping a PrefixFilterPolicy if opt.prefix_extractor is set) are
+    /// used.
     pub fn new(mut opt: Options, file: Rc<Box<dyn RandomAccess>>, size: usize) -> Result<Table> {
+        let user_cmp = opt.cmp.clone();
         opt.cmp = Rc::new(Box::new(InternalKeyCmp(opt.cmp.clone())));
+        if let Some(ref extractor) = opt.prefix_extractor {
+            opt.filter_policy = Rc::new(Box::new(PrefixFilterPolicy::new(
+                opt.filter_policy,
+                extractor.clone(),
+            )));
+        }
         opt.filter_policy = Rc::new(Box::new(InternalFilterPolicy::new(opt.filter_policy)));
-        Table::new_raw(opt, file, size)
+        let mut table = Table::new_raw(opt, file, size)?;
+        table.user_cmp = Some(user_cmp);
+        Ok(table)
+    }
+
+    /// before_block returns true if no entry acceptable for a get() of `key` can be in a block
+    /// starting with `first_key`. For internal keys, an entry with the same user key but an older
+    /// sequence number is acceptable, so only the user keys are compared.
+    fn before_block(&self, key: &[u8], first_key: &[u8]) -> bool {
+        match self.user_cmp {
+            Some(ref ucmp) => {
+                ucmp.cmp(parse_internal_key(key).2, parse_internal_key(first_key).2)
+                    == Ordering::Less
+            }
+            None => self.opt.cmp.cmp(key, first_key) == Ordering::Less,
+        }
     }
 
     /// block_cache_handle creates a CacheKey for a block with a given offset to be used in the
@@ -129,8 +296,12 @@ impl Table {
         }
 
         // Two times as_ref(): First time to get a ref from Rc<>, then on from Box<>.
-        let b =
-            table_block::read_table_block(self.opt.clone(), self.file.as_ref().as_ref(), location)?;
+        let b = table_block::read_table_block(
+            self.opt.clone(),
+            self.file.as_ref().as_ref(),
+            location,
+            self.opt.paranoid_checks,
+        )?;
 
         // insert a cheap copy (Rc).
         self.opt
@@ -154,6 +325,42 @@ impl Table {
         self.footer.meta_index.offset()
     }
 
+    /// block_stats reads every data block of the table and returns statistics about each, in
+    /// file order. The blocks are read directly from the file, bypassing the block cache.
+    pub fn block_stats(&self) -> Result<Vec<BlockStat>> {
+        let mut stats = vec![];
+        let mut index_iter = self.indexblock.iter();
+        let (mut key, mut val) = (vec![], vec![]);
+
+        while index_iter.advance() {
+            index_iter.current(&mut key, &mut val);
+            let handle = BlockHandle::decode(&val).unwrap().0;
+            let block = table_block::read_table_block(
+                self.opt.clone(),
+                self.file.as_ref().as_ref(),
+                &handle,
+                true,
+            )?;
+
+            let mut stat = BlockStat {
+                offset: handle.offset(),
+                raw_size: block.size(),
+                compressed_size: handle.size(),
+                ..Default::default()
+            };
+            let mut block_iter = block.iter();
+            while block_iter.advance() {
+                block_iter.current(&mut stat.last_key, &mut val);
+                if stat.num_entries == 0 {
+                    stat.first_key.clone_from(&stat.last_key);
+                }
+                stat.num_entries += 1;
+            }
+            stats.push(stat);
+        }
+        Ok(stats)
+    }
+
     /// Iterators read from the file; thus only one iterator can be borrowed (mutably) per scope
     pub fn iter(&self) -> TableIterator {
         TableIterator {
@@ -161,9 +368,59 @@ impl Table {
             current_block_off: 0,
             index_block: self.indexblock.iter(),
             table: self.clone(),
+            past_end: false,
+            stats: None,
         }
     }
 
+    /// iter_with_stats is like iter(), but counts the data blocks loaded by the iterator in
+    /// `stats`.
+    pub fn iter_with_stats(&self, stats: Option<BlockReadStats>) -> TableIterator {
+        let mut iter = self.iter();
+        iter.stats = stats;
+        iter
+    }
+
+    /// prefix_may_match returns false if the filters show that the table contains no key starting
+    /// with `prefix`. This requires a prefix extractor (see `Options::prefix_extractor`) for whose
+    /// domain `prefix` is valid, and keys ordered bytewise; otherwise it returns true.
+    ///
+    /// Only the filters of blocks which may contain keys with the prefix are checked.
+    pub fn prefix_may_match(&self, prefix: &[u8]) -> bool {
+        let (filters, extractor) = match (&self.filters, &self.opt.prefix_extractor) {
+            (Some(f), Some(e)) if e.in_domain(prefix) => (f, e),
+            _ => return true,
+        };
+        let prefix = extractor.transform(prefix);
+        let lookup_key = LookupKey::new(prefix, MAX_SEQUENCE_NUMBER);
+        let seek_key = if self.user_cmp.is_some() {
+            lookup_key.internal_key()
+        } else {
+            prefix
+        };
+
+        let mut index_iter = self.indexblock.iter();
+        index_iter.seek(seek_key);
+        while let Some((last_in_block, h)) = current_key_val(&index_iter) {
+            let handle = BlockHandle::decode(&h).unwrap().0;
+            if filters.key_may_match(handle.offset(), seek_key) {
+                return true;
+            }
+            let last_in_block = if self.user_cmp.is_some() {
+                parse_internal_key(&last_in_block).2
+            } else {
+                &last_in_block
+            };
+            // last_in_block is at least the prefix; if it doesn't start with it, all following
+            // blocks only contain bigger keys.
+            if !last_in_block.starts_with(prefix) {
+                break;
+            }
+            index_iter.advance();
+        }
+        false
+    }
+
     /// Retrieve next-biggest entry for key from table. This function uses the attached filters, so is better suited if
     /// you frequently look for non-existing values (as it will detect the non-existence of an
     /// entry in a block without having to load the block).
@@ -176,18 +433,44 @@ impl Table {
     /// exact match; it depends on other comparators than the one that the table reader knows
     /// whether a match is acceptable.
     pub fn get(&self, key: InternalKey) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let (mut k, mut v) = (vec![], vec![]);
+        if self.get_into(key, &mut k, &mut v)? {
+            Ok(Some((k, v)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// get_into is like get(), but stores the entry's key and value in the supplied buffers and
+    /// returns whether an entry was found. The buffers' contents are unspecified if it wasn't.
+    pub fn get_into(
+        &self,
+        key: InternalKey,
+        kbuf: &mut Vec<u8>,
+        vbuf: &mut Vec<u8>,
+    ) -> Result<bool> {
         let mut index_iter = self.indexblock.iter();
         index_iter.seek(key);
 
         let handle;
         if let Some((last_in_block, h)) = current_key_val(&index_iter) {
-            if self.opt.cmp.cmp(key, &last_in_block) == Ordering::Less {
-                handle = BlockHandle::decode(&h).unwrap().0;
+            // The index key is a separator >= every key in its block; it may be the block's last
+            // key itself.
+            if self.opt.cmp.cmp(key, &last_in_block) != Ordering::Greater {
+                let (block_handle, handle_len) = BlockHandle::decode(&h).unwrap();
+                // If the index stores the first key of the block, we may be able to tell that the
+                // key falls between two blocks without reading this one.
+                if self.footer.version >= FORMAT_VERSION_FIRST_KEY_INDEX
+                    && self.before_block(key, &h[handle_len..])
+                {
+                    return Ok(false);
+                }
+                handle = block_handle;
             } else {
-                return Ok(None);
+                return Ok(false);
             }
         } else {
-            return Ok(None);
+            return Ok(false);
         }
 
         // found correct block.
@@ -195,7 +478,7 @@ impl Table {
         // Check bloom (or whatever) filter
         if let Some(ref filters) = self.filters {
             if !filters.key_may_match(handle.offset(), key) {
-                return Ok(None);
+                return Ok(false);
             }
         }
 
@@ -205,12 +488,7 @@ impl Table {
 
         // Go to entry and check if it's the wanted entry.
         iter.seek(key);
-        if let Some((k, v)) = current_key_val(&iter) {
-            if self.opt.cmp.cmp(&k, key) >= Ordering::Equal {
-                return Ok(Some((k, v)));
-            }
-        }
-        Ok(None)
+        Ok(iter.current(kbuf, vbuf) && self.opt.cmp.cmp(kbuf, key) >= Ordering::Equal)
     }
 }
 
@@ -227,6 +505,9 @@ pub struct TableIterator {
     current_block: Option<BlockIter>,
     current_block_off: usize,
     index_block: BlockIter,
+    // Set after a seek past the last entry.
+    past_end: bool,
+    stats: Option<BlockReadStats>,
 }
 
 impl TableIterator {
@@ -246,6 +527,9 @@ impl TableIterator {
     fn load_block(&mut self, handle: &[u8]) -> Result<()> {
         let (new_block_handle, _) = BlockHandle::decode(handle).unwrap();
         let block = self.table.read_block(&new_block_handle)?;
+        if let Some(ref stats) = self.stats {
+            stats.record(new_block_handle.size());
+        }
         self.current_block = Some(block.iter());
         self.current_block_off = new_block_handle.offset();
         Ok(())
@@ -254,6 +538,9 @@ impl TableIterator {
 
 impl LdbIterator for TableIterator {
     fn advance(&mut self) -> bool {
+        if self.past_end {
+            return false;
+        }
         // Uninitialized case.
         if self.current_block.is_none() {
             match self.skip_to_next_entry() {
@@ -293,24 +580,55 @@ impl LdbIterator for TableIterator {
         // first seek in index block, rewind by one entry (so we get the next smaller index entry),
         // then set current_block and seek there
 
+        self.past_end = false;
         self.index_block.seek(to);
 
-        // It's possible that this is a seek past-last; reset in that case.
+        // It's possible that this is a seek past-last.
         if let Some((past_block, handle)) = current_key_val(&self.index_block) {
             if self.table.opt.cmp.cmp(to, &past_block) <= Ordering::Equal {
                 // ok, found right block: continue
                 if let Ok(()) = self.load_block(&handle) {
                     // current_block is always set if load_block() returned Ok.
-                    self.current_block.as_mut().unwrap().seek(to);
+                    let cb = self.current_block.as_mut().unwrap();
+                    cb.seek(to);
+                    if !cb.valid() {
+                        // `to` is between the last key of this block and the separator; the
+                        // first entry of the next block is the one we're looking for.
+                        self.current_block = None;
+                        if !self.advance() {
+                            self.past_end = true;
+                        }
+                    }
                     return;
                 }
             }
+        } else {
+            self.reset();
+            self.past_end = true;
+            return;
         }
         // Reached in case of failure.
         self.reset();
     }
 
+    fn seek_to_last(&mut self) {
+        // Move to the last entry of the last block.
+        self.reset();
+        self.index_block.seek_to_last();
+        if let Some((_, handle)) = current_key_val(&self.index_block) {
+            if self.load_block(&handle).is_ok() {
+                self.current_block.as_mut().unwrap().seek_to_last();
+                return;
+            }
+        }
+        self.reset();
+    }
+
     fn prev(&mut self) -> bool {
+        if self.past_end {
+            self.seek_to_last();
+            return self.valid();
+        }
         // happy path: current block contains previous entry
         if let Some(ref mut cb) = self.current_block {
             if cb.prev() {
@@ -339,6 +657,7 @@ impl LdbIterator for TableIterator {
     fn reset(&mut self) {
         self.index_block.reset();
         self.current_block = None;
+        self.past_end = false;
     }
 
     // This iterator is special in that it's valid even before the first call to advance().
@@ -356,16 +675,61 @@ impl LdbIterator for TableIterator {
     }
 }
 
+#[cfg(test)]
+pub mod testutil {
+    use super::*;
+    use crate::{table_builder::TableBuilder, test_util::LdbIteratorIter};
+
+    /// numbered_entries returns n entries with the keys key000000, key000001, ... and the value
+    /// val.
+    pub fn numbered_entries(n: usize, val: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        (0..n)
+            .map(|i| (format!("key{:06}", i).into_bytes(), val.to_vec()))
+            .collect()
+    }
+
+    /// open_raw_table opens a table of raw keys (no format) from its contents.
+    pub fn open_raw_table(opt: Options, contents: Vec<u8>) -> Table {
+        let size = contents.len();
+        Table::new_raw(opt, Rc::new(Box::new(contents)), size).unwrap()
+    }
+
+    /// build_raw_table builds a table of raw keys from entries, which must be sorted, and returns
+    /// its contents together with the opened table.
+    pub fn build_raw_table<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(
+        opt: Options,
+        entries: I,
+    ) -> (Vec<u8>, Table) {
+        let mut d = vec![];
+        let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+        b.add_all(entries.into_iter()).unwrap();
+        b.finish().unwrap();
+        (d.clone(), open_raw_table(opt, d))
+    }
+
+    /// table_entries returns all entries of table.
+    pub fn table_entries(table: &Table) -> Vec<(Vec<u8>, Vec<u8>)> {
+        LdbIteratorIter::wrap(&mut table.iter()).collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
+    use std::{cell::RefCell, io::Write, path::Path};
+
     use crate::{
+        cache::Cache,
         compressor::{self, CompressorId},
-        filter::BloomPolicy,
+        env::Env,
+        error::StatusCode,
+        filter::{BloomPolicy, FixedPrefixExtractor, NoFilterPolicy},
         key_types::LookupKey,
-        options,
+        mem_env::MemEnv,
+        options::{self, ChecksumType},
         table_builder::TableBuilder,
-        test_util::{test_iterator_properties, LdbIteratorIter},
+        test_util::{test_iterator_properties, test_iterator_seek_past_end, LdbIteratorIter},
+        types::share,
     };
 
     use super::*;
@@ -446,6 +810,229 @@ mod tests {
         Rc::new(Box::new(src))
     }
 
+    /// RecordingFile records the offsets of all reads.
+    struct RecordingFile(Vec<u8>, Rc<RefCell<Vec<usize>>>);
+
+    impl RandomAccess for RecordingFile {
+        fn read_at(&self, off: usize, dst: &mut [u8]) -> Result<usize> {
+            self.1.borrow_mut().push(off);
+            self.0.read_at(off, dst)
+        }
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_table_zstd_roundtrip() {
+        let mut opt = options::for_test();
+        opt.block_size = 32;
+        opt.compressor = compressor::ZstdCompressor::ID;
+
+        let mut d = Vec::with_capacity(512);
+        {
+            let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+            for &(k, v) in build_data().iter() {
+                b.add(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            b.finish().unwrap();
+        }
+        let size = d.len();
+
+        let table = Table::new_raw(opt, wrap_buffer(d), size).unwrap();
+        let entries: Vec<_> = LdbIteratorIter::wrap(&mut table.iter()).collect();
+        assert_eq!(build_data().len(), entries.len());
+        for ((k, v), (ek, ev)) in entries.iter().zip(build_data()) {
+            assert_eq!(ek.as_bytes(), k.as_slice());
+            assert_eq!(ev.as_bytes(), v.as_slice());
+        }
+        assert_eq!(
+            Some((b"xyz".to_vec(), b"xxx".to_vec())),
+            table.get(b"xyz").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_table_prefix_filter() {
+        let mut opt = Options {
+            block_restart_interval: 1,
+            block_size: 32,
+            filter_policy: Rc::new(Box::new(BloomPolicy::new(10))),
+            prefix_extractor: Some(Rc::new(Box::new(FixedPrefixExtractor::new(2)))),
+            ..Default::default()
+        };
+
+        let mut d = Vec::with_capacity(512);
+        {
+            let mut b = TableBuilder::new(opt.clone(), &mut d);
+            for (i, k) in ["aa1", "aa2", "aa3", "ac1", "ac2", "ae1", "ae2", "ae3"]
+                .iter()
+                .enumerate()
+            {
+                b.add(
+                    LookupKey::new(k.as_bytes(), i as u64 + 1).internal_key(),
+                    b"val",
+                )
+                .unwrap();
+            }
+            b.finish().unwrap();
+        }
+        let size = d.len();
+        let data = Rc::new(Box::new(d) as Box<dyn RandomAccess>);
+
+        let table = Table::new(opt.clone(), data.clone(), size).unwrap();
+        assert!(table.filters.is_some());
+        for p in [&b"aa"[..], b"ac", b"ae", b"ac1", b"ac9", b"a"] {
+            assert!(table.prefix_may_match(p), "{:?}", p);
+        }
+        for p in [&b"ab"[..], b"ad", b"ab9", b"af", b"00"] {
+            assert!(!table.prefix_may_match(p), "{:?}", p);
+        }
+        // Point lookups still work with prefix filters.
+        let key = LookupKey::new(b"ac2", 10);
+        assert!(table.get(key.internal_key()).unwrap().is_some());
+
+        // The filters can't be used with a different or without an extractor.
+        opt.prefix_extractor = Some(Rc::new(Box::new(FixedPrefixExtractor::new(1))));
+        let table = Table::new(opt.clone(), data.clone(), size).unwrap();
+        assert!(table.filters.is_none());
+        opt.prefix_extractor = None;
+        let table = Table::new(opt, data, size).unwrap();
+        assert!(table.filters.is_none());
+        assert!(table.prefix_may_match(b"ab"));
+    }
+
+    #[test]
+    fn test_table_open_skips_metaindex_without_filter() {
+        let (src, size) = build_table(build_data());
+        let metaindex_off = read_footer(&src, size).unwrap().meta_index.offset();
+
+        let reads = Rc::new(RefCell::new(vec![]));
+        let file: Rc<Box<dyn RandomAccess>> =
+            Rc::new(Box::new(RecordingFile(src.clone(), reads.clone())));
+        let opt = Options {
+            filter_policy: Rc::new(Box::new(NoFilterPolicy::new())),
+            ..options::for_test()
+        };
+        let table = Table::new_raw(opt, file, size).unwrap();
+        assert!(table.filters.is_none());
+        assert!(!reads.borrow().contains(&metaindex_off));
+        assert_eq!(
+            Some(("abc".as_bytes().to_vec(), "def".as_bytes().to_vec())),
+            table.get("abc".as_bytes()).unwrap()
+        );
+
+        // With a filter policy, the metaindex block is read.
+        let reads = Rc::new(RefCell::new(vec![]));
+        let file: Rc<Box<dyn RandomAccess>> = Rc::new(Box::new(RecordingFile(src, reads.clone())));
+        let table = Table::new_raw(options::for_test(), file, size).unwrap();
+        assert!(table.filters.is_some());
+        assert!(reads.borrow().contains(&metaindex_off));
+    }
+
+    #[test]
+    fn test_table_first_key_index() {
+        let build = |index_first_key| {
+            let mut d = vec![];
+            let opt = Options {
+                block_size: 32,
+                index_first_key,
+                filter_policy: Rc::new(Box::new(NoFilterPolicy::new())),
+                ..options::for_test()
+            };
+            let mut b = TableBuilder::new_raw(opt, &mut d);
+            for &(k, v) in build_data().iter() {
+                b.add(k.as_bytes(), v.as_bytes()).unwrap();
+            }
+            b.finish().unwrap();
+            d
+        };
+        let open = |src: Vec<u8>| {
+            let reads = Rc::new(RefCell::new(vec![]));
+            let size = src.len();
+            let file: Rc<Box<dyn RandomAccess>> =
+                Rc::new(Box::new(RecordingFile(src, reads.clone())));
+            let opt = Options {
+                filter_policy: Rc::new(Box::new(NoFilterPolicy::new())),
+                ..options::for_test()
+            };
+            (Table::new_raw(opt, file, size).unwrap(), reads)
+        };
+
+        let (table, reads) = open(build(true));
+        assert_eq!(FORMAT_VERSION_FIRST_KEY_INDEX, table.footer.version);
+        // Offset of the second block, starting with "bsr".
+        let second_block = table.approx_offset_of(b"bsr");
+        assert!(second_block > 0);
+
+        // "bo" sorts after the separator of the first block, but before the first key of the
+        // second block: it's not found, and the second block is not read.
+        reads.borrow_mut().clear();
+        assert_eq!(None, table.get(b"bo").unwrap());
+        assert!(!reads.borrow().contains(&second_block));
+
+        assert_eq!(
+            Some((b"bsr".to_vec(), b"a00".to_vec())),
+            table.get(b"bsr").unwrap()
+        );
+        assert!(reads.borrow().contains(&second_block));
+
+        // Iteration and seeking are not affected by the index format.
+        let mut iter = table.iter();
+        let entries: Vec<_> = LdbIteratorIter::wrap(&mut iter).collect();
+        assert_eq!(build_data().len(), entries.len());
+        iter.seek(b"bo");
+        assert_eq!(
+            Some((b"bsr".to_vec(), b"a00".to_vec())),
+            current_key_val(&iter)
+        );
+
+        // Without the first key, the second block has to be read.
+        let (table, reads) = open(build(false));
+        assert_eq!(table_builder::FORMAT_VERSION_DEFAULT, table.footer.version);
+        reads.borrow_mut().clear();
+        table.get(b"bo").unwrap();
+        assert!(reads.borrow().contains(&second_block));
+    }
+
+    #[test]
+    fn test_table_first_key_index_internal_keys() {
+        let mut d = vec![];
+        let opt = Options {
+            block_restart_interval: 1,
+            block_size: 32,
+            index_first_key: true,
+            ..options::for_test()
+        };
+        let keys: Vec<Vec<u8>> = build_data()
+            .iter()
+            .enumerate()
+            .map(|(i, (k, _))| {
+                LookupKey::new(k.as_bytes(), i as u64 + 1)
+                    .internal_key()
+                    .to_vec()
+            })
+            .collect();
+        {
+            let mut b = TableBuilder::new(opt.clone(), &mut d);
+            for (k, (_, v)) in keys.iter().zip(build_data()) {
+                b.add(k, v.as_bytes()).unwrap();
+            }
+            b.finish().unwrap();
+        }
+        let size = d.len();
+        let table = Table::new(opt, wrap_buffer(d), size).unwrap();
+
+        // A lookup key with a newer sequence number sorts before the first key of a block, but
+        // still finds it.
+        for (k, v) in build_data() {
+            let (found, val) = table
+                .get(LookupKey::new(k.as_bytes(), 100).internal_key())
+                .unwrap()
+                .unwrap();
+            assert_eq!(k.as_bytes(), parse_internal_key(&found).2);
+            assert_eq!(v.as_bytes(), val.as_slice());
+        }
+    }
+
     #[test]
     fn test_table_approximate_offset() {
         let (src, size) = build_table(build_data());
@@ -464,6 +1051,39 @@ mod tests {
         assert_eq!(137, table.approx_offset_of("{aa".as_bytes()));
     }
 
+    #[test]
+    fn test_table_block_stats() {
+        let data = build_data();
+        let (src, size) = build_table(data.clone());
+        let mut opt = options::for_test();
+        opt.compressor = compressor::SnappyCompressor::ID;
+
+        let table = Table::new_raw(opt.clone(), wrap_buffer(src), size).unwrap();
+        let stats = table.block_stats().unwrap();
+
+        assert_eq!(3, stats.len());
+        assert_eq!(
+            data.len(),
+            stats.iter().map(|s| s.num_entries).sum::<usize>()
+        );
+        assert_eq!(b"abc", stats[0].first_key.as_slice());
+        assert_eq!(b"bcd", stats[0].last_key.as_slice());
+        assert_eq!(b"zzz", stats[2].first_key.as_slice());
+        assert_eq!(b"zzz", stats[2].last_key.as_slice());
+        assert_eq!(0, stats[0].offset);
+        for w in stats.windows(2) {
+            // Each block is followed by its compression type and checksum.
+            let trailer =
+                table_builder::TABLE_BLOCK_COMPRESS_LEN + table_builder::TABLE_BLOCK_CKSUM_LEN;
+            assert_eq!(w[0].offset + w[0].compressed_size + trailer, w[1].offset);
+        }
+        for s in &stats {
+            assert!(s.raw_size > 0 && s.compressed_size > 0);
+        }
+        // The blocks were not added to the block cache.
+        assert_eq!(0, opt.block_cache.borrow().count());
+    }
+
     #[test]
     fn test_table_block_cache_use() {
         let (src, size) = build_table(build_data());
@@ -539,6 +1159,83 @@ mod tests {
         assert_eq!(j, 6);
     }
 
+    #[test]
+    fn test_dump_table() {
+        let (src, _) = build_internal_table();
+        let env = MemEnv::new();
+        let path = Path::new("000005.ldb");
+        env.open_writable_file(path)
+            .unwrap()
+            .write_all(&src)
+            .unwrap();
+
+        let mut out = vec![];
+        dump_table(&env, path, &options::for_test(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("footer: version=1 metaindex=(offset="));
+        assert_eq!("index:", lines[1]);
+
+        // Every data block is listed in the index, and every entry is in a data block.
+        let blocks = lines.iter().filter(|l| l.starts_with("data block")).count();
+        assert!(blocks > 1);
+        assert_eq!(blocks, lines.iter().filter(|l| l.contains(" -> ")).count());
+        assert_eq!(
+            build_data().len(),
+            lines.iter().filter(|l| l.contains(" => ")).count()
+        );
+        assert!(lines.contains(&"  'abc' @ 2 : TypeValue => def"));
+        assert!(lines.contains(&"  'zzz' @ 8 : TypeValue => 111"));
+
+        assert!(dump_table(
+            &env,
+            Path::new("000006.ldb"),
+            &options::for_test(),
+            &mut vec![]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_table_iterators_share_file() {
+        let (src, size) = build_table(build_data());
+        let data = build_data();
+
+        let env = MemEnv::new();
+        let path = Path::new("shared.ldb");
+        env.open_writable_file(path)
+            .unwrap()
+            .write_all(&src)
+            .unwrap();
+
+        // With a cache holding a single block, interleaved iterators read the file alternately.
+        let mut opt = options::for_test();
+        opt.block_cache = share(Cache::with_capacity_bytes(1));
+        let file = Rc::new(env.open_random_access_file(path).unwrap());
+        let table = Table::new_raw(opt, file, size).unwrap();
+
+        let mut fwd = table.iter();
+        let mut bwd = table.iter();
+        bwd.seek_to_last();
+        for i in 0..data.len() {
+            assert!(fwd.advance());
+            let (k, v) = current_key_val(&fwd).unwrap();
+            assert_eq!(
+                (data[i].0.as_bytes(), data[i].1.as_bytes()),
+                (&k[..], &v[..])
+            );
+
+            let j = data.len() - 1 - i;
+            let (k, v) = current_key_val(&bwd).unwrap();
+            assert_eq!(
+                (data[j].0.as_bytes(), data[j].1.as_bytes()),
+                (&k[..], &v[..])
+            );
+            assert_eq!(j > 0, bwd.prev());
+        }
+        assert!(!fwd.advance());
+    }
+
     #[test]
     fn test_table_iterator_filter() {
         let (src, size) = build_table(build_data());
@@ -592,6 +1289,7 @@ mod tests {
         let (src, size) = build_table(data);
         let table = Table::new_raw(Options::default(), wrap_buffer(src), size).unwrap();
         test_iterator_properties(table.iter());
+        test_iterator_seek_past_end(table.iter(), b"zzz");
     }
 
     #[test]
@@ -658,6 +1356,57 @@ mod tests {
         assert!(iter.valid());
     }
 
+    #[test]
+    fn test_table_iterator_seek_absent() {
+        let (src, size) = build_table(build_data());
+        let data = build_data();
+
+        let table = Table::new_raw(Options::default(), wrap_buffer(src), size).unwrap();
+        let mut iter = table.iter();
+
+        // Keys that are not in the table, including ones past the last key of a block.
+        for (target, want) in [
+            ("a", 0),
+            ("abcd", 1),
+            ("bce", 3),
+            ("bzz", 4),
+            ("xzy", 5),
+            ("zz", 6),
+        ] {
+            iter.seek(target.as_bytes());
+            assert_eq!(
+                Some((
+                    data[want].0.as_bytes().to_vec(),
+                    data[want].1.as_bytes().to_vec()
+                )),
+                current_key_val(&iter),
+                "seek to {}",
+                target
+            );
+        }
+
+        // Iterate forward and backward from a seeked position, across block boundaries.
+        iter.seek(b"bce");
+        for (k, v) in &data[4..] {
+            assert!(iter.advance());
+            assert_eq!(
+                Some((k.as_bytes().to_vec(), v.as_bytes().to_vec())),
+                current_key_val(&iter)
+            );
+        }
+        assert!(!iter.advance());
+
+        iter.seek(b"bce");
+        for (k, v) in data[..3].iter().rev() {
+            assert!(iter.prev());
+            assert_eq!(
+                Some((k.as_bytes().to_vec(), v.as_bytes().to_vec())),
+                current_key_val(&iter)
+            );
+        }
+        assert!(!iter.prev());
+    }
+
     #[test]
     fn test_table_get() {
         let (src, size) = build_table(build_data());
@@ -685,6 +1434,25 @@ mod tests {
         assert!(table.get("zz{".as_bytes()).unwrap().is_none());
     }
 
+    #[test]
+    fn test_table_get_into() {
+        let (src, size) = build_table(build_data());
+        let table = Table::new_raw(Options::default(), wrap_buffer(src), size).unwrap();
+
+        let (mut k, mut v) = (Vec::with_capacity(16), Vec::with_capacity(16));
+        let (kptr, vptr) = (k.as_ptr(), v.as_ptr());
+        for (key, val) in build_data() {
+            assert!(table.get_into(key.as_bytes(), &mut k, &mut v).unwrap());
+            assert_eq!(
+                (key.as_bytes(), val.as_bytes()),
+                (k.as_slice(), v.as_slice())
+            );
+        }
+        assert!(!table.get_into(b"aaa", &mut k, &mut v).unwrap());
+        // The buffers were reused.
+        assert_eq!((kptr, vptr), (k.as_ptr(), v.as_ptr()));
+    }
+
     // This test verifies that the table and filters work with internal keys. This means:
     // The table contains keys in InternalKey format and it uses a filter wrapped by
     // InternalFilterPolicy.
@@ -726,7 +1494,11 @@ mod tests {
 
         src[10] += 1;
 
-        let table = Table::new_raw(Options::default(), wrap_buffer(src), size).unwrap();
+        let opt = Options {
+            paranoid_checks: true,
+            ..Default::default()
+        };
+        let table = Table::new_raw(opt, wrap_buffer(src), size).unwrap();
 
         assert!(table.filters.is_some());
         assert_eq!(table.filters.as_ref().unwrap().num(), 1);
@@ -751,4 +1523,65 @@ mod tests {
             panic!("Should have hit 5th record in table!");
         }
     }
+
+    #[test]
+    fn test_table_reader_checksum_toggle() {
+        let (mut src, size) = build_table(build_data());
+        let first = Table::new_raw(options::for_test(), wrap_buffer(src.clone()), size)
+            .unwrap()
+            .block_stats()
+            .unwrap()[0]
+            .clone();
+        let handle = BlockHandle::new(first.offset, first.compressed_size);
+        // Corrupt the checksum following the first data block.
+        src[first.offset + first.compressed_size + 1] ^= 0xff;
+
+        let mut opt = options::for_test();
+        opt.paranoid_checks = true;
+        let table = Table::new_raw(opt, wrap_buffer(src.clone()), size).unwrap();
+        assert_eq!(
+            StatusCode::Corruption,
+            table.read_block(&handle).err().unwrap().code
+        );
+        let mut iter = table.iter();
+        assert_eq!(4, LdbIteratorIter::wrap(&mut iter).count());
+
+        // Without paranoid checks, the data block is returned anyway.
+        let table = Table::new_raw(options::for_test(), wrap_buffer(src), size).unwrap();
+        assert!(table.read_block(&handle).is_ok());
+        let mut iter = table.iter();
+        assert_eq!(build_data().len(), LdbIteratorIter::wrap(&mut iter).count());
+    }
+
+    #[test]
+    fn test_table_reader_checksum_types() {
+        let types = [ChecksumType::Crc32c, ChecksumType::Crc32];
+        for write_type in types {
+            let mut opt = options::for_test();
+            opt.block_size = 32;
+            opt.checksum_type = write_type;
+            let mut d = vec![];
+            {
+                let mut b = TableBuilder::new_raw(opt.clone(), &mut d);
+                for &(k, v) in build_data().iter() {
+                    b.add(k.as_bytes(), v.as_bytes()).unwrap();
+                }
+                b.finish().unwrap();
+            }
+            let size = d.len();
+
+            for read_type in types {
+                opt.checksum_type = read_type;
+                opt.paranoid_checks = true;
+                let table = Table::new_raw(opt.clone(), wrap_buffer(d.clone()), size);
+                if read_type == write_type {
+                    let mut iter = table.unwrap().iter();
+                    assert_eq!(build_data().len(), LdbIteratorIter::wrap(&mut iter).count());
+                } else {
+                    // The index block is always verified.
+                    assert_eq!(StatusCode::Corruption, table.err().unwrap().code);
+                }
+            }
+        }
+    }
 }
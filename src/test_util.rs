@@ -1,4 +1,4 @@
-use std::cmp::Ordering;
+use std::{cell::Cell, cmp::Ordering, rc::Rc};
 
 use crate::{
     cmp::{Cmp, DefaultCmp},
@@ -24,12 +24,13 @@ impl<'a> TestLdbIter<'a> {
 
 impl<'a> LdbIterator for TestLdbIter<'a> {
     fn advance(&mut self) -> bool {
-        if self.ix == self.v.len() - 1 {
-            self.ix += 1;
-            false
-        } else if !self.init {
+        if !self.init {
             self.init = true;
-            true
+            self.ix = 0;
+            self.valid()
+        } else if self.ix + 1 >= self.v.len() {
+            self.ix = self.v.len();
+            false
         } else {
             self.ix += 1;
             true
@@ -93,6 +94,60 @@ impl<'a, It: LdbIterator> Iterator for LdbIteratorIter<'a, It> {
     }
 }
 
+/// CountingCmp is a bytewise comparator counting its invocations of cmp. Giving it an id other
+/// than DefaultCmp's makes it act as a different comparator.
+pub struct CountingCmp {
+    calls: Rc<Cell<usize>>,
+    id: &'static str,
+}
+
+impl CountingCmp {
+    pub fn new(calls: Rc<Cell<usize>>) -> CountingCmp {
+        CountingCmp {
+            calls,
+            id: DefaultCmp.id(),
+        }
+    }
+
+    pub fn with_id(self, id: &'static str) -> CountingCmp {
+        CountingCmp { id, ..self }
+    }
+}
+
+impl Cmp for CountingCmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.calls.set(self.calls.get() + 1);
+        DefaultCmp.cmp(a, b)
+    }
+    fn find_shortest_sep(&self, a: &[u8], b: &[u8]) -> Vec<u8> {
+        DefaultCmp.find_shortest_sep(a, b)
+    }
+    fn find_short_succ(&self, key: &[u8]) -> Vec<u8> {
+        DefaultCmp.find_short_succ(key)
+    }
+    fn id(&self) -> &'static str {
+        self.id
+    }
+}
+
+/// ReverseCmp orders keys in reverse bytewise order.
+pub struct ReverseCmp;
+
+impl Cmp for ReverseCmp {
+    fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        b.cmp(a)
+    }
+    fn find_shortest_sep(&self, a: &[u8], _: &[u8]) -> Vec<u8> {
+        a.to_vec()
+    }
+    fn find_short_succ(&self, key: &[u8]) -> Vec<u8> {
+        key.to_vec()
+    }
+    fn id(&self) -> &'static str {
+        "test.ReverseComparator"
+    }
+}
+
 /// This shared test takes an iterator with exactly four elements and tests that it fulfills the
 /// generic iterator properties. Every iterator defined in this code base should pass this test.
 pub fn test_iterator_properties<It: LdbIterator>(mut it: It) {
@@ -137,6 +192,36 @@ pub fn test_iterator_properties<It: LdbIterator>(mut it: It) {
     assert!(!it.valid());
 }
 
+/// This shared test checks the behavior of an iterator after a seek to `past_end`, a key greater
+/// than all of its entries: The iterator is invalid, advance() returns false, and prev() moves
+/// to the last entry. The iterator must not be empty.
+pub fn test_iterator_seek_past_end<It: LdbIterator>(mut it: It, past_end: &[u8]) {
+    let mut last = None;
+    while it.advance() {
+        last = current_key_val(&it);
+    }
+    assert!(last.is_some());
+
+    it.seek(past_end);
+    assert!(!it.valid());
+    assert!(it.prev());
+    assert!(it.valid());
+    assert_eq!(last, current_key_val(&it));
+
+    it.seek(past_end);
+    assert!(!it.advance());
+    assert!(!it.valid());
+    assert!(!it.advance());
+    assert!(it.prev());
+    assert_eq!(last, current_key_val(&it));
+
+    // reset() starts over.
+    it.seek(past_end);
+    it.reset();
+    assert!(it.advance());
+    assert!(it.valid());
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -168,6 +253,27 @@ mod tests {
                 ("abf".as_bytes(), "deg".as_bytes()),
             ];
         }
-        test_iterator_properties(TestLdbIter::new(v));
+        test_iterator_properties(TestLdbIter::new(v.clone()));
+        test_iterator_seek_past_end(TestLdbIter::new(v), b"xyz");
+    }
+
+    #[test]
+    fn test_test_util_seek_to_last() {
+        // TestLdbIter uses the default implementation.
+        let v = vec![
+            ("abc".as_bytes(), "def".as_bytes()),
+            ("abd".as_bytes(), "deg".as_bytes()),
+        ];
+        let mut iter = TestLdbIter::new(v);
+        iter.seek_to_last();
+        assert_eq!(
+            Some((b"abd".to_vec(), b"deg".to_vec())),
+            current_key_val(&iter)
+        );
+        assert!(!iter.advance());
+
+        let mut iter = TestLdbIter::new(vec![]);
+        iter.seek_to_last();
+        assert!(!iter.valid());
     }
 }
@@ -0,0 +1,256 @@
+//! Support for `Options::ttl`: values are stored together with the time they were written, and
+//! expire once they are older than the TTL.
+
+use std::time::Duration;
+
+use integer_encoding::FixedInt;
+
+use crate::{
+    key_types::{parse_internal_key, LookupKey, ValueType},
+    options::Options,
+    types::LdbIterator,
+    write_batch::WriteBatch,
+};
+
+/// Length of the write time stored in front of values.
+pub const TIMESTAMP_LEN: usize = 8;
+
+/// Expiry decides which values have expired at a given point in time.
+#[derive(Clone, Copy, Debug)]
+pub struct Expiry {
+    now: u64,
+    ttl: u64,
+}
+
+impl Expiry {
+    pub fn new(now: u64, ttl: Duration) -> Expiry {
+        Expiry {
+            now,
+            ttl: ttl.as_micros().min(u64::MAX as u128) as u64,
+        }
+    }
+
+    /// from_options returns an Expiry for the current time of the options' environment, or None
+    /// if no TTL is set.
+    pub fn from_options(opt: &Options) -> Option<Expiry> {
+        opt.ttl.map(|ttl| Expiry::new(opt.env.micros(), ttl))
+    }
+
+    /// stamp returns val prefixed with the current time.
+    pub fn stamp(&self, val: &[u8]) -> Vec<u8> {
+        let mut stamped = Vec::with_capacity(TIMESTAMP_LEN + val.len());
+        stamped.extend_from_slice(&self.now.encode_fixed_vec());
+        stamped.extend_from_slice(val);
+        stamped
+    }
+
+    /// stamp_batch returns a copy of batch in which the values of puts are stamped with the
+    /// current time.
+    pub fn stamp_batch(&self, batch: &WriteBatch) -> WriteBatch {
+        let mut stamped = WriteBatch::new();
+        stamped.set_sync(batch.is_sync());
+        for (typ, k, v) in batch.iter_typed() {
+            match (typ, v) {
+                (ValueType::TypeValue, Some(v)) => stamped.put_typed(typ, k, &self.stamp(v)),
+                (_, v) => stamped.put_typed(typ, k, v.unwrap_or_default()),
+            }
+        }
+        stamped
+    }
+
+    /// is_expired returns true if the stamped value val was written more than the TTL ago.
+    /// Values too short to carry a stamp never expire.
+    pub fn is_expired(&self, val: &[u8]) -> bool {
+        match u64::decode_fixed(val.get(..TIMESTAMP_LEN).unwrap_or_default()) {
+            Some(written) => self.now.saturating_sub(written) > self.ttl,
+            None => false,
+        }
+    }
+
+    /// resolve returns false if the stamped value val has expired, and otherwise removes the stamp
+    /// from it.
+    pub fn resolve(&self, val: &mut Vec<u8>) -> bool {
+        if self.is_expired(val) {
+            return false;
+        }
+        val.drain(..TIMESTAMP_LEN.min(val.len()));
+        true
+    }
+}
+
+/// unstamped returns val without the stamp written in front of it.
+pub fn unstamped(val: &[u8]) -> &[u8] {
+    val.get(TIMESTAMP_LEN..).unwrap_or_default()
+}
+
+/// TtlIter wraps an iterator over internal keys and stamped values, and turns expired values
+/// into deletions. If `strip` is set, the stamps are removed from the other values.
+pub struct TtlIter {
+    inner: Box<dyn LdbIterator>,
+    expiry: Expiry,
+    strip: bool,
+}
+
+impl TtlIter {
+    pub fn new(inner: Box<dyn LdbIterator>, expiry: Expiry, strip: bool) -> TtlIter {
+        TtlIter {
+            inner,
+            expiry,
+            strip,
+        }
+    }
+
+    /// wrap wraps each of iters in a TtlIter if expiry is set.
+    pub fn wrap(
+        iters: Vec<Box<dyn LdbIterator>>,
+        expiry: Option<Expiry>,
+        strip: bool,
+    ) -> Vec<Box<dyn LdbIterator>> {
+        match expiry {
+            Some(expiry) => iters
+                .into_iter()
+                .map(|it| Box::new(TtlIter::new(it, expiry, strip)) as Box<dyn LdbIterator>)
+                .collect(),
+            None => iters,
+        }
+    }
+}
+
+impl LdbIterator for TtlIter {
+    fn advance(&mut self) -> bool {
+        self.inner.advance()
+    }
+
+    fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool {
+        if !self.inner.current(key, val) {
+            return false;
+        }
+        let (typ, seq, ukey) = parse_internal_key(key);
+        if typ != ValueType::TypeValue || seq == 0 {
+            return true;
+        }
+        let live = if self.strip {
+            self.expiry.resolve(val)
+        } else {
+            !self.expiry.is_expired(val)
+        };
+        if !live {
+            let deletion = LookupKey::new_full(ukey, seq, ValueType::TypeDeletion);
+            *key = deletion.internal_key().to_vec();
+            val.clear();
+        }
+        true
+    }
+
+    fn seek(&mut self, key: &[u8]) {
+        self.inner.seek(key)
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset()
+    }
+
+    fn valid(&self) -> bool {
+        self.inner.valid()
+    }
+
+    fn prev(&mut self) -> bool {
+        self.inner.prev()
+    }
+
+    fn seek_to_first(&mut self) {
+        self.inner.seek_to_first()
+    }
+
+    fn seek_to_last(&mut self) {
+        self.inner.seek_to_last()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memtable::MemTable, options, test_util::LdbIteratorIter};
+
+    #[test]
+    fn test_ttl_iter() {
+        let opt = options::for_test();
+        let old = Expiry::new(1_000, Duration::from_micros(500));
+        let new = Expiry::new(2_000, Duration::from_micros(500));
+        let mut mt = MemTable::new(opt.cmp.clone());
+        mt.add(1, ValueType::TypeValue, b"aaa", &old.stamp(b"a"));
+        mt.add(2, ValueType::TypeValue, b"bbb", &new.stamp(b"b"));
+        mt.add(3, ValueType::TypeDeletion, b"ccc", b"");
+
+        let expiry = Expiry::new(2_000, Duration::from_micros(500));
+        assert!(expiry.is_expired(&old.stamp(b"a")));
+        assert!(!expiry.is_expired(&new.stamp(b"b")));
+        assert!(!expiry.is_expired(b"short"));
+
+        let mut it = TtlIter::new(Box::new(mt.iter()), expiry, true);
+        let entries: Vec<_> = LdbIteratorIter::wrap(&mut it)
+            .map(|(k, v)| {
+                let (typ, seq, ukey) = parse_internal_key(&k);
+                (ukey.to_vec(), seq, typ, v)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (b"aaa".to_vec(), 1, ValueType::TypeDeletion, vec![]),
+                (b"bbb".to_vec(), 2, ValueType::TypeValue, b"b".to_vec()),
+                (b"ccc".to_vec(), 3, ValueType::TypeDeletion, vec![]),
+            ],
+            entries
+        );
+
+        // Without strip, live values keep their stamp.
+        let mut it = TtlIter::new(Box::new(mt.iter()), expiry, false);
+        let (mut k, mut v) = (vec![], vec![]);
+        it.seek(LookupKey::new(b"bbb", 10).internal_key());
+        assert!(it.current(&mut k, &mut v));
+        assert_eq!(b"b", unstamped(&v));
+        assert_eq!(new.stamp(b"b"), v);
+
+        // Seeking to the last entry works if it is an expired value.
+        mt.add(4, ValueType::TypeValue, b"ddd", &old.stamp(b"d"));
+        let mut it = TtlIter::new(Box::new(mt.iter()), expiry, true);
+        it.seek_to_last();
+        assert!(it.current(&mut k, &mut v));
+        let (typ, seq, ukey) = parse_internal_key(&k);
+        assert_eq!(
+            (b"ddd".as_slice(), 4, ValueType::TypeDeletion),
+            (ukey, seq, typ)
+        );
+        it.seek_to_first();
+        assert!(it.current(&mut k, &mut v));
+        assert_eq!(b"aaa", parse_internal_key(&k).2);
+    }
+
+    #[test]
+    fn test_ttl_stamp_batch() {
+        let expiry = Expiry::new(7, Duration::from_secs(1));
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"1");
+        batch.delete(b"b");
+        batch.merge(b"c", b"2");
+        batch.set_sync(true);
+        let stamped = expiry.stamp_batch(&batch);
+        assert!(stamped.is_sync());
+        let ops: Vec<_> = stamped
+            .iter_typed()
+            .map(|(t, k, v)| (t, k.to_vec(), v.map(|v| v.to_vec())))
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    ValueType::TypeValue,
+                    b"a".to_vec(),
+                    Some(expiry.stamp(b"1"))
+                ),
+                (ValueType::TypeDeletion, b"b".to_vec(), None),
+                (ValueType::TypeMerge, b"c".to_vec(), Some(b"2".to_vec())),
+            ],
+            ops
+        );
+    }
+}
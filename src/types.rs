@@ -22,6 +22,17 @@ pub enum Direction {
     Reverse,
 }
 
+/// The outcome of a lookup, as returned by `DB::get_detailed()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GetResult {
+    /// The key has a live value.
+    Found(Vec<u8>),
+    /// The newest entry for the key is a deletion, or the key is covered by a range tombstone.
+    Deleted,
+    /// No entry exists for the key.
+    NotFound,
+}
+
 /// Denotes a key range
 pub struct Range<'a> {
     pub start: &'a [u8],
@@ -41,9 +52,12 @@ pub trait LdbIterator {
     fn advance(&mut self) -> bool;
     /// Return the currentite4m(i.e the item most recently returned by `next()`)
     fn current(&self, key: &mut Vec<u8>, val: &mut Vec<u8>) -> bool;
-    /// Seek the iterator to `key` or the next bigger key. If the seek is invalid (past last
-    /// element, or before first element the iterator is reset() and not valid.
-    /// After a seek to an existing key, current() returns that entry.
+    /// Seek the iterator to `key` or the next bigger key. After a seek to an existing key,
+    /// current() returns that entry.
+    ///
+    /// If `key` is past the last element, the iterator is `!valid()` afterwards; `advance()` then
+    /// returns false, while `prev()` moves to the last element.
+    /// test_util::test_iterator_seek_past_end() verifies this.
     fn seek(&mut self, key: &[u8]);
     /// Resets the iterator to be `!valid()`, i.e. positioned before the first element.
     fn reset(&mut self);
@@ -77,6 +91,45 @@ pub trait LdbIterator {
         self.reset();
         self.advance();
     }
+
+    /// seek_to_last seeks to the last element, or makes the iterator `!valid()` if it is empty.
+    /// The default implementation scans the whole iterator and seeks back to the last key seen;
+    /// implementations that can find the end directly should override it.
+    fn seek_to_last(&mut self) {
+        self.reset();
+        let (mut key, mut val) = (vec![], vec![]);
+        let mut found = false;
+        while self.advance() {
+            found = self.current(&mut key, &mut val);
+        }
+        if found {
+            self.seek(&key);
+        }
+    }
+
+    /// count_remaining advances the iterator until it is exhausted and returns the number of
+    /// entries from the current position onwards. If the iterator is `valid()`, the current entry
+    /// is counted; on an iterator that is `!valid()` it counts all entries.
+    fn count_remaining(&mut self) -> usize {
+        let mut n = if self.valid() { 1 } else { 0 };
+        while self.advance() {
+            n += 1;
+        }
+        n
+    }
+
+    /// collect_kv advances the iterator until it is exhausted and returns all entries from the
+    /// current position onwards, including the current entry like `count_remaining()`.
+    fn collect_kv(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries = vec![];
+        if let Some(kv) = current_key_val(self) {
+            entries.push(kv);
+        }
+        while let Some(kv) = self.next() {
+            entries.push(kv);
+        }
+        entries
+    }
 }
 
 /// current_key_val is a helper allocating two vectors and filling them with the current key/value
@@ -109,6 +162,9 @@ impl LdbIterator for Box<dyn LdbIterator> {
     fn prev(&mut self) -> bool {
         self.as_mut().prev()
     }
+    fn seek_to_last(&mut self) {
+        self.as_mut().seek_to_last()
+    }
 }
 
 /// The unique (sequential) number of a file
@@ -124,6 +180,8 @@ pub struct FileMetaData {
     // these are in InternalKey format:
     pub smallest: Vec<u8>,
     pub largest: Vec<u8>,
+    // The largest sequence number of an entry in the file, or 0 if unknown.
+    pub largest_seqno: SequenceNumber,
 }
 
 #[derive(Debug, Clone, PartialEq)]
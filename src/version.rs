@@ -4,9 +4,12 @@ use std::rc::Rc;
 use crate::{
     cmp::InternalKeyCmp,
     error::Result,
-    key_types::{parse_internal_key, InternalKey, LookupKey, UserKey, ValueType},
-    table_reader::TableIterator,
-    types::{FileNum, LdbIterator, Shared, MAX_SEQUENCE_NUMBER, NUM_LEVELS},
+    key_types::{
+        max_covering_seq, parse_internal_key, InternalKey, LookupKey, RangeTombstone, UserKey,
+        ValueType,
+    },
+    table_reader::{BlockReadStats, TableIterator},
+    types::{FileNum, GetResult, LdbIterator, Shared, MAX_SEQUENCE_NUMBER, NUM_LEVELS},
     {cmp::Cmp, table_cache::TableCache, types::FileMetaData},
 };
 
@@ -21,15 +24,23 @@ pub struct GetStats {
     level: usize,
 }
 
+/// The type and value of an entry, as returned by `Version::get_entry()`.
+type Entry = (ValueType, Vec<u8>);
+
 pub struct Version {
     table_cache: Shared<TableCache>,
     user_cmp: Rc<Box<dyn Cmp>>,
     pub files: [Vec<FileMetaHandle>; NUM_LEVELS],
+    // Range tombstones that may still cover entries in the files.
+    pub range_tombstones: Vec<RangeTombstone>,
 
     pub file_to_compact: Option<FileMetaHandle>,
     pub file_to_compact_lvl: usize,
     pub compaction_score: Option<f64>,
     pub compaction_level: Option<usize>,
+
+    // If set, files in level 0 are assumed to not overlap (see Options::assume_disjoint_l0).
+    pub assume_disjoint_l0: bool,
 }
 
 impl Version {
@@ -38,10 +49,12 @@ impl Version {
             table_cache: cache,
             user_cmp: ucmp,
             files: Default::default(),
+            range_tombstones: vec![],
             file_to_compact: None,
             file_to_compact_lvl: 0,
             compaction_score: None,
             compaction_level: None,
+            assume_disjoint_l0: false,
         }
     }
 
@@ -57,15 +70,58 @@ impl Version {
 
     /// get returns the value for the specified key using the persistent tables contained in this
     /// Version.
-    #[allow(unused_assignments)]
     pub fn get(&self, key: InternalKey) -> Result<Option<(Vec<u8>, GetStats)>> {
+        match self.get_detailed(key)? {
+            (GetResult::Found(v), stats) => Ok(Some((v, stats))),
+            _ => Ok(None),
+        }
+    }
+
+    /// get_detailed is like get(), but distinguishes between keys that were never written and
+    /// keys whose newest entry is a deletion (or which are covered by a range tombstone).
+    ///
+    /// A merge operand is returned like a value; use get_entry() to tell them apart.
+    pub fn get_detailed(&self, key: InternalKey) -> Result<(GetResult, GetStats)> {
+        let (entry, stats) = self.get_entry(key)?;
+        let r = match entry {
+            Some((ValueType::TypeDeletion, _)) => GetResult::Deleted,
+            Some((_, v)) => GetResult::Found(v),
+            None => GetResult::NotFound,
+        };
+        Ok((r, stats))
+    }
+
+    /// get_entry returns the type and value of the newest entry for key, or None if there is
+    /// none. Entries covered by a range tombstone are returned as `TypeDeletion`.
+    pub fn get_entry(&self, key: InternalKey) -> Result<(Option<Entry>, GetStats)> {
+        let mut buf = vec![];
+        let (typ, stats) = self.get_entry_into(key, &mut buf)?;
+        Ok((typ.map(|t| (t, buf)), stats))
+    }
+
+    /// get_entry_into is like get_entry(), but stores the value in buf instead of allocating a
+    /// new vector. buf is left empty if no value is found.
+    #[allow(unused_assignments)]
+    pub fn get_entry_into(
+        &self,
+        key: InternalKey,
+        buf: &mut Vec<u8>,
+    ) -> Result<(Option<ValueType>, GetStats)> {
+        buf.clear();
         let levels = self.get_overlapping(key);
         let ikey = key;
-        let ukey = parse_internal_key(ikey).2;
+        let (_, seq, ukey) = parse_internal_key(ikey);
+        let tombstone_seq = max_covering_seq(
+            self.range_tombstones.iter(),
+            self.user_cmp.as_ref().as_ref(),
+            ukey,
+            seq,
+        );
         let mut stats = GetStats {
             file: None,
             level: 0,
         };
+        let mut kbuf = vec![];
 
         for (level, files) in levels.iter().enumerate() {
             let mut last_read = None;
@@ -81,22 +137,31 @@ impl Version {
                 // We receive both key and value from the table. Because we're using InternalKey
                 // keys, we now need to check whether the found entry's user key is equal to the
                 // one we're looking for (get() just returns the next-bigger key).
-                if let Ok(Some((k, v))) = self.table_cache.borrow_mut().get(f.borrow().num, ikey) {
+                let found =
+                    self.table_cache
+                        .borrow_mut()
+                        .get_into(f.borrow().num, ikey, &mut kbuf, buf);
+                if let Ok(true) = found {
                     // We don't need to check the sequence number; get() will not return an entry
                     // with a higher sequence number than the one in the supplied key.
-                    let (typ, _, foundkey) = parse_internal_key(&k);
-                    if typ == ValueType::TypeValue
-                        && self.user_cmp.cmp(foundkey, ukey) == Ordering::Equal
-                    {
-                        return Ok(Some((v, stats)));
-                    } else if typ == ValueType::TypeDeletion {
-                        // Skip looking once we have found a deletion.
-                        return Ok(None);
+                    let (typ, fseq, foundkey) = parse_internal_key(&kbuf);
+                    if self.user_cmp.cmp(foundkey, ukey) != Ordering::Equal {
+                        continue;
                     }
+                    if tombstone_seq.is_some_and(|s| fseq < s) {
+                        buf.clear();
+                        return Ok((Some(ValueType::TypeDeletion), stats));
+                    }
+                    // Skip looking once we have found an entry.
+                    return Ok((Some(typ), stats));
                 }
             }
         }
-        Ok(None)
+        buf.clear();
+        if tombstone_seq.is_some() {
+            return Ok((Some(ValueType::TypeDeletion), stats));
+        }
+        Ok((None, stats))
     }
 
     /// get_overlapping returns the files overlapping key in each level.
@@ -105,26 +170,37 @@ impl Version {
         let ikey = key;
         let ukey = parse_internal_key(key).2;
 
-        let files = &self.files[0];
-        levels[0].reserve(files.len());
-        for f_ in files {
-            let f = f_.borrow();
-            let (fsmallest, flargest) = (
-                parse_internal_key(&f.smallest).2,
-                parse_internal_key(&f.largest).2,
-            );
-            if self.user_cmp.cmp(ukey, fsmallest) >= Ordering::Equal
-                && self.user_cmp.cmp(ukey, flargest) <= Ordering::Equal
-            {
-                levels[0].push(f_.clone());
+        // Level 0 files may overlap, unless we have been told otherwise; in that case, level 0 is
+        // searched like all other levels.
+        let first_sorted_level = if self.assume_disjoint_l0 { 0 } else { 1 };
+
+        if first_sorted_level > 0 {
+            let files = &self.files[0];
+            levels[0].reserve(files.len());
+            for f_ in files {
+                let f = f_.borrow();
+                let (fsmallest, flargest) = (
+                    parse_internal_key(&f.smallest).2,
+                    parse_internal_key(&f.largest).2,
+                );
+                if self.user_cmp.cmp(ukey, fsmallest) >= Ordering::Equal
+                    && self.user_cmp.cmp(ukey, flargest) <= Ordering::Equal
+                {
+                    levels[0].push(f_.clone());
+                }
             }
+            // Sort by newest first.
+            levels[0].sort_by(|a, b| b.borrow().num.cmp(&a.borrow().num));
         }
-        // Sort by newest first.
-        levels[0].sort_by(|a, b| b.borrow().num.cmp(&a.borrow().num));
 
         let icmp = InternalKeyCmp(self.user_cmp.clone());
 
-        for (level, item) in levels.iter_mut().enumerate().take(NUM_LEVELS).skip(1) {
+        for (level, item) in levels
+            .iter_mut()
+            .enumerate()
+            .take(NUM_LEVELS)
+            .skip(first_sorted_level)
+        {
             let files = &self.files[level];
             if let Some(ix) = find_file(&icmp, files, ikey) {
                 let f = files[ix].borrow();
@@ -343,7 +419,7 @@ impl Version {
     }
 
     /// new_concat_iter returns an itarator that iterates over the files in a level. Note that this
-    /// only really makes sense for levels > 0
+    /// only really makes sense for levels > 0 (or level 0 if `assume_disjoint_l0` is set)
     fn new_concat_iter(&self, level: usize) -> VersionIter {
         new_version_iter(
             self.files[level].clone(),
@@ -355,19 +431,34 @@ impl Version {
     /// new_iters returns a set of iterators that can be merged to yield all entries in this
     /// version
     pub fn new_iters(&self) -> Result<Vec<Box<dyn LdbIterator>>> {
+        self.new_iters_with_stats(None)
+    }
+
+    /// new_iters_with_stats is like new_iters(), but the data blocks loaded by the iterators are
+    /// counted in `stats`.
+    pub fn new_iters_with_stats(
+        &self,
+        stats: Option<BlockReadStats>,
+    ) -> Result<Vec<Box<dyn LdbIterator>>> {
         let mut iters: Vec<Box<dyn LdbIterator>> = vec![];
-        for f in &self.files[0] {
-            iters.push(Box::new(
-                self.table_cache
-                    .borrow_mut()
-                    .get_table(f.borrow().num)?
-                    .iter(),
-            ));
+        let mut first_sorted_level = 0;
+        if !self.assume_disjoint_l0 {
+            for f in &self.files[0] {
+                iters.push(Box::new(
+                    self.table_cache
+                        .borrow_mut()
+                        .get_table(f.borrow().num)?
+                        .iter_with_stats(stats.clone()),
+                ));
+            }
+            first_sorted_level = 1;
         }
 
-        for l in 1..NUM_LEVELS {
+        for l in first_sorted_level..NUM_LEVELS {
             if !self.files[l].is_empty() {
-                iters.push(Box::new(self.new_concat_iter(l)));
+                let mut iter = self.new_concat_iter(l);
+                iter.stats.clone_from(&stats);
+                iters.push(Box::new(iter));
             }
         }
 
@@ -388,6 +479,8 @@ pub fn new_version_iter(
         cmp: InternalKeyCmp(ucmp),
         current: None,
         current_ix: 0,
+        past_end: false,
+        stats: None,
     }
 }
 
@@ -404,11 +497,31 @@ pub struct VersionIter {
 
     current: Option<TableIterator>,
     current_ix: usize,
+    // Set after a seek past the last entry.
+    past_end: bool,
+    stats: Option<BlockReadStats>,
+}
+
+impl VersionIter {
+    /// load_table_at returns an iterator over the table at files[ix], positioned at key.
+    fn load_table_at(&self, ix: usize, key: &[u8]) -> Option<TableIterator> {
+        let tbl = self
+            .cache
+            .borrow_mut()
+            .get_table(self.files[ix].borrow().num)
+            .ok()?;
+        let mut iter = tbl.iter_with_stats(self.stats.clone());
+        iter.seek(key);
+        Some(iter)
+    }
 }
 
 impl LdbIterator for VersionIter {
     fn advance(&mut self) -> bool {
         assert!(!self.files.is_empty());
+        if self.past_end {
+            return false;
+        }
         if let Some(ref mut t) = self.current {
             if t.advance() {
                 return true;
@@ -427,7 +540,7 @@ impl LdbIterator for VersionIter {
             .borrow_mut()
             .get_table(self.files[self.current_ix].borrow().num)
         {
-            self.current = Some(tbl.iter());
+            self.current = Some(tbl.iter_with_stats(self.stats.clone()));
         } else {
             return false;
         }
@@ -442,27 +555,28 @@ impl LdbIterator for VersionIter {
     }
 
     fn seek(&mut self, key: &[u8]) {
-        if let Some(ix) = find_file(&self.cmp, &self.files, key) {
-            if let Ok(tbl) = self
-                .cache
-                .borrow_mut()
-                .get_table(self.files[ix].borrow().num)
-            {
-                let mut iter = tbl.iter();
-                iter.seek(key);
-                if iter.valid() {
+        match find_file(&self.cmp, &self.files, key) {
+            Some(ix) => {
+                if let Some(iter) = self.load_table_at(ix, key).filter(|it| it.valid()) {
                     self.current_ix = ix;
                     self.current = Some(iter);
+                    self.past_end = false;
                     return;
                 }
+                self.reset();
+            }
+            None => {
+                // key is past the largest key of the last file.
+                self.reset();
+                self.past_end = true;
             }
         }
-        self.reset();
     }
 
     fn reset(&mut self) {
         self.current = None;
         self.current_ix = 0;
+        self.past_end = false;
     }
 
     fn valid(&self) -> bool {
@@ -470,19 +584,28 @@ impl LdbIterator for VersionIter {
     }
 
     fn prev(&mut self) -> bool {
-        if let Some(ref mut t) = self.current {
+        if self.past_end {
+            // Move to the last entry of the last table.
+            self.past_end = false;
+            let ix = self.files.len() - 1;
+            if let Some(iter) = self.load_table_at(ix, &self.files[ix].borrow().largest) {
+                // The saved largest key must be in the table.
+                assert!(iter.valid());
+                self.current_ix = ix;
+                self.current = Some(iter);
+                return true;
+            }
+        } else if let Some(ref mut t) = self.current {
             if t.prev() {
                 return true;
             } else if self.current_ix > 0 {
-                let f = &self.files[self.current_ix - 1];
                 // Find previous table, seek to last entry.
-                if let Ok(tbl) = self.cache.borrow_mut().get_table(f.borrow().num) {
-                    let mut iter = tbl.iter();
-                    iter.seek(&f.borrow().largest);
+                let ix = self.current_ix - 1;
+                if let Some(iter) = self.load_table_at(ix, &self.files[ix].borrow().largest) {
                     // The saved largest key must be in the table.
                     assert!(iter.valid());
-                    self.current_ix -= 1;
-                    *t = iter;
+                    self.current_ix = ix;
+                    self.current = Some(iter);
                     return true;
                 }
             }
@@ -490,6 +613,18 @@ impl LdbIterator for VersionIter {
         self.reset();
         false
     }
+
+    fn seek_to_last(&mut self) {
+        self.reset();
+        if self.files.is_empty() {
+            return;
+        }
+        let ix = self.files.len() - 1;
+        if let Some(iter) = self.load_table_at(ix, &self.files[ix].borrow().largest) {
+            self.current_ix = ix;
+            self.current = Some(iter);
+        }
+    }
 }
 
 /// total_size returns the sum of sizes of the given files.
@@ -572,7 +707,7 @@ pub mod testutil {
         cmp::DefaultCmp,
         env::Env,
         options::{self, Options},
-        table_builder::TableBuilder,
+        table_builder::write_table_entries,
         table_cache::table_file_name,
         types::{share, FileNum},
     };
@@ -590,6 +725,7 @@ pub mod testutil {
             num,
             smallest: LookupKey::new(smallest, smallestix).internal_key().to_vec(),
             largest: LookupKey::new(largest, largestix).internal_key().to_vec(),
+            largest_seqno: smallestix.max(largestix),
         })
     }
 
@@ -602,33 +738,19 @@ pub mod testutil {
         startseq: u64,
         num: FileNum,
     ) -> FileMetaHandle {
-        let dst = me
-            .open_writable_file(Path::new(&table_file_name("db", num)))
-            .unwrap();
-        let mut seq = startseq;
-        let keys: Vec<Vec<u8>> = contents
-            .iter()
-            .map(|&(k, _, typ)| {
-                seq += 1;
-                LookupKey::new_full(k, seq - 1, typ).internal_key().to_vec()
-            })
-            .collect();
-
-        let mut tbl = TableBuilder::new(options::for_test(), dst);
-        for i in 0..contents.len() {
-            tbl.add(&keys[i], contents[i].1).unwrap();
-            seq += 1;
-        }
-
-        let f = new_file(
-            num,
-            contents[0].0,
+        let path = table_file_name("db", num);
+        let md = write_table_entries(
+            me.as_ref(),
+            Path::new(&path),
+            contents,
             startseq,
-            contents[contents.len() - 1].0,
-            startseq + (contents.len() - 1) as u64,
-        );
-        f.borrow_mut().size = tbl.finish().unwrap();
-        f
+            &options::for_test(),
+        )
+        .unwrap();
+        share(FileMetaData {
+            allowed_seeks: 10,
+            ..md
+        })
     }
 
     pub fn make_version() -> (Version, Options) {
@@ -714,21 +836,26 @@ pub mod testutil {
 
 #[cfg(test)]
 mod tests {
-    use std::{cmp::Ordering, rc::Rc};
+    use std::{cell::Cell, cmp::Ordering, rc::Rc};
 
     use time_test::time_test;
 
     use crate::{
         cmp::{Cmp, DefaultCmp, InternalKeyCmp},
         error::Result,
-        key_types::LookupKey,
+        key_types::{parse_internal_key, LookupKey, ValueType},
         merging_iter::MergingIter,
         options,
-        test_util::{test_iterator_properties, LdbIteratorIter},
-        types::{MAX_SEQUENCE_NUMBER, NUM_LEVELS},
+        table_cache::TableCache,
+        test_util::{
+            test_iterator_properties, test_iterator_seek_past_end, CountingCmp, LdbIteratorIter,
+        },
+        types::{share, MAX_SEQUENCE_NUMBER, NUM_LEVELS},
         version::{
             key_is_after_file, key_is_before_file, some_file_overlaps_range,
-            some_file_overlaps_range_disjoint, testutil::new_file,
+            some_file_overlaps_range_disjoint,
+            testutil::{new_file, write_table},
+            Version,
         },
     };
 
@@ -753,6 +880,10 @@ mod tests {
         let v = make_version().0;
         let iter = v.new_concat_iter(3);
         test_iterator_properties(iter);
+        test_iterator_seek_past_end(
+            v.new_concat_iter(3),
+            LookupKey::new(b"zzz", MAX_SEQUENCE_NUMBER).internal_key(),
+        );
     }
 
     #[test]
@@ -832,6 +963,69 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_version_assume_disjoint_l0() {
+        let opts = options::for_test();
+        let cmps = Rc::new(Cell::new(0));
+        let mut v = Version::new(
+            share(TableCache::new("db", opts.clone(), 100)),
+            Rc::new(Box::new(CountingCmp::new(cmps.clone()))),
+        );
+
+        // Append-only data: every level-0 file contains keys larger than the previous one.
+        let mut keys = vec![];
+        for num in 1..9 {
+            let contents: Vec<(Vec<u8>, Vec<u8>)> = ["a", "b"]
+                .iter()
+                .map(|s| (format!("k{}{}", num, s).into_bytes(), vec![num as u8]))
+                .collect();
+            let contents: Vec<(&[u8], &[u8], ValueType)> = contents
+                .iter()
+                .map(|(k, v)| (k.as_slice(), v.as_slice(), ValueType::TypeValue))
+                .collect();
+            let f = write_table(&opts.env, &contents, 2 * num, num);
+            v.files[0].push(f);
+            keys.extend(contents.iter().map(|c| (c.0.to_vec(), c.1.to_vec())));
+        }
+
+        let count_get_cmps = |v: &Version| {
+            cmps.set(0);
+            for (k, val) in &keys {
+                let (found, _) = v
+                    .get(LookupKey::new(k, MAX_SEQUENCE_NUMBER).internal_key())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(val, &found);
+            }
+            assert!(v
+                .get(LookupKey::new(b"k0", MAX_SEQUENCE_NUMBER).internal_key())
+                .unwrap()
+                .is_none());
+            cmps.get()
+        };
+
+        let overlapping_cmps = count_get_cmps(&v);
+        v.assume_disjoint_l0 = true;
+        let disjoint_cmps = count_get_cmps(&v);
+        assert!(
+            disjoint_cmps < overlapping_cmps,
+            "{} >= {}",
+            disjoint_cmps,
+            overlapping_cmps
+        );
+
+        // Level 0 is iterated using a single concatenating iterator.
+        let iters = v.new_iters().unwrap();
+        assert_eq!(1, iters.len());
+        let icmp: Rc<Box<dyn Cmp>> =
+            Rc::new(Box::new(InternalKeyCmp(Rc::new(Box::new(DefaultCmp)))));
+        let mut miter = MergingIter::new(icmp, iters);
+        let found: Vec<_> = LdbIteratorIter::wrap(&mut miter)
+            .map(|(k, v)| (parse_internal_key(&k).2.to_vec(), v))
+            .collect();
+        assert_eq!(keys, found);
+    }
+
     #[test]
     fn test_version_get_overlapping_basic() {
         let v = make_version().0;
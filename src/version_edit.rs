@@ -8,7 +8,7 @@ use integer_encoding::{VarIntReader, VarIntWriter};
 
 use crate::{
     error::{err, Result, Status, StatusCode},
-    key_types::InternalKey,
+    key_types::{InternalKey, RangeTombstone},
     types::{FileMetaData, FileNum, SequenceNumber},
 };
 
@@ -28,6 +28,11 @@ enum EditTag {
     DeletedFile = 6,
     NewFile = 7,
     PrevLogNumber = 9, // sic!
+    FilterPolicy = 10,
+    RangeTombstone = 11,
+    DeletedRangeTombstone = 12,
+    // Like NewFile, followed by the largest sequence number in the file.
+    NewFileWithSeqno = 13,
 }
 
 fn tag_to_enum(t: u32) -> Option<EditTag> {
@@ -40,6 +45,10 @@ fn tag_to_enum(t: u32) -> Option<EditTag> {
         6 => Some(EditTag::DeletedFile),
         7 => Some(EditTag::NewFile),
         9 => Some(EditTag::PrevLogNumber),
+        10 => Some(EditTag::FilterPolicy),
+        11 => Some(EditTag::RangeTombstone),
+        12 => Some(EditTag::DeletedRangeTombstone),
+        13 => Some(EditTag::NewFileWithSeqno),
         _ => None,
     }
 }
@@ -63,8 +72,10 @@ fn read_length_prefixed<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
 }
 
 /// Manages changes to the set of managed SSTables and logfiles.
+#[derive(Clone)]
 pub struct VersionEdit {
-    comparator: Option<String>,
+    pub comparator: Option<String>,
+    pub filter_policy: Option<String>,
     pub log_number: Option<FileNum>,
     pub prev_log_number: Option<FileNum>,
     pub next_file_number: Option<FileNum>,
@@ -73,6 +84,9 @@ pub struct VersionEdit {
     pub compaction_ptrs: Vec<CompactionPointer>,
     pub deleted: HashSet<(usize, FileNum)>,
     pub new_files: Vec<(usize, FileMetaData)>,
+    // Range tombstones are identified by their sequence number.
+    pub new_range_tombstones: Vec<RangeTombstone>,
+    pub deleted_range_tombstones: HashSet<SequenceNumber>,
 }
 
 impl Default for VersionEdit {
@@ -85,6 +99,7 @@ impl VersionEdit {
     pub fn new() -> VersionEdit {
         VersionEdit {
             comparator: None,
+            filter_policy: None,
             log_number: None,
             prev_log_number: None,
             next_file_number: None,
@@ -92,6 +107,8 @@ impl VersionEdit {
             compaction_ptrs: Vec::with_capacity(8),
             deleted: HashSet::with_capacity(8),
             new_files: Vec::with_capacity(8),
+            new_range_tombstones: vec![],
+            deleted_range_tombstones: HashSet::new(),
         }
     }
 
@@ -107,10 +124,22 @@ impl VersionEdit {
         self.deleted.insert((level, file_num));
     }
 
+    pub fn add_range_tombstone(&mut self, t: RangeTombstone) {
+        self.new_range_tombstones.push(t)
+    }
+
+    pub fn delete_range_tombstone(&mut self, seq: SequenceNumber) {
+        self.deleted_range_tombstones.insert(seq);
+    }
+
     pub fn set_comparator_name(&mut self, name: &str) {
         self.comparator = Some(name.to_string())
     }
 
+    pub fn set_filter_policy_name(&mut self, name: &str) {
+        self.filter_policy = Some(name.to_string())
+    }
+
     pub fn set_log_num(&mut self, num: u64) {
         self.log_number = Some(num)
     }
@@ -146,6 +175,12 @@ impl VersionEdit {
             buf.write_all(cmp.as_bytes()).unwrap();
         }
 
+        if let Some(ref fpol) = self.filter_policy {
+            buf.write_varint(EditTag::FilterPolicy as u32).unwrap();
+            buf.write_varint(fpol.len()).unwrap();
+            buf.write_all(fpol.as_bytes()).unwrap();
+        }
+
         if let Some(lognum) = self.log_number {
             buf.write_varint(EditTag::LogNumber as u32).unwrap();
             buf.write_varint(lognum).unwrap();
@@ -173,14 +208,23 @@ impl VersionEdit {
             buf.write_all(cptr.key.as_ref()).unwrap();
         }
 
-        for df in self.deleted.iter() {
+        // Sets are written in sorted order, so that encoding the same edit always produces the
+        // same bytes.
+        let mut deleted: Vec<_> = self.deleted.iter().collect();
+        deleted.sort();
+        for df in deleted {
             buf.write_varint(EditTag::DeletedFile as u32).unwrap();
             buf.write_varint(df.0).unwrap();
             buf.write_varint(df.1).unwrap();
         }
 
         for nf in self.new_files.iter() {
-            buf.write_varint(EditTag::NewFile as u32).unwrap();
+            // Files without a known sequence number are written in the original format.
+            if nf.1.largest_seqno > 0 {
+                buf.write_varint(EditTag::NewFileWithSeqno as u32).unwrap();
+            } else {
+                buf.write_varint(EditTag::NewFile as u32).unwrap();
+            }
             buf.write_varint(nf.0).unwrap();
             buf.write_varint(nf.1.num).unwrap();
             buf.write_varint(nf.1.size).unwrap();
@@ -189,6 +233,26 @@ impl VersionEdit {
             buf.write_all(nf.1.smallest.as_ref()).unwrap();
             buf.write_varint(nf.1.largest.len()).unwrap();
             buf.write_all(nf.1.largest.as_ref()).unwrap();
+            if nf.1.largest_seqno > 0 {
+                buf.write_varint(nf.1.largest_seqno).unwrap();
+            }
+        }
+
+        for t in self.new_range_tombstones.iter() {
+            buf.write_varint(EditTag::RangeTombstone as u32).unwrap();
+            buf.write_varint(t.begin.len()).unwrap();
+            buf.write_all(&t.begin).unwrap();
+            buf.write_varint(t.end.len()).unwrap();
+            buf.write_all(&t.end).unwrap();
+            buf.write_varint(t.seq).unwrap();
+        }
+
+        let mut deleted_range_tombstones: Vec<_> = self.deleted_range_tombstones.iter().collect();
+        deleted_range_tombstones.sort();
+        for seq in deleted_range_tombstones {
+            buf.write_varint(EditTag::DeletedRangeTombstone as u32)
+                .unwrap();
+            buf.write_varint(*seq).unwrap();
         }
 
         buf
@@ -210,6 +274,15 @@ impl VersionEdit {
                         }
                     }
 
+                    EditTag::FilterPolicy => {
+                        let buf = read_length_prefixed(&mut reader)?;
+                        if let Ok(f) = String::from_utf8(buf) {
+                            ve.filter_policy = Some(f);
+                        } else {
+                            return err(StatusCode::Corruption, "Bad filter policy encoding");
+                        }
+                    }
+
                     EditTag::LogNumber => {
                         if let Ok(ln) = reader.read_varint() {
                             ve.log_number = Some(ln);
@@ -269,12 +342,23 @@ impl VersionEdit {
                         }
                     }
 
-                    EditTag::NewFile => {
+                    EditTag::NewFile | EditTag::NewFileWithSeqno => {
+                        let with_seqno = matches!(tag, EditTag::NewFileWithSeqno);
                         if let Ok(lvl) = reader.read_varint() {
                             if let Ok(num) = reader.read_varint() {
                                 if let Ok(size) = reader.read_varint() {
                                     let smallest = read_length_prefixed(&mut reader).unwrap();
                                     let largest = read_length_prefixed(&mut reader).unwrap();
+                                    let largest_seqno = if !with_seqno {
+                                        0
+                                    } else if let Ok(seqno) = reader.read_varint() {
+                                        seqno
+                                    } else {
+                                        return err(
+                                            StatusCode::IOError,
+                                            "Couldn't read largest sequence number",
+                                        );
+                                    };
                                     ve.new_files.push((
                                         lvl,
                                         FileMetaData {
@@ -282,6 +366,7 @@ impl VersionEdit {
                                             size,
                                             smallest,
                                             largest,
+                                            largest_seqno,
                                             allowed_seeks: 0,
                                         },
                                     ))
@@ -295,6 +380,25 @@ impl VersionEdit {
                             return err(StatusCode::IOError, "Couldn't read file level");
                         }
                     }
+
+                    EditTag::RangeTombstone => {
+                        let begin = read_length_prefixed(&mut reader)?;
+                        let end = read_length_prefixed(&mut reader)?;
+                        if let Ok(seq) = reader.read_varint() {
+                            ve.new_range_tombstones
+                                .push(RangeTombstone { begin, end, seq });
+                        } else {
+                            return err(StatusCode::IOError, "Couldn't read tombstone sequence");
+                        }
+                    }
+
+                    EditTag::DeletedRangeTombstone => {
+                        if let Ok(seq) = reader.read_varint() {
+                            ve.deleted_range_tombstones.insert(seq);
+                        } else {
+                            return err(StatusCode::IOError, "Couldn't read tombstone sequence");
+                        }
+                    }
                 }
             } else {
                 return err(
@@ -310,7 +414,7 @@ impl VersionEdit {
 
 #[cfg(test)]
 mod tests {
-    use crate::types::FileMetaData;
+    use crate::{key_types::RangeTombstone, types::FileMetaData};
 
     use super::CompactionPointer;
     use super::VersionEdit;
@@ -320,6 +424,7 @@ mod tests {
         let mut ve = VersionEdit::new();
 
         ve.set_comparator_name("abcdef");
+        ve.set_filter_policy_name("leveldb.BuiltinBloomFilter2");
         ve.set_log_num(123);
         ve.set_next_file(456);
         ve.set_compact_pointer(0, &[0, 1, 2]);
@@ -333,15 +438,37 @@ mod tests {
                 size: 234,
                 smallest: vec![5, 6, 7],
                 largest: vec![8, 9, 0],
+                largest_seqno: 0,
+            },
+        );
+        ve.add_file(
+            1,
+            FileMetaData {
+                allowed_seeks: 0,
+                num: 902,
+                size: 345,
+                smallest: vec![1, 2, 3],
+                largest: vec![4, 5, 6],
+                largest_seqno: 4321,
             },
         );
         ve.delete_file(1, 132);
+        ve.add_range_tombstone(RangeTombstone {
+            begin: b"k20".to_vec(),
+            end: b"k80".to_vec(),
+            seq: 77,
+        });
+        ve.delete_range_tombstone(66);
 
         let encoded = ve.encode();
 
         let decoded = VersionEdit::decode_from(encoded.as_ref()).unwrap();
 
         assert_eq!(decoded.comparator, Some("abcdef".to_string()));
+        assert_eq!(
+            decoded.filter_policy,
+            Some("leveldb.BuiltinBloomFilter2".to_string())
+        );
         assert_eq!(decoded.log_number, Some(123));
         assert_eq!(decoded.next_file_number, Some(456));
         assert_eq!(decoded.compaction_ptrs.len(), 3);
@@ -366,7 +493,7 @@ mod tests {
                 key: vec![6, 7, 8],
             }
         );
-        assert_eq!(decoded.new_files.len(), 1);
+        assert_eq!(decoded.new_files.len(), 2);
         assert_eq!(
             decoded.new_files[0],
             (
@@ -377,10 +504,68 @@ mod tests {
                     size: 234,
                     smallest: vec![5, 6, 7],
                     largest: vec![8, 9, 0],
+                    largest_seqno: 0,
                 }
             )
         );
+        assert_eq!(4321, decoded.new_files[1].1.largest_seqno);
         assert_eq!(decoded.deleted.len(), 1);
         assert!(decoded.deleted.contains(&(1, 132)));
+        assert_eq!(
+            decoded.new_range_tombstones,
+            vec![RangeTombstone {
+                begin: b"k20".to_vec(),
+                end: b"k80".to_vec(),
+                seq: 77,
+            }]
+        );
+        assert_eq!(decoded.deleted_range_tombstones.len(), 1);
+        assert!(decoded.deleted_range_tombstones.contains(&66));
+    }
+
+    #[test]
+    fn test_version_edit_round_trip() {
+        let mut ve = VersionEdit::new();
+        ve.set_comparator_name("leveldb.BytewiseComparator");
+        ve.set_log_num(10);
+        ve.set_prev_log_num(9);
+        ve.set_next_file(20);
+        ve.set_last_seq(1000);
+        ve.set_compact_pointer(3, &[1, 2, 3]);
+        for i in 0..5u64 {
+            ve.add_file(
+                i as usize % 3,
+                FileMetaData {
+                    num: 11 + i,
+                    size: 100 * i as usize,
+                    smallest: vec![i as u8; 9],
+                    largest: vec![i as u8 + 1; 9],
+                    largest_seqno: 100 * i,
+                    ..Default::default()
+                },
+            );
+        }
+        for (level, num) in [(0, 3), (2, 5), (1, 4), (0, 7), (6, 8)] {
+            ve.delete_file(level, num);
+        }
+        for seq in [30, 10, 20] {
+            ve.delete_range_tombstone(seq);
+        }
+
+        let encoded = ve.encode();
+        let decoded = VersionEdit::decode_from(&encoded).unwrap();
+        assert_eq!(encoded, decoded.encode());
+
+        assert_eq!(Some(10), decoded.log_number);
+        assert_eq!(Some(9), decoded.prev_log_number);
+        assert_eq!(Some(20), decoded.next_file_number);
+        assert_eq!(Some(1000), decoded.last_seq);
+        assert_eq!(ve.compaction_ptrs, decoded.compaction_ptrs);
+        assert_eq!(ve.new_files, decoded.new_files);
+        assert_eq!(ve.deleted, decoded.deleted);
+        assert_eq!(
+            ve.deleted_range_tombstones,
+            decoded.deleted_range_tombstones
+        );
     }
 }
@@ -2,24 +2,27 @@ use crate::{
     cmp::{Cmp, InternalKeyCmp},
     env::Env,
     error::{err, Result, StatusCode},
-    key_types::{parse_internal_key, InternalKey, UserKey},
+    key_types::{parse_internal_key, InternalKey, RangeTombstone, UserKey},
     log::{LogReader, LogWriter},
     merging_iter::MergingIter,
     options::Options,
     table_cache::TableCache,
     types::{
-        parse_file_name, share, FileMetaData, FileNum, FileType, LdbIterator, Shared, NUM_LEVELS,
+        parse_file_name, share, FileMetaData, FileNum, FileType, LdbIterator, SequenceNumber,
+        Shared, NUM_LEVELS,
     },
     version::{new_version_iter, total_size, FileMetaHandle, Version},
     version_edit::VersionEdit,
 };
 
+use std::cell::RefCell;
 use std::collections::HashSet;
 use std::io::Write;
 use std::path::Path;
-use std::rc::Rc;
+use std::rc::{Rc, Weak};
 use std::{cmp::Ordering, path::PathBuf};
 
+#[derive(Clone)]
 pub struct Compaction {
     level: usize,
     max_file_size: usize,
@@ -180,6 +183,9 @@ pub struct VersionSet {
     pub prev_log_num: u64,
 
     current: Option<Shared<Version>>,
+    // Versions replaced by a newer one, which may still be in use (e.g. by iterators). Their
+    // files are kept until the last reference is dropped.
+    old_versions: Vec<Weak<RefCell<Version>>>,
     compaction_ptrs: [Vec<u8>; NUM_LEVELS],
 
     descriptor_log: Option<LogWriter<Box<dyn Write>>>,
@@ -189,7 +195,9 @@ impl VersionSet {
     // Note: opt.cmp should not contain an InternalKeyCmp at this point, but instead the default or
     // user-supplied one.
     pub fn new<P: AsRef<Path>>(db: P, opt: Options, cache: Shared<TableCache>) -> VersionSet {
-        let v = share(Version::new(cache.clone(), opt.cmp.clone()));
+        let mut v = Version::new(cache.clone(), opt.cmp.clone());
+        v.assume_disjoint_l0 = opt.assume_disjoint_l0;
+        let v = share(v);
         VersionSet {
             dbname: db.as_ref().to_owned(),
             cmp: InternalKeyCmp(opt.cmp.clone()),
@@ -203,6 +211,7 @@ impl VersionSet {
             prev_log_num: 0,
 
             current: Some(v),
+            old_versions: vec![],
             compaction_ptrs: Default::default(),
             descriptor_log: None,
         }
@@ -212,10 +221,12 @@ impl VersionSet {
         self.current.as_ref().unwrap().borrow().level_summary()
     }
 
-    /// live_files return the files that are currently active.
+    /// live_files return the files that are currently active: those of the current version, and
+    /// those of older versions that are still referenced.
     pub fn live_files(&self) -> HashSet<FileNum> {
         let mut files = HashSet::new();
-        if let Some(ref version) = self.current {
+        let old = self.old_versions.iter().filter_map(Weak::upgrade);
+        for version in self.current.iter().cloned().chain(old) {
             for level in 0..NUM_LEVELS {
                 for file in &version.borrow().files[level] {
                     files.insert(file.borrow().num);
@@ -232,7 +243,15 @@ impl VersionSet {
         self.current.as_ref().unwrap().clone()
     }
 
+    /// add_version makes `v` the current version. The previous one is remembered as long as
+    /// it is referenced elsewhere.
     pub fn add_version(&mut self, v: Version) {
+        if let Some(old) = self.current.take() {
+            if Rc::strong_count(&old) > 1 {
+                self.old_versions.push(Rc::downgrade(&old));
+            }
+        }
+        self.old_versions.retain(|v| v.strong_count() > 0);
         self.current = Some(share(v));
     }
 
@@ -261,7 +280,9 @@ impl VersionSet {
         v.compaction_score.unwrap_or(0.0) >= 1.0 || v.file_to_compact.is_some()
     }
 
-    fn approximate_offset(&self, v: &Shared<Version>, key: InternalKey) -> usize {
+    /// approximate_offset returns the approximate number of bytes that the files of version v
+    /// use for keys before key.
+    pub fn approximate_offset(&self, v: &Shared<Version>, key: InternalKey) -> usize {
         let mut offset = 0;
         for level in 0..NUM_LEVELS {
             for f in &v.borrow().files[level] {
@@ -455,6 +476,7 @@ impl VersionSet {
 
         let mut edit = VersionEdit::new();
         edit.set_comparator_name(self.opt.cmp.id());
+        edit.set_filter_policy_name(self.opt.filter_policy.name());
 
         // Save compaction pointers.
         for level in 0..NUM_LEVELS {
@@ -472,6 +494,9 @@ impl VersionSet {
                 edit.add_file(level, f.borrow().clone());
             }
         }
+        for t in &current.range_tombstones {
+            edit.add_range_tombstone(t.clone());
+        }
 
         self.descriptor_log
             .as_mut()
@@ -497,6 +522,7 @@ impl VersionSet {
         edit.set_last_seq(self.last_seq);
 
         let mut v = Version::new(self.cache.clone(), self.opt.cmp.clone());
+        v.assume_disjoint_l0 = self.opt.assume_disjoint_l0;
         {
             let mut builder = Builder::new();
             builder.apply(&edit, &mut self.compaction_ptrs);
@@ -509,6 +535,7 @@ impl VersionSet {
             edit.set_next_file(self.next_file_num);
             self.descriptor_log = Some(LogWriter::new(
                 self.opt.env.open_writable_file(Path::new(&descname))?,
+                self.opt.checksum_type,
             ));
             self.write_snapshot()?;
         }
@@ -516,7 +543,7 @@ impl VersionSet {
         let encoded = edit.encode();
         if let Some(ref mut lw) = self.descriptor_log {
             lw.add_record(&encoded)?;
-            lw.flush()?;
+            lw.sync(self.opt.env.as_ref().as_ref())?;
         }
         set_current_file(&self.opt.env, &self.dbname, self.manifest_num)?;
 
@@ -534,7 +561,7 @@ impl VersionSet {
 
         for l in 0..NUM_LEVELS - 1 {
             let score: f64 = if l == 0 {
-                v.files[l].len() as f64 / 4.0
+                v.files[l].len() as f64 / self.opt.l0_compaction_trigger as f64
             } else {
                 let mut max_bytes = 10.0 * f64::from(1 << 20);
                 for _ in 0..l - 1 {
@@ -569,6 +596,7 @@ impl VersionSet {
 
         let descfilename = self.dbname.join(current);
         let mut builder = Builder::new();
+        let mut filter_policy_changed = false;
         {
             let mut descfile = self
                 .opt
@@ -578,18 +606,18 @@ impl VersionSet {
                 &mut descfile,
                 // checksum=
                 true,
+                self.opt.checksum_type,
             );
 
             let mut log_number = None;
             let mut prev_log_number = None;
             let mut next_file_number = None;
             let mut last_seq = None;
+            let mut filter_policy = None;
+            let mut comparator = None;
 
             let mut buf = Vec::new();
-            while let Ok(size) = logreader.read(&mut buf) {
-                if size == 0 {
-                    break;
-                }
+            while let Ok(true) = logreader.read_record(&mut buf) {
                 let edit = VersionEdit::decode_from(&buf)?;
                 builder.apply(&edit, &mut self.compaction_ptrs);
                 if let Some(ln) = edit.log_number {
@@ -604,6 +632,43 @@ impl VersionSet {
                 if let Some(pln) = edit.prev_log_number {
                     prev_log_number = Some(pln);
                 }
+                if edit.filter_policy.is_some() {
+                    filter_policy = edit.filter_policy;
+                }
+                if edit.comparator.is_some() {
+                    comparator = edit.comparator;
+                }
+            }
+
+            // Unlike the filter policy, the comparator determines the order of keys in all
+            // existing files, so reading them with a different one would return garbage.
+            if let Some(ref cmp) = comparator {
+                if cmp != self.opt.cmp.id() {
+                    return err(
+                        StatusCode::InvalidArgument,
+                        &format!(
+                            "comparator mismatch: database uses {}, but {} was supplied",
+                            cmp,
+                            self.opt.cmp.id()
+                        ),
+                    );
+                }
+            }
+
+            // Tables written with another filter policy can still be read, just without using
+            // their filters. Newly written tables use the current policy; a new manifest
+            // recording it is written below.
+            if let Some(ref fpol) = filter_policy {
+                if fpol != self.opt.filter_policy.name() {
+                    log!(
+                        self.opt.log,
+                        "warning: filter policy changed from {} to {}; existing tables are read \
+                          without filters until they are compacted",
+                        fpol,
+                        self.opt.filter_policy.name()
+                    );
+                    filter_policy_changed = true;
+                }
             }
 
             if let Some(ln) = log_number {
@@ -640,7 +705,30 @@ impl VersionSet {
         }
 
         let mut v = Version::new(self.cache.clone(), self.opt.cmp.clone());
+        v.assume_disjoint_l0 = self.opt.assume_disjoint_l0;
         builder.save_to(&self.cmp, self.current.as_ref().unwrap(), &mut v);
+
+        // The recorded last sequence number may be stale (e.g. if the log containing the newest
+        // entries is gone); new writes must not reuse sequence numbers stored in tables or range
+        // tombstones, or they would be hidden by older entries.
+        let max_file_seq = v
+            .files
+            .iter()
+            .flatten()
+            .map(|f| f.borrow().largest_seqno)
+            .chain(v.range_tombstones.iter().map(|t| t.seq))
+            .max()
+            .unwrap_or(0);
+        if max_file_seq > self.last_seq {
+            log!(
+                self.opt.log,
+                "manifest last_seq={} is smaller than the largest sequence number in tables ({})",
+                self.last_seq,
+                max_file_seq
+            );
+            self.last_seq = max_file_seq;
+        }
+
         self.finalize(&mut v);
         self.add_version(v);
         self.manifest_num = self.next_file_num - 1;
@@ -656,7 +744,11 @@ impl VersionSet {
             self.last_seq
         );
 
-        // A new manifest needs to be written only if we don't reuse the existing one.
+        // A new manifest needs to be written only if we don't reuse the existing one, or if the
+        // filter policy name has to be updated.
+        if filter_policy_changed {
+            return Ok(true);
+        }
         Ok(!self.reuse_manifest(&descfilename, current))
     }
 
@@ -695,7 +787,7 @@ impl VersionSet {
                 .open_appendable_file(Path::new(current_manifest_path));
             if let Ok(f) = s {
                 log!(self.opt.log, "reusing manifest {:?}", current_manifest_path);
-                self.descriptor_log = Some(LogWriter::new(f));
+                self.descriptor_log = Some(LogWriter::new(f, self.opt.checksum_type));
                 self.manifest_num = num;
                 return true;
             } else {
@@ -748,6 +840,9 @@ struct Builder {
     // (added, deleted) files per level.
     deleted: [Vec<FileNum>; NUM_LEVELS],
     added: [Vec<FileMetaHandle>; NUM_LEVELS],
+    // (added, deleted) range tombstones.
+    added_tombstones: Vec<RangeTombstone>,
+    deleted_tombstones: HashSet<SequenceNumber>,
 }
 
 impl Builder {
@@ -755,6 +850,8 @@ impl Builder {
         Builder {
             deleted: Default::default(),
             added: Default::default(),
+            added_tombstones: vec![],
+            deleted_tombstones: HashSet::new(),
         }
     }
 
@@ -780,6 +877,10 @@ impl Builder {
                 .collect();
             self.added[level].push(share(f));
         }
+        self.added_tombstones
+            .extend(edit.new_range_tombstones.iter().cloned());
+        self.deleted_tombstones
+            .extend(edit.deleted_range_tombstones.iter());
     }
 
     // maybe_add_file adds a file f at level to version v, if it's not already marked
@@ -814,6 +915,15 @@ impl Builder {
     /// save_to saves the edits applied to the builder to v, adding all non-deleted files from
     /// Version base to v.
     fn save_to(&mut self, cmp: &InternalKeyCmp, base: &Shared<Version>, v: &mut Version) {
+        v.range_tombstones = base
+            .borrow()
+            .range_tombstones
+            .iter()
+            .chain(self.added_tombstones.iter())
+            .filter(|t| !self.deleted_tombstones.contains(&t.seq))
+            .cloned()
+            .collect();
+
         for level in 0..NUM_LEVELS {
             sort_files_by_smallest(cmp, &mut self.added[level]);
             // The base version should already have sorted files.
@@ -868,9 +978,10 @@ fn current_file_name<P: AsRef<Path>>(dbname: P) -> PathBuf {
 
 #[allow(clippy::borrowed_box)]
 pub fn read_current_file(env: &Box<dyn Env>, dbname: &Path) -> Result<String> {
-    let mut current = String::new();
-    let mut f = env.open_sequential_file(Path::new(&current_file_name(dbname)))?;
-    f.read_to_string(&mut current)?;
+    let current = match String::from_utf8(env.read_to_vec(&current_file_name(dbname))?) {
+        Ok(s) => s,
+        Err(_) => return err(StatusCode::Corruption, "current file is not valid UTF-8"),
+    };
     if current.is_empty() || !current.ends_with('\n') {
         return err(
             StatusCode::Corruption,
@@ -894,6 +1005,7 @@ pub fn set_current_file<P: AsRef<Path>>(
         let mut f = env.open_writable_file(Path::new(&tempfile))?;
         f.write_all(manifest_base.as_os_str().as_encoded_bytes())?;
         f.write_all("\n".as_bytes())?;
+        env.sync(f.as_mut())?;
     }
     let currentfile = current_file_name(dbname);
     if let Err(e) = env.rename(Path::new(&tempfile), Path::new(&currentfile)) {
@@ -1121,7 +1233,7 @@ mod tests {
             // Write first manifest to be recovered from.
             let manifest = manifest_file_name("db", 19);
             let mffile = opt.env.open_writable_file(Path::new(&manifest)).unwrap();
-            let mut lw = LogWriter::new(mffile);
+            let mut lw = LogWriter::new(mffile, opt.checksum_type);
             lw.add_record(&ve.encode()).unwrap();
             lw.flush().unwrap();
             set_current_file(opt.env.as_ref(), "db", 19).unwrap();
@@ -1135,7 +1247,8 @@ mod tests {
             assert_eq!(30, vs.last_seq);
             assert_eq!(0, vs.current.as_ref().unwrap().borrow().files[0].len());
             assert_eq!(0, vs.current.as_ref().unwrap().borrow().files[1].len());
-            assert_eq!(35, vs.write_snapshot().unwrap());
+            // Includes the filter policy name.
+            assert_eq!(64, vs.write_snapshot().unwrap());
         }
 
         // Simulate compaction by adding a file.
@@ -1174,10 +1287,30 @@ mod tests {
             // current version.
             assert_eq!(0, vs.current.as_ref().unwrap().borrow().files[0].len());
             assert_eq!(1, vs.current.as_ref().unwrap().borrow().files[1].len());
-            assert_eq!(63, vs.write_snapshot().unwrap());
+            assert_eq!(92, vs.write_snapshot().unwrap());
         }
     }
 
+    #[test]
+    fn test_version_set_live_files_of_old_versions() {
+        let (v, opt) = make_version();
+        let cache = share(TableCache::new("db", opt.clone(), 100));
+        let mut vs = VersionSet::new("db", opt.clone(), cache.clone());
+        vs.add_version(v);
+
+        // Files of a replaced version are live while it is referenced.
+        let pinned = vs.current();
+        vs.add_version(Version::new(cache.clone(), opt.cmp.clone()));
+        assert_eq!(9, vs.live_files().len());
+        vs.add_version(Version::new(cache.clone(), opt.cmp.clone()));
+        assert_eq!(9, vs.live_files().len());
+
+        drop(pinned);
+        assert!(vs.live_files().is_empty());
+        vs.add_version(Version::new(cache, opt.cmp.clone()));
+        assert!(vs.old_versions.is_empty());
+    }
+
     #[test]
     fn test_version_set_utils() {
         let (v, opt) = make_version();
@@ -19,6 +19,8 @@ const HEADER_SIZE: usize = 12;
 pub struct WriteBatch {
     entries: Vec<u8>,
     sync: bool,
+    // The count is stored as a fixed32 in the header; this is the largest count we accept.
+    max_count: u32,
 }
 
 impl Default for WriteBatch {
@@ -35,6 +37,7 @@ impl WriteBatch {
         WriteBatch {
             entries: v,
             sync: false,
+            max_count: u32::MAX,
         }
     }
 
@@ -43,6 +46,11 @@ impl WriteBatch {
         self.sync = sync;
     }
 
+    /// is_sync returns whether a flush was requested with `set_sync()`.
+    pub fn is_sync(&self) -> bool {
+        self.sync
+    }
+
     pub fn set_contents(&mut self, from: &[u8]) {
         self.entries.clear();
         self.entries.extend_from_slice(from);
@@ -52,6 +60,7 @@ impl WriteBatch {
         WriteBatch {
             entries: buf,
             sync: false,
+            max_count: u32::MAX,
         }
     }
 
@@ -62,43 +71,98 @@ impl WriteBatch {
     }
 
     /// Adds an entry to a WriteBatch, to be added to the database.
-    #[allow(unused_assignments)]
+    ///
+    /// Panics if the batch already holds the maximum number of operations (`u32::MAX`).
     pub fn put(&mut self, k: &[u8], v: &[u8]) {
-        self.entries
-            .write_all(&[ValueType::TypeValue as u8])
-            .unwrap();
-        let _ = self.entries.write_varint(k.len()).unwrap();
-        self.entries.write_all(k).unwrap();
-        let _ = self.entries.write_varint(v.len()).unwrap();
-        self.entries.write_all(v).unwrap();
-
-        let c = self.count();
-        self.set_count(c + 1);
+        self.put_typed(ValueType::TypeValue, k, v)
     }
 
     /// Marks an entry to be deleted from the database.
+    ///
+    /// Panics if the batch already holds the maximum number of operations (`u32::MAX`).
     pub fn delete(&mut self, k: &[u8]) {
-        let _ = self
-            .entries
-            .write(&[ValueType::TypeDeletion as u8])
-            .unwrap();
+        self.put_typed(ValueType::TypeDeletion, k, b"")
+    }
+
+    /// Marks all entries with keys in [begin, end) to be deleted from the database.
+    ///
+    /// Panics if the batch already holds the maximum number of operations (`u32::MAX`).
+    pub fn delete_range(&mut self, begin: &[u8], end: &[u8]) {
+        self.put_typed(ValueType::TypeRangeDeletion, begin, end)
+    }
+
+    /// Adds a merge operand for `k`, to be combined with the current value by the merge operator
+    /// (see `Options::merge_operator`).
+    ///
+    /// Panics if the batch already holds the maximum number of operations (`u32::MAX`).
+    pub fn merge(&mut self, k: &[u8], v: &[u8]) {
+        self.put_typed(ValueType::TypeMerge, k, v)
+    }
 
+    /// Adds an operation of type `t`. For a deletion, `v` is ignored; for a range deletion, `k`
+    /// and `v` are the beginning and end of the range.
+    ///
+    /// Panics if the batch already holds the maximum number of operations (`u32::MAX`).
+    pub fn put_typed(&mut self, t: ValueType, k: &[u8], v: &[u8]) {
+        let c = self.checked_next_count();
+        self.entries.write_all(&[t as u8]).unwrap();
         self.entries.write_varint(k.len()).unwrap();
         self.entries.write_all(k).unwrap();
+        if t != ValueType::TypeDeletion {
+            self.entries.write_varint(v.len()).unwrap();
+            self.entries.write_all(v).unwrap();
+        }
+
+        self.set_count(c);
+    }
+
+    /// Appends the operations of `other` to this batch. The sequence number and sync flag of this
+    /// batch are kept.
+    ///
+    /// Panics if the combined batch would hold more than `u32::MAX` operations.
+    pub fn append(&mut self, other: &WriteBatch) {
+        let c = self
+            .count()
+            .checked_add(other.count())
+            .filter(|c| *c <= self.max_count)
+            .expect("WriteBatch count overflow");
+        self.entries
+            .extend_from_slice(&other.entries[HEADER_SIZE..]);
+        self.set_count(c);
+    }
 
+    /// checked_next_count returns the count the batch will have after adding one more operation.
+    /// It panics instead of letting the fixed32 count in the header wrap around.
+    fn checked_next_count(&self) -> u32 {
         let c = self.count();
-        self.set_count(c + 1);
+        assert!(
+            c < self.max_count,
+            "WriteBatch count overflow: batch already holds {} operations",
+            c
+        );
+        c + 1
     }
 
-    /// Clear the contents of a WriteBatch
+    /// Clear the contents of a WriteBatch. The header is kept, with sequence and count reset to 0.
     pub fn clear(&mut self) {
-        self.entries.clear()
+        self.entries.clear();
+        self.entries.resize(HEADER_SIZE, 0);
     }
 
     pub fn byte_size(&self) -> usize {
         self.entries.len()
     }
 
+    /// Returns the size of the encoded operations, without the header.
+    pub fn approximate_size(&self) -> usize {
+        self.entries.len().saturating_sub(HEADER_SIZE)
+    }
+
+    /// Returns true if the batch contains no operations.
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
     pub fn set_count(&mut self, c: u32) {
         c.encode_fixed(&mut self.entries[COUNT_OFFSET..COUNT_OFFSET + 4]);
     }
@@ -116,6 +180,8 @@ impl WriteBatch {
         u64::decode_fixed(&self.entries[SEQNUM_OFFSET..SEQNUM_OFFSET + 8]).unwrap()
     }
 
+    /// Returns an iterator over the puts and deletions in this batch. Range deletions and merge
+    /// operands are skipped.
     pub fn iter(&self) -> WriteBatchIter {
         WriteBatchIter {
             batch: self,
@@ -123,16 +189,31 @@ impl WriteBatch {
         }
     }
 
+    /// Returns an iterator over all operations in this batch, including range deletions and merge
+    /// operands, with their type. The value is None for deletions, and the end of the range for
+    /// range deletions.
+    pub fn iter_typed(&self) -> impl Iterator<Item = (ValueType, &[u8], Option<&[u8]>)> {
+        let mut iter = self.iter();
+        std::iter::from_fn(move || {
+            let (typ, k, v) = iter.next_op()?;
+            Some((typ, k, (typ != ValueType::TypeDeletion).then_some(v)))
+        })
+    }
+
     pub fn insert_into_memtable(&self, mut seq: SequenceNumber, mt: &mut MemTable) {
-        for (k, v) in self.iter() {
-            match v {
-                Some(v_) => mt.add(seq, ValueType::TypeValue, k, v_),
-                None => mt.add(seq, ValueType::TypeDeletion, k, b""),
+        let mut iter = self.iter();
+        while let Some((typ, k, v)) = iter.next_op() {
+            match typ {
+                ValueType::TypeValue | ValueType::TypeMerge => mt.add(seq, typ, k, v),
+                ValueType::TypeDeletion => mt.add(seq, typ, k, b""),
+                ValueType::TypeRangeDeletion => mt.add_range_tombstone(seq, k, v),
             }
             seq += 1;
         }
     }
 
+    /// encode sets the sequence number of the batch and returns its serialized form. The count
+    /// is left untouched.
     pub fn encode(&mut self, seq: SequenceNumber) -> Vec<u8> {
         self.set_sequence(seq);
         self.entries.clone()
@@ -144,15 +225,20 @@ pub struct WriteBatchIter<'a> {
     ix: usize,
 }
 
-// The iterator also plays the role of the decoder.
-impl<'a> Iterator for WriteBatchIter<'a> {
-    type Item = (&'a [u8], Option<&'a [u8]>);
-    fn next(&mut self) -> Option<Self::Item> {
+impl<'a> WriteBatchIter<'a> {
+    /// next_op decodes the next operation of any type. The value of a deletion is empty; the
+    /// value of a range deletion is the end of the range.
+    fn next_op(&mut self) -> Option<(ValueType, &'a [u8], &'a [u8])> {
         if self.ix >= self.batch.entries.len() {
             return None;
         }
 
-        let tag = self.batch.entries[self.ix];
+        let typ = match self.batch.entries[self.ix] {
+            0 => ValueType::TypeDeletion,
+            2 => ValueType::TypeRangeDeletion,
+            3 => ValueType::TypeMerge,
+            _ => ValueType::TypeValue,
+        };
         self.ix += 1;
 
         let (klen, l) = usize::decode_var(&self.batch.entries[self.ix..])?;
@@ -160,15 +246,28 @@ impl<'a> Iterator for WriteBatchIter<'a> {
         let k = &self.batch.entries[self.ix..self.ix + klen];
         self.ix += klen;
 
-        if tag == ValueType::TypeValue as u8 {
-            let (vlen, m) = usize::decode_var(&self.batch.entries[self.ix..])?;
-            self.ix += m;
-            let v = &self.batch.entries[self.ix..self.ix + vlen];
-            self.ix += vlen;
+        if typ == ValueType::TypeDeletion {
+            return Some((typ, k, &[]));
+        }
+        let (vlen, m) = usize::decode_var(&self.batch.entries[self.ix..])?;
+        self.ix += m;
+        let v = &self.batch.entries[self.ix..self.ix + vlen];
+        self.ix += vlen;
+
+        Some((typ, k, v))
+    }
+}
 
-            Some((k, Some(v)))
-        } else {
-            Some((k, None))
+// The iterator also plays the role of the decoder.
+impl<'a> Iterator for WriteBatchIter<'a> {
+    type Item = (&'a [u8], Option<&'a [u8]>);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_op()? {
+                (ValueType::TypeValue, k, v) => return Some((k, Some(v))),
+                (ValueType::TypeDeletion, k, _) => return Some((k, None)),
+                (ValueType::TypeRangeDeletion | ValueType::TypeMerge, _, _) => continue,
+            }
         }
     }
 }
@@ -176,6 +275,11 @@ impl<'a> Iterator for WriteBatchIter<'a> {
 #[cfg(test)]
 mod tests {
     use super::WriteBatch;
+    use crate::{
+        key_types::{LookupKey, RangeTombstone, ValueType},
+        memtable::MemTable,
+        options,
+    };
 
     #[test]
     fn test_write_betch() {
@@ -212,4 +316,161 @@ mod tests {
 
         assert_eq!(b.encode(1).len(), 49);
     }
+
+    #[test]
+    fn test_write_batch_delete_range() {
+        let mut b = WriteBatch::new();
+        b.put(b"abc", b"def");
+        b.delete_range(b"abc", b"abz");
+        b.delete(b"xyz");
+        assert_eq!(3, b.count());
+
+        // Range deletions are skipped by iter(), but still take up a sequence number.
+        let ops: Vec<_> = b.iter().collect();
+        assert_eq!(
+            vec![(&b"abc"[..], Some(&b"def"[..])), (&b"xyz"[..], None)],
+            ops
+        );
+
+        let mut mt = MemTable::new(options::for_test().cmp);
+        b.insert_into_memtable(10, &mut mt);
+        assert_eq!(
+            vec![RangeTombstone {
+                begin: b"abc".to_vec(),
+                end: b"abz".to_vec(),
+                seq: 11
+            }],
+            mt.range_tombstones()
+        );
+        assert_eq!((None, true), mt.get(&LookupKey::new(b"xyz", 20)));
+    }
+
+    #[test]
+    fn test_write_batch_put_typed() {
+        let mut b = WriteBatch::new();
+        b.put_typed(ValueType::TypeValue, b"abc", b"def");
+        b.put_typed(ValueType::TypeDeletion, b"abd", b"ignored");
+        b.put_typed(ValueType::TypeRangeDeletion, b"b", b"c");
+        b.put(b"xyz", b"");
+        b.delete(b"xzz");
+        assert_eq!(5, b.count());
+
+        let want = vec![
+            (ValueType::TypeValue, &b"abc"[..], Some(&b"def"[..])),
+            (ValueType::TypeDeletion, &b"abd"[..], None),
+            (ValueType::TypeRangeDeletion, &b"b"[..], Some(&b"c"[..])),
+            (ValueType::TypeValue, &b"xyz"[..], Some(&b""[..])),
+            (ValueType::TypeDeletion, &b"xzz"[..], None),
+        ];
+        assert_eq!(want, b.iter_typed().collect::<Vec<_>>());
+
+        // The encoding is the same as that of the untyped methods.
+        let mut b2 = WriteBatch::new();
+        b2.put(b"abc", b"def");
+        b2.delete(b"abd");
+        b2.delete_range(b"b", b"c");
+        b2.put(b"xyz", b"");
+        b2.delete(b"xzz");
+        assert_eq!(b2.encode(1), b.encode(1));
+
+        let mut b3 = WriteBatch::new();
+        b3.set_contents(&b.encode(7));
+        assert_eq!(want, b3.iter_typed().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_write_batch_encode_keeps_count() {
+        let mut b = WriteBatch::new();
+        b.put(b"abc", b"def");
+        b.delete(b"xyz");
+
+        let enc = b.encode(0xffff_ffff_ffff);
+        assert_eq!(2, b.count());
+        assert_eq!(0xffff_ffff_ffff, b.sequence());
+
+        let mut b2 = WriteBatch::new();
+        b2.set_contents(&enc);
+        assert_eq!(2, b2.count());
+        assert_eq!(0xffff_ffff_ffff, b2.sequence());
+
+        b.clear();
+        assert_eq!(0, b.count());
+        assert_eq!(0, b.sequence());
+        assert_eq!(0, b.iter().count());
+    }
+
+    #[test]
+    #[should_panic(expected = "WriteBatch count overflow")]
+    fn test_write_batch_count_overflow() {
+        let mut b = WriteBatch::new();
+        b.max_count = 3;
+
+        b.put(b"a", b"1");
+        b.put(b"b", b"2");
+        b.delete(b"c");
+        assert_eq!(3, b.count());
+        b.put(b"d", b"4");
+    }
+
+    #[test]
+    fn test_write_batch_append() {
+        let mut b = WriteBatch::new();
+        b.put(b"abc", b"def");
+        b.delete(b"xyz");
+        b.set_sequence(42);
+        b.set_sync(true);
+
+        let mut b2 = WriteBatch::new();
+        b2.put(b"123", b"456");
+        b2.delete(b"abc");
+        b2.set_sequence(7);
+
+        let size = b.byte_size() + b2.byte_size() - 12;
+        b.append(&b2);
+        assert_eq!(4, b.count());
+        assert_eq!(size, b.byte_size());
+        assert_eq!(42, b.sequence());
+        assert!(b.sync);
+        assert_eq!(
+            vec![
+                (&b"abc"[..], Some(&b"def"[..])),
+                (&b"xyz"[..], None),
+                (&b"123"[..], Some(&b"456"[..])),
+                (&b"abc"[..], None),
+            ],
+            b.iter().collect::<Vec<_>>()
+        );
+
+        // Appending an empty batch changes nothing.
+        b.append(&WriteBatch::new());
+        assert_eq!(4, b.count());
+        assert_eq!(size, b.byte_size());
+    }
+
+    #[test]
+    fn test_write_batch_clear_and_reuse() {
+        let mut b = WriteBatch::new();
+        assert!(b.is_empty());
+        assert_eq!(0, b.approximate_size());
+
+        b.put(b"abc", b"def");
+        b.delete(b"xyz");
+        assert!(!b.is_empty());
+        assert_eq!(b.byte_size() - 12, b.approximate_size());
+
+        b.clear();
+        assert!(b.is_empty());
+        assert_eq!(0, b.approximate_size());
+        assert_eq!(12, b.byte_size());
+
+        b.put(b"123", b"456");
+        b.set_sequence(5);
+        assert_eq!(1, b.count());
+        assert_eq!(5, b.sequence());
+        assert_eq!(9, b.approximate_size());
+        assert_eq!(
+            vec![(&b"123"[..], Some(&b"456"[..]))],
+            b.iter().collect::<Vec<_>>()
+        );
+    }
 }
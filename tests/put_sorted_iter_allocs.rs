@@ -0,0 +1,68 @@
+//! Compares the allocations of a bulk load through `DB::put_sorted_iter()` with those of a
+//! `WriteBatch`. This is a separate test binary because it replaces the global allocator.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use my_leveldb::{in_memory, WriteBatch, DB};
+
+/// CountingAlloc counts the allocations made by the current thread.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(|a| a.get());
+    f();
+    ALLOCATIONS.with(|a| a.get()) - before
+}
+
+#[test]
+fn test_put_sorted_iter_allocates_less_than_batch() {
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..100_000)
+        .map(|i| {
+            (
+                format!("key{:08}", i).into_bytes(),
+                format!("val{}", i).into_bytes(),
+            )
+        })
+        .collect();
+
+    let mut db_batch = DB::open("db", in_memory()).unwrap();
+    let batch_allocs = count_allocations(|| {
+        let mut wb = WriteBatch::new();
+        for (k, v) in &entries {
+            wb.put(k, v);
+        }
+        db_batch.write(wb, false).unwrap();
+    });
+
+    let mut db = DB::open("db", in_memory()).unwrap();
+    let sorted_allocs = count_allocations(|| {
+        db.put_sorted_iter(entries.iter().map(|(k, v)| (k, v)))
+            .unwrap();
+    });
+    assert!(
+        sorted_allocs < batch_allocs,
+        "{} >= {}",
+        sorted_allocs,
+        batch_allocs
+    );
+}